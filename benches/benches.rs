@@ -23,6 +23,48 @@ fn pfh_compile_construct(c: &mut Criterion) {
     });
 }
 
+fn pfh_compile_construct_files(c: &mut Criterion) {
+    use papyrus::code::construct_source_files;
+    use papyrus::compile::build_compile_dir;
+
+    let linking = papyrus::linking::LinkingConfiguration::default();
+    let mut map: papyrus::code::ModsMap = vec![
+        ("lib".into(), src_code()),
+        ("test".into(), src_code()),
+        ("test/inner".into(), src_code()),
+        ("test/inner/deep".into(), src_code()),
+    ]
+    .into_iter()
+    .collect();
+
+    let m = map.clone();
+    let l = papyrus::linking::LinkingConfiguration::default();
+    c.bench_function("construct_source_files", move |b| {
+        b.iter(|| construct_source_files(&m, &l, &StaticFiles::new()))
+    });
+
+    let dir = "target/bench/build_compile_dir";
+
+    let m = map.clone();
+    let l = papyrus::linking::LinkingConfiguration::default();
+    c.bench_function("build_compile_dir unchanged", move |b| {
+        b.iter(|| build_compile_dir(dir, &m, &l, &StaticFiles::new()).unwrap())
+    });
+
+    // only the lib module changes between iterations
+    c.bench_function("build_compile_dir lib changed", move |b| {
+        b.iter(|| {
+            let lib = map.get_mut(std::path::Path::new("lib")).unwrap();
+            if lib.stmts.len() > 3 {
+                lib.stmts.pop();
+            } else {
+                lib.stmts.push(lib.stmts[0].clone());
+            }
+            build_compile_dir(dir, &map, &linking, &StaticFiles::new()).unwrap()
+        })
+    });
+}
+
 fn rustfmt(c: &mut Criterion) {
     let code = "let a = 1 ; let b = 2 ; a + b ";
     c.bench_function("format code", move |b| {
@@ -30,7 +72,12 @@ fn rustfmt(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, pfh_compile_construct, rustfmt);
+criterion_group!(
+    benches,
+    pfh_compile_construct,
+    pfh_compile_construct_files,
+    rustfmt
+);
 criterion_main!(benches);
 
 fn src_code() -> SourceCode {
@@ -51,6 +98,7 @@ fn src_code() -> SourceCode {
                 semi: false,
             }]),
         ],
+        vis: String::new(),
     }
 }
 
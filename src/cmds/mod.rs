@@ -26,6 +26,12 @@
 //! reached, which is `Ctrl+d`. Verbatim mode is especially useful for inputing multi-line strings and
 //! if injecting code into the REPL from another program using stdin.
 //!
+//! ## Multi-line Input
+//! Input which is incomplete, such as an unclosed block `{` or a statement ending with a semi-colon,
+//! is edited as a single block. `Up` and `Down` move between the lines of the block (and through the
+//! history when on the first or last line), lines are auto-indented after a `{`, and `Alt+Enter`
+//! inserts a new line without submitting the input.
+//!
 //! ## Mutable Mode
 //! The `mut` command will place the REPL into mutable mode, which makes access to `app_data` a `&mut`
 //! pointer. Mutable mode avoids having state change on each REPL cycle, rather, when in mutable mode,
@@ -42,6 +48,14 @@
 //! glob patterns matching module paths, for example `:mod clear test/**` will clear all inputs under
 //! the module path `test/`. _`:mod clear` clears all previous REPL input in the **current module**._
 //!
+//! ## Dependencies
+//! Crates from `crates.io` can be used by declaring them with `extern crate`. The version and features
+//! can be specified in a trailing comment, such as `extern crate serde; // 1.0 features=derive`. The
+//! comment takes an optional version, followed by `features=a,b`, `default-features=false`,
+//! `git=url`, or `path=dir`. The `dep add` command does the same, for example
+//! `:dep add serde 1.0 --features derive --no-default-features`. Dependencies without a version use
+//! any version (`"*"`).
+//!
 //! ## Static Files
 //! The `static-files` command allows the importing of file-system based rust documents into the REPL
 //! compilation. Rust files must be relative to the REPL working directory, and will be imported using
@@ -59,6 +73,23 @@
 //! used. To recursively add files `**/*.rs` can be used. This applies to removing static files using
 //! the `rm` command.
 //!
+//! ## Types
+//! The `type` command evaluates an expression and shows its type, without keeping the input. For
+//! example `:type out3` shows the type of a previous output.
+//!
+//! ## Exporting
+//! The `export` command writes the REPL code out as a standalone binary crate, such that a
+//! prototyping session can graduate into a real project. `:export path/to/project` writes a
+//! `Cargo.toml` with the referenced crates as dependencies, the `lib` module's statements inside
+//! `main`, other modules as files, and copies over the static files.
+//!
+//! ## Sessions
+//! The `session` command saves and loads the REPL session, which includes the input of every module,
+//! the static files, and linked external libraries. `:session save name` saves the session into the
+//! `sessions` folder of the compilation directory, and `:session load name` will restore it. Sessions
+//! can also be saved to or loaded from a file path, such as `:session save ./exploring.session`. Use
+//! `:session ls` to list the saved sessions.
+//!
 //! # Extending Commands
 //! ## Setup
 //!
@@ -207,6 +238,7 @@
 //! custom-cmds-app [out2]: "hello, world!"
 //! ```
 use super::*;
+use crate::code::CrateType;
 use crate::repl::{Editing, EditingIndex, ReplData};
 use cmdtree::{BuildError, Builder, BuilderChain, Commander};
 use std::{
@@ -242,6 +274,8 @@ pub enum CommandResult<D> {
     EditReplace(EditingIndex, String),
     /// Switch to a module.
     SwitchModule(PathBuf),
+    /// Evaluate an expression to show its type, without keeping the input.
+    TypeOf(String),
     /// Take an action on the `ReplData`.
     ActionOnReplData(ReplDataAction<D>),
     /// Take an action on data `D` and/or `ReplData`.
@@ -291,6 +325,21 @@ fn papyrus_cmdr<D>(
         .add_action("mut", "Begin a mutable block of code", |_, _| {
             CommandResult::BeginMutBlock
         })
+        .add_action(
+            "type",
+            "Show the type of an expression without keeping it. args: expr",
+            |wtr, args| type_of(wtr, args),
+        )
+        .add_action(
+            "persist",
+            "Keep values of previous statements, only evaluating new ones. args: on|off",
+            |wtr, args| persist(wtr, args),
+        )
+        .add_action(
+            "export",
+            "Export the REPL code as a binary crate. args: directory",
+            |wtr, args| export_project(wtr, args),
+        )
         .begin_class("edit", "Edit previous input")
         .begin_class("stmt", "Edit previous statements")
         .add_action(
@@ -317,6 +366,13 @@ fn papyrus_cmdr<D>(
             |wtr, args| clear_modules(args, wtr),
         )
         .end_class()
+        .begin_class("dep", "Handle crate dependencies")
+        .add_action(
+            "add",
+            "Add a crate dependency. args: name [version] [--features a,b] [--no-default-features] [--git url] [--path dir]",
+            |wtr, args| add_dependency(wtr, args),
+        )
+        .end_class()
         .begin_class("static-files", "Handle static files")
         .add_action(
             "add",
@@ -330,6 +386,19 @@ fn papyrus_cmdr<D>(
         )
         .add_action("ls", "List imported static files", |_, _| ls_static_files())
         .end_class()
+        .begin_class("session", "Save and load REPL sessions")
+        .add_action(
+            "save",
+            "Save the session. args: session-name or file-path",
+            |wtr, args| save_session(wtr, args),
+        )
+        .add_action(
+            "load",
+            "Load a session, replacing the current one. args: session-name or file-path",
+            |wtr, args| load_session(wtr, args),
+        )
+        .add_action("ls", "List saved sessions", |_, _| ls_sessions())
+        .end_class()
         .into_commander()
 }
 
@@ -356,7 +425,7 @@ fn make_all_parents(path: &Path) -> Vec<PathBuf> {
         .collect()
 }
 
-fn make_path(path: &str) -> Option<PathBuf> {
+pub(crate) fn make_path(path: &str) -> Option<PathBuf> {
     let path = path.trim();
 
     let path = path.replace(".rs", "").replace("mod", "").replace("-", "_");
@@ -470,6 +539,60 @@ fn clear_modules<D, W: Write>(args: &[&str], mut wtr: W) -> CommandResult<D> {
     }
 }
 
+// ------ DEPENDENCIES ---------------------------------------------------------
+fn add_dependency<D>(wtr: &mut dyn Write, args: &[&str]) -> CommandResult<D> {
+    match parse_dependency_args(args) {
+        Ok(c) => CommandResult::repl_data_fn(move |data, _| {
+            let msg = format!(
+                "added dependency `{} = {}`",
+                c.cargo_name,
+                c.dependency_spec()
+            );
+            data.add_dependency(c.clone());
+            msg
+        }),
+        Err(e) => {
+            writeln!(wtr, "{}", e).ok();
+            CommandResult::Empty
+        }
+    }
+}
+
+/// Builds the crate as `extern crate name; // spec` so the specification is kept in the source.
+fn parse_dependency_args(args: &[&str]) -> Result<CrateType, String> {
+    let name = match args.first() {
+        Some(name) if !name.starts_with('-') => name.replace('-', "_"),
+        _ => return Err("add expects a crate name".to_string()),
+    };
+
+    let mut spec = Vec::new();
+    let mut args = args[1..].iter();
+    while let Some(&arg) = args.next() {
+        let mut value = |key: &str| {
+            args.next()
+                .map(|v| format!("{}={}", key, v))
+                .ok_or_else(|| format!("{} expects a value", arg))
+        };
+        match arg {
+            "--features" => spec.push(value("features")?),
+            "--git" => spec.push(value("git")?),
+            "--path" => spec.push(value("path")?),
+            "--no-default-features" => spec.push("default-features=false".to_string()),
+            x if spec.is_empty() && !x.starts_with('-') => spec.push(x.to_string()),
+            x => return Err(format!("unexpected argument `{}`", x)),
+        }
+    }
+
+    let mut c =
+        CrateType::parse_str(&format!("extern crate {};", name)).map_err(|e| e.to_string())?;
+    if !spec.is_empty() {
+        let spec = spec.join(" ");
+        c.parse_spec_comment(&spec)?;
+        c.src_line = format!("{} // {}", c.src_line, spec);
+    }
+    Ok(c)
+}
+
 // ------ STATIC FILES ---------------------------------------------------------
 fn add_static_file<D>(wtr: &mut dyn Write, args: &[&str]) -> CommandResult<D> {
     if let Some(&path) = args.get(0) {
@@ -530,6 +653,123 @@ fn ls_static_files<D>() -> CommandResult<D> {
     })
 }
 
+// ------ TYPE -----------------------------------------------------------------
+fn type_of<D>(wtr: &mut dyn Write, args: &[&str]) -> CommandResult<D> {
+    if args.is_empty() {
+        writeln!(wtr, "type expects an expression").ok();
+        CommandResult::Empty
+    } else {
+        CommandResult::TypeOf(args.join(" "))
+    }
+}
+
+// ------ PERSIST --------------------------------------------------------------
+fn persist<D>(wtr: &mut dyn Write, args: &[&str]) -> CommandResult<D> {
+    let persistent = match args {
+        ["on"] => true,
+        ["off"] => false,
+        _ => {
+            writeln!(wtr, "persist expects `on` or `off`").ok();
+            return CommandResult::Empty;
+        }
+    };
+    CommandResult::repl_data_fn(move |data, _| {
+        data.with_persistent_evaluation(persistent);
+        if persistent {
+            "persistent evaluation on".to_string()
+        } else {
+            "persistent evaluation off".to_string()
+        }
+    })
+}
+
+// ------ EXPORT ---------------------------------------------------------------
+fn export_project<D>(wtr: &mut dyn Write, args: &[&str]) -> CommandResult<D> {
+    if let Some(&dir) = args.first() {
+        let dir = PathBuf::from(dir);
+        CommandResult::repl_data_fn(move |data, _| match data.export_project(&dir) {
+            Ok(_) => format!("exported project to `{}`", dir.display()),
+            Err(e) => format!("failed to export project: {}", e),
+        })
+    } else {
+        writeln!(wtr, "export expects a directory").ok();
+        CommandResult::Empty
+    }
+}
+
+// ------ SESSIONS -------------------------------------------------------------
+const SESSION_EXT: &str = "session";
+
+/// Sessions are saved in `sessions` in the compilation directory.
+fn sessions_dir<D>(data: &ReplData<D>) -> PathBuf {
+    data.compilation_dir().join("sessions")
+}
+
+/// A plain name is saved into the sessions directory, otherwise the argument is a file path.
+fn session_path<D>(data: &ReplData<D>, arg: &str) -> PathBuf {
+    let path = Path::new(arg);
+    if path.components().count() == 1 && path.extension().is_none() {
+        sessions_dir(data).join(arg).with_extension(SESSION_EXT)
+    } else {
+        path.to_path_buf()
+    }
+}
+
+fn save_session<D>(wtr: &mut dyn Write, args: &[&str]) -> CommandResult<D> {
+    if let Some(&arg) = args.first() {
+        let arg = arg.to_string();
+        CommandResult::repl_data_fn(move |data, _| {
+            let path = session_path(data, &arg);
+            match data.save_session(&path) {
+                Ok(_) => format!("saved session to `{}`", path.display()),
+                Err(e) => format!("failed to save session: {}", e),
+            }
+        })
+    } else {
+        writeln!(wtr, "save expects a session name or file path").ok();
+        CommandResult::Empty
+    }
+}
+
+fn load_session<D>(wtr: &mut dyn Write, args: &[&str]) -> CommandResult<D> {
+    if let Some(&arg) = args.first() {
+        let arg = arg.to_string();
+        CommandResult::repl_data_fn(move |data, _| {
+            let path = session_path(data, &arg);
+            match data.load_session(&path) {
+                Ok(_) => format!("loaded session from `{}`", path.display()),
+                Err(e) => format!("failed to load session: {}", e),
+            }
+        })
+    } else {
+        writeln!(wtr, "load expects a session name or file path").ok();
+        CommandResult::Empty
+    }
+}
+
+fn ls_sessions<D>() -> CommandResult<D> {
+    CommandResult::repl_data_fn(|data, wtr| {
+        let mut names: Vec<_> = fs::read_dir(sessions_dir(data))
+            .into_iter()
+            .flatten()
+            .filter_map(Result::ok)
+            .map(|e| e.path())
+            .filter(|p| p.extension().map(|x| x == SESSION_EXT).unwrap_or(false))
+            .filter_map(|p| p.file_stem().map(|x| x.to_string_lossy().into_owned()))
+            .collect();
+        names.sort();
+
+        if names.is_empty() {
+            writeln!(wtr, "no saved sessions").ok();
+        } else {
+            for name in names {
+                writeln!(wtr, "{}", name).ok();
+            }
+        }
+        String::new()
+    })
+}
+
 fn foreach_glob_path<F>(glob: &str, wtr: &mut dyn Write, mut f: F)
 where
     F: FnMut(PathBuf, &mut dyn Write),
@@ -625,4 +865,155 @@ mod tests {
         buf.clear();
         rm_static_file::<()>(&mut buf, &["what"]);
     }
+
+    #[test]
+    fn test_dep_interface() {
+        let mut buf = Vec::new();
+        add_dependency::<()>(&mut buf, &[]);
+        add_dependency::<()>(&mut buf, &["serde", "1.0", "--features"]);
+        add_dependency::<()>(&mut buf, &["serde", "--what"]);
+        add_dependency::<()>(&mut buf, &["serde", "new"]);
+        assert_eq!(
+            std::str::from_utf8(&buf).unwrap(),
+            "add expects a crate name
+--features expects a value
+unexpected argument `--what`
+unexpected `new` in dependency specification
+"
+        );
+
+        let c = parse_dependency_args(&[
+            "serde-json",
+            "1.0",
+            "--features",
+            "raw_value,std",
+            "--no-default-features",
+        ])
+        .unwrap();
+        assert_eq!(c.cargo_name, "serde-json");
+        assert_eq!(
+            c.src_line,
+            "extern crate serde_json; // 1.0 features=raw_value,std default-features=false"
+        );
+        assert_eq!(
+            c.dependency_spec(),
+            r#"{ version = "1.0", features = ["raw_value", "std"], default-features = false }"#
+        );
+        assert_eq!(CrateType::parse_str(&c.src_line), Ok(c));
+
+        let mut data = ReplData::<()>::default();
+        let mut add = |args: &[&str]| match add_dependency(&mut buf, args) {
+            CommandResult::ActionOnReplData(f) => f(&mut data, &mut Vec::new()),
+            _ => unreachable!(),
+        };
+        assert_eq!(add(&["rand"]), r#"added dependency `rand = "*"`"#);
+        assert_eq!(
+            add(&["rand", "--git", "https://github.com/rust-random/rand"]),
+            r#"added dependency `rand = { git = "https://github.com/rust-random/rand" }`"#
+        );
+        assert_eq!(data.current_src().crates.len(), 1);
+        assert!(data.current_src().crates[0].source.is_some());
+    }
+
+    #[test]
+    fn test_type_interface() {
+        let mut buf = Vec::new();
+        assert!(matches!(type_of::<()>(&mut buf, &[]), CommandResult::Empty));
+        assert_eq!(
+            std::str::from_utf8(&buf).unwrap(),
+            "type expects an expression\n"
+        );
+
+        match type_of::<()>(&mut buf, &["vec![1,", "2]"]) {
+            CommandResult::TypeOf(expr) => assert_eq!(expr, "vec![1, 2]"),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_persist_interface() {
+        let mut buf = Vec::new();
+        assert!(matches!(persist::<()>(&mut buf, &[]), CommandResult::Empty));
+        assert!(matches!(
+            persist::<()>(&mut buf, &["yes"]),
+            CommandResult::Empty
+        ));
+        assert_eq!(
+            std::str::from_utf8(&buf).unwrap(),
+            "persist expects `on` or `off`\npersist expects `on` or `off`\n"
+        );
+
+        let mut data = ReplData::<()>::default();
+        match persist::<()>(&mut buf, &["on"]) {
+            CommandResult::ActionOnReplData(f) => {
+                assert_eq!(f(&mut data, &mut buf), "persistent evaluation on")
+            }
+            _ => unreachable!(),
+        }
+        assert!(data.persistent_evaluation());
+        match persist::<()>(&mut buf, &["off"]) {
+            CommandResult::ActionOnReplData(f) => {
+                assert_eq!(f(&mut data, &mut buf), "persistent evaluation off")
+            }
+            _ => unreachable!(),
+        }
+        assert!(!data.persistent_evaluation());
+    }
+
+    #[test]
+    fn test_session_interface() {
+        let mut buf = Vec::new();
+        save_session::<()>(&mut buf, &[]);
+        load_session::<()>(&mut buf, &[]);
+        assert_eq!(
+            std::str::from_utf8(&buf).unwrap(),
+            "save expects a session name or file path\nload expects a session name or file path\n"
+        );
+
+        let mut data = ReplData::<()>::default();
+        data.with_compilation_dir("target/testing/session-cmds")
+            .unwrap();
+        fs::remove_dir_all("target/testing/session-cmds/sessions").ok();
+        assert_eq!(
+            session_path(&data, "yesterday"),
+            Path::new("target/testing/session-cmds/sessions/yesterday.session")
+        );
+        assert_eq!(
+            session_path(&data, "some/file.txt"),
+            Path::new("some/file.txt")
+        );
+        assert_eq!(session_path(&data, "file.txt"), Path::new("file.txt"));
+
+        let run = |data: &mut ReplData<()>, r: CommandResult<()>| {
+            let mut buf = Vec::new();
+            let s = match r {
+                CommandResult::ActionOnReplData(f) => f(data, &mut buf),
+                _ => unreachable!(),
+            };
+            (s, String::from_utf8(buf).unwrap())
+        };
+
+        let (_, out) = run(&mut data, ls_sessions());
+        assert_eq!(out, "no saved sessions\n");
+
+        let (s, _) = run(&mut data, save_session(&mut buf, &["yesterday"]));
+        assert_eq!(
+            s,
+            "saved session to `target/testing/session-cmds/sessions/yesterday.session`"
+        );
+        let (s, _) = run(&mut data, load_session(&mut buf, &["yesterday"]));
+        assert_eq!(
+            s,
+            "loaded session from `target/testing/session-cmds/sessions/yesterday.session`"
+        );
+        let (s, _) = run(&mut data, load_session(&mut buf, &["nope"]));
+        assert!(s.starts_with("failed to load session: an io error occurred"));
+
+        let (_, out) = run(&mut data, ls_sessions());
+        assert_eq!(out, "yesterday\n");
+
+        buf.clear();
+        export_project::<()>(&mut buf, &[]);
+        assert_eq!(buf.as_slice(), &b"export expects a directory\n"[..]);
+    }
 }
@@ -71,6 +71,11 @@ pub struct Input {
     pub stmts: Vec<Statement>,
     /// The referenced crates.
     pub crates: Vec<CrateType>,
+    /// Inline module blocks (`mod name { ... }`), as the module visibility, name, and contents.
+    ///
+    /// These are inserted as nested modules in the [`ModsMap`], relative to the current module.
+    /// The visibility is empty for private modules.
+    pub mods: Vec<(String, String, Input)>,
 }
 
 /// The flattened representation of source code.
@@ -83,6 +88,8 @@ pub struct SourceCode {
     pub stmts: Vec<StmtGrp>,
     /// The referenced crates.
     pub crates: Vec<CrateType>,
+    /// The visibility of the module declaration (`pub`, `pub(crate)`, etc.), empty if private.
+    pub vis: String,
 }
 
 impl Default for SourceCode {
@@ -91,6 +98,7 @@ impl Default for SourceCode {
             items: Vec::new(),
             stmts: Vec::new(),
             crates: Vec::new(),
+            vis: String::new(),
         }
     }
 }
@@ -138,7 +146,7 @@ impl StmtGrp {
     }
 
     /// Stringfy's the statements and assigns trailing expressions with `let out# = expr;`.
-    fn assign_let_binding(&self, input_num: usize, buf: &mut String) {
+    pub(crate) fn assign_let_binding(&self, input_num: usize, buf: &mut String) {
         let stmts = &self.0;
 
         for stmt in &stmts[0..stmts.len().saturating_sub(1)] {
@@ -158,6 +166,28 @@ impl StmtGrp {
         }
     }
 
+    /// The names bound by the `let` statements of the group, and the `out#` binding.
+    ///
+    /// Names are unique, in order of first binding.
+    pub(crate) fn bindings(&self, input_num: usize) -> Vec<String> {
+        let mut names = Vec::new();
+        for stmt in self.0.iter().filter(|x| x.expr.starts_with("let")) {
+            if let Ok(syn::Stmt::Local(local)) = syn::parse_str(&format!("{};", stmt.expr)) {
+                pat_bindings(&local.pat, &mut names);
+            }
+        }
+        if !self.0.is_empty() {
+            names.push(format!("out{}", input_num));
+        }
+        let mut unique = Vec::with_capacity(names.len());
+        for name in names {
+            if !unique.contains(&name) {
+                unique.push(name);
+            }
+        }
+        unique
+    }
+
     fn assign_let_binding_length(&self, input_num: usize) -> usize {
         let stmts = &self.0;
         let mut cap = 0;
@@ -179,7 +209,34 @@ impl StmtGrp {
     }
 }
 
+fn pat_bindings(pat: &syn::Pat, names: &mut Vec<String>) {
+    use syn::Pat::*;
+    match pat {
+        Ident(x) => {
+            names.push(x.ident.to_string());
+            if let Some((_, pat)) = &x.subpat {
+                pat_bindings(pat, names);
+            }
+        }
+        Box(x) => pat_bindings(&x.pat, names),
+        Reference(x) => pat_bindings(&x.pat, names),
+        Type(x) => pat_bindings(&x.pat, names),
+        Or(x) => {
+            if let Some(pat) = x.cases.first() {
+                pat_bindings(pat, names);
+            }
+        }
+        Slice(x) => x.elems.iter().for_each(|p| pat_bindings(p, names)),
+        Tuple(x) => x.elems.iter().for_each(|p| pat_bindings(p, names)),
+        TupleStruct(x) => x.pat.elems.iter().for_each(|p| pat_bindings(p, names)),
+        Struct(x) => x.fields.iter().for_each(|f| pat_bindings(&f.pat, names)),
+        _ => (),
+    }
+}
+
 /// Construct a single string containing all the source code in `mods_map`.
+///
+/// The compile directory is written with [`construct_source_files`] instead.
 pub fn construct_source_code<'a>(
     mods_map: &'a ModsMap,
     linking_config: &LinkingConfiguration,
@@ -196,6 +253,12 @@ pub fn construct_source_code<'a>(
         external.construct_code_str(&mut contents);
     }
 
+    contents.push_str(CATCH_FN);
+    contents.push_str(RENDER_MOD);
+    if linking_config.persistent {
+        contents.push_str(PERSIST_FN);
+    }
+
     // do the lib first
     if let Some(lib) = mods_map.get(Path::new("lib")) {
         // add static file links
@@ -232,8 +295,9 @@ pub fn construct_source_code<'a>(
             _ => (),
         }
 
-        contents.push_str("mod ");
-        contents.push_str(
+        push_mod_decl(
+            &mut contents,
+            &src_code.vis,
             file.iter()
                 .last()
                 .and_then(|x| x.to_str())
@@ -267,6 +331,540 @@ pub fn construct_source_code<'a>(
     (contents, map)
 }
 
+/// The source file of a module, relative to the `src` directory of the compile directory.
+///
+/// `lib` is written to `lib.rs`, other modules mirror the module path, such that `a/b` is written
+/// to `a/b.rs`.
+pub fn mod_file_path(path: &Path) -> PathBuf {
+    if path == Path::new("lib") {
+        PathBuf::from("lib.rs")
+    } else {
+        path.with_extension("rs")
+    }
+}
+
+/// The module whose source file from [`construct_source_files`] is `file`, relative to the `src`
+/// directory.
+pub fn file_mod<'a>(mods_map: &'a ModsMap, file: &Path) -> Option<&'a Path> {
+    mods_map
+        .keys()
+        .find(|x| mod_file_path(x) == file)
+        .map(|x| x.as_path())
+}
+
+/// Construct the source code in `mods_map` as one file per module.
+///
+/// Returns the file paths, relative to the `src` directory, with the file contents. `lib.rs`
+/// contains the crate level code and each module declares its child modules with `mod name;`.
+/// The contents of a module's file only depend on that module, so a module which is not evaluated
+/// into keeps the same file and can be reused by incremental compilation.
+pub fn construct_source_files(
+    mods_map: &ModsMap,
+    linking_config: &LinkingConfiguration,
+    static_files: &StaticFiles,
+) -> Vec<(PathBuf, String)> {
+    let lib = Path::new("lib");
+    std::iter::once(lib)
+        .chain(mods_map.keys().map(|x| x.as_path()).filter(|x| *x != lib))
+        .map(|path| {
+            let (contents, _) = construct_mod_file(mods_map, path, linking_config, static_files);
+            (mod_file_path(path), contents)
+        })
+        .collect()
+}
+
+/// Construct the source file of the module at `path`, returning the contents and the return range
+/// of the evaluation function if the module exists.
+fn construct_mod_file(
+    mods_map: &ModsMap,
+    path: &Path,
+    linking_config: &LinkingConfiguration,
+    static_files: &StaticFiles,
+) -> (String, Option<ReturnRange>) {
+    let is_lib = path == Path::new("lib");
+    let no_files = StaticFiles::new();
+    let mut contents = String::new();
+
+    let files = if is_lib {
+        // add in external crates
+        for external in linking_config.external_libs.iter() {
+            external.construct_code_str(&mut contents);
+        }
+
+        contents.push_str(CATCH_FN);
+        contents.push_str(RENDER_MOD);
+        if linking_config.persistent {
+            contents.push_str(PERSIST_FN);
+        }
+
+        // add static file links
+        for n in static_files
+            .iter()
+            .map(|x| x.path.as_path())
+            .filter_map(static_file_mod_name)
+        {
+            contents += "mod ";
+            contents += n;
+            contents += ";\n";
+        }
+
+        &no_files // don't pass through as handled as mods above
+    } else {
+        static_files
+    };
+
+    let rng = mods_map.get(path).map(|src_code| {
+        let mod_path = into_mod_path_vec(path);
+        let start = contents.len();
+        let (_, rng) = append_buffer_length(src_code, &mod_path, linking_config, files);
+        append_buffer(src_code, &mod_path, linking_config, files, &mut contents);
+        rng.start + start..rng.end + start
+    });
+
+    // child modules go last, top placed items such as inner attributes must come first
+    let children = mods_map.keys().filter(|x| {
+        x.as_path() != Path::new("lib")
+            && if is_lib {
+                x.iter().count() == 1
+            } else {
+                x.parent() == Some(path)
+            }
+    });
+    for (child, src_code) in children.filter_map(|x| {
+        let name = x.file_name().and_then(|x| x.to_str())?;
+        Some((name, mods_map.get(x)?))
+    }) {
+        push_mod_decl(&mut contents, &src_code.vis, child);
+        contents += ";\n";
+    }
+
+    (contents, rng)
+}
+
+/// The return type of the evaluation function, which opens the function body.
+const EVAL_FN_RETURN: &str = ") -> Result<(kserd::Kserd<'static>, String), String> {\n";
+/// Statements are evaluated inside a closure, allowing the use of the `?` operator. The closure
+/// is invoked by [`CATCH_FN`].
+const EVAL_CLOSURE_OPEN: &str =
+    "crate::__papyrus_catch(|| -> Result<(kserd::Kserd<'static>, String), Box<dyn std::error::Error>> {\n";
+const EVAL_CLOSURE_CLOSE: &str = "})\n";
+
+/// Invokes the evaluation closure, mapping any error into a string to pass across the library
+/// boundary.
+///
+/// A panic is caught and its details passed back as the error, starting with the
+/// `PANIC_MARKER` of `compile::execute` and followed by the message, file, line, column, and
+/// backtrace separated by `\x1f`.
+///
+/// The library links its own copy of `std`, so its stdout and stderr are flushed here, the host
+/// flushing its own buffers does not reach them.
+///
+/// Thread locals are avoided, the library is unloaded before the thread exits so a thread local
+/// destructor would segfault. This means panics on other threads are not printed while evaluating.
+const CATCH_FN: &str = r#"#[doc(hidden)]
+pub fn __papyrus_catch<F>(eval: F) -> Result<(kserd::Kserd<'static>, String), String>
+where
+    F: FnOnce() -> Result<(kserd::Kserd<'static>, String), Box<dyn std::error::Error>>,
+{
+    static PANIC: std::sync::Mutex<Option<String>> = std::sync::Mutex::new(None);
+    let prev = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|info| {
+        let payload = info.payload();
+        let msg = payload
+            .downcast_ref::<&str>()
+            .map(|x| x.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        let loc = info
+            .location()
+            .map(|x| format!("{}\x1f{}\x1f{}", x.file(), x.line(), x.column()))
+            .unwrap_or_else(|| "\x1f\x1f".to_string());
+        let bt = std::backtrace::Backtrace::capture();
+        let bt = match bt.status() {
+            std::backtrace::BacktraceStatus::Captured => bt.to_string(),
+            _ => String::new(),
+        };
+        *PANIC.lock().unwrap_or_else(|e| e.into_inner()) =
+            Some(format!("{}\x1f{}\x1f{}", msg, loc, bt));
+    }));
+    let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(eval));
+    std::panic::set_hook(prev);
+    std::io::Write::flush(&mut std::io::stdout()).ok();
+    std::io::Write::flush(&mut std::io::stderr()).ok();
+    match r {
+        Ok(r) => r.map_err(|e| e.to_string()),
+        Err(_) => Err(format!(
+            "\x1epapyrus-panic\x1f{}",
+            PANIC.lock().unwrap_or_else(|e| e.into_inner()).take().unwrap_or_default()
+        )),
+    }
+}
+"#;
+
+/// The identity of a rendered output using its `Debug` implementation, the value is the text.
+pub const DEBUG_ID: &str = "fmt::Debug";
+/// The identity of a rendered output using its `Display` implementation, the value is the text.
+pub const DISPLAY_ID: &str = "fmt::Display";
+/// The identity of a rendered output which could not be formatted, the value is the type name.
+pub const TYPE_NAME_ID: &str = "any::type_name";
+
+/// Renders an output into a `Kserd` alongside its type name, preferring `ToKserd`, then `Debug`, then `Display`, and
+/// falling back to the type name.
+///
+/// The implementation is chosen with autoref specialization. The method is called on `Wrap` behind
+/// five references, each rendering trait is implemented at a different depth of references, and
+/// method resolution picks the implementation with the most references that applies. Borrowed
+/// values from a persistent store are cloned to use `ToKserd`.
+const RENDER_MOD: &str = r#"#[doc(hidden)]
+pub mod __papyrus_render {
+    use std::cell::Cell;
+    use std::marker::PhantomData;
+    type Rendered = Result<(kserd::Kserd<'static>, String), Box<dyn std::error::Error>>;
+    pub struct Wrap<'a, T> {
+        value: Cell<Option<T>>,
+        borrowed: bool,
+        kserd: PhantomData<kserd::Kserd<'a>>,
+    }
+    impl<'a, T> Wrap<'a, T> {
+        pub fn new(value: T) -> Self {
+            Wrap { value: Cell::new(Some(value)), borrowed: false, kserd: PhantomData }
+        }
+        pub fn borrowed(value: T) -> Self {
+            Wrap { borrowed: true, ..Self::new(value) }
+        }
+        fn take(&self) -> T {
+            self.value.take().expect("output is rendered once")
+        }
+        fn type_name(&self) -> String {
+            // types of the REPL are shown without the crate name
+            let name = std::any::type_name::<T>().replace(concat!(env!("CARGO_CRATE_NAME"), "::"), "");
+            match name.strip_prefix('&') {
+                Some(name) if self.borrowed => name.to_string(),
+                _ => name,
+            }
+        }
+    }
+    fn text(id: &'static str, text: String, type_name: String) -> Rendered {
+        let mut kserd = kserd::Kserd::new_string(text);
+        // the identity is owned, a borrow of the library's memory dangles once it is unloaded
+        kserd.id = Some(kserd::Kstr::owned(id.to_string()));
+        Ok((kserd, type_name))
+    }
+    pub trait ViaToKserd {
+        fn __papyrus_render(self) -> Rendered;
+    }
+    impl<'a, T: kserd::ToKserd<'a>> ViaToKserd for &&&&&Wrap<'a, T> {
+        fn __papyrus_render(self) -> Rendered {
+            let name = self.type_name();
+            Ok((self.take().into_kserd()?.into_owned(), name))
+        }
+    }
+    pub trait ViaClonedToKserd {
+        fn __papyrus_render(self) -> Rendered;
+    }
+    impl<'a, 'b, T: kserd::ToKserd<'a> + Clone> ViaClonedToKserd for &&&&Wrap<'a, &'b T> {
+        fn __papyrus_render(self) -> Rendered {
+            let name = self.type_name();
+            Ok((Clone::clone(self.take()).into_kserd()?.into_owned(), name))
+        }
+    }
+    pub trait ViaDebug {
+        fn __papyrus_render(self) -> Rendered;
+    }
+    impl<'a, T: std::fmt::Debug> ViaDebug for &&&Wrap<'a, T> {
+        fn __papyrus_render(self) -> Rendered {
+            text("fmt::Debug", format!("{:?}", self.take()), self.type_name())
+        }
+    }
+    pub trait ViaDisplay {
+        fn __papyrus_render(self) -> Rendered;
+    }
+    impl<'a, T: std::fmt::Display> ViaDisplay for &&Wrap<'a, T> {
+        fn __papyrus_render(self) -> Rendered {
+            text("fmt::Display", self.take().to_string(), self.type_name())
+        }
+    }
+    pub trait ViaTypeName {
+        fn __papyrus_render(self) -> Rendered;
+    }
+    impl<'a, T> ViaTypeName for &Wrap<'a, T> {
+        fn __papyrus_render(self) -> Rendered {
+            text("any::type_name", self.type_name(), self.type_name())
+        }
+    }
+}
+"#;
+/// Opens the expression rendering the output, see [`RENDER_MOD`].
+const RENDER_OPEN: &str = "{ use crate::__papyrus_render::*; (&&&&&crate::__papyrus_render::Wrap::";
+/// Closes the expression rendering the output.
+const RENDER_CLOSE: &str = ")).__papyrus_render() }\n";
+
+/// Writes the expression returning the output of the last statement group.
+fn write_return_expr(c: usize, persistent: bool, buf: &mut String) {
+    buf.push_str(RENDER_OPEN);
+    // persisted outputs are borrowed from the store
+    buf.push_str(if persistent {
+        "borrowed(out"
+    } else {
+        "new(out"
+    });
+    buf.push_str(&c.saturating_sub(1).to_string());
+    buf.push_str(RENDER_CLOSE);
+}
+
+/// The length of [`write_return_expr`].
+fn return_expr_length(c: usize, persistent: bool) -> usize {
+    RENDER_OPEN.len()
+        + if persistent { 12 } else { 7 }
+        + c.saturating_sub(1).to_string().len()
+        + RENDER_CLOSE.len()
+}
+
+/// The store argument of the evaluation function when evaluating with a persistent store.
+const STORE_ARG: &str = "mut __store: &mut Vec<Box<dyn std::any::Any + Send>>";
+/// Evaluates and stores a statement group's bindings, or retrieves them if already stored.
+///
+/// The references handed out live as long as the store argument of the evaluation function, they
+/// cannot be moved into a spawned thread, app data, or a `static`, which require `'static`. The
+/// store is only pushed to while evaluating and the values are boxed, so the references stay
+/// valid until the evaluation returns. Only shared references are given out so values cannot be
+/// mutated while referenced.
+const PERSIST_FN: &str = r#"#[doc(hidden)]
+pub fn __papyrus_persist<'s, T, F>(
+    store: &mut &'s mut Vec<Box<dyn std::any::Any + Send>>,
+    idx: usize,
+    eval: F,
+) -> Result<&'s T, Box<dyn std::error::Error>>
+where
+    T: std::any::Any + Send,
+    F: FnOnce() -> Result<T, Box<dyn std::error::Error>>,
+{
+    if store.len() == idx {
+        let value = eval()?;
+        store.push(Box::new(value));
+    }
+    let value = store
+        .get(idx)
+        .and_then(|x| (**x).downcast_ref::<T>())
+        .ok_or("persisted value has changed type")?;
+    // the box is neither moved nor dropped while the store is borrowed for 's
+    Ok(unsafe { &*(value as *const T) })
+}
+"#;
+
+/// Writes the statement groups such that each group is evaluated at most once, its bindings
+/// persisted in the store and then bound by reference for the following groups.
+fn write_persistent_stmts(stmts: &[StmtGrp], buf: &mut String) {
+    for (i, grp) in stmts.iter().enumerate() {
+        let (open, close) = persistent_grp_wrap(grp, i);
+        buf.push_str(&open);
+        grp.assign_let_binding(i, buf);
+        buf.push_str(&close);
+    }
+}
+
+/// The code wrapping a statement group to persist its bindings, as `(open, close)`.
+fn persistent_grp_wrap(grp: &StmtGrp, input_num: usize) -> (String, String) {
+    let mut tuple = String::from("(");
+    for name in grp.bindings(input_num) {
+        tuple.push_str(&name);
+        tuple.push_str(", ");
+    }
+    tuple.push(')');
+
+    let open = format!(
+        "let {} = crate::__papyrus_persist(&mut __store, {}, || -> Result<_, Box<dyn std::error::Error>> {{\n",
+        tuple, input_num
+    );
+    let close = format!("\nOk({})\n}})?;\n", tuple);
+    (open, close)
+}
+
+/// A piece of REPL input in a module's [`SourceCode`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputLoc {
+    /// A statement, indexed by statement group (the `out#` number) and statement within the group.
+    Stmt {
+        /// The statement group index.
+        grp: usize,
+        /// The statement index within the group.
+        stmt: usize,
+    },
+    /// An item, indexed into the module's items.
+    Item(usize),
+}
+
+/// Locates the REPL input that the byte range `span` of a source file from
+/// [`construct_source_files`] falls in.
+///
+/// `file` is relative to the `src` directory. Returns the module, the input, and `span` relative
+/// to the input's code, clamped to it. `None` is returned if `file` is not a module's file or the
+/// span starts in generated code.
+pub fn locate_input<'a>(
+    mods_map: &'a ModsMap,
+    linking_config: &LinkingConfiguration,
+    static_files: &StaticFiles,
+    file: &Path,
+    span: std::ops::Range<usize>,
+) -> Option<(&'a Path, InputLoc, std::ops::Range<usize>)> {
+    let path = file_mod(mods_map, file)?;
+    let src = mods_map.get(path)?;
+    let (_, rng) = construct_mod_file(mods_map, path, linking_config, static_files);
+    let no_files = StaticFiles::new();
+    let files = if path == Path::new("lib") {
+        &no_files // lib links static files as mods instead
+    } else {
+        static_files
+    };
+
+    let (loc, start, len) = input_positions(src, path, linking_config, files, rng?)
+        .into_iter()
+        .find(|(_, start, len)| span.start >= *start && span.start <= start + len)?;
+    let s = span.start - start;
+    let e = span.end.saturating_sub(start).min(len).max(s);
+    Some((path, loc, s..e))
+}
+
+/// Whether the byte range `span` of a source file from [`construct_source_files`] falls in the code
+/// wrapping a statement group to persist its bindings.
+///
+/// `file` is relative to the `src` directory. Spans in REPL input are not in the wrapper.
+pub fn in_persist_wrapper(
+    mods_map: &ModsMap,
+    linking_config: &LinkingConfiguration,
+    static_files: &StaticFiles,
+    file: &Path,
+    span: std::ops::Range<usize>,
+) -> bool {
+    if !linking_config.persistent
+        || locate_input(mods_map, linking_config, static_files, file, span.clone()).is_some()
+    {
+        return false;
+    }
+
+    let path = match file_mod(mods_map, file) {
+        Some(x) => x,
+        None => return false,
+    };
+    let rng = match construct_mod_file(mods_map, path, linking_config, static_files).1 {
+        Some(x) => x,
+        None => return false,
+    };
+
+    let grps = grp_lengths(&mods_map[path], linking_config);
+    let mut start = rng.start - grps.iter().map(|x| x.2 + x.3).sum::<usize>();
+    for (_, _, open, len) in grps {
+        if span.start >= start && span.start < start + open + len {
+            return true;
+        }
+        start += open + len;
+    }
+    false
+}
+
+/// Each statement group with the length of the code before it, and the length of it and the code
+/// after it. This follows the layout of [`append_buffer`].
+fn grp_lengths<'a>(
+    src_code: &'a SourceCode,
+    linking_config: &LinkingConfiguration,
+) -> Vec<(usize, &'a StmtGrp, usize, usize)> {
+    src_code
+        .stmts
+        .iter()
+        .enumerate()
+        .map(|(i, grp)| {
+            let (open, close) = if linking_config.persistent {
+                persistent_grp_wrap(grp, i)
+            } else {
+                (String::new(), String::from("\n"))
+            };
+            (
+                i,
+                grp,
+                open.len(),
+                grp.assign_let_binding_length(i) + close.len(),
+            )
+        })
+        .collect()
+}
+
+/// The start offset and length of each input of a module, given its return range.
+///
+/// This follows the layout of [`append_buffer`].
+fn input_positions(
+    src_code: &SourceCode,
+    path: &Path,
+    linking_config: &LinkingConfiguration,
+    static_files: &StaticFiles,
+    rng: ReturnRange,
+) -> Vec<(InputLoc, usize, usize)> {
+    let mut positions = Vec::new();
+    let c = src_code.stmts.len();
+
+    // statements are directly before the return statement
+    let stmts = grp_lengths(src_code, linking_config);
+    let stmts_len: usize = stmts.iter().map(|x| x.2 + x.3).sum();
+    let stmts_start = rng.start - stmts_len;
+
+    // walk back to the module start
+    let mut start = stmts_start
+        - if c >= 1 { EVAL_CLOSURE_OPEN.len() } else { 0 }
+        - EVAL_FN_RETURN.len()
+        - linking_config.construct_fn_args_length()
+        - 32
+        - eval_fn_name_length(&into_mod_path_vec(path));
+    if linking_config.persistent {
+        start -= STORE_ARG.len()
+            + if linking_config.data_type.is_some() {
+                2
+            } else {
+                0
+            };
+    }
+    start -= static_files
+        .iter()
+        .map(|x| x.path.as_path())
+        .filter_map(static_file_mod_name)
+        .map(|x| x.len() + 13)
+        .sum::<usize>();
+    if !linking_config.persistent_module_code.is_empty() {
+        start -= linking_config.persistent_module_code.len() + 1;
+    }
+    start -= src_code
+        .items
+        .iter()
+        .filter(|x| x.1)
+        .map(|x| x.0.len() + 1)
+        .sum::<usize>();
+
+    for (i, item) in src_code.items.iter().enumerate().filter(|x| (x.1).1) {
+        positions.push((InputLoc::Item(i), start, item.0.len()));
+        start += item.0.len() + 1;
+    }
+
+    let mut start = stmts_start;
+    for (i, grp, open, len) in stmts {
+        let mut pos = start + open;
+        let n = grp.0.len();
+        for (j, stmt) in grp.0.iter().enumerate() {
+            if j + 1 == n {
+                pos += 10 + i.to_string().len(); // let out# =
+            }
+            positions.push((InputLoc::Stmt { grp: i, stmt: j }, pos, stmt.expr.len()));
+            pos += stmt.expr.len() + if stmt.semi { 2 } else { 1 };
+        }
+        start += open + len;
+    }
+
+    let mut start = rng.end + 1 + if c >= 1 { EVAL_CLOSURE_CLOSE.len() } else { 0 } + 2;
+    for (i, item) in src_code.items.iter().enumerate().filter(|x| !(x.1).1) {
+        positions.push((InputLoc::Item(i), start, item.0.len()));
+        start += item.0.len() + 1;
+    }
+
+    positions
+}
+
 /// Constructs the evaluation function name given the mod sequence path.
 /// Appends to the buffer.
 pub fn eval_fn_name<S: AsRef<str>>(mod_path: &[S], buf: &mut String) {
@@ -284,6 +882,59 @@ fn eval_fn_name_length<S: AsRef<str>>(mod_path: &[S]) -> usize {
     12 + mod_path.iter().map(|x| x.as_ref().len() + 1).sum::<usize>()
 }
 
+/// Inserts inline modules relative to the `parent` module, creating them if necessary.
+///
+/// `on_insert` is invoked for each touched module in order, with the previous module, or `None`
+/// if the module was created. A module declared with a visibility takes on that visibility.
+pub(crate) fn insert_mods<F>(
+    mods_map: &mut ModsMap,
+    parent: &Path,
+    mods: Vec<(String, String, Input)>,
+    on_insert: &mut F,
+) where
+    F: FnMut(&Path, Option<&SourceCode>),
+{
+    for (vis, name, input) in mods {
+        let path = if parent == Path::new("lib") {
+            PathBuf::from(name)
+        } else {
+            parent.join(name)
+        };
+
+        on_insert(&path, mods_map.get(&path));
+
+        let Input {
+            items,
+            crates,
+            mods,
+            ..
+        } = input;
+        let src = mods_map.entry(path.clone()).or_default();
+        src.items.extend(items);
+        src.crates.extend(crates);
+        if !vis.is_empty() {
+            src.vis = vis;
+        }
+
+        insert_mods(mods_map, &path, mods, on_insert);
+    }
+}
+
+/// Pushes the declaration of the module `name`, `mod name` prefixed by the visibility.
+pub(crate) fn push_mod_decl(buf: &mut String, vis: &str, name: &str) {
+    if !vis.is_empty() {
+        buf.push_str(vis);
+        buf.push(' ');
+    }
+    buf.push_str("mod ");
+    buf.push_str(name);
+}
+
+fn mod_decl_length(vis: &str, name: &str) -> usize {
+    let vis = if vis.is_empty() { 0 } else { vis.len() + 1 };
+    vis + 4 + name.len()
+}
+
 /// Transforms a path into a vector of components.
 pub fn into_mod_path_vec(path: &Path) -> Vec<&str> {
     path.iter().filter_map(|x| x.to_str()).collect()
@@ -325,6 +976,11 @@ fn calc_capacity<'a>(
         cap += external.construct_code_str_length();
     }
 
+    cap += CATCH_FN.len() + RENDER_MOD.len();
+    if linking_config.persistent {
+        cap += PERSIST_FN.len();
+    }
+
     // do the lib first
     if let Some(lib) = mods_map.get(Path::new("lib")) {
         let static_files_len: usize = static_files
@@ -355,13 +1011,10 @@ fn calc_capacity<'a>(
             _ => (),
         }
 
-        cap += 4; // mod
-        cap += file
-            .iter()
-            .last()
-            .and_then(|x| x.to_str())
-            .map(|x| x.len())
-            .unwrap_or(0);
+        cap += mod_decl_length(
+            &src_code.vis,
+            file.iter().last().and_then(|x| x.to_str()).unwrap_or(""),
+        );
         cap += 3; // }\n
 
         let (src_code_len, src_code_return) = append_buffer_length(
@@ -421,22 +1074,33 @@ fn append_buffer<S: AsRef<str>>(
     buf.push_str("#[no_mangle]\npub extern \"C\" fn "); // 31 len
     eval_fn_name(mod_path, buf);
     buf.push('(');
+    if linking_config.persistent {
+        buf.push_str(STORE_ARG);
+        if linking_config.data_type.is_some() {
+            buf.push_str(", ");
+        }
+    }
     linking_config.construct_fn_args(buf);
-    buf.push_str(") -> kserd::Kserd<'static> {\n"); // 29 len
+    buf.push_str(EVAL_FN_RETURN);
 
     // add stmts
     let c = src_code.stmts.len();
     if c >= 1 {
         // only add statements if more than zero!
-        src_code.stmts.iter().enumerate().for_each(|(i, x)| {
-            x.assign_let_binding(i, buf);
-            buf.push('\n');
-        });
-        buf.push_str("kserd::ToKserd::into_kserd(out");
-        buf.push_str(&c.saturating_sub(1).to_string());
-        buf.push_str(").unwrap().into_owned()\n");
+        // statements are wrapped in a closure such that the `?` operator can be used
+        buf.push_str(EVAL_CLOSURE_OPEN);
+        if linking_config.persistent {
+            write_persistent_stmts(&src_code.stmts, buf);
+        } else {
+            src_code.stmts.iter().enumerate().for_each(|(i, x)| {
+                x.assign_let_binding(i, buf);
+                buf.push('\n');
+            });
+        }
+        write_return_expr(c, linking_config.persistent, buf);
+        buf.push_str(EVAL_CLOSURE_CLOSE);
     } else {
-        buf.push_str("kserd::Kserd::new_str(\"no statements\")\n");
+        buf.push_str("Ok((kserd::Kserd::new_str(\"no statements\"), String::new()))\n");
     }
     buf.push_str("}\n");
 
@@ -474,28 +1138,43 @@ fn append_buffer_length<S: AsRef<str>>(
         .sum::<usize>();
 
     // wrap stmts
-    cap += 31 + eval_fn_name_length(mod_path) + 1 + linking_config.construct_fn_args_length() + 29;
+    cap += 31 + eval_fn_name_length(mod_path) + 1 + linking_config.construct_fn_args_length();
+    if linking_config.persistent {
+        cap += STORE_ARG.len()
+            + if linking_config.data_type.is_some() {
+                2
+            } else {
+                0
+            };
+    }
+    cap += EVAL_FN_RETURN.len();
 
     // add stmts
     let c = src_code.stmts.len();
     let (add, rng) = if c >= 1 {
-        let stmts = src_code
-            .stmts
-            .iter()
-            .enumerate()
-            .map(|(i, x)| x.assign_let_binding_length(i) + 1)
-            .sum::<usize>();
-        let return_str = 30 // kserd::ToKserd::into_kserd(out
-            + c.saturating_sub(1).to_string().len()
-            + 24; // ).unwrap().into_owned()\n
+        let stmts = EVAL_CLOSURE_OPEN.len()
+            + if linking_config.persistent {
+                // binding names require parsing, so just write out the statements
+                let mut buf = String::new();
+                write_persistent_stmts(&src_code.stmts, &mut buf);
+                buf.len()
+            } else {
+                src_code
+                    .stmts
+                    .iter()
+                    .enumerate()
+                    .map(|(i, x)| x.assign_let_binding_length(i) + 1)
+                    .sum::<usize>()
+            };
+        let return_str = return_expr_length(c, linking_config.persistent);
 
         (
-            stmts + return_str,
+            stmts + return_str + EVAL_CLOSURE_CLOSE.len(),
             cap + stmts..cap + stmts + return_str - 1,
         )
     } else {
-        // kserd::Kserd::new_str("no statements")\n
-        (39, cap..cap + 38)
+        // Ok((kserd::Kserd::new_str("no statements"), String::new()))\n
+        (60, cap..cap + 59)
     };
     cap += add + 2; // }\n
 
@@ -531,6 +1210,10 @@ pub struct Statement {
 ///
 /// Crates are parsed and made suitable for `Cargo.toml`. The input line is kept verbatim.
 ///
+/// The dependency specification can be given in a trailing comment, with an optional version
+/// followed by `key=value` options: `features` (comma separated), `default-features`, `git`,
+/// and `path`. A comment which does not follow this syntax is ignored.
+///
 /// # Examples
 /// ```rust
 /// # use papyrus::code::CrateType;
@@ -538,6 +1221,15 @@ pub struct Statement {
 /// let cr = CrateType::parse_str(input).unwrap();
 /// assert_eq!(&cr.src_line, input);
 /// assert_eq!(&cr.cargo_name, "a-crate");
+/// assert_eq!(cr.dependency_spec(), r#""*""#);
+///
+/// let cr = CrateType::parse_str("extern crate serde; // 1.0 features=derive").unwrap();
+/// assert_eq!(cr.version.as_deref(), Some("1.0"));
+/// assert_eq!(cr.features, vec!["derive".to_string()]);
+/// assert_eq!(
+///     cr.dependency_spec(),
+///     r#"{ version = "1.0", features = ["derive"] }"#
+/// );
 /// ```
 #[derive(Clone, Debug, PartialEq)]
 pub struct CrateType {
@@ -547,12 +1239,33 @@ pub struct CrateType {
     /// The name to use in cargo.
     /// Usually `crate_name` will turn into `crate-name`. The default behaviour is to replace `_` with a dash (`-`).
     pub cargo_name: String,
+    /// The version requirement. If `None`, any version (`"*"`) is used.
+    pub version: Option<String>,
+    /// Features to enable.
+    pub features: Vec<String>,
+    /// Flag whether to enable the default features.
+    pub default_features: bool,
+    /// Source of the crate if not from `crates.io`.
+    pub source: Option<CrateSource>,
+}
+
+/// The source of a crate dependency.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CrateSource {
+    /// A git repository url.
+    Git(String),
+    /// A local path to the crate.
+    Path(PathBuf),
 }
 
 impl CrateType {
     /// Parses a string to return the `CrateType`.
     pub fn parse_str(string: &str) -> Result<Self, &'static str> {
-        let line = string
+        let (decl, comment) = match string.find("//") {
+            Some(idx) => (&string[..idx], Some(string[idx + 2..].trim())),
+            None => (string, None),
+        };
+        let line = decl
             .replace(';', "")
             .replace('_', "-")
             .trim()
@@ -561,18 +1274,111 @@ impl CrateType {
             .expect("string should have one line")
             .to_string();
         if line.contains("extern crate ") {
-            Ok(CrateType {
+            let mut c = CrateType {
                 src_line: string.to_string(),
                 cargo_name: line
                     .split(' ')
                     .nth(2)
                     .expect("should always have trailing item")
                     .to_string(),
-            })
+                version: None,
+                features: Vec::new(),
+                default_features: true,
+                source: None,
+            };
+            if let Some(comment) = comment {
+                c.parse_spec_comment(comment).ok(); // not a specification, just a comment
+            }
+            Ok(c)
         } else {
             Err("line needs `extern crate NAME;`")
         }
     }
+
+    /// Parse the dependency specification from a comment such as
+    /// `1.0 features=derive,rc default-features=false`, setting the fields.
+    ///
+    /// If the comment does not parse, no fields are altered.
+    pub fn parse_spec_comment(&mut self, comment: &str) -> Result<(), String> {
+        let mut c = self.clone();
+        for (i, token) in comment.split_whitespace().enumerate() {
+            match token.split_once('=') {
+                Some(("version", v)) => c.version = Some(v.to_string()),
+                Some(("features", v)) => c.features.extend(
+                    v.split(',')
+                        .filter(|x| !x.is_empty())
+                        .map(ToString::to_string),
+                ),
+                Some(("default-features", v)) => {
+                    c.default_features = v
+                        .parse()
+                        .map_err(|_| format!("default-features expects a bool, found `{}`", v))?
+                }
+                Some(("git", v)) => c.source = Some(CrateSource::Git(v.to_string())),
+                Some(("path", v)) => c.source = Some(CrateSource::Path(v.into())),
+                Some((k, _)) => return Err(format!("unknown dependency option `{}`", k)),
+                None if i == 0 && is_version_req(token) => c.version = Some(token.to_string()),
+                None => {
+                    return Err(format!(
+                        "unexpected `{}` in dependency specification",
+                        token
+                    ))
+                }
+            }
+        }
+        *self = c;
+        Ok(())
+    }
+
+    /// The dependency specification to use in `Cargo.toml`.
+    ///
+    /// Only a version renders as a string (`"*"` if there is no version), otherwise an inline
+    /// table is used.
+    pub fn dependency_spec(&self) -> String {
+        let version = self.version.as_deref();
+        if self.features.is_empty() && self.default_features && self.source.is_none() {
+            return toml_str(version.unwrap_or("*"));
+        }
+
+        let mut fields = Vec::new();
+        if let Some(v) = version {
+            fields.push(format!("version = {}", toml_str(v)));
+        }
+        match &self.source {
+            Some(CrateSource::Git(url)) => fields.push(format!("git = {}", toml_str(url))),
+            Some(CrateSource::Path(path)) => {
+                fields.push(format!("path = {}", toml_str(&path.to_string_lossy())))
+            }
+            None if version.is_none() => fields.push(String::from(r#"version = "*""#)),
+            None => (),
+        }
+        if !self.features.is_empty() {
+            let features: Vec<_> = self.features.iter().map(|x| toml_str(x)).collect();
+            fields.push(format!("features = [{}]", features.join(", ")));
+        }
+        if !self.default_features {
+            fields.push(String::from("default-features = false"));
+        }
+
+        format!("{{ {} }}", fields.join(", "))
+    }
+
+    /// Flag whether this crate specifies more than any version.
+    pub fn has_spec(&self) -> bool {
+        self.version.is_some()
+            || !self.features.is_empty()
+            || !self.default_features
+            || self.source.is_some()
+    }
+}
+
+fn is_version_req(s: &str) -> bool {
+    s.starts_with(|c: char| c.is_ascii_digit() || "^~=<>*".contains(c))
+}
+
+/// A basic TOML string, escaping backslashes and quotes.
+fn toml_str(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
 }
 
 // ###### STATIC FILES ###################################################################
@@ -827,6 +1633,10 @@ mod tests {
             Ok(CrateType {
                 src_line: s,
                 cargo_name: String::from("somelib"),
+                version: None,
+                features: Vec::new(),
+                default_features: true,
+                source: None,
             })
         );
 
@@ -837,6 +1647,10 @@ mod tests {
             Ok(CrateType {
                 src_line: s,
                 cargo_name: String::from("some-lib"),
+                version: None,
+                features: Vec::new(),
+                default_features: true,
+                source: None,
             })
         );
 
@@ -847,6 +1661,10 @@ mod tests {
             Ok(CrateType {
                 src_line: s,
                 cargo_name: String::from("some"),
+                version: None,
+                features: Vec::new(),
+                default_features: true,
+                source: None,
             })
         );
 
@@ -857,10 +1675,57 @@ mod tests {
             Ok(CrateType {
                 src_line: s,
                 cargo_name: String::from("some-lib"),
+                version: None,
+                features: Vec::new(),
+                default_features: true,
+                source: None,
             })
         );
     }
 
+    #[test]
+    fn crate_type_spec_test() {
+        let c = CrateType::parse_str("extern crate a_b; // ^0.2").unwrap();
+        assert_eq!(c.cargo_name, "a-b");
+        assert_eq!(c.dependency_spec(), r#""^0.2""#);
+
+        let c = CrateType::parse_str(
+            "extern crate a; // features=x_y, default-features=false git=https://x.com/a.git",
+        )
+        .unwrap();
+        assert_eq!(c.features, vec!["x_y".to_string()]);
+        assert_eq!(
+            c.source,
+            Some(CrateSource::Git("https://x.com/a.git".into()))
+        );
+        assert_eq!(
+            c.dependency_spec(),
+            r#"{ git = "https://x.com/a.git", features = ["x_y"], default-features = false }"#
+        );
+
+        let c = CrateType::parse_str(r#"extern crate a; // 1 path=C:\a"b"#).unwrap();
+        assert_eq!(
+            c.dependency_spec(),
+            r#"{ version = "1", path = "C:\\a\"b" }"#
+        );
+
+        let c = CrateType::parse_str("extern crate a; // default-features=false").unwrap();
+        assert_eq!(
+            c.dependency_spec(),
+            r#"{ version = "*", default-features = false }"#
+        );
+
+        // not a specification
+        let mut c = CrateType::parse_str("extern crate a; // 1.0 is the version").unwrap();
+        assert!(!c.has_spec());
+        assert_eq!(c.dependency_spec(), r#""*""#);
+        assert!(c.parse_spec_comment("1.0 colour=red").is_err());
+        assert!(c.parse_spec_comment("default-features=no").is_err());
+        assert!(!c.has_spec());
+        assert!(c.parse_spec_comment("version=0.1").is_ok());
+        assert_eq!(c.version.as_deref(), Some("0.1"));
+    }
+
     #[test]
     fn assign_let_binding_test() {
         let mut grp = StmtGrp(vec![]);
@@ -904,6 +1769,268 @@ mod tests {
         assert_eq!(grp.assign_let_binding_length(100), ans.len());
     }
 
+    #[test]
+    fn bindings_test() {
+        let grp = StmtGrp(vec![
+            Statement {
+                expr: "let a = 1".to_string(),
+                semi: true,
+            },
+            Statement {
+                expr: "let (b, mut c): (i32, _) = (2, 3)".to_string(),
+                semi: true,
+            },
+            Statement {
+                expr: "let Foo { x, y: ref z @ Some(_), .. } = foo".to_string(),
+                semi: true,
+            },
+            Statement {
+                expr: "let a = [a, b]".to_string(),
+                semi: true,
+            },
+            Statement {
+                expr: "a.len()".to_string(),
+                semi: false,
+            },
+        ]);
+        assert_eq!(grp.bindings(3), ["a", "b", "c", "x", "z", "out3"]);
+        assert!(StmtGrp(vec![]).bindings(0).is_empty());
+    }
+
+    #[test]
+    fn construct_persistent_test() {
+        let mut src_code = SourceCode::default();
+        src_code.stmts.push(StmtGrp(vec![
+            Statement {
+                expr: "let a = 1".to_string(),
+                semi: true,
+            },
+            Statement {
+                expr: "a".to_string(),
+                semi: false,
+            },
+        ]));
+        src_code.stmts.push(StmtGrp(vec![Statement {
+            expr: "out0 + a".to_string(),
+            semi: false,
+        }]));
+        let mod_path: &[&str] = &[];
+        let linking_config = LinkingConfiguration {
+            persistent: true,
+            ..Default::default()
+        };
+
+        let mut s = String::new();
+        append_buffer(
+            &src_code,
+            mod_path,
+            &linking_config,
+            &StaticFiles::new(),
+            &mut s,
+        );
+        let (len, rng) =
+            append_buffer_length(&src_code, mod_path, &linking_config, &StaticFiles::new());
+
+        let ans = r##"#[no_mangle]
+pub extern "C" fn _intern_eval(mut __store: &mut Vec<Box<dyn std::any::Any + Send>>) -> Result<(kserd::Kserd<'static>, String), String> {
+crate::__papyrus_catch(|| -> Result<(kserd::Kserd<'static>, String), Box<dyn std::error::Error>> {
+let (a, out0, ) = crate::__papyrus_persist(&mut __store, 0, || -> Result<_, Box<dyn std::error::Error>> {
+let a = 1;
+let out0 = a;
+Ok((a, out0, ))
+})?;
+let (out1, ) = crate::__papyrus_persist(&mut __store, 1, || -> Result<_, Box<dyn std::error::Error>> {
+let out1 = out0 + a;
+Ok((out1, ))
+})?;
+{ use crate::__papyrus_render::*; (&&&&&crate::__papyrus_render::Wrap::borrowed(out1)).__papyrus_render() }
+})
+}
+"##;
+
+        assert_eq!(&s, ans);
+        assert_eq!(len, ans.len());
+        assert_eq!(
+            &s[rng],
+            "{ use crate::__papyrus_render::*; (&&&&&crate::__papyrus_render::Wrap::borrowed(out1)).__papyrus_render() }"
+        );
+
+        // the helper is added to the top of the library
+        let map = vec![("lib".into(), src_code)].into_iter().collect();
+        let (s, _) = construct_source_code(&map, &linking_config, &StaticFiles::new());
+        assert!(s.starts_with(&format!("{}{}{}", CATCH_FN, RENDER_MOD, PERSIST_FN)));
+    }
+
+    #[test]
+    fn locate_input_test() {
+        let stmt = |expr: &str, semi| Statement {
+            expr: expr.to_string(),
+            semi,
+        };
+        let mut src = SourceCode::default();
+        src.items.push(("use std::fmt;".to_string(), true));
+        src.items.push(("fn foo() -> i32 { 1 }".to_string(), false));
+        src.stmts.push(StmtGrp(vec![
+            stmt("let a = foo()", true),
+            stmt("a + 1", false),
+        ]));
+        src.stmts.push(StmtGrp(vec![stmt("out0 * 2", false)]));
+
+        let mut map = ModsMap::new();
+        map.insert("lib".into(), src.clone());
+        map.insert("inner".into(), src);
+        let mut files = StaticFiles::new();
+        files.insert(StaticFile {
+            path: "file.rs".into(),
+            codehash: Box::new([0; 32]),
+            crates: Vec::new(),
+        });
+
+        for persistent in &[false, true] {
+            let linking = LinkingConfiguration {
+                persistent: *persistent,
+                persistent_module_code: "use std::io;".to_string(),
+                ..Default::default()
+            };
+            let code = construct_source_files(&map, &linking, &files);
+
+            let locate = |file: &str, needle: &str| {
+                let file = Path::new(file);
+                let src = &code.iter().find(|x| x.0 == file).unwrap().1;
+                let start = src.find(needle).unwrap();
+                locate_input(&map, &linking, &files, file, start..start + needle.len())
+            };
+
+            let lib = Path::new("lib");
+            let inner = Path::new("inner");
+            assert_eq!(
+                locate("lib.rs", "use std::fmt;"),
+                Some((lib, InputLoc::Item(0), 0..13))
+            );
+            assert_eq!(
+                locate("lib.rs", "{ 1 }"),
+                Some((lib, InputLoc::Item(1), 16..21))
+            );
+            assert_eq!(
+                locate("lib.rs", "foo()"),
+                Some((lib, InputLoc::Stmt { grp: 0, stmt: 0 }, 8..13))
+            );
+            assert_eq!(
+                locate("lib.rs", "a + 1"),
+                Some((lib, InputLoc::Stmt { grp: 0, stmt: 1 }, 0..5))
+            );
+            assert_eq!(
+                locate("lib.rs", "* 2"),
+                Some((lib, InputLoc::Stmt { grp: 1, stmt: 0 }, 5..8))
+            );
+            assert_eq!(
+                locate("inner.rs", "* 2"),
+                Some((inner, InputLoc::Stmt { grp: 1, stmt: 0 }, 5..8))
+            );
+            assert_eq!(
+                locate("inner.rs", "fn foo"),
+                Some((inner, InputLoc::Item(1), 0..6))
+            );
+            // generated code
+            assert_eq!(locate("lib.rs", "intern_eval"), None);
+            assert_eq!(locate("lib.rs", "mod inner;"), None);
+            // not a module file
+            assert_eq!(
+                locate_input(&map, &linking, &files, Path::new("file.rs"), 0..1),
+                None
+            );
+
+            let wrapper = |file: &str, needle: &str| {
+                let file = Path::new(file);
+                let src = &code.iter().find(|x| x.0 == file).unwrap().1;
+                src.find(needle).is_some_and(|start| {
+                    in_persist_wrapper(&map, &linking, &files, file, start..start + needle.len())
+                })
+            };
+            assert_eq!(wrapper("lib.rs", "crate::__papyrus_persist"), *persistent);
+            assert_eq!(wrapper("inner.rs", "Ok((out1, ))"), *persistent);
+            assert!(!wrapper("lib.rs", "a + 1"));
+            assert!(!wrapper("lib.rs", "intern_eval"));
+            assert!(!wrapper("lib.rs", "__papyrus_render::Wrap"));
+        }
+    }
+
+    #[test]
+    fn construct_source_files_test() {
+        let mut src = SourceCode::default();
+        src.items.push(("#![allow(unused)]".to_string(), true));
+        src.items.push(("fn a() {}".to_string(), false));
+
+        let map: ModsMap = vec![
+            ("lib".into(), SourceCode::default()),
+            ("foo".into(), src),
+            (
+                "foo/bar".into(),
+                SourceCode {
+                    vis: "pub".to_string(),
+                    ..SourceCode::default()
+                },
+            ),
+            ("test".into(), SourceCode::default()),
+        ]
+        .into_iter()
+        .collect();
+        let static_files = vec![StaticFile {
+            path: "file.rs".into(),
+            codehash: Box::new([0; 32]),
+            crates: vec![],
+        }]
+        .into_iter()
+        .collect();
+        let linking = LinkingConfiguration::default();
+
+        let files = construct_source_files(&map, &linking, &static_files);
+        let paths: Vec<_> = files.iter().map(|x| x.0.as_path()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                Path::new("lib.rs"),
+                Path::new("foo.rs"),
+                Path::new("foo/bar.rs"),
+                Path::new("test.rs")
+            ]
+        );
+
+        let no_stmts = r#"Ok((kserd::Kserd::new_str("no statements"), String::new()))"#;
+        assert_eq!(
+            files[0].1,
+            format!(
+                "{}{}mod file;\n#[no_mangle]\npub extern \"C\" fn _lib_intern_eval() -> Result<(kserd::Kserd<'static>, String), String> {{\n{}\n}}\nmod foo;\nmod test;\n",
+                CATCH_FN, RENDER_MOD, no_stmts
+            )
+        );
+        assert_eq!(
+            files[1].1,
+            format!(
+                "#![allow(unused)]\nuse crate::file;\n#[no_mangle]\npub extern \"C\" fn _foo_intern_eval() -> Result<(kserd::Kserd<'static>, String), String> {{\n{}\n}}\nfn a() {{}}\npub mod bar;\n",
+                no_stmts
+            )
+        );
+        assert!(files[2].1.contains("fn _foo_bar_intern_eval()"));
+        assert!(!files[2].1.contains("mod "));
+
+        // the single file source declares the same visibility
+        let (code, _) = construct_source_code(&map, &linking, &static_files);
+        assert!(code.contains("mod foo {\n"));
+        assert!(code.contains("\npub mod bar {\n"));
+
+        // the lib file is always constructed
+        let files = construct_source_files(&ModsMap::new(), &linking, &StaticFiles::new());
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].1, format!("{}{}", CATCH_FN, RENDER_MOD));
+
+        assert_eq!(
+            file_mod(&map, Path::new("foo/bar.rs")),
+            Some(Path::new("foo/bar"))
+        );
+        assert_eq!(file_mod(&map, Path::new("file.rs")), None);
+    }
+
     #[test]
     fn construct_test() {
         use linking::LinkingConfiguration;
@@ -924,14 +2051,17 @@ mod tests {
             append_buffer_length(&src_code, &mod_path, &linking_config, &StaticFiles::new());
 
         let ans = r##"#[no_mangle]
-pub extern "C" fn _intern_eval() -> kserd::Kserd<'static> {
-kserd::Kserd::new_str("no statements")
+pub extern "C" fn _intern_eval() -> Result<(kserd::Kserd<'static>, String), String> {
+Ok((kserd::Kserd::new_str("no statements"), String::new()))
 }
 "##;
         assert_eq!(&s, ans);
         assert_eq!(len, ans.len());
-        assert_eq!(rng, 73..111);
-        assert_eq!(&ans[rng], r#"kserd::Kserd::new_str("no statements")"#);
+        assert_eq!(rng, 99..158);
+        assert_eq!(
+            &ans[rng],
+            r#"Ok((kserd::Kserd::new_str("no statements"), String::new()))"#
+        );
 
         // alter mod path
         let mod_path = ["some".to_string(), "path".to_string()];
@@ -948,14 +2078,17 @@ kserd::Kserd::new_str("no statements")
             append_buffer_length(&src_code, &mod_path, &linking_config, &StaticFiles::new());
 
         let ans = r##"#[no_mangle]
-pub extern "C" fn _some_path_intern_eval() -> kserd::Kserd<'static> {
-kserd::Kserd::new_str("no statements")
+pub extern "C" fn _some_path_intern_eval() -> Result<(kserd::Kserd<'static>, String), String> {
+Ok((kserd::Kserd::new_str("no statements"), String::new()))
 }
 "##;
         assert_eq!(&s, ans);
         assert_eq!(len, ans.len());
-        assert_eq!(rng, 83..121);
-        assert_eq!(&ans[rng], r#"kserd::Kserd::new_str("no statements")"#);
+        assert_eq!(rng, 109..168);
+        assert_eq!(
+            &ans[rng],
+            r#"Ok((kserd::Kserd::new_str("no statements"), String::new()))"#
+        );
 
         // alter the linking config
         let mut linking_config = LinkingConfiguration {
@@ -975,14 +2108,17 @@ kserd::Kserd::new_str("no statements")
             append_buffer_length(&src_code, &mod_path, &linking_config, &StaticFiles::new());
 
         let ans = r##"#[no_mangle]
-pub extern "C" fn _some_path_intern_eval(app_data: &String) -> kserd::Kserd<'static> {
-kserd::Kserd::new_str("no statements")
+pub extern "C" fn _some_path_intern_eval(app_data: &String) -> Result<(kserd::Kserd<'static>, String), String> {
+Ok((kserd::Kserd::new_str("no statements"), String::new()))
 }
 "##;
         assert_eq!(&s, ans);
         assert_eq!(len, ans.len());
-        assert_eq!(rng, 100..138);
-        assert_eq!(&ans[rng], r#"kserd::Kserd::new_str("no statements")"#);
+        assert_eq!(rng, 126..185);
+        assert_eq!(
+            &ans[rng],
+            r#"Ok((kserd::Kserd::new_str("no statements"), String::new()))"#
+        );
 
         // add an item and new input
         src_code.items.push(("fn a() {}".to_string(), false));
@@ -1000,16 +2136,19 @@ kserd::Kserd::new_str("no statements")
             append_buffer_length(&src_code, &mod_path, &linking_config, &StaticFiles::new());
 
         let ans = r##"#[no_mangle]
-pub extern "C" fn _some_path_intern_eval(app_data: &String) -> kserd::Kserd<'static> {
-kserd::Kserd::new_str("no statements")
+pub extern "C" fn _some_path_intern_eval(app_data: &String) -> Result<(kserd::Kserd<'static>, String), String> {
+Ok((kserd::Kserd::new_str("no statements"), String::new()))
 }
 fn a() {}
 fn b() {}
 "##;
         assert_eq!(&s, ans);
         assert_eq!(len, ans.len());
-        assert_eq!(rng, 100..138);
-        assert_eq!(&ans[rng], r#"kserd::Kserd::new_str("no statements")"#);
+        assert_eq!(rng, 126..185);
+        assert_eq!(
+            &ans[rng],
+            r#"Ok((kserd::Kserd::new_str("no statements"), String::new()))"#
+        );
 
         // add stmts
         src_code.stmts.push(StmtGrp(vec![
@@ -1057,22 +2196,24 @@ fn b() {}
         let ans = r##"#![feature(UP_TOP)]
 some-injected-persistent-code
 #[no_mangle]
-pub extern "C" fn _some_path_intern_eval(app_data: &String) -> kserd::Kserd<'static> {
+pub extern "C" fn _some_path_intern_eval(app_data: &String) -> Result<(kserd::Kserd<'static>, String), String> {
+crate::__papyrus_catch(|| -> Result<(kserd::Kserd<'static>, String), Box<dyn std::error::Error>> {
 let a = 1;
 let out0 = b;
 let c = 2;
 let out1 = d;
-kserd::ToKserd::into_kserd(out1).unwrap().into_owned()
+{ use crate::__papyrus_render::*; (&&&&&crate::__papyrus_render::Wrap::new(out1)).__papyrus_render() }
+})
 }
 fn a() {}
 fn b() {}
 "##;
         assert_eq!(&s, ans);
         assert_eq!(len, ans.len());
-        assert_eq!(rng, 200..254);
+        assert_eq!(rng, 325..427);
         assert_eq!(
             &ans[rng],
-            "kserd::ToKserd::into_kserd(out1).unwrap().into_owned()"
+            "{ use crate::__papyrus_render::*; (&&&&&crate::__papyrus_render::Wrap::new(out1)).__papyrus_render() }"
         );
     }
 
@@ -1095,40 +2236,45 @@ fn b() {}
 
         let (s, map) = construct_source_code(&map, &linking, &StaticFiles::new());
 
-        let ans = r##"#[no_mangle]
-pub extern "C" fn _lib_intern_eval() -> kserd::Kserd<'static> {
-kserd::Kserd::new_str("no statements")
+        let ans = &format!(
+            "{}{}{}",
+            CATCH_FN,
+            RENDER_MOD,
+            r##"#[no_mangle]
+pub extern "C" fn _lib_intern_eval() -> Result<(kserd::Kserd<'static>, String), String> {
+Ok((kserd::Kserd::new_str("no statements"), String::new()))
 }
 mod foo {
 #[no_mangle]
-pub extern "C" fn _foo_intern_eval() -> kserd::Kserd<'static> {
-kserd::Kserd::new_str("no statements")
+pub extern "C" fn _foo_intern_eval() -> Result<(kserd::Kserd<'static>, String), String> {
+Ok((kserd::Kserd::new_str("no statements"), String::new()))
 }
 mod bar {
 #[no_mangle]
-pub extern "C" fn _foo_bar_intern_eval() -> kserd::Kserd<'static> {
-kserd::Kserd::new_str("no statements")
+pub extern "C" fn _foo_bar_intern_eval() -> Result<(kserd::Kserd<'static>, String), String> {
+Ok((kserd::Kserd::new_str("no statements"), String::new()))
 }
 }}
 mod test {
 #[no_mangle]
-pub extern "C" fn _test_intern_eval() -> kserd::Kserd<'static> {
-kserd::Kserd::new_str("no statements")
+pub extern "C" fn _test_intern_eval() -> Result<(kserd::Kserd<'static>, String), String> {
+Ok((kserd::Kserd::new_str("no statements"), String::new()))
 }
 mod inner {
 #[no_mangle]
-pub extern "C" fn _test_inner_intern_eval() -> kserd::Kserd<'static> {
-kserd::Kserd::new_str("no statements")
+pub extern "C" fn _test_inner_intern_eval() -> Result<(kserd::Kserd<'static>, String), String> {
+Ok((kserd::Kserd::new_str("no statements"), String::new()))
 }
 }
 mod inner2 {
 #[no_mangle]
-pub extern "C" fn _test_inner2_intern_eval() -> kserd::Kserd<'static> {
-kserd::Kserd::new_str("no statements")
+pub extern "C" fn _test_inner2_intern_eval() -> Result<(kserd::Kserd<'static>, String), String> {
+Ok((kserd::Kserd::new_str("no statements"), String::new()))
 }
-}}"##;
+}}"##
+        );
 
-        let return_stmt = r#"kserd::Kserd::new_str("no statements")"#;
+        let return_stmt = r#"Ok((kserd::Kserd::new_str("no statements"), String::new()))"#;
         assert_eq!(&s, ans);
         assert_eq!(
             &ans[map.get(Path::new("lib")).unwrap().clone()],
@@ -1210,13 +2356,18 @@ kserd::Kserd::new_str("no statements")
 
         let (s, _map) = construct_source_code(&map, &linking, &StaticFiles::new());
 
-        let ans = r##"Up Top
+        let ans = &format!(
+            "{}{}{}",
+            CATCH_FN,
+            RENDER_MOD,
+            r##"Up Top
 #[no_mangle]
-pub extern "C" fn _lib_intern_eval() -> kserd::Kserd<'static> {
-kserd::Kserd::new_str("no statements")
+pub extern "C" fn _lib_intern_eval() -> Result<(kserd::Kserd<'static>, String), String> {
+Ok((kserd::Kserd::new_str("no statements"), String::new()))
 }
 Test1
-"##;
+"##
+        );
         assert_eq!(&s, ans);
     }
 
@@ -1332,52 +2483,57 @@ Test1
 
         let (s, map) = construct_source_code(&map, &linking, &static_files);
 
-        let ans = r##"mod bar2;
+        let ans = &format!(
+            "{}{}{}",
+            CATCH_FN,
+            RENDER_MOD,
+            r##"mod bar2;
 mod foo2;
 #[no_mangle]
-pub extern "C" fn _lib_intern_eval() -> kserd::Kserd<'static> {
-kserd::Kserd::new_str("no statements")
+pub extern "C" fn _lib_intern_eval() -> Result<(kserd::Kserd<'static>, String), String> {
+Ok((kserd::Kserd::new_str("no statements"), String::new()))
 }
 mod foo {
 use crate::bar2;
 use crate::foo2;
 #[no_mangle]
-pub extern "C" fn _foo_intern_eval() -> kserd::Kserd<'static> {
-kserd::Kserd::new_str("no statements")
+pub extern "C" fn _foo_intern_eval() -> Result<(kserd::Kserd<'static>, String), String> {
+Ok((kserd::Kserd::new_str("no statements"), String::new()))
 }
 mod bar {
 use crate::bar2;
 use crate::foo2;
 #[no_mangle]
-pub extern "C" fn _foo_bar_intern_eval() -> kserd::Kserd<'static> {
-kserd::Kserd::new_str("no statements")
+pub extern "C" fn _foo_bar_intern_eval() -> Result<(kserd::Kserd<'static>, String), String> {
+Ok((kserd::Kserd::new_str("no statements"), String::new()))
 }
 }}
 mod test {
 use crate::bar2;
 use crate::foo2;
 #[no_mangle]
-pub extern "C" fn _test_intern_eval() -> kserd::Kserd<'static> {
-kserd::Kserd::new_str("no statements")
+pub extern "C" fn _test_intern_eval() -> Result<(kserd::Kserd<'static>, String), String> {
+Ok((kserd::Kserd::new_str("no statements"), String::new()))
 }
 mod inner {
 use crate::bar2;
 use crate::foo2;
 #[no_mangle]
-pub extern "C" fn _test_inner_intern_eval() -> kserd::Kserd<'static> {
-kserd::Kserd::new_str("no statements")
+pub extern "C" fn _test_inner_intern_eval() -> Result<(kserd::Kserd<'static>, String), String> {
+Ok((kserd::Kserd::new_str("no statements"), String::new()))
 }
 }
 mod inner2 {
 use crate::bar2;
 use crate::foo2;
 #[no_mangle]
-pub extern "C" fn _test_inner2_intern_eval() -> kserd::Kserd<'static> {
-kserd::Kserd::new_str("no statements")
+pub extern "C" fn _test_inner2_intern_eval() -> Result<(kserd::Kserd<'static>, String), String> {
+Ok((kserd::Kserd::new_str("no statements"), String::new()))
 }
-}}"##;
+}}"##
+        );
 
-        let return_stmt = r#"kserd::Kserd::new_str("no statements")"#;
+        let return_stmt = r#"Ok((kserd::Kserd::new_str("no statements"), String::new()))"#;
         println!("{}", s);
         assert_eq!(&s, ans);
         assert_eq!(
@@ -1478,15 +2634,20 @@ kserd::Kserd::new_str("no statements")
 
         let (s, map) = construct_source_code(&map, &linking, &static_files);
 
-        let ans = r##"mod bar2;
+        let ans = &format!(
+            "{}{}{}",
+            CATCH_FN,
+            RENDER_MOD,
+            r##"mod bar2;
 mod foo2;
 #[no_mangle]
-pub extern "C" fn _lib_intern_eval() -> kserd::Kserd<'static> {
-kserd::Kserd::new_str("no statements")
+pub extern "C" fn _lib_intern_eval() -> Result<(kserd::Kserd<'static>, String), String> {
+Ok((kserd::Kserd::new_str("no statements"), String::new()))
 }
-"##;
+"##
+        );
 
-        let return_stmt = r#"kserd::Kserd::new_str("no statements")"#;
+        let return_stmt = r#"Ok((kserd::Kserd::new_str("no statements"), String::new()))"#;
         println!("{}", s);
         assert_eq!(&s, ans);
         assert_eq!(
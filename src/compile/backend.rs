@@ -0,0 +1,280 @@
+use super::build::{compile_cancellable, extern_args, lib_file_path, run_compiler};
+use super::construct::cargotoml_contents;
+use super::{CompilationError, LIBRARY_NAME};
+use crate::linking::LinkingConfiguration;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// Compiles a compile directory, built with [`build_compile_dir`](super::build_compile_dir),
+/// into a library.
+///
+/// A REPL's backend is set with
+/// [`ReplData::with_compiler_backend`](crate::repl::ReplData::with_compiler_backend).
+pub trait CompilerBackend: Send {
+    /// Compile `compile_dir`, returning the path to the library.
+    ///
+    /// `cancelled` is polled while compiling, if it returns true compilation stops with
+    /// [`CompilationError::Cancelled`]. Compiler output which is not a diagnostic is passed to
+    /// `stderr_line_cb` line by line.
+    fn compile(
+        &mut self,
+        compile_dir: &Path,
+        linking_config: &LinkingConfiguration,
+        cancelled: &mut dyn FnMut() -> bool,
+        stderr_line_cb: &mut dyn FnMut(&str),
+    ) -> Result<PathBuf, CompilationError>;
+}
+
+/// Compiles with `cargo rustc`, see [`compile_cancellable`]. This is the default backend.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CargoBackend;
+
+impl CompilerBackend for CargoBackend {
+    fn compile(
+        &mut self,
+        compile_dir: &Path,
+        linking_config: &LinkingConfiguration,
+        cancelled: &mut dyn FnMut() -> bool,
+        stderr_line_cb: &mut dyn FnMut(&str),
+    ) -> Result<PathBuf, CompilationError> {
+        compile_cancellable(compile_dir, linking_config, cancelled, stderr_line_cb)
+    }
+}
+
+/// Invokes `rustc` directly, avoiding the overhead of `cargo`, when no crates are referenced.
+///
+/// The first compilation goes through `cargo`, which builds the `kserd` dependency. Following
+/// compilations pass the `kserd` rlib that was built to `rustc`. Compilations referencing other
+/// crates always go through `cargo`.
+#[derive(Debug, Default)]
+pub struct RustcBackend {
+    prebuilt: Option<Prebuilt>,
+}
+
+/// Dependencies built by `cargo` for a compile directory.
+#[derive(Debug)]
+struct Prebuilt {
+    compile_dir: PathBuf,
+    manifest: String,
+    kserd: PathBuf,
+}
+
+impl CompilerBackend for RustcBackend {
+    fn compile(
+        &mut self,
+        compile_dir: &Path,
+        linking_config: &LinkingConfiguration,
+        cancelled: &mut dyn FnMut() -> bool,
+        stderr_line_cb: &mut dyn FnMut(&str),
+    ) -> Result<PathBuf, CompilationError> {
+        let manifest = fs::read_to_string(compile_dir.join("Cargo.toml"))
+            .map_err(CompilationError::IOError)?;
+
+        let prebuilt = self
+            .prebuilt
+            .as_ref()
+            .filter(|x| x.compile_dir == compile_dir && x.manifest == manifest && x.kserd.exists());
+
+        if let Some(prebuilt) = prebuilt {
+            let mut cmd = Command::new("rustc");
+            cmd.current_dir(compile_dir)
+                .env("CARGO_CRATE_NAME", LIBRARY_NAME)
+                .args(["--crate-name", LIBRARY_NAME])
+                .args(["--edition", "2018", "--crate-type", "cdylib"])
+                .args(["--error-format=json", "-Awarnings"])
+                .args(["-C", "incremental=target/debug/rustc-incremental"])
+                .args(["--out-dir", "target/debug"])
+                .args(["-L", "dependency=target/debug/deps", "--extern"])
+                .arg(format!("kserd={}", prebuilt.kserd.display()))
+                .args(extern_args(linking_config))
+                .arg("src/lib.rs");
+
+            return run_compiler(cmd, lib_file_path(compile_dir), cancelled, stderr_line_cb);
+        }
+
+        let r = compile_cancellable(compile_dir, linking_config, &mut *cancelled, stderr_line_cb);
+
+        // the dependencies are built even if the code fails to compile
+        self.prebuilt = if manifest == cargotoml_contents(LIBRARY_NAME, std::iter::empty()) {
+            find_rlib(&compile_dir.join("target/debug/deps"), "kserd")
+                .and_then(|x| x.canonicalize().ok())
+                .map(|kserd| Prebuilt {
+                    compile_dir: compile_dir.to_path_buf(),
+                    manifest,
+                    kserd,
+                })
+        } else {
+            None
+        };
+
+        r
+    }
+}
+
+/// Finds the most recently built rlib of `crate_name` in `deps`.
+fn find_rlib(deps: &Path, crate_name: &str) -> Option<PathBuf> {
+    let prefix = format!("lib{}-", crate_name);
+    fs::read_dir(deps)
+        .ok()?
+        .filter_map(Result::ok)
+        .filter(|x| {
+            let name = x.file_name();
+            let name = name.to_string_lossy();
+            name.starts_with(&prefix) && name.ends_with(".rlib")
+        })
+        .filter_map(|x| Some((x.metadata().ok()?.modified().ok()?, x.path())))
+        .max()
+        .map(|x| x.1)
+}
+
+/// A backend which does not compile, for testing without a Rust toolchain.
+///
+/// The function is given the `.rs` files of the `src` directory, such as the generated `lib.rs`,
+/// module files like `a/b.rs`, and static files, as paths relative to `src` with their contents
+/// sorted by path. It returns the result of the compilation.
+pub struct MockBackend {
+    compile: Box<MockFn>,
+}
+
+type MockFn = dyn FnMut(&[(PathBuf, String)]) -> Result<PathBuf, CompilationError> + Send;
+
+impl MockBackend {
+    /// A backend returning the result of `compile` for each compilation.
+    pub fn new<F>(compile: F) -> Self
+    where
+        F: FnMut(&[(PathBuf, String)]) -> Result<PathBuf, CompilationError> + Send + 'static,
+    {
+        MockBackend {
+            compile: Box::new(compile),
+        }
+    }
+}
+
+impl CompilerBackend for MockBackend {
+    fn compile(
+        &mut self,
+        compile_dir: &Path,
+        _: &LinkingConfiguration,
+        cancelled: &mut dyn FnMut() -> bool,
+        _: &mut dyn FnMut(&str),
+    ) -> Result<PathBuf, CompilationError> {
+        if cancelled() {
+            return Err(CompilationError::Cancelled);
+        }
+        let mut files = Vec::new();
+        read_rs_files(&compile_dir.join("src"), Path::new(""), &mut files)
+            .map_err(CompilationError::IOError)?;
+        files.sort();
+        (self.compile)(&files)
+    }
+}
+
+/// Read the `.rs` files under `dir`, recursively, pushing their paths relative to `dir`.
+fn read_rs_files(
+    dir: &Path,
+    rel: &Path,
+    files: &mut Vec<(PathBuf, String)>,
+) -> std::io::Result<()> {
+    for entry in fs::read_dir(dir.join(rel))? {
+        let entry = entry?;
+        let path = rel.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            read_rs_files(dir, &path, files)?;
+        } else if path.extension().is_some_and(|x| x == "rs") {
+            let contents = fs::read_to_string(dir.join(&path))?;
+            files.push((path, contents));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::code::{SourceCode, Statement, StaticFiles, StmtGrp};
+    use crate::compile::{build_compile_dir, exec, unshackle_library_file};
+    use ::kserd::Kserd;
+
+    fn files(expr: &str) -> crate::code::ModsMap {
+        let mut code = SourceCode::default();
+        code.stmts.push(StmtGrp(vec![Statement {
+            expr: expr.to_string(),
+            semi: false,
+        }]));
+        vec![("lib".into(), code)].into_iter().collect()
+    }
+
+    #[test]
+    fn find_rlib_test() {
+        let dir = Path::new("target/testing/find_rlib_test");
+        fs::create_dir_all(dir).unwrap();
+        for name in &["libkserd-a.rlib", "libkserd-b.rmeta", "libkserd_x-c.rlib"] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        assert_eq!(find_rlib(dir, "kserd"), Some(dir.join("libkserd-a.rlib")));
+        assert_eq!(find_rlib(dir, "serde"), None);
+        assert_eq!(find_rlib(&dir.join("missing"), "kserd"), None);
+    }
+
+    #[test]
+    fn rustc_backend_test() {
+        let compile_dir = Path::new("target/testing/rustc_backend_test");
+        let linking = LinkingConfiguration::default();
+        let mut backend = RustcBackend::default();
+        let mut compile = |expr: &str| {
+            build_compile_dir(compile_dir, &files(expr), &linking, &StaticFiles::new()).unwrap();
+            backend
+                .compile(compile_dir, &linking, &mut || false, &mut |_| ())
+                .map(unshackle_library_file)
+        };
+
+        // first compilation goes through cargo
+        let path = compile("2+2").unwrap();
+        let r = exec::<_, _, std::io::Sink>(path, "_lib_intern_eval", &(), None).unwrap();
+        assert_eq!(r.0, Kserd::new_num(4));
+
+        let path = compile("3+3").unwrap();
+        let r = exec::<_, _, std::io::Sink>(path, "_lib_intern_eval", &(), None).unwrap();
+        assert_eq!(r.0, Kserd::new_num(6));
+        // second compilation invokes rustc directly
+        assert!(compile_dir.join("target/debug/rustc-incremental").exists());
+
+        match compile("2 + \"a\"") {
+            Err(CompilationError::Diagnostics(d)) => {
+                assert_eq!(d[0].level, "error");
+                assert_eq!(d[0].spans[0].file_name, "src/lib.rs");
+                assert!(d.iter().all(|x| !x.message.starts_with("aborting")));
+            }
+            _ => panic!("expecting diagnostics"),
+        }
+        assert!(backend.prebuilt.is_some());
+    }
+
+    #[test]
+    fn mock_backend_test() {
+        let compile_dir = Path::new("target/testing/mock_backend_test");
+        fs::remove_dir_all(compile_dir).ok();
+        let linking = LinkingConfiguration::default();
+        let mut mods = files("2+2");
+        let mut code = SourceCode::default();
+        code.items.push(("fn b() {}".to_string(), false));
+        mods.insert("a/b".into(), code);
+        build_compile_dir(compile_dir, &mods, &linking, &StaticFiles::new()).unwrap();
+
+        let mut backend = MockBackend::new(|files| {
+            let paths: Vec<_> = files.iter().map(|x| x.0.as_path()).collect();
+            assert_eq!(paths, vec![Path::new("a/b.rs"), Path::new("lib.rs")]);
+            assert!(files[0].1.contains("fn b() {}"));
+            assert!(files[1].1.contains("let out0 = 2+2;"));
+            Ok("lib.so".into())
+        });
+        let r = backend.compile(compile_dir, &linking, &mut || false, &mut |_| ());
+        assert_eq!(r.unwrap(), Path::new("lib.so"));
+
+        let r = backend.compile(compile_dir, &linking, &mut || true, &mut |_| ());
+        assert!(matches!(r, Err(CompilationError::Cancelled)));
+    }
+}
@@ -1,31 +1,76 @@
-use super::LIBRARY_NAME;
+use super::{Diagnostic, LIBRARY_NAME};
+use crossbeam_channel::RecvTimeoutError;
 use std::io::{self, BufRead, BufReader};
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::time::Duration;
 use std::{error, fmt};
 
+/// How often cancellation is checked.
+const CANCEL_POLL: Duration = Duration::from_millis(20);
+
 /// Run `rustc` in the given compilation directory.
+///
+/// Compiler messages are parsed into [`Diagnostic`]s, returned as
+/// [`CompilationError::Diagnostics`] if compilation fails.
 pub fn compile<P, F>(
     compile_dir: P,
     linking_config: &crate::linking::LinkingConfiguration,
-    mut stderr_line_cb: F,
+    stderr_line_cb: F,
+) -> Result<PathBuf, CompilationError>
+where
+    P: AsRef<Path>,
+    F: FnMut(&str),
+{
+    compile_cancellable(compile_dir, linking_config, || false, stderr_line_cb)
+}
+
+/// Same as [`compile`] but polls `cancelled` while compiling.
+///
+/// If `cancelled` returns true the `cargo` process is killed and
+/// [`CompilationError::Cancelled`] is returned.
+pub fn compile_cancellable<P, C, F>(
+    compile_dir: P,
+    linking_config: &crate::linking::LinkingConfiguration,
+    cancelled: C,
+    stderr_line_cb: F,
 ) -> Result<PathBuf, CompilationError>
 where
     P: AsRef<Path>,
+    C: FnMut() -> bool,
     F: FnMut(&str),
 {
     let compile_dir = compile_dir.as_ref();
+
+    let mut args = vec![
+        "rustc".to_owned(),
+        "--message-format=json".to_owned(),
+        "--".to_owned(),
+        "-Awarnings".to_owned(),
+    ];
+    args.extend(extern_args(linking_config));
+
+    let mut cmd = Command::new("cargo");
+    cmd.current_dir(compile_dir).args(&args);
+
+    run_compiler(cmd, lib_file_path(compile_dir), cancelled, stderr_line_cb)
+}
+
+/// The path of the library compiled in `compile_dir`.
+pub(super) fn lib_file_path(compile_dir: &Path) -> PathBuf {
     let lib_file = compile_dir.join("target/debug/");
-    let lib_file = if cfg!(windows) {
+    if cfg!(windows) {
         lib_file.join(format!("{}.dll", LIBRARY_NAME))
     } else if cfg!(target_os = "macos") {
         lib_file.join(format!("lib{}.dylib", LIBRARY_NAME))
     } else {
         lib_file.join(format!("lib{}.so", LIBRARY_NAME))
-    };
-
-    let mut args = vec!["rustc".to_owned(), "--".to_owned(), "-Awarnings".to_owned()];
+    }
+}
 
+/// The `rustc` arguments to link the external libraries.
+pub(super) fn extern_args(linking_config: &crate::linking::LinkingConfiguration) -> Vec<String> {
+    let mut args = Vec::new();
     for external in linking_config.external_libs.iter() {
         args.push("-L".to_owned());
         args.push(format!("dependency={}", external.deps_path().display()));
@@ -36,31 +81,83 @@ where
             external.lib_path().display()
         ));
     }
+    args
+}
 
-    let mut child = Command::new("cargo")
-        .current_dir(compile_dir)
-        .args(&args)
+/// Runs a compiler command which outputs JSON messages, returning `lib_file` if it succeeds.
+///
+/// JSON diagnostics on stdout or stderr are collected, other stderr lines are passed to
+/// `stderr_line_cb`. The process is killed if `cancelled` returns true.
+pub(super) fn run_compiler<C, F>(
+    mut cmd: Command,
+    lib_file: PathBuf,
+    mut cancelled: C,
+    mut stderr_line_cb: F,
+) -> Result<PathBuf, CompilationError>
+where
+    C: FnMut() -> bool,
+    F: FnMut(&str),
+{
+    let mut child = cmd
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .spawn()
         .map_err(|_| CompilationError::NoBuildCommand)?;
 
-    let stderr = {
-        let rdr = BufReader::new(child.stderr.as_mut().expect("stderr should be piped"));
-        let mut s = String::new();
-        for line in rdr.lines() {
-            let line = line.unwrap();
-            stderr_line_cb(&line);
-            s.push_str(&line);
-            s.push('\n');
+    // read stdout on another thread so neither pipe fills up
+    let stdout = child.stdout.take().expect("stdout should be piped");
+    let diagnostics = std::thread::spawn(move || {
+        BufReader::new(stdout)
+            .lines()
+            .map_while(Result::ok)
+            .filter_map(|line| Diagnostic::from_json(&line))
+            .collect::<Vec<_>>()
+    });
+
+    // read stderr on another thread so cancellation can be polled
+    let (tx, rx) = crossbeam_channel::unbounded();
+    let stderr = child.stderr.take().expect("stderr should be piped");
+    std::thread::spawn(move || {
+        for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut stderr = String::new();
+    let mut stderr_diagnostics = Vec::new();
+    loop {
+        if cancelled() {
+            child.kill().ok();
+            child.wait().ok();
+            return Err(CompilationError::Cancelled);
         }
-        s
-    };
+
+        match rx.recv_timeout(CANCEL_POLL) {
+            Ok(line) => match Diagnostic::from_json(&line) {
+                Some(diagnostic) => stderr_diagnostics.push(diagnostic),
+                None => {
+                    stderr_line_cb(&line);
+                    stderr.push_str(&line);
+                    stderr.push('\n');
+                }
+            },
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
 
     match child.wait() {
         Ok(ex) => {
+            let mut diagnostics = diagnostics.join().unwrap_or_default();
+            diagnostics.extend(stderr_diagnostics);
+            // rustc summarises the errors, cargo does not pass the summary on
+            diagnostics.retain(|x| !x.message.starts_with("aborting due to"));
             if ex.success() {
                 Ok(lib_file)
+            } else if diagnostics.iter().any(|x| x.level == "error") {
+                Err(CompilationError::Diagnostics(diagnostics))
             } else {
                 Err(CompilationError::CompileError(stderr))
             }
@@ -114,6 +211,10 @@ pub enum CompilationError {
     NoBuildCommand,
     /// A compiling error occured, with the contents of the stderr.
     CompileError(String),
+    /// Compilation failed with compiler errors.
+    Diagnostics(Vec<Diagnostic>),
+    /// Compilation was cancelled.
+    Cancelled,
     /// Generic IO errors.
     IOError(io::Error),
 }
@@ -127,6 +228,16 @@ impl fmt::Display for CompilationError {
                 write!(f, "cargo build command failed to start, is rust installed?")
             }
             CompilationError::CompileError(e) => write!(f, "{}", e),
+            CompilationError::Diagnostics(diagnostics) => {
+                for (i, d) in diagnostics.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}", d)?;
+                }
+                Ok(())
+            }
+            CompilationError::Cancelled => write!(f, "compilation cancelled"),
             CompilationError::IOError(e) => write!(f, "io error occurred: {}", e),
         }
     }
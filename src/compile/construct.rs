@@ -1,17 +1,21 @@
 use super::LIBRARY_NAME;
 use crate::{
-    code::{self, CrateType, ModsMap, StaticFiles},
+    code::{self, CrateType, ModsMap, SourceCode, StaticFiles},
     linking,
 };
 use std::{
     fs,
     io::{self, Write},
-    path::Path,
+    path::{Path, PathBuf},
 };
 
 /// Constructs the compile directory.
 /// Takes a list of source files and writes the contents to file.
 /// Builds `Cargo.toml` using crates found in `SourceFile`.
+///
+/// Each module is written to its own file following the module path, see
+/// [`construct_source_files`](code::construct_source_files). Files are only written if their
+/// contents changed, leaving unchanged modules untouched for incremental compilation.
 pub fn build_compile_dir<P>(
     compile_dir: P,
     mods_map: &ModsMap,
@@ -22,6 +26,7 @@ where
     P: AsRef<Path>,
 {
     let compile_dir = compile_dir.as_ref();
+    let src_dir = compile_dir.join("src");
 
     let crates = mods_map
         .iter()
@@ -30,19 +35,277 @@ where
     let crates = dedup_crates(crates);
 
     // write cargo toml contents
-    create_file_and_dir(compile_dir.join("Cargo.toml"))?
-        .write_all(cargotoml_contents(LIBRARY_NAME, crates.into_iter()).as_bytes())?;
+    write_if_changed(
+        compile_dir.join("Cargo.toml"),
+        cargotoml_contents(LIBRARY_NAME, crates.into_iter()).as_bytes(),
+    )?;
 
-    let (src_code, _map) = code::construct_source_code(mods_map, linking_config, static_files);
+    // module files are written into the static files directory
+    check_static_file_conflicts(mods_map, static_files, code::mod_file_path)?;
 
-    create_file_and_dir(compile_dir.join("src/lib.rs"))?.write_all(src_code.as_bytes())?;
+    for (file, contents) in code::construct_source_files(mods_map, linking_config, static_files) {
+        write_if_changed(src_dir.join(file), contents.as_bytes())?;
+    }
+
+    Ok(())
+}
+
+/// Constructs a standalone binary crate in `export_dir` from the REPL source code.
+///
+/// The `lib` module becomes `src/main.rs`, with its statements placed in `main`. Other modules are
+/// written as files following the module path, with statements placed in a `pub fn run()`. A
+/// module's statements are run before the `run` functions of its child modules, and `main` prints
+/// the final output of `lib`. The crates are added to `[dependencies]`, and static files are copied
+/// from `static_files_dir`.
+///
+/// External libraries cannot be linked in a cargo project, instead they are listed as comments
+/// in `Cargo.toml`.
+pub fn build_export_dir<P, Q>(
+    export_dir: P,
+    package_name: &str,
+    mods_map: &ModsMap,
+    linking_config: &linking::LinkingConfiguration,
+    static_files: &StaticFiles,
+    static_files_dir: Q,
+) -> io::Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let export_dir = export_dir.as_ref();
+    let src_dir = export_dir.join("src");
+
+    let crates = mods_map
+        .iter()
+        .flat_map(|kvp| kvp.1.crates.iter())
+        .chain(static_files.iter().flat_map(|x| x.crates.iter()));
+    let crates = dedup_crates(crates);
+
+    let mut externs: Vec<_> = linking_config
+        .external_libs
+        .iter()
+        .map(|x| x.lib_path())
+        .collect();
+    externs.sort();
+
+    create_file_and_dir(export_dir.join("Cargo.toml"))?.write_all(
+        export_cargotoml_contents(package_name, crates.into_iter(), externs.into_iter()).as_bytes(),
+    )?;
+
+    let static_mods: Vec<_> = static_files
+        .iter()
+        .map(|x| x.path.as_path())
+        .filter_map(code::static_file_mod_name)
+        .collect();
+
+    let mod_file = |path: &Path| {
+        if path == Path::new("lib") {
+            PathBuf::from("main.rs")
+        } else {
+            path.with_extension("rs")
+        }
+    };
+
+    // check for conflicts before writing any source files
+    check_static_file_conflicts(mods_map, static_files, mod_file)?;
+
+    for sf in static_files {
+        let file = src_dir.join(&sf.path);
+        fs::create_dir_all(file.parent().expect("should have parent"))?;
+        fs::copy(static_files_dir.as_ref().join(&sf.path), file)?;
+    }
+
+    // a module has a run function if it, or any module below it, has statements
+    let has_run = |path: &Path| {
+        mods_map
+            .iter()
+            .filter(|x| x.0.starts_with(path))
+            .any(|x| !x.1.stmts.is_empty())
+    };
+
+    for (path, src_code) in mods_map {
+        let is_lib = path == Path::new("lib");
+        let children: Vec<_> = mods_map
+            .keys()
+            .filter(|x| x.parent() == Some(path) || is_lib && x.iter().count() == 1)
+            .filter(|x| x.as_path() != Path::new("lib"))
+            .filter_map(|x| {
+                let name = x.file_name().and_then(|x| x.to_str())?;
+                Some((mods_map[x].vis.as_str(), name, has_run(x)))
+            })
+            .collect();
+        let mods = children.iter().map(|x| (x.0, x.1));
+        let runs: Vec<_> = children.iter().filter(|x| x.2).map(|x| x.1).collect();
+
+        let contents = if is_lib {
+            export_module_contents(
+                src_code,
+                static_mods.iter().map(|x| ("", *x)).chain(mods),
+                &runs,
+                linking_config,
+                &[],
+                true,
+            )
+        } else {
+            export_module_contents(src_code, mods, &runs, linking_config, &static_mods, false)
+        };
+
+        create_file_and_dir(src_dir.join(mod_file(path)))?.write_all(contents.as_bytes())?;
+    }
+
+    Ok(())
+}
+
+/// The contents of a single module file of an exported project.
+///
+/// `mods` are the child modules to declare, as the visibility and name. `runs` are the child
+/// modules with a `run` function, which is called after the module's statements.
+fn export_module_contents<'a>(
+    src_code: &SourceCode,
+    mods: impl Iterator<Item = (&'a str, &'a str)>,
+    runs: &[&str],
+    linking_config: &linking::LinkingConfiguration,
+    static_mods: &[&str],
+    main: bool,
+) -> String {
+    let mut buf = String::new();
+
+    for item in src_code.items.iter().filter(|x| x.1) {
+        buf.push_str(&item.0);
+        buf.push('\n');
+    }
+
+    for (vis, name) in mods {
+        code::push_mod_decl(&mut buf, vis, name);
+        buf.push_str(";\n");
+    }
+
+    if !linking_config.persistent_module_code.is_empty() {
+        buf.push_str(&linking_config.persistent_module_code);
+        buf.push('\n');
+    }
+
+    for m in static_mods {
+        buf.push_str("use crate::");
+        buf.push_str(m);
+        buf.push_str(";\n");
+    }
+
+    // the final output is printed, unless it is the `()` finishing an input
+    let print_output = main
+        && src_code
+            .stmts
+            .last()
+            .and_then(|x| x.0.last())
+            .map(|x| x.expr != "()")
+            .unwrap_or(false);
+
+    if main || !src_code.stmts.is_empty() || !runs.is_empty() {
+        buf.push_str(if main {
+            "\nfn main()"
+        } else {
+            "\npub fn run()"
+        });
+        buf.push_str(" -> Result<(), Box<dyn std::error::Error>> {\n");
+        for (i, grp) in src_code.stmts.iter().enumerate() {
+            grp.assign_let_binding(i, &mut buf);
+            buf.push('\n');
+        }
+        for m in runs {
+            buf.push_str(m);
+            buf.push_str("::run()?;\n");
+        }
+        if print_output {
+            buf.push_str("{\nuse __papyrus_print::*;\n(&&&Wrap(out");
+            buf.push_str(&(src_code.stmts.len() - 1).to_string());
+            buf.push_str(")).print();\n}\n");
+        }
+        buf.push_str("Ok(())\n}\n");
+    }
+
+    for item in src_code.items.iter().filter(|x| !x.1) {
+        buf.push('\n');
+        buf.push_str(&item.0);
+        buf.push('\n');
+    }
+
+    if print_output {
+        buf.push('\n');
+        buf.push_str(EXPORT_PRINT_MOD);
+    }
+
+    buf
+}
+
+/// Prints the final output of an exported project, preferring `Debug`, then `Display`, and falling
+/// back to the type name. The implementation is chosen with autoref specialization, as in the
+/// rendering of REPL outputs.
+const EXPORT_PRINT_MOD: &str = r#"mod __papyrus_print {
+    pub struct Wrap<T>(pub T);
+    pub trait ViaDebug {
+        fn print(self);
+    }
+    impl<T: std::fmt::Debug> ViaDebug for &&&Wrap<T> {
+        fn print(self) {
+            println!("{:?}", self.0)
+        }
+    }
+    pub trait ViaDisplay {
+        fn print(self);
+    }
+    impl<T: std::fmt::Display> ViaDisplay for &&Wrap<T> {
+        fn print(self) {
+            println!("{}", self.0)
+        }
+    }
+    pub trait ViaTypeName {
+        fn print(self);
+    }
+    impl<T> ViaTypeName for &Wrap<T> {
+        fn print(self) {
+            println!("{}", std::any::type_name::<T>())
+        }
+    }
+}
+"#;
+
+/// Errors if a module's file, given by `mod_file` relative to `src`, or the module name clashes
+/// with a static file.
+fn check_static_file_conflicts<F>(
+    mods_map: &ModsMap,
+    static_files: &StaticFiles,
+    mod_file: F,
+) -> io::Result<()>
+where
+    F: Fn(&Path) -> PathBuf,
+{
+    let static_mods: Vec<_> = static_files
+        .iter()
+        .map(|x| x.path.as_path())
+        .filter_map(code::static_file_mod_name)
+        .collect();
+
+    for path in mods_map.keys() {
+        let file = mod_file(path);
+        let same_mod = path.iter().count() == 1 && static_mods.iter().any(|m| Path::new(m) == path);
+        if same_mod || static_files.iter().any(|x| x.path == file) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "module `{}` conflicts with a static file of the same name",
+                    path.display()
+                ),
+            ));
+        }
+    }
 
     Ok(())
 }
 
 fn dedup_crates<'a>(crates: impl Iterator<Item = &'a CrateType>) -> Vec<&'a CrateType> {
     let mut crates: Vec<&CrateType> = crates.collect();
-    crates.sort_by_key(|x| &x.cargo_name);
+    // crates with a specification take precedence
+    crates.sort_by_key(|x| (&x.cargo_name, !x.has_spec()));
     crates.dedup_by_key(|x| &x.cargo_name);
     crates
 }
@@ -57,7 +320,26 @@ fn create_file_and_dir<P: AsRef<Path>>(file: P) -> io::Result<fs::File> {
     fs::File::create(file)
 }
 
-fn cargotoml_contents<'a, I: Iterator<Item = &'a CrateType>>(lib_name: &str, crates: I) -> String {
+/// Writes `contents` to `file` if the file's contents hash differently, creating the directory to
+/// it if required.
+///
+/// Unchanged files keep their modification time, which `cargo` and `rustc` use to skip work.
+fn write_if_changed<P: AsRef<Path>>(file: P, contents: &[u8]) -> io::Result<()> {
+    let file = file.as_ref();
+    let unchanged = fs::read(file)
+        .map(|x| blake3::hash(&x) == blake3::hash(contents))
+        .unwrap_or(false);
+    if unchanged {
+        Ok(())
+    } else {
+        create_file_and_dir(file)?.write_all(contents)
+    }
+}
+
+pub(super) fn cargotoml_contents<'a, I: Iterator<Item = &'a CrateType>>(
+    lib_name: &str,
+    crates: I,
+) -> String {
     format!(
         r#"[package]
 name = "{lib_name}"
@@ -75,12 +357,45 @@ kserd = {{ version = "0.3", default-features = false, features = [ "format" ] }}
 "#,
         lib_name = lib_name,
         crates = crates
-            .map(|c| format!(r#"{} = "*""#, c.cargo_name))
+            .map(|c| format!("{} = {}", c.cargo_name, c.dependency_spec()))
             .collect::<Vec<_>>()
             .join("\n")
     )
 }
 
+fn export_cargotoml_contents<'a, I, E>(name: &str, crates: I, externs: E) -> String
+where
+    I: Iterator<Item = &'a CrateType>,
+    E: Iterator<Item = &'a Path>,
+{
+    let mut s = format!(
+        r#"[package]
+name = "{}"
+version = "0.1.0"
+edition = "2018"
+
+[dependencies]
+"#,
+        name
+    );
+
+    for c in crates {
+        s.push_str(&format!("{} = {}\n", c.cargo_name, c.dependency_spec()));
+    }
+
+    let mut externs = externs.peekable();
+    if externs.peek().is_some() {
+        s.push_str(
+            "\n# The following libraries were linked externally and must be added manually.\n",
+        );
+        for e in externs {
+            s.push_str(&format!("# {}\n", e.display()));
+        }
+    }
+
+    s
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -104,6 +419,206 @@ mod tests {
         assert!(!p.exists());
     }
 
+    #[test]
+    fn build_compile_dir_test() {
+        use crate::code::{Statement, StmtGrp};
+
+        let dir = Path::new("target/testing/build-compile-dir-test");
+        fs::remove_dir_all(dir).ok();
+
+        let mut mods_map = ModsMap::new();
+        mods_map.insert("lib".into(), SourceCode::default());
+        mods_map.insert("a".into(), SourceCode::default());
+        mods_map.insert("a/inner".into(), SourceCode::default());
+        let linking_config = linking::LinkingConfiguration::default();
+        let static_files = StaticFiles::new();
+
+        build_compile_dir(dir, &mods_map, &linking_config, &static_files).unwrap();
+
+        let read = |f: &str| fs::read_to_string(dir.join(f)).unwrap();
+        assert!(read("src/lib.rs").ends_with("}\nmod a;\n"));
+        assert!(read("src/a.rs").ends_with("}\nmod inner;\n"));
+        assert!(read("src/a/inner.rs").contains("fn _a_inner_intern_eval()"));
+
+        // only changed files are written
+        let modified = |f: &str| fs::metadata(dir.join(f)).unwrap().modified().unwrap();
+        let (toml, lib, a) = (
+            modified("Cargo.toml"),
+            modified("src/lib.rs"),
+            modified("src/a.rs"),
+        );
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        mods_map
+            .get_mut(Path::new("lib"))
+            .unwrap()
+            .stmts
+            .push(StmtGrp(vec![Statement {
+                expr: "1".to_string(),
+                semi: false,
+            }]));
+        build_compile_dir(dir, &mods_map, &linking_config, &static_files).unwrap();
+        assert!(read("src/lib.rs").contains("let out0 = 1;"));
+        assert_ne!(modified("src/lib.rs"), lib);
+        assert_eq!(modified("src/a.rs"), a);
+        assert_eq!(modified("Cargo.toml"), toml);
+
+        // module files cannot overwrite a static file
+        let mut static_files = StaticFiles::new();
+        static_files.insert(code::StaticFile {
+            path: "a/inner.rs".into(),
+            codehash: Box::new([0; 32]),
+            crates: Vec::new(),
+        });
+        let e = build_compile_dir(dir, &mods_map, &linking_config, &static_files).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+    }
+
+    #[test]
+    fn build_export_dir_test() {
+        use crate::code::{Statement, StmtGrp};
+
+        let dir = Path::new("target/testing/build-export-dir-test");
+        let sf_dir = Path::new("target/testing/build-export-dir-test-static");
+        fs::remove_dir_all(dir).ok();
+        create_file_and_dir(sf_dir.join("foo.rs"))
+            .unwrap()
+            .write_all(b"pub fn foo() -> u8 { 2 }")
+            .unwrap();
+
+        let stmts = |v: &[&str]| {
+            StmtGrp(
+                v.iter()
+                    .map(|x| Statement {
+                        expr: x.trim_end_matches(';').to_string(),
+                        semi: x.ends_with(';'),
+                    })
+                    .collect(),
+            )
+        };
+
+        let mut mods_map = ModsMap::new();
+        let mut lib = SourceCode::default();
+        lib.items.push(("#![allow(unused)]".to_string(), true));
+        lib.items.push(("fn one() -> u8 { 1 }".to_string(), false));
+        lib.stmts.push(stmts(&["let a = one();", "a + foo()"]));
+        lib.stmts
+            .push(stmts(&["let b: u8 = \"3\".parse()?;", "out0 + b"]));
+        mods_map.insert("lib".into(), lib);
+        let mut inner = SourceCode::default();
+        inner.stmts.push(stmts(&["println!(\"inner\")"]));
+        inner.vis = "pub".to_string();
+        mods_map.insert("a".into(), SourceCode::default());
+        mods_map.insert("a/inner".into(), inner);
+
+        let mut static_files = StaticFiles::new();
+        static_files.insert(code::StaticFile {
+            path: "foo.rs".into(),
+            codehash: Box::new([0; 32]),
+            crates: Vec::new(),
+        });
+
+        let linking_config = linking::LinkingConfiguration {
+            persistent_module_code: "use crate::foo::*;".to_string(),
+            ..Default::default()
+        };
+
+        build_export_dir(
+            dir,
+            "export-test",
+            &mods_map,
+            &linking_config,
+            &static_files,
+            sf_dir,
+        )
+        .unwrap();
+
+        let read = |f: &str| fs::read_to_string(dir.join(f)).unwrap();
+
+        assert_eq!(
+            read("src/main.rs"),
+            format!(
+                r#"#![allow(unused)]
+mod foo;
+mod a;
+use crate::foo::*;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {{
+let a = one();
+let out0 = a + foo();
+let b: u8 = "3".parse()?;
+let out1 = out0 + b;
+a::run()?;
+{{
+use __papyrus_print::*;
+(&&&Wrap(out1)).print();
+}}
+Ok(())
+}}
+
+fn one() -> u8 {{ 1 }}
+
+{}"#,
+                EXPORT_PRINT_MOD
+            )
+        );
+        assert_eq!(
+            read("src/a.rs"),
+            "pub mod inner;\nuse crate::foo::*;\nuse crate::foo;\n\npub fn run() -> Result<(), Box<dyn std::error::Error>> {\ninner::run()?;\nOk(())\n}\n"
+        );
+        assert_eq!(
+            read("src/a/inner.rs"),
+            "use crate::foo::*;\nuse crate::foo;\n\npub fn run() -> Result<(), Box<dyn std::error::Error>> {\nlet out0 = println!(\"inner\");\nOk(())\n}\n"
+        );
+        assert_eq!(read("src/foo.rs"), "pub fn foo() -> u8 { 2 }");
+
+        // the exported project is a valid binary crate, running the modules and printing the
+        // final output
+        let output = std::process::Command::new("cargo")
+            .current_dir(dir)
+            .args(["run", "--offline", "--quiet"])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "inner\n6\n");
+
+        // outputs without Debug fall back to Display or the type name
+        let mut lib = SourceCode::default();
+        lib.items.push(("struct S;".to_string(), false));
+        lib.stmts.push(stmts(&["S"]));
+        let map: ModsMap = vec![("lib".into(), lib)].into_iter().collect();
+        let dir2 = Path::new("target/testing/build-export-dir-test-type-name");
+        fs::remove_dir_all(dir2).ok();
+        build_export_dir(
+            dir2,
+            "export-test",
+            &map,
+            &Default::default(),
+            &StaticFiles::new(),
+            sf_dir,
+        )
+        .unwrap();
+        let output = std::process::Command::new("cargo")
+            .current_dir(dir2)
+            .args(["run", "--offline", "--quiet"])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "export_test::S\n");
+
+        // static files cannot share a name with a module
+        mods_map.insert("foo".into(), SourceCode::default());
+        let e = build_export_dir(
+            dir,
+            "export-test",
+            &mods_map,
+            &linking_config,
+            &static_files,
+            sf_dir,
+        )
+        .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+    }
+
     #[test]
     fn test_dedup_crates() {
         let crates = vec![
@@ -116,4 +631,20 @@ mod tests {
         let v: Vec<_> = crates.iter().map(|x| &x.cargo_name).collect();
         assert_eq!(&v, &["rand", "third"]);
     }
+
+    #[test]
+    fn cargotoml_contents_test() {
+        let mut serde = CrateType::parse_str("extern crate serde;").unwrap();
+        let crates = [
+            CrateType::parse_str("extern crate rand;").unwrap(),
+            serde.clone(),
+        ];
+        serde.version = Some("1.0".to_string());
+        serde.features.push("derive".to_string());
+        let crates = dedup_crates(crates.iter().chain(Some(&serde)));
+
+        let s = cargotoml_contents("lib", crates.into_iter());
+        assert!(s.contains("\nrand = \"*\"\n"));
+        assert!(s.contains("\nserde = { version = \"1.0\", features = [\"derive\"] }\n"));
+    }
 }
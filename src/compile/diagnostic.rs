@@ -0,0 +1,336 @@
+use crate::code::{self, InputLoc, ModsMap, StaticFiles};
+use crate::linking::LinkingConfiguration;
+use serde_json::Value;
+use std::fmt::{self, Write};
+use std::path::Path;
+
+/// A compiler diagnostic, parsed from `rustc`'s JSON message format.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    /// The level, such as `error` or `warning`.
+    pub level: String,
+    /// The error code, such as `E0308`.
+    pub code: Option<String>,
+    /// The main message.
+    pub message: String,
+    /// The source code locations of the diagnostic.
+    pub spans: Vec<DiagnosticSpan>,
+    /// Attached notes and help messages.
+    pub children: Vec<Diagnostic>,
+    /// The diagnostic rendered by `rustc`, referencing the generated source code.
+    pub rendered: Option<String>,
+}
+
+/// A source code location of a [`Diagnostic`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiagnosticSpan {
+    /// The file, relative to the compile directory.
+    pub file_name: String,
+    /// The start byte offset in the file.
+    pub byte_start: usize,
+    /// The end byte offset in the file.
+    pub byte_end: usize,
+    /// The 1-based start line.
+    pub line_start: usize,
+    /// The 1-based start column.
+    pub column_start: usize,
+    /// Whether this is the main location of the diagnostic.
+    pub is_primary: bool,
+    /// A message attached to the location.
+    pub label: Option<String>,
+}
+
+impl Diagnostic {
+    /// Parse a line of `cargo`'s or `rustc`'s JSON message output.
+    ///
+    /// A cargo message is only a diagnostic if its reason is `compiler-message`, `None` is returned
+    /// for other messages or invalid json.
+    pub fn from_json(line: &str) -> Option<Self> {
+        let value: Value = serde_json::from_str(line).ok()?;
+        match value.get("reason") {
+            Some(reason) if reason == "compiler-message" => Self::from_value(value.get("message")?),
+            Some(_) => None,
+            None => Self::from_value(&value),
+        }
+    }
+
+    fn from_value(value: &Value) -> Option<Self> {
+        let string = |v: &Value, key| v.get(key).and_then(Value::as_str).map(String::from);
+        let num = |v: &Value, key| v.get(key).and_then(Value::as_u64).unwrap_or(0) as usize;
+
+        let spans = value
+            .get("spans")
+            .and_then(Value::as_array)
+            .map(|x| x.as_slice())
+            .unwrap_or(&[])
+            .iter()
+            .map(|span| DiagnosticSpan {
+                file_name: string(span, "file_name").unwrap_or_default(),
+                byte_start: num(span, "byte_start"),
+                byte_end: num(span, "byte_end"),
+                line_start: num(span, "line_start"),
+                column_start: num(span, "column_start"),
+                is_primary: span
+                    .get("is_primary")
+                    .and_then(Value::as_bool)
+                    .unwrap_or(false),
+                label: string(span, "label"),
+            })
+            .collect();
+
+        let children = value
+            .get("children")
+            .and_then(Value::as_array)
+            .map(|x| x.iter().filter_map(Self::from_value).collect())
+            .unwrap_or_default();
+
+        Some(Diagnostic {
+            level: string(value, "level")?,
+            code: value.get("code").and_then(|x| string(x, "code")),
+            message: string(value, "message")?,
+            spans,
+            children,
+            rendered: string(value, "rendered"),
+        })
+    }
+
+    /// Whether this is an error with a primary span in the code wrapping statement groups to
+    /// persist their bindings, such as bindings which are not `Send + 'static`.
+    pub fn blames_persistence(
+        &self,
+        mods_map: &ModsMap,
+        linking_config: &LinkingConfiguration,
+        static_files: &StaticFiles,
+    ) -> bool {
+        self.level == "error"
+            && self.spans.iter().filter(|x| x.is_primary).any(|span| {
+                Path::new(&span.file_name)
+                    .strip_prefix("src")
+                    .map(|file| {
+                        code::in_persist_wrapper(
+                            mods_map,
+                            linking_config,
+                            static_files,
+                            file,
+                            span.byte_start..span.byte_end,
+                        )
+                    })
+                    .unwrap_or(false)
+            })
+    }
+
+    /// Render the diagnostic with carets under the REPL input.
+    ///
+    /// Spans in the generated module files are mapped back to the statement or item they fall in
+    /// using the same arguments the source code was constructed with. Spans in generated code are
+    /// omitted, spans in other files reference the file.
+    pub fn render(
+        &self,
+        mods_map: &ModsMap,
+        linking_config: &LinkingConfiguration,
+        static_files: &StaticFiles,
+    ) -> String {
+        let mut s = String::new();
+        self.write_header(&mut s);
+
+        let mut prev = None;
+        let mut spans: Vec<_> = self.spans.iter().collect();
+        spans.sort_by_key(|x| !x.is_primary); // primary first, stable
+
+        for span in spans {
+            let file = Path::new(&span.file_name)
+                .strip_prefix("src")
+                .ok()
+                .filter(|f| code::file_mod(mods_map, f).is_some());
+            let file = match file {
+                Some(x) => x,
+                None => {
+                    write!(
+                        &mut s,
+                        "\n --> {}:{}:{}",
+                        span.file_name, span.line_start, span.column_start
+                    )
+                    .ok();
+                    prev = None;
+                    continue;
+                }
+            };
+
+            let located = code::locate_input(
+                mods_map,
+                linking_config,
+                static_files,
+                file,
+                span.byte_start..span.byte_end,
+            );
+            let (path, loc, rng) = match located {
+                Some(x) => x,
+                None => continue,
+            };
+            let src = match mods_map.get(path) {
+                Some(x) => x,
+                None => continue,
+            };
+            let text = match loc {
+                InputLoc::Stmt { grp, stmt } => &src.stmts[grp].0[stmt].expr,
+                InputLoc::Item(i) => &src.items[i].0,
+            };
+
+            if prev != Some((path, loc)) {
+                write_input_header(&mut s, path, loc);
+                prev = Some((path, loc));
+            }
+
+            let marker = if span.is_primary { '^' } else { '-' };
+            write_marked_line(&mut s, text, rng, marker, span.label.as_deref());
+        }
+
+        for child in &self.children {
+            write!(&mut s, "\n  = {}: {}", child.level, child.message).ok();
+        }
+
+        s
+    }
+
+    fn write_header(&self, buf: &mut String) {
+        buf.push_str(&self.level);
+        if let Some(code) = &self.code {
+            buf.push('[');
+            buf.push_str(code);
+            buf.push(']');
+        }
+        buf.push_str(": ");
+        buf.push_str(&self.message);
+    }
+}
+
+/// Writes the location of a REPL input, such as ` --> [out0]`.
+pub(super) fn write_input_header(buf: &mut String, path: &Path, loc: InputLoc) {
+    buf.push_str("\n --> ");
+    if path != Path::new("lib") {
+        write!(buf, "{} ", path.display()).ok();
+    }
+    match loc {
+        InputLoc::Stmt { grp, .. } => write!(buf, "[out{}]", grp).ok(),
+        InputLoc::Item(i) => write!(buf, "[item {}]", i).ok(),
+    };
+    buf.push_str("\n  |");
+}
+
+/// Writes the line of `text` containing the start of `rng` with markers underneath.
+pub(super) fn write_marked_line(
+    buf: &mut String,
+    text: &str,
+    rng: std::ops::Range<usize>,
+    marker: char,
+    label: Option<&str>,
+) {
+    let line_start = text[..rng.start].rfind('\n').map(|x| x + 1).unwrap_or(0);
+    let line_end = text[rng.start..]
+        .find('\n')
+        .map(|x| x + rng.start)
+        .unwrap_or_else(|| text.len());
+    let line = &text[line_start..line_end];
+
+    let indent = text[line_start..rng.start].chars().count();
+    let width = text[rng.start..rng.end.min(line_end)]
+        .chars()
+        .count()
+        .max(1);
+
+    buf.push_str("\n  | ");
+    buf.push_str(line);
+    buf.push_str("\n  | ");
+    buf.extend(std::iter::repeat(' ').take(indent));
+    buf.extend(std::iter::repeat(marker).take(width));
+    if let Some(label) = label {
+        buf.push(' ');
+        buf.push_str(label);
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.rendered {
+            Some(rendered) => write!(f, "{}", rendered.trim_end()),
+            None => {
+                let mut s = String::new();
+                self.write_header(&mut s);
+                write!(f, "{}", s)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::code::{SourceCode, Statement, StmtGrp};
+
+    #[test]
+    fn from_json_test() {
+        let json = r#"{"reason":"compiler-message","package_id":"papyrus_mem_code 0.1.0","message":{"message":"mismatched types","code":{"code":"E0308","explanation":null},"level":"error","spans":[{"file_name":"src/lib.rs","byte_start":10,"byte_end":13,"line_start":2,"line_end":2,"column_start":5,"column_end":8,"is_primary":true,"text":[],"label":"expected `i32`, found `&str`","suggested_replacement":null,"suggestion_applicability":null,"expansion":null}],"children":[{"message":"try this","code":null,"level":"help","spans":[],"children":[],"rendered":null}],"rendered":"error[E0308]: mismatched types\n"}}"#;
+        let d = Diagnostic::from_json(json).unwrap();
+        assert_eq!(d.level, "error");
+        assert_eq!(d.code.as_deref(), Some("E0308"));
+        assert_eq!(d.message, "mismatched types");
+        assert_eq!(d.spans.len(), 1);
+        assert_eq!(d.spans[0].byte_start, 10);
+        assert!(d.spans[0].is_primary);
+        assert_eq!(d.children[0].level, "help");
+        assert_eq!(d.to_string(), "error[E0308]: mismatched types");
+
+        assert_eq!(
+            Diagnostic::from_json(r#"{"reason":"compiler-artifact"}"#),
+            None
+        );
+        assert_eq!(Diagnostic::from_json("Compiling papyrus"), None);
+    }
+
+    #[test]
+    fn render_test() {
+        let mut src = SourceCode::default();
+        src.stmts.push(StmtGrp(vec![
+            Statement {
+                expr: "let a = 1".to_string(),
+                semi: true,
+            },
+            Statement {
+                expr: "a + \"b\"".to_string(),
+                semi: false,
+            },
+        ]));
+        let mut map = ModsMap::new();
+        map.insert("lib".into(), src);
+        let linking = LinkingConfiguration::default();
+        let files = StaticFiles::new();
+        let code = &code::construct_source_files(&map, &linking, &files)[0].1;
+
+        let start = code.find("\"b\"").unwrap();
+        let d = Diagnostic {
+            level: "error".to_string(),
+            code: Some("E0277".to_string()),
+            message: "cannot add `&str` to `{integer}`".to_string(),
+            spans: vec![DiagnosticSpan {
+                file_name: "src/lib.rs".to_string(),
+                byte_start: start,
+                byte_end: start + 3,
+                line_start: 0,
+                column_start: 0,
+                is_primary: true,
+                label: Some("no implementation".to_string()),
+            }],
+            children: vec![],
+            rendered: None,
+        };
+
+        assert_eq!(
+            d.render(&map, &linking, &files),
+            r#"error[E0277]: cannot add `&str` to `{integer}`
+ --> [out0]
+  |
+  | a + "b"
+  |     ^^^ no implementation"#
+        );
+    }
+}
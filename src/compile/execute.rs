@@ -1,19 +1,202 @@
+use super::diagnostic::{write_input_header, write_marked_line};
+use crate::code::{self, ModsMap, StaticFiles};
+use crate::linking::LinkingConfiguration;
 use ::kserd::Kserd;
 use libloading::{Library, Symbol};
-use std::path::Path;
+use std::{any::Any, borrow::Cow, error, fmt, io::Write, path::Path};
+
+/// Starts the error returned by an evaluation function when the evaluated code panicked.
+///
+/// Matches the generated `__papyrus_catch` function.
+pub(crate) const PANIC_MARKER: &str = "\x1epapyrus-panic\x1f";
 
 /// We don't type anything here. You must be **VERY** careful to pass through the correct borrow to match the
 /// function signature!
-type DataFunc<D> = unsafe fn(D) -> Kserd<'static>;
+type DataFunc<D> = unsafe fn(D) -> Result<(Kserd<'static>, String), String>;
+
+/// The evaluation function when evaluating with a persistent store.
+type PersistFunc<D> =
+    unsafe fn(&mut Vec<Box<dyn Any + Send>>, D) -> Result<(Kserd<'static>, String), String>;
+
+/// The output, the type name of the output, and the library which produced it.
+type ExecResult = Result<(Kserd<'static>, String, Library), ExecError>;
+
+/// An error executing an evaluation function.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExecError {
+    /// The evaluation did not run to completion, such as the library failing to load or the
+    /// worker process crashing.
+    Failed(Cow<'static, str>),
+    /// The evaluated code returned an error through the `?` operator.
+    Returned(String),
+    /// The evaluated code panicked.
+    Panicked(EvalPanic),
+}
+
+/// The details of a panic in evaluated code.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EvalPanic {
+    /// The panic message.
+    pub message: String,
+    /// The file, relative to the compile directory, the panic occurred in.
+    pub file: String,
+    /// The 1-based line of the panic.
+    pub line: usize,
+    /// The 1-based column of the panic.
+    pub column: usize,
+    /// The backtrace, captured if enabled with the `RUST_BACKTRACE` environment variable.
+    pub backtrace: Option<String>,
+}
+
+impl ExecError {
+    /// The error of an evaluation function, which is either a returned error or an encoded panic.
+    pub(crate) fn from_returned(e: String) -> Self {
+        match e.strip_prefix(PANIC_MARKER) {
+            Some(panic) => ExecError::Panicked(EvalPanic::decode(panic)),
+            None => ExecError::Returned(e),
+        }
+    }
+
+    /// Render the error, with any panic location mapped back to the REPL input. See
+    /// [`EvalPanic::render`].
+    pub fn render(
+        &self,
+        mods_map: &ModsMap,
+        linking_config: &LinkingConfiguration,
+        static_files: &StaticFiles,
+    ) -> String {
+        match self {
+            ExecError::Panicked(panic) => panic.render(mods_map, linking_config, static_files),
+            e => e.to_string(),
+        }
+    }
+}
 
-type ExecResult = Result<(Kserd<'static>, Library), &'static str>;
+impl EvalPanic {
+    /// Decodes the fields following the [`PANIC_MARKER`].
+    fn decode(s: &str) -> Self {
+        let mut fields = s.splitn(5, '\x1f');
+        let mut next = || fields.next().unwrap_or_default();
+        let message = next().to_string();
+        let file = next().to_string();
+        let line = next().parse().unwrap_or_default();
+        let column = next().parse().unwrap_or_default();
+        let backtrace = Some(next()).filter(|x| !x.is_empty()).map(String::from);
+
+        EvalPanic {
+            message,
+            file,
+            line,
+            column,
+            backtrace,
+        }
+    }
+
+    /// Encodes the panic as an evaluation function would return it.
+    pub(crate) fn encode(&self) -> String {
+        format!(
+            "{}{}\x1f{}\x1f{}\x1f{}\x1f{}",
+            PANIC_MARKER,
+            self.message,
+            self.file,
+            self.line,
+            self.column,
+            self.backtrace.as_deref().unwrap_or_default()
+        )
+    }
 
-pub(crate) fn exec<P: AsRef<Path>, D>(
+    /// Render the panic with a marker under the REPL input it occurred in.
+    ///
+    /// The location is mapped back using the same arguments the source code was constructed with.
+    /// Locations outside the generated module files reference the file.
+    pub fn render(
+        &self,
+        mods_map: &ModsMap,
+        linking_config: &LinkingConfiguration,
+        static_files: &StaticFiles,
+    ) -> String {
+        let mut s = format!("evaluation panicked: {}", self.message);
+
+        let file = Path::new(&self.file).strip_prefix("src").ok();
+        let located = file.and_then(|file| {
+            let (_, src) = code::construct_source_files(mods_map, linking_config, static_files)
+                .into_iter()
+                .find(|x| x.0 == file)?;
+            let i = byte_offset(&src, self.line, self.column)?;
+            code::locate_input(mods_map, linking_config, static_files, file, i..i)
+        });
+
+        match located {
+            Some((path, loc, rng)) => {
+                let src = &mods_map[path];
+                let text = match loc {
+                    code::InputLoc::Stmt { grp, stmt } => &src.stmts[grp].0[stmt].expr,
+                    code::InputLoc::Item(i) => &src.items[i].0,
+                };
+                write_input_header(&mut s, path, loc);
+                write_marked_line(&mut s, text, rng, '^', None);
+            }
+            None if !self.file.is_empty() => {
+                s.push_str(&format!(
+                    "\n --> {}:{}:{}",
+                    self.file, self.line, self.column
+                ));
+            }
+            None => (),
+        }
+
+        if let Some(bt) = &self.backtrace {
+            s.push_str("\nstack backtrace:\n");
+            s.push_str(bt.trim_end());
+        }
+
+        s
+    }
+}
+
+/// The byte offset of a 1-based line and (character) column.
+fn byte_offset(src: &str, line: usize, column: usize) -> Option<usize> {
+    let start: usize = src
+        .split('\n')
+        .take(line.checked_sub(1)?)
+        .map(|x| x.len() + 1)
+        .sum();
+    let line = src.get(start..)?.split('\n').next()?;
+    let col = line
+        .char_indices()
+        .nth(column.saturating_sub(1))
+        .map(|x| x.0)
+        .unwrap_or_else(|| line.len());
+    Some(start + col)
+}
+
+impl error::Error for ExecError {}
+
+impl fmt::Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExecError::Failed(e) => write!(f, "{}", e),
+            ExecError::Returned(e) => write!(f, "evaluation returned an error: {}", e),
+            ExecError::Panicked(panic) => write!(f, "evaluation panicked: {}", panic.message),
+        }
+    }
+}
+
+/// Execute the evaluation function of a library.
+///
+/// If `wtr` is given, stdout and stderr are captured into it while executing.
+pub(crate) fn exec<P: AsRef<Path>, D, W: Write + Send>(
     library_file: P,
     function_name: &str,
     app_data: D,
+    wtr: Option<&mut W>,
 ) -> ExecResult {
-    exec_no_redirect(library_file, function_name, app_data)
+    match wtr {
+        Some(wtr) => super::redirect::capture(wtr, || {
+            exec_no_redirect(library_file, function_name, app_data)
+        }),
+        None => exec_no_redirect(library_file, function_name, app_data),
+    }
 }
 
 fn exec_no_redirect<P: AsRef<Path>, Data>(
@@ -21,17 +204,75 @@ fn exec_no_redirect<P: AsRef<Path>, Data>(
     function_name: &str,
     app_data: Data,
 ) -> ExecResult {
-    let lib = get_lib(library_file)?;
-    let func = get_func(&lib, function_name)?;
+    let lib = get_lib(library_file).map_err(failed)?;
+    let func = get_func(&lib, function_name).map_err(failed)?;
 
     let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| unsafe { func(app_data) }));
 
     match res {
-        Ok(kserd) => Ok((kserd, lib)),
-        Err(_) => Err("a panic occured with evaluation"),
+        Ok(Ok((kserd, type_name))) => Ok((kserd, type_name, lib)),
+        Ok(Err(e)) => Err(ExecError::from_returned(e)),
+        Err(_) => Err(failed("a panic occured with evaluation")),
+    }
+}
+
+/// Execute the evaluation function of a library compiled with
+/// [`persistent`](crate::linking::LinkingConfiguration::persistent) set.
+///
+/// Values the function stores are pushed onto `store`. They may reference code in the returned
+/// library, so the library must outlive them. If evaluation fails any newly stored values are
+/// dropped before the library is.
+///
+/// If `wtr` is given, stdout and stderr are captured into it while executing.
+pub(crate) fn exec_persistent<P: AsRef<Path>, D, W: Write + Send>(
+    library_file: P,
+    function_name: &str,
+    store: &mut Vec<Box<dyn Any + Send>>,
+    app_data: D,
+    wtr: Option<&mut W>,
+) -> ExecResult {
+    match wtr {
+        Some(wtr) => super::redirect::capture(wtr, || {
+            exec_persistent_no_redirect(library_file, function_name, store, app_data)
+        }),
+        None => exec_persistent_no_redirect(library_file, function_name, store, app_data),
+    }
+}
+
+fn exec_persistent_no_redirect<P: AsRef<Path>, D>(
+    library_file: P,
+    function_name: &str,
+    store: &mut Vec<Box<dyn Any + Send>>,
+    app_data: D,
+) -> ExecResult {
+    let lib = get_lib(library_file).map_err(failed)?;
+    let func: Symbol<PersistFunc<D>> = unsafe {
+        lib.get(function_name.as_bytes())
+            .map_err(|_| failed("failed to find function in library"))?
+    };
+
+    let len = store.len();
+    let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| unsafe {
+        func(store, app_data)
+    }));
+
+    if !matches!(res, Ok(Ok(_))) {
+        while store.len() > len {
+            store.pop();
+        }
+    }
+
+    match res {
+        Ok(Ok((kserd, type_name))) => Ok((kserd, type_name, lib)),
+        Ok(Err(e)) => Err(ExecError::from_returned(e)),
+        Err(_) => Err(failed("a panic occured with evaluation")),
     }
 }
 
+fn failed(e: &'static str) -> ExecError {
+    ExecError::Failed(Cow::Borrowed(e))
+}
+
 fn get_lib<P: AsRef<Path>>(path: P) -> Result<Library, &'static str> {
     // If segfaults are occurring maybe use this, SIGSEV?
     // This is shown in https://github.com/nagisa/rust_libloading/issues/41
@@ -54,3 +295,86 @@ fn get_func<'l, Data>(
             .map_err(|_| "failed to find function in library")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::code::{SourceCode, Statement, StmtGrp};
+
+    #[test]
+    fn from_returned_test() {
+        assert_eq!(
+            ExecError::from_returned("oops".to_string()),
+            ExecError::Returned("oops".to_string())
+        );
+
+        let panic = EvalPanic {
+            message: "explicit panic".to_string(),
+            file: "src/lib.rs".to_string(),
+            line: 4,
+            column: 1,
+            backtrace: None,
+        };
+        assert_eq!(
+            ExecError::from_returned(panic.encode()),
+            ExecError::Panicked(panic.clone())
+        );
+
+        let panic = EvalPanic {
+            backtrace: Some("0: main".to_string()),
+            ..panic
+        };
+        assert_eq!(
+            ExecError::from_returned(panic.encode()),
+            ExecError::Panicked(panic)
+        );
+    }
+
+    #[test]
+    fn render_panic_test() {
+        let mut src = SourceCode::default();
+        src.stmts.push(StmtGrp(vec![Statement {
+            expr: "None::<i32>.unwrap()".to_string(),
+            semi: false,
+        }]));
+        let mut map = ModsMap::new();
+        map.insert("lib".into(), src);
+        let linking = LinkingConfiguration::default();
+        let files = StaticFiles::new();
+        let (code, _) = code::construct_source_code(&map, &linking, &files);
+
+        let (line, text) = code
+            .lines()
+            .enumerate()
+            .find(|x| x.1.contains("None::<i32>.unwrap()"))
+            .unwrap();
+        let column = text.find(".unwrap").unwrap() + 2;
+
+        let panic = EvalPanic {
+            message: "called `Option::unwrap()` on a `None` value".to_string(),
+            file: "src/lib.rs".to_string(),
+            line: line + 1,
+            column,
+            backtrace: None,
+        };
+        assert_eq!(
+            panic.render(&map, &linking, &files),
+            r#"evaluation panicked: called `Option::unwrap()` on a `None` value
+ --> [out0]
+  |
+  | None::<i32>.unwrap()
+  |             ^"#
+        );
+
+        let panic = EvalPanic {
+            file: "src/other.rs".to_string(),
+            line: 2,
+            column: 3,
+            ..panic
+        };
+        assert_eq!(
+            panic.render(&map, &linking, &files),
+            "evaluation panicked: called `Option::unwrap()` on a `None` value\n --> src/other.rs:2:3"
+        );
+    }
+}
@@ -1,12 +1,23 @@
 //! Pertains to compiling a working directory into a library, then executing a function in that library.
 
+mod backend;
 mod build;
 mod construct;
+mod diagnostic;
 mod execute;
-
-pub use self::build::{compile, unshackle_library_file, CompilationError};
-pub use self::construct::build_compile_dir;
-pub(crate) use self::execute::exec;
+mod redirect;
+mod worker;
+
+pub use self::backend::{CargoBackend, CompilerBackend, MockBackend, RustcBackend};
+pub use self::build::{compile, compile_cancellable, unshackle_library_file, CompilationError};
+pub use self::construct::{build_compile_dir, build_export_dir};
+pub use self::diagnostic::{Diagnostic, DiagnosticSpan};
+pub(crate) use self::execute::{exec, exec_persistent};
+pub use self::execute::{EvalPanic, ExecError};
+#[cfg(feature = "runnable")]
+pub(crate) use self::redirect::terminal;
+pub(crate) use self::worker::exec_in_worker;
+pub use self::worker::{run_if_worker, ExecutionBackend};
 
 /// The library name to compile as.c
 const LIBRARY_NAME: &str = "papyrus_mem_code";
@@ -35,7 +46,7 @@ mod tests {
         let path = compile(&compile_dir, &linking_config, |_| ()).unwrap();
 
         // eval
-        let r = exec::<_, _>(path, "_lib_intern_eval", &()).unwrap(); // execute library fn
+        let r = exec::<_, _, std::io::Sink>(path, "_lib_intern_eval", &(), None).unwrap(); // execute library fn
 
         assert_eq!(r.0, Kserd::new_num(4));
     }
@@ -60,7 +71,7 @@ mod tests {
         let path = compile(&compile_dir, &linking_config, |_| ()).unwrap();
 
         // eval
-        let r = exec::<_, _>(path, "_lib_intern_eval", &()).unwrap(); // execute library fn
+        let r = exec::<_, _, std::io::Sink>(path, "_lib_intern_eval", &(), None).unwrap(); // execute library fn
 
         assert_eq!(r.0, Kserd::new_num(4));
     }
@@ -91,7 +102,7 @@ mod tests {
         let path = compile(&compile_dir, &linking_config, |_| ()).unwrap();
 
         // eval
-        let r = exec::<_, _>(path, "_lib_intern_eval", &()).unwrap(); // execute library fn
+        let r = exec::<_, _, std::io::Sink>(path, "_lib_intern_eval", &(), None).unwrap(); // execute library fn
 
         assert_eq!(r.0, Kserd::new_num(4));
     }
@@ -122,9 +133,91 @@ mod tests {
         let path = compile(&compile_dir, &linking_config, |_| ()).unwrap();
 
         // eval
-        let r = exec(path, "_lib_intern_eval", &()).unwrap(); // execute library fn
+        let mut output = Vec::new();
+        let r = exec(path, "_lib_intern_eval", &(), Some(&mut output)).unwrap(); // execute library fn
+
+        assert_eq!(r.0, Kserd::new_num(4));
+        assert_eq!(r.1, "i32");
+    }
+
+    #[test]
+    fn exec_and_capture_test() {
+        let compile_dir = "target/testing/exec_and_capture_test";
+        let files = vec![{
+            let mut code = SourceCode::default();
+            code.stmts.push(StmtGrp(vec![
+                Statement {
+                    expr: "println!(\"Hello, world!\")".to_string(),
+                    semi: true,
+                },
+                Statement {
+                    expr: "eprintln!(\"oops\")".to_string(),
+                    semi: true,
+                },
+                Statement {
+                    expr: "2+2".to_string(),
+                    semi: false,
+                },
+            ]));
+            ("lib".into(), code)
+        }]
+        .into_iter()
+        .collect();
+        let linking_config = LinkingConfiguration::default();
+
+        build_compile_dir(compile_dir, &files, &linking_config, &StaticFiles::new()).unwrap();
+        let path = compile(compile_dir, &linking_config, |_| ()).unwrap();
 
+        let mut output = Vec::new();
+        let r = exec(path, "_lib_intern_eval", &(), Some(&mut output)).unwrap();
         assert_eq!(r.0, Kserd::new_num(4));
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("Hello, world!\noops\n"), "{}", output);
+    }
+
+    #[test]
+    fn try_operator_build_compile_eval_test() {
+        let compile_dir = "target/testing/try_operator_build_compile_eval_test";
+        let files = vec![{
+            let mut code = SourceCode::default();
+            code.stmts.push(StmtGrp(vec![Statement {
+                expr: "\"4\".parse::<u8>()?".to_string(),
+                semi: false,
+            }]));
+            ("lib".into(), code)
+        }]
+        .into_iter()
+        .collect();
+        let linking_config = LinkingConfiguration::default();
+
+        build_compile_dir(compile_dir, &files, &linking_config, &StaticFiles::new()).unwrap();
+        let path = compile(compile_dir, &linking_config, |_| ()).unwrap();
+        let r = exec::<_, _, std::io::Sink>(path, "_lib_intern_eval", &(), None).unwrap(); // execute library fn
+
+        assert_eq!(r.0, Kserd::new_num(4));
+
+        // an error is passed back
+        let compile_dir = "target/testing/try_operator_err_build_compile_eval_test";
+        let files = vec![{
+            let mut code = SourceCode::default();
+            code.stmts.push(StmtGrp(vec![Statement {
+                expr: "\"a\".parse::<u8>()?".to_string(),
+                semi: false,
+            }]));
+            ("lib".into(), code)
+        }]
+        .into_iter()
+        .collect();
+
+        build_compile_dir(compile_dir, &files, &linking_config, &StaticFiles::new()).unwrap();
+        let path = compile(compile_dir, &linking_config, |_| ()).unwrap();
+        let r = exec::<_, _, std::io::Sink>(path, "_lib_intern_eval", &(), None); // execute library fn
+
+        assert_eq!(
+            r.map(|x| x.0).unwrap_err().to_string(),
+            "evaluation returned an error: invalid digit found in string"
+        );
     }
 
     #[test]
@@ -143,33 +236,61 @@ mod tests {
         let r = compile(&compile_dir, &linking_config, |_| ());
         assert!(r.is_err());
         match r.unwrap_err() {
-            CompilationError::CompileError(_) => (),
-            _ => panic!("expecting CompileError"),
+            CompilationError::Diagnostics(d) => {
+                assert_eq!(d[0].level, "error");
+                let rendered = d[0].render(&files, &linking_config, &StaticFiles::new());
+                assert!(rendered.contains("\n --> [out0]\n  |\n  | 2+\n"));
+            }
+            _ => panic!("expecting Diagnostics"),
         }
     }
 
-    // TODO enable when not on nightly
-    // Maybe look into why it doesn't work on nightly?
-    // #[test]
-    // fn fail_eval_test() {
-    //     let compile_dir = "target/testing/fail_eval_test";
-    //     let files = vec![fail_eval_file()];
-    //     let linking_config = LinkingConfiguration::default();
-
-    //     // build
-    //     build_compile_dir(&compile_dir, files.iter(), &linking_config).unwrap();
-    //     assert!(fs::read_to_string(&format!("{}/src/lib.rs", compile_dir))
-    //         .unwrap()
-    //         .contains("\nlet out0 = panic!(\"eval panic\");"));
-
-    //     // compile
-    //     let path = compile(&compile_dir, &linking_config, |_| ()).unwrap();
-
-    //     // eval
-    //     let r = exec::<_, _, std::io::Sink>(&path, "_lib_intern_eval", &(), None); // execute library fn
-    //     assert!(r.is_err());
-    //     assert_eq!(r, Err("a panic occured with evaluation"));
-    // }
+    #[test]
+    fn cancel_compile_test() {
+        let compile_dir = "target/testing/cancel_compile";
+        let files = vec![pass_compile_eval_file()].into_iter().collect();
+        let linking_config = LinkingConfiguration::default();
+
+        build_compile_dir(compile_dir, &files, &linking_config, &StaticFiles::new()).unwrap();
+        let r = compile_cancellable(compile_dir, &linking_config, || true, |_| ());
+        match r {
+            Err(CompilationError::Cancelled) => (),
+            _ => panic!("expecting Cancelled"),
+        }
+    }
+
+    #[test]
+    fn fail_eval_test() {
+        let compile_dir = "target/testing/fail_eval_test";
+        let files = vec![fail_eval_file()].into_iter().collect();
+        let linking_config = LinkingConfiguration::default();
+
+        // build
+        build_compile_dir(compile_dir, &files, &linking_config, &StaticFiles::new()).unwrap();
+        assert!(fs::read_to_string(format!("{}/src/lib.rs", compile_dir))
+            .unwrap()
+            .contains("\nlet out0 = None::<i32>.unwrap();"));
+
+        // compile
+        let path = compile(compile_dir, &linking_config, |_| ()).unwrap();
+
+        // eval
+        let r = exec::<_, _, std::io::Sink>(path, "_lib_intern_eval", &(), None); // execute library fn
+        let e = r.map(|x| x.0).unwrap_err();
+        match &e {
+            ExecError::Panicked(panic) => {
+                assert_eq!(panic.message, "called `Option::unwrap()` on a `None` value");
+                assert_eq!(panic.file, "src/lib.rs");
+            }
+            e => panic!("expecting Panicked, got {:?}", e),
+        }
+        let rendered = e.render(&files, &linking_config, &StaticFiles::new());
+        assert!(
+            rendered.contains("\n --> [out0]\n  |\n  | None::<i32>.unwrap()\n  |             ^"),
+            "{}",
+            rendered
+        );
+    }
 
     fn pass_compile_eval_file() -> (PathBuf, SourceCode) {
         let mut code = SourceCode::default();
@@ -180,6 +301,15 @@ mod tests {
         ("lib".into(), code)
     }
 
+    fn fail_eval_file() -> (PathBuf, SourceCode) {
+        let mut code = SourceCode::default();
+        code.stmts.push(StmtGrp(vec![Statement {
+            expr: "None::<i32>.unwrap()".to_string(),
+            semi: false,
+        }]));
+        ("lib".into(), code)
+    }
+
     fn fail_compile_file() -> (PathBuf, SourceCode) {
         let mut code = SourceCode::default();
         code.stmts.push(StmtGrp(vec![Statement {
@@ -227,7 +357,7 @@ mod tests {
         let path = compile(&compile_dir, &linking_config, |_| ()).unwrap();
 
         // eval
-        let r = exec::<_, _>(path, "_lib_intern_eval", &()).unwrap(); // execute library fn
+        let r = exec::<_, _, std::io::Sink>(path, "_lib_intern_eval", &(), None).unwrap(); // execute library fn
 
         assert_eq!(r.0, Kserd::new_num(4));
     }
@@ -0,0 +1,273 @@
+//! Capturing the standard output and error of evaluated code.
+//!
+//! On unix file descriptors 1 and 2 are pointed at a pipe while the code executes, and a thread
+//! streams the pipe into a writer. Other platforms do not capture output.
+use std::io::{self, Write};
+
+/// Run `f`, writing anything the process writes to stdout or stderr into `wtr` as it is produced.
+///
+/// Only one capture is active at a time, concurrent captures wait. If the redirection cannot be
+/// set up `f` is run without capturing.
+#[cfg(unix)]
+pub(crate) fn capture<W, F, R>(wtr: &mut W, f: F) -> R
+where
+    W: Write + Send,
+    F: FnOnce() -> R,
+{
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    let _lock = unix::LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    let (redirect, rdr) = match unix::Redirect::new() {
+        Ok(x) => x,
+        Err(e) => {
+            error!("failed to redirect stdio: {}", e);
+            return f();
+        }
+    };
+
+    let done = AtomicBool::new(false);
+
+    std::thread::scope(|scope| {
+        let jh = scope.spawn(|| unix::forward(rdr, wtr, &done));
+
+        let r = {
+            let _redirect = redirect; // restored on drop, even if `f` panics
+            f()
+        };
+        done.store(true, Ordering::SeqCst);
+
+        match jh.join() {
+            Ok(Err(e)) => error!("failed forwarding captured output: {}", e),
+            Err(_) => error!("capturing output thread panicked"),
+            Ok(Ok(())) => (),
+        }
+
+        r
+    })
+}
+
+/// Run `f`, output is not captured on this platform.
+#[cfg(not(unix))]
+pub(crate) fn capture<W, F, R>(_wtr: &mut W, f: F) -> R
+where
+    W: Write + Send,
+    F: FnOnce() -> R,
+{
+    f()
+}
+
+/// A writer to the terminal, being stdout as it was before any capturing.
+///
+/// Writing to the terminal while output is being captured must go through this, otherwise the
+/// text is captured as well.
+#[cfg(all(unix, feature = "runnable"))]
+pub(crate) fn terminal() -> unix::Terminal {
+    unix::Terminal::get()
+}
+
+/// A writer to the terminal.
+#[cfg(all(not(unix), feature = "runnable"))]
+pub(crate) fn terminal() -> io::Stdout {
+    io::stdout()
+}
+
+/// Writes the valid UTF-8 prefix of `buf` to `wtr`, keeping a trailing partial character.
+fn write_utf8<W: Write>(buf: &mut Vec<u8>, wtr: &mut W) -> io::Result<()> {
+    let valid = match std::str::from_utf8(buf) {
+        Err(e) if e.error_len().is_none() => e.valid_up_to(),
+        _ => buf.len(),
+    };
+    wtr.write_all(&buf[..valid])?;
+    buf.drain(..valid);
+    wtr.flush()
+}
+
+#[cfg(unix)]
+mod unix {
+    use super::*;
+    use libc::c_int;
+    use std::fs::File;
+    use std::io::Read;
+    use std::os::unix::io::{FromRawFd, RawFd};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Mutex, OnceLock};
+
+    /// Milliseconds to wait for more output once the capture has finished.
+    const DRAIN_POLL_MS: c_int = 20;
+
+    pub static LOCK: Mutex<()> = Mutex::new(());
+
+    /// A duplicate of stdout made before the first capture.
+    static TERMINAL: OnceLock<RawFd> = OnceLock::new();
+
+    /// Redirects stdout and stderr to a pipe, restoring them on drop.
+    pub struct Redirect {
+        saved: [RawFd; 2],
+    }
+
+    impl Redirect {
+        /// Returns the redirect and the read end of the pipe.
+        pub fn new() -> io::Result<(Self, File)> {
+            terminal_fd();
+            flush_std();
+
+            let mut fds = [0; 2];
+            cvt(unsafe { libc::pipe(fds.as_mut_ptr()) })?;
+            let [rdr, wtr] = fds;
+            // the pipe ends are not inherited, child processes get the redirected fds
+            for &fd in &fds {
+                cvt(unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) })?;
+            }
+            let rdr = unsafe { File::from_raw_fd(rdr) };
+
+            let r = Self::point_to(wtr);
+            unsafe { libc::close(wtr) };
+            r.map(|redirect| (redirect, rdr))
+        }
+
+        fn point_to(wtr: RawFd) -> io::Result<Self> {
+            let out = dup_cloexec(1)?;
+            let err = dup_cloexec(2).inspect_err(|_| unsafe {
+                libc::close(out);
+            })?;
+            let redirect = Redirect { saved: [out, err] };
+
+            cvt(unsafe { libc::dup2(wtr, 1) })?;
+            cvt(unsafe { libc::dup2(wtr, 2) })?;
+            Ok(redirect)
+        }
+    }
+
+    impl Drop for Redirect {
+        fn drop(&mut self) {
+            flush_std();
+            for (fd, saved) in [1, 2].iter().zip(&self.saved) {
+                unsafe {
+                    libc::dup2(*saved, *fd);
+                    libc::close(*saved);
+                }
+            }
+        }
+    }
+
+    /// Writes the pipe into `wtr` until it closes, or there is no more output once `done` is set.
+    pub fn forward<W: Write>(mut rdr: File, wtr: &mut W, done: &AtomicBool) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        let mut buf = Vec::new();
+        let mut chunk = [0; 4096];
+
+        loop {
+            // child processes can hold the pipe open past the capture, so do not wait for the end
+            let finished = done.load(Ordering::SeqCst);
+            let mut pollfd = libc::pollfd {
+                fd: rdr.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            match unsafe { libc::poll(&mut pollfd, 1, DRAIN_POLL_MS) } {
+                -1 => match io::Error::last_os_error() {
+                    e if e.kind() == io::ErrorKind::Interrupted => continue,
+                    e => return Err(e),
+                },
+                0 if finished => break,
+                0 => continue,
+                _ => (),
+            }
+
+            let n = rdr.read(&mut chunk)?;
+            if n == 0 {
+                break;
+            }
+            buf.extend_from_slice(&chunk[..n]);
+            write_utf8(&mut buf, wtr)?;
+        }
+
+        wtr.write_all(&buf)?;
+        wtr.flush()
+    }
+
+    /// Unbuffered writer to the terminal.
+    #[cfg(feature = "runnable")]
+    pub struct Terminal(std::mem::ManuallyDrop<File>);
+
+    #[cfg(feature = "runnable")]
+    impl Terminal {
+        pub fn get() -> Self {
+            // the fd is never closed, ManuallyDrop stops the File closing it
+            Terminal(std::mem::ManuallyDrop::new(unsafe {
+                File::from_raw_fd(terminal_fd())
+            }))
+        }
+    }
+
+    #[cfg(feature = "runnable")]
+    impl Write for Terminal {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    fn terminal_fd() -> RawFd {
+        *TERMINAL.get_or_init(|| dup_cloexec(1).unwrap_or(1))
+    }
+
+    fn dup_cloexec(fd: RawFd) -> io::Result<RawFd> {
+        cvt(unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) })
+    }
+
+    fn flush_std() {
+        io::stdout().flush().ok();
+        io::stderr().flush().ok();
+    }
+
+    fn cvt(r: c_int) -> io::Result<c_int> {
+        if r == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(r)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_utf8_test() {
+        let mut out = Vec::new();
+        let mut buf = "a€".as_bytes().to_vec();
+        buf.pop();
+        write_utf8(&mut buf, &mut out).unwrap();
+        assert_eq!(out, b"a");
+        assert_eq!(buf.len(), 2);
+
+        buf.push("€".as_bytes()[2]);
+        write_utf8(&mut buf, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "a€");
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn capture_test() {
+        let mut out = Vec::new();
+        let r = capture(&mut out, || {
+            // println! is captured by the test harness, write to the fds instead
+            io::stdout().write_all(b"to stdout\n").unwrap();
+            io::stdout().flush().unwrap();
+            io::stderr().write_all(b"to stderr\n").unwrap();
+            1
+        });
+        assert_eq!(r, 1);
+
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("to stdout\nto stderr\n"), "{}", out);
+    }
+}
@@ -0,0 +1,351 @@
+use super::{exec, ExecError};
+use ::kserd::{Kserd, Kstr, Number, Value};
+use serde_json::{json, Value as Json};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+/// Environment variable set on the worker process, the value is the library file.
+const WORKER_LIB_VAR: &str = "PAPYRUS_WORKER_LIB";
+/// Environment variable set on the worker process, the value is the function name.
+const WORKER_FN_VAR: &str = "PAPYRUS_WORKER_FN";
+/// Environment variable set on the worker process, the value is the file to write the result to.
+///
+/// The result is kept apart from stdout so output of the evaluated code cannot be mistaken for it.
+const WORKER_RESULT_VAR: &str = "PAPYRUS_WORKER_RESULT";
+/// How often the worker is checked for an interrupt.
+const INTERRUPT_POLL: Duration = Duration::from_millis(20);
+
+/// How the compiled code is executed.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum ExecutionBackend {
+    /// Load the library into the REPL process. This is the default.
+    ///
+    /// This is the fastest and supports app data, but a crash in the evaluated code (such as a
+    /// segfault, abort, or `process::exit`) takes down the REPL with it.
+    #[default]
+    InProcess,
+    /// Load the library in a child worker process and send back the result in a file.
+    ///
+    /// A crash only ends the worker and is reported as an evaluation error. The worker cannot
+    /// access app data, and statements are always replayed.
+    ///
+    /// The `program` is started with environment variables set, and must call [`run_if_worker`]
+    /// before doing anything else.
+    Worker {
+        /// The worker program, usually the current executable.
+        program: PathBuf,
+    },
+}
+
+impl ExecutionBackend {
+    /// A worker backend using the current executable.
+    pub fn current_exe_worker() -> io::Result<Self> {
+        std::env::current_exe().map(|program| ExecutionBackend::Worker { program })
+    }
+}
+
+/// Evaluate and exit if this process was started as a worker.
+///
+/// Call this at the start of `main` for programs used as an
+/// [`ExecutionBackend::Worker`]. If the process is not a worker this returns immediately.
+pub fn run_if_worker() {
+    let (lib, fn_name, result_file) = match (
+        std::env::var_os(WORKER_LIB_VAR),
+        std::env::var(WORKER_FN_VAR),
+        std::env::var_os(WORKER_RESULT_VAR),
+    ) {
+        (Some(lib), Ok(fn_name), Some(file)) => (lib, fn_name, file),
+        _ => return,
+    };
+
+    let res = exec(&lib, &fn_name, &(), None::<&mut io::Sink>).map(|(kserd, type_name, lib)| {
+        let json = encode_kserd(&kserd);
+        drop(kserd); // drop before the library that created it
+        drop(lib);
+        (json, type_name)
+    });
+
+    let msg = match res {
+        Ok((json, type_name)) => json!({ "ok": json, "type": type_name }),
+        Err(ExecError::Failed(e)) => json!({ "failed": e }),
+        Err(ExecError::Returned(e)) => json!({ "err": e }),
+        Err(ExecError::Panicked(panic)) => json!({ "err": panic.encode() }),
+    };
+
+    std::fs::write(result_file, msg.to_string()).ok();
+    io::stdout().flush().ok();
+    std::process::exit(0);
+}
+
+/// Execute a library function in a worker process, returning the output and its type name.
+///
+/// Output of the evaluated code is forwarded to stdout and stderr, or captured into `wtr` if
+/// given. `interrupt` is polled while the worker runs, if it returns a message the worker is
+/// killed and the message returned as the error.
+pub(crate) fn exec_in_worker<F, W>(
+    program: &Path,
+    library_file: &Path,
+    function_name: &str,
+    interrupt: F,
+    wtr: Option<&mut W>,
+) -> Result<(Kserd<'static>, String), ExecError>
+where
+    F: FnMut() -> Option<Cow<'static, str>>,
+    W: Write + Send,
+{
+    match wtr {
+        Some(wtr) => super::redirect::capture(wtr, || {
+            exec_no_redirect(program, library_file, function_name, interrupt)
+        }),
+        None => exec_no_redirect(program, library_file, function_name, interrupt),
+    }
+}
+
+fn exec_no_redirect<F>(
+    program: &Path,
+    library_file: &Path,
+    function_name: &str,
+    interrupt: F,
+) -> Result<(Kserd<'static>, String), ExecError>
+where
+    F: FnMut() -> Option<Cow<'static, str>>,
+{
+    let result_file = std::env::temp_dir().join(format!(
+        "papyrus.{}.result",
+        uuid::Uuid::new_v4().to_hyphenated()
+    ));
+    let r = exec_with_result_file(
+        program,
+        library_file,
+        function_name,
+        interrupt,
+        &result_file,
+    );
+    std::fs::remove_file(&result_file).ok();
+    r
+}
+
+fn exec_with_result_file<F>(
+    program: &Path,
+    library_file: &Path,
+    function_name: &str,
+    mut interrupt: F,
+    result_file: &Path,
+) -> Result<(Kserd<'static>, String), ExecError>
+where
+    F: FnMut() -> Option<Cow<'static, str>>,
+{
+    // output of the evaluated code goes straight to the inherited stdout and stderr
+    let mut child = Command::new(program)
+        .env(WORKER_LIB_VAR, library_file)
+        .env(WORKER_FN_VAR, function_name)
+        .env(WORKER_RESULT_VAR, result_file)
+        .stdin(Stdio::null())
+        .spawn()
+        .map_err(|e| failed(format!("failed to start worker process: {}", e)))?;
+
+    let status = loop {
+        if let Some(msg) = interrupt() {
+            child.kill().ok();
+            child.wait().ok();
+            return Err(failed(msg));
+        }
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) => std::thread::sleep(INTERRUPT_POLL),
+            Err(e) => return Err(failed(format!("failed waiting on worker process: {}", e))),
+        }
+    };
+
+    match std::fs::read_to_string(result_file) {
+        Ok(msg) => parse_result(&msg),
+        // the worker may have been interrupted by the same signal
+        Err(_) => Err(failed(
+            interrupt().unwrap_or_else(|| Cow::Owned(crash_message(status))),
+        )),
+    }
+}
+
+fn parse_result(msg: &str) -> Result<(Kserd<'static>, String), ExecError> {
+    let msg: Json =
+        serde_json::from_str(msg).map_err(|e| failed(format!("invalid worker result: {}", e)))?;
+    let string = |key| msg.get(key).and_then(Json::as_str).map(String::from);
+    match (msg.get("ok"), string("err"), string("failed")) {
+        (Some(json), _, _) => decode_kserd(json)
+            .map(|kserd| (kserd, string("type").unwrap_or_default()))
+            .ok_or_else(|| failed("invalid worker result: malformed kserd")),
+        (None, Some(e), _) => Err(ExecError::from_returned(e)),
+        (None, None, Some(e)) => Err(failed(e)),
+        (None, None, None) => Err(failed("invalid worker result")),
+    }
+}
+
+fn failed<E: Into<Cow<'static, str>>>(e: E) -> ExecError {
+    ExecError::Failed(e.into())
+}
+
+#[cfg(unix)]
+fn crash_message(status: std::process::ExitStatus) -> String {
+    use std::os::unix::process::ExitStatusExt;
+
+    match status.signal() {
+        Some(sig) => format!("evaluation crashed ({})", signal_name(sig)),
+        None => exited_message(status),
+    }
+}
+
+#[cfg(not(unix))]
+fn crash_message(status: std::process::ExitStatus) -> String {
+    exited_message(status)
+}
+
+fn exited_message(status: std::process::ExitStatus) -> String {
+    match status.code() {
+        Some(code) => format!("evaluation exited without a result (exit code {})", code),
+        None => "evaluation exited without a result".to_string(),
+    }
+}
+
+#[cfg(unix)]
+fn signal_name(sig: i32) -> Cow<'static, str> {
+    match sig {
+        4 => Cow::Borrowed("SIGILL"),
+        6 => Cow::Borrowed("SIGABRT"),
+        7 if cfg!(target_os = "linux") => Cow::Borrowed("SIGBUS"),
+        10 if !cfg!(target_os = "linux") => Cow::Borrowed("SIGBUS"),
+        8 => Cow::Borrowed("SIGFPE"),
+        9 => Cow::Borrowed("SIGKILL"),
+        11 => Cow::Borrowed("SIGSEGV"),
+        15 => Cow::Borrowed("SIGTERM"),
+        x => Cow::Owned(format!("signal {}", x)),
+    }
+}
+
+/// Encodes a `Kserd` as tagged json to send it from the worker. Numbers are encoded as strings to
+/// retain their precision. This differs from the natural json of `repl::kserd_to_json`.
+fn encode_kserd(kserd: &Kserd) -> Json {
+    let list = |x: &[Kserd]| Json::Array(x.iter().map(encode_kserd).collect());
+    let val = match &kserd.val {
+        Value::Unit => json!({ "unit": null }),
+        Value::Bool(x) => json!({ "bool": x }),
+        Value::Num(Number::Uint(x)) => json!({ "uint": x.to_string() }),
+        Value::Num(Number::Int(x)) => json!({ "int": x.to_string() }),
+        Value::Num(Number::Float(x)) => json!({ "float": x.to_string() }),
+        Value::Str(x) => json!({ "str": x.as_str() }),
+        Value::Barr(x) => json!({ "barr": x.as_bytes() }),
+        Value::Tuple(x) => json!({ "tuple": list(x) }),
+        Value::Seq(x) => json!({ "seq": list(x) }),
+        Value::Cntr(x) => {
+            let fields: serde_json::Map<_, _> = x
+                .iter()
+                .map(|(k, v)| (k.as_str().to_string(), encode_kserd(v)))
+                .collect();
+            json!({ "cntr": fields })
+        }
+        Value::Map(x) => {
+            let entries: Vec<_> = x
+                .iter()
+                .map(|(k, v)| json!([encode_kserd(k), encode_kserd(v)]))
+                .collect();
+            json!({ "map": entries })
+        }
+    };
+    json!({ "id": kserd.id.as_ref().map(|x| x.as_str()), "val": val })
+}
+
+/// Decodes a `Kserd` encoded with [`encode_kserd`].
+fn decode_kserd(json: &Json) -> Option<Kserd<'static>> {
+    let list = |x: &Json| -> Option<Vec<Kserd<'static>>> {
+        x.as_array()?.iter().map(decode_kserd).collect()
+    };
+
+    let val = json.get("val")?.as_object()?;
+    let (tag, x) = val.iter().next()?;
+    let val = match tag.as_str() {
+        "unit" => Value::Unit,
+        "bool" => Value::Bool(x.as_bool()?),
+        "uint" => Value::Num(Number::Uint(parse_num(x)?)),
+        "int" => Value::Num(Number::Int(parse_num(x)?)),
+        "float" => Value::Num(Number::Float(parse_num(x)?)),
+        "str" => Value::Str(Kstr::from(x.as_str()?.to_string())),
+        "barr" => {
+            let bytes: Option<Vec<u8>> = x
+                .as_array()?
+                .iter()
+                .map(|x| x.as_u64().map(|x| x as u8))
+                .collect();
+            Value::Barr(bytes?.into())
+        }
+        "tuple" => Value::Tuple(list(x)?),
+        "seq" => Value::Seq(list(x)?),
+        "cntr" => Value::Cntr(
+            x.as_object()?
+                .iter()
+                .map(|(k, v)| decode_kserd(v).map(|v| (Kstr::from(k.clone()), v)))
+                .collect::<Option<_>>()?,
+        ),
+        "map" => Value::Map(
+            x.as_array()?
+                .iter()
+                .map(|kv| Some((decode_kserd(kv.get(0)?)?, decode_kserd(kv.get(1)?)?)))
+                .collect::<Option<BTreeMap<_, _>>>()?,
+        ),
+        _ => return None,
+    };
+
+    let id = json.get("id")?.as_str().map(|x| Kstr::from(x.to_string()));
+    Some(Kserd { id, val })
+}
+
+fn parse_num<T: std::str::FromStr>(json: &Json) -> Option<T> {
+    json.as_str().and_then(|x| x.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_decode_kserd_test() {
+        let kserd = Kserd::new_cntr(vec![
+            ("a", Kserd::new_num(u128::MAX)),
+            ("b", Kserd::new_num(-1.5)),
+            ("c", Kserd::new_str("hello")),
+            ("d", Kserd::new_barrv(vec![0, 255])),
+            (
+                "e",
+                Kserd::new(Value::Tuple(vec![Kserd::new_unit(), Kserd::new_bool(true)])),
+            ),
+            (
+                "f",
+                Kserd::new_map(vec![(Kserd::new_num(1), Kserd::new(Value::Seq(vec![])))]),
+            ),
+        ])
+        .unwrap();
+        let kserd = Kserd {
+            id: Some(Kstr::brwed("Foo")),
+            ..kserd
+        };
+
+        let json = encode_kserd(&kserd);
+        assert_eq!(decode_kserd(&json), Some(kserd));
+    }
+
+    #[test]
+    fn parse_result_test() {
+        assert_eq!(
+            parse_result("{\"err\":\"e\"}"),
+            Err(ExecError::Returned("e".to_string()))
+        );
+        assert_eq!(parse_result("{\"failed\":\"f\"}"), Err(failed("f")));
+        assert_eq!(
+            parse_result("{\"ok\":{\"id\":null,\"val\":{\"unit\":null}},\"type\":\"()\"}"),
+            Ok((Kserd::new_unit(), "()".to_string()))
+        );
+        assert!(parse_result("hello").is_err());
+    }
+}
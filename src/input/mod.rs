@@ -44,6 +44,22 @@ pub fn determine_result(input: &str, line: &str, treat_as_cmd: bool) -> InputRes
     }
 }
 
+/// The input to evaluate for `code` as a whole, `None` if more input is required.
+///
+/// Code missing a final expression, such as `let a = 1;`, is finished with `()`.
+pub(crate) fn finish_input(code: &str, treat_as_cmd: bool) -> Option<String> {
+    match determine_result(code, code, treat_as_cmd) {
+        InputResult::More => {
+            let input = format!("{}\n()", code);
+            match determine_result(&input, &input, treat_as_cmd) {
+                InputResult::Program(_) => Some(input),
+                _ => None,
+            }
+        }
+        _ => Some(code.to_string()),
+    }
+}
+
 fn is_command(line: &str) -> bool {
     line.starts_with(crate::CMD_PREFIX)
 }
@@ -1,7 +1,7 @@
 use super::*;
 use crate::code::{self, Statement};
 use syn::export::ToTokens;
-use syn::{self, Block, File, Item, Stmt};
+use syn::{self, AttrStyle, Attribute, Block, File, Item, ItemMod, Stmt};
 
 /// Parses a line of input as a command.
 /// Returns either a `Command` value or an `InputError` value.
@@ -22,7 +22,8 @@ pub fn parse_program(code: &str) -> InputResult {
 
     let reterr = |e: syn::Error| {
         let e = e.to_string();
-        if &e == "LexError" {
+        // an unclosed delimiter fails lexing, newer proc-macro2 versions display the span
+        if e.starts_with("LexError") {
             InputResult::More
         } else {
             InputResult::InputError(e)
@@ -44,18 +45,24 @@ pub fn parse_program(code: &str) -> InputResult {
                     items,
                     stmts: vec![],
                     crates: vec![],
+                    mods: vec![],
                 })
             })
             .unwrap_or_else(reterr);
     }
 
-    let code = format!("{{ {} }}", code); // wrap in a block so the parser can parse through it without need to guess the type!
+    let specs = crate_spec_comments(code);
+
+    // wrap in a block so the parser can parse through it without need to guess the type!
+    // the newline stops a trailing line comment from commenting out the closing brace
+    let code = format!("{{ {}\n}}", code);
 
     syn::parse_str::<Block>(&code)
         .map(|block| {
             let mut stmts = Vec::new();
             let mut items: Vec<code::Item> = Vec::new();
             let mut crates = Vec::new();
+            let mut mods = Vec::new();
             for stmt in block.stmts {
                 match stmt {
                     Stmt::Local(local) => {
@@ -78,7 +85,7 @@ pub fn parse_program(code: &str) -> InputResult {
                             expr: fmt(string),
                             semi,
                         }), // macro item are plopped in as exprs
-                        ParseItemResult::Error(s) => return InputResult::InputError(s),
+                        ParseItemResult::Mod(vis, name, input) => mods.push((vis, name, input)),
                     },
                     Stmt::Expr(expr) => match parse_expr(expr) {
                         Ok(string) => stmts.push(Statement {
@@ -96,15 +103,48 @@ pub fn parse_program(code: &str) -> InputResult {
                     },
                 }
             }
-            InputResult::Program(Input {
+            let mut input = Input {
                 items,
                 stmts,
                 crates,
-            })
+                mods,
+            };
+            apply_crate_specs(&mut input, &specs);
+            InputResult::Program(input)
         })
         .unwrap_or_else(reterr)
 }
 
+/// Comments are lost when parsing, so crates with a dependency specification in a trailing
+/// comment (`extern crate serde; // 1.0 features=derive`) are found in the source lines.
+fn crate_spec_comments(code: &str) -> Vec<CrateType> {
+    code.lines()
+        .filter_map(|line| {
+            let comment = line.find("//")?;
+            let start = line[..comment].rfind("extern crate ")?;
+            let decl = line[start..comment].trim_end();
+            if decl.ends_with(';') && decl.matches(';').count() == 1 {
+                CrateType::parse_str(&line[start..]).ok()
+            } else {
+                None
+            }
+        })
+        .filter(CrateType::has_spec)
+        .collect()
+}
+
+fn apply_crate_specs(input: &mut Input, specs: &[CrateType]) {
+    for c in input.crates.iter_mut() {
+        if let Some(spec) = specs.iter().find(|x| x.cargo_name == c.cargo_name) {
+            *c = spec.clone();
+        }
+    }
+
+    for (_, _, input) in input.mods.iter_mut() {
+        apply_crate_specs(input, specs);
+    }
+}
+
 #[cfg(feature = "format")]
 fn fmt(s: String) -> String {
     crate::fmt::format(&s).unwrap_or(s)
@@ -119,56 +159,11 @@ enum ParseItemResult {
     Span(String),
     ExternCrate(String),
     MacroBlock(String, bool),
-    Error(String),
+    Mod(String, String, Input),
 }
 
 fn parse_item(item: Item) -> ParseItemResult {
-    match &item {
-        Item::Static(_) => {
-            error!("haven't handled item variant Static");
-            ParseItemResult::Error("haven't handled item variant Static. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-        }
-        Item::Const(_) => {
-            error!("haven't handled item variant Const");
-            ParseItemResult::Error("haven't handled item variant Const. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-        }
-        Item::Mod(_) => {
-            error!("haven't handled item variant Mod");
-            ParseItemResult::Error("haven't handled item variant Mod. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-        }
-        Item::ForeignMod(_) => {
-            error!("haven't handled item variant ForeignMod");
-            ParseItemResult::Error("haven't handled item variant ForeignMod. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-        }
-        Item::Type(_) => {
-            error!("haven't handled item variant Type");
-            ParseItemResult::Error("haven't handled item variant Type. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-        }
-
-        Item::Enum(_) => {
-            error!("haven't handled item variant Enum");
-            ParseItemResult::Error("haven't handled item variant Enum. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-        }
-        Item::Union(_) => {
-            error!("haven't handled item variant Union");
-            ParseItemResult::Error("haven't handled item variant Union. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-        }
-        Item::Trait(_) => {
-            error!("haven't handled item variant Trait");
-            ParseItemResult::Error("haven't handled item variant Trait. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-        }
-        Item::TraitAlias(_) => {
-            error!("haven't handled item variant TraitAlias");
-            ParseItemResult::Error("haven't handled item variant TraitAlias. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-        }
-        Item::Macro2(_) => {
-            error!("haven't handled item variant Macro2");
-            ParseItemResult::Error("haven't handled item variant Macro2. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-        }
-        Item::Verbatim(_) => {
-            error!("haven't handled item variant Verbatim");
-            ParseItemResult::Error("haven't handled item variant Verbatim. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-        }
+    match item {
         Item::ExternCrate(_) => {
             let s = format!("{}", item.into_token_stream());
             debug!("Item parsed, its a crate: {}", s);
@@ -176,141 +171,82 @@ fn parse_item(item: Item) -> ParseItemResult {
         }
         Item::Macro(m) => {
             let semi = m.semi_token.is_some();
-            ParseItemResult::MacroBlock(item.into_token_stream().to_string(), semi)
+            ParseItemResult::MacroBlock(m.into_token_stream().to_string(), semi)
+        }
+        // only inline modules with no outer attributes can be moved into the modules map, others
+        // are kept verbatim as an item
+        Item::Mod(ItemMod {
+            attrs,
+            vis,
+            ident,
+            content: Some((_, items)),
+            ..
+        }) if attrs.iter().all(is_inner_attr) => {
+            debug!("Item parsed, its a module: {}", ident);
+            let vis = vis.into_token_stream().to_string().replace("pub (", "pub(");
+            ParseItemResult::Mod(vis, ident.to_string(), parse_mod_items(attrs, items))
         }
         _ => ParseItemResult::Span(item.into_token_stream().to_string()),
     }
 }
 
+/// Flattens the contents of an inline module into an `Input`.
+///
+/// Inner attributes (`#![...]`) are placed at the top of the module. Macros are treated as items
+/// since a module cannot contain statements.
+fn parse_mod_items(attrs: Vec<Attribute>, items: Vec<Item>) -> Input {
+    let mut input = Input {
+        items: attrs
+            .into_iter()
+            .map(|attr| (fmt(attr.into_token_stream().to_string()), true))
+            .collect(),
+        stmts: vec![],
+        crates: vec![],
+        mods: vec![],
+    };
+
+    for item in items {
+        match parse_item(item) {
+            ParseItemResult::ExternCrate(string) => match CrateType::parse_str(&fmt(string)) {
+                Ok(c) => input.crates.push(c),
+                Err(e) => error!("crate parsing failed: {}", e),
+            },
+            ParseItemResult::Span(string) | ParseItemResult::MacroBlock(string, _) => {
+                input.items.push((fmt(string), false))
+            }
+            ParseItemResult::Mod(vis, name, inner) => input.mods.push((vis, name, inner)),
+        }
+    }
+
+    input
+}
+
+fn is_inner_attr(attr: &Attribute) -> bool {
+    matches!(attr.style, AttrStyle::Inner(_))
+}
+
+/// Parses an expression, returning the code string.
+///
+/// Every expression is passed through verbatim except for those which do not make sense as a
+/// statement in the REPL, which return an error describing why.
 fn parse_expr(expr: Expr) -> Result<String, String> {
-    match expr {
-		Expr::Box(_) => {
-			error!("haven't handled expr variant Box");
-			Err("haven't handled expr variant Box. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Array(_) => {
-			error!("haven't handled expr variant Array");
-			Err("haven't handled expr variant Array. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Unary(_) => {
-			error!("haven't handled expr variant Unary");
-			Err("haven't handled expr variant Unary. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Lit(_) => {
-			error!("haven't handled expr variant Lit");
-			Err("haven't handled expr variant Lit. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Cast(_) => {
-			error!("haven't handled expr variant Cast");
-			Err("haven't handled expr variant Cast. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Type(_) => {
-			error!("haven't handled expr variant Type");
-			Err("haven't handled expr variant Type. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::If(_) => {
-			error!("haven't handled expr variant If");
-			Err("haven't handled expr variant If. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::While(_) => {
-			error!("haven't handled expr variant While");
-			Err("haven't handled expr variant While. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Loop(_) => {
-			error!("haven't handled expr variant For");
-			Err("haven't handled expr variant For. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Match(_) => {
-			error!("haven't handled expr variant Match");
-			Err("haven't handled expr variant Match. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Closure(_) => {
-			error!("haven't handled expr variant Closure");
-			Err("haven't handled expr variant Closure. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Unsafe(_) => {
-			error!("haven't handled expr variant Unsafe");
-			Err("haven't handled expr variant Unsafe. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Block(_) => {
-			error!("haven't handled expr variant Block");
-			Err("haven't handled expr variant Block. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Assign(_) => {
-			error!("haven't handled expr variant Assign");
-			Err("haven't handled expr variant Assign. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::AssignOp(_) => {
-			error!("haven't handled expr variant AssignOp");
-			Err("haven't handled expr variant AssignOp. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Field(_) => {
-			error!("haven't handled expr variant Field");
-			Err("haven't handled expr variant Field. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Index(_) => {
-			Err("haven't handled expr variant Index. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Range(_) => {
-			error!("haven't handled expr variant Range");
-			Err("haven't handled expr variant Range. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Reference(_) => {
-			error!("haven't handled expr variant Reference");
-			Err("haven't handled expr variant Reference. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Break(_) => {
-			error!("haven't handled expr variant Break");
-			Err("haven't handled expr variant Break. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Continue(_) => {
-			error!("haven't handled expr variant Continue");
-			Err("haven't handled expr variant Continue. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Return(_) => {
-			error!("haven't handled expr variant Return");
-			Err("haven't handled expr variant Return. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Struct(_) => {
-			error!("haven't handled expr variant Struct");
-			Err("haven't handled expr variant Struct. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Repeat(_) => {
-			error!("haven't handled expr variant Repeat");
-			Err("haven't handled expr variant Repeat. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Paren(_) => {
-			error!("haven't handled expr variant Paren");
-			Err("haven't handled expr variant Paren. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Group(_) => {
-			error!("haven't handled expr variant Group");
-			Err("haven't handled expr variant Group. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Try(_) => {
-			error!("haven't handled expr variant Try");
-			Err("haven't handled expr variant Try. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Async(_) => {
-			error!("haven't handled expr variant Async");
-			Err("haven't handled expr variant Async. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::TryBlock(_) => {
-			error!("haven't handled expr variant TryBlock");
-			Err("haven't handled expr variant TryBlock. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Yield(_) => {
-			error!("haven't handled expr variant Yield");
-			Err("haven't handled expr variant Yield. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Verbatim(_) => {
-			error!("haven't handled expr variant Verbatim");
-			Err("haven't handled expr variant Verbatim. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		_ => {
-			let s = format!("{}", expr.into_token_stream());
-			debug!("Expression parsed: {:?}", s);
-			Ok(s)
-		}
-	}
+    let unsupported = match &expr {
+        Expr::Yield(_) => Some("`yield` is not supported, generators cannot be evaluated"),
+        Expr::Return(_) => Some("`return` is not supported outside of a function"),
+        Expr::Break(_) => Some("`break` is only supported inside of a loop"),
+        Expr::Continue(_) => Some("`continue` is only supported inside of a loop"),
+        _ => None,
+    };
+
+    match unsupported {
+        Some(e) => {
+            error!("unsupported expression: {}", e);
+            Err(e.to_string())
+        }
+        None => {
+            let s = format!("{}", expr.into_token_stream());
+            debug!("Expression parsed: {:?}", s);
+            Ok(s)
+        }
+    }
 }
@@ -12,6 +12,18 @@ fn test_unclosed_delimiter() {
     assert_eq!(parse_program("let a = \""), InputResult::More);
 }
 
+#[test]
+fn finish_input_test() {
+    assert_eq!(finish_input("2+2", false), Some("2+2".to_string()));
+    assert_eq!(
+        finish_input("let a = 1;", false),
+        Some("let a = 1;\n()".to_string())
+    );
+    assert_eq!(finish_input("fn a() {", false), None);
+    assert_eq!(finish_input(":help", false), Some(":help".to_string()));
+    assert_eq!(finish_input("help", true), Some("help".to_string()));
+}
+
 #[cfg(feature = "format")] // have to turn formatting on to check this
 #[test]
 fn test_items() {
@@ -20,7 +32,8 @@ fn test_items() {
         InputResult::Program(Input {
             items: vec![("fn b() {}".to_string(), false)],
             stmts: vec![],
-            crates: vec![]
+            crates: vec![],
+            mods: vec![]
         })
     ); // Item::Fn
     assert_eq!(
@@ -35,7 +48,8 @@ struct A {
                 false
             )],
             stmts: vec![],
-            crates: vec![]
+            crates: vec![],
+            mods: vec![]
         })
     ); // Item::Struct
     assert_eq!(
@@ -43,7 +57,8 @@ struct A {
         InputResult::Program(Input {
             items: vec![],
             stmts: vec![],
-            crates: vec![CrateType::parse_str(&"extern crate rand as r;").unwrap()]
+            crates: vec![CrateType::parse_str(&"extern crate rand as r;").unwrap()],
+            mods: vec![]
         })
     ); // Item::ExternCrate
     assert_eq!(
@@ -51,7 +66,8 @@ struct A {
         InputResult::Program(Input {
             items: vec![("impl Eq for MyStruct {}".to_string(), false)],
             stmts: vec![],
-            crates: vec![]
+            crates: vec![],
+            mods: vec![]
         })
     ); // Item::Impl
     assert_eq!(
@@ -59,7 +75,8 @@ struct A {
         InputResult::Program(Input {
             items: vec![("use something::a::crate;".to_string(), false)],
             stmts: vec![],
-            crates: vec![]
+            crates: vec![],
+            mods: vec![]
         })
     ); // Item::Use
     assert_eq!(
@@ -67,12 +84,145 @@ struct A {
         InputResult::Program(Input {
             items: vec![("#![feature(test)]".to_string(), true)],
             stmts: vec![],
-            crates: vec![]
+            crates: vec![],
+            mods: vec![]
         })
     ); // Item::Use
 }
 
 #[cfg(feature = "format")] // have to turn formatting on to check this
+#[test]
+fn test_item_variants() {
+    let item = |code: &str, expected: &str| {
+        assert_eq!(
+            parse_program(code),
+            InputResult::Program(Input {
+                items: vec![(expected.to_string(), false)],
+                stmts: vec![],
+                crates: vec![],
+                mods: vec![]
+            })
+        );
+    };
+
+    item("enum A { B, C(u8) }", "enum A {\n    B,\n    C(u8),\n}"); // Item::Enum
+    item("trait T { fn t(&self); }", "trait T {\n    fn t(&self);\n}"); // Item::Trait
+    item("const A: u8 = 1;", "const A: u8 = 1;"); // Item::Const
+    item("static A: u8 = 1;", "static A: u8 = 1;"); // Item::Static
+    item("type A = u8;", "type A = u8;"); // Item::Type
+    item(
+        "union A { a: u8, b: u16 }",
+        "union A {\n    a: u8,\n    b: u16,\n}",
+    ); // Item::Union
+    item(
+        "extern \"C\" { fn abs(x: i32) -> i32; }",
+        "extern \"C\" {\n    fn abs(x: i32) -> i32;\n}",
+    ); // Item::ForeignMod
+    item("trait A = B + C;", "trait A = B + C;"); // Item::TraitAlias
+    item(
+        "macro m($a:expr) { $a }",
+        "macro m($ a : expr) {\n    $a\n}",
+    ); // Item::Macro2
+    item("mod a;", "mod a;"); // Item::Mod, file based
+    item("#[cfg(test)] mod a {}", "#[cfg(test)]\nmod a {}"); // Item::Mod, with outer attribute
+}
+
+#[cfg(feature = "format")] // have to turn formatting on to check this
+#[test]
+fn test_inline_mods() {
+    assert_eq!(
+        parse_program(
+            "pub mod a { #![allow(dead_code)] extern crate rand; fn b() {} pub(crate) mod c { struct D; } }"
+        ),
+        InputResult::Program(Input {
+            items: vec![],
+            stmts: vec![],
+            crates: vec![],
+            mods: vec![(
+                "pub".to_string(),
+                "a".to_string(),
+                Input {
+                    items: vec![
+                        ("#![allow(dead_code)]".to_string(), true),
+                        ("fn b() {}".to_string(), false)
+                    ],
+                    stmts: vec![],
+                    crates: vec![CrateType::parse_str("extern crate rand;").unwrap()],
+                    mods: vec![(
+                        "pub(crate)".to_string(),
+                        "c".to_string(),
+                        Input {
+                            items: vec![("struct D;".to_string(), false)],
+                            stmts: vec![],
+                            crates: vec![],
+                            mods: vec![]
+                        }
+                    )]
+                }
+            )]
+        })
+    );
+
+    // items and stmts can be mixed with mods
+    assert_eq!(
+        parse_program("mod a {} fn b() {} 2+2"),
+        InputResult::Program(Input {
+            items: vec![("fn b() {}".to_string(), false)],
+            stmts: vec![Statement {
+                expr: "2 + 2".to_string(),
+                semi: false
+            }],
+            crates: vec![],
+            mods: vec![(
+                String::new(),
+                "a".to_string(),
+                Input {
+                    items: vec![],
+                    stmts: vec![],
+                    crates: vec![],
+                    mods: vec![]
+                }
+            )]
+        })
+    );
+}
+
+#[cfg(feature = "format")] // have to turn formatting on to check this
+#[test]
+fn test_crate_spec_comments() {
+    let crates = |s: &str| match parse_program(s) {
+        InputResult::Program(input) => input,
+        _ => panic!("expecting program"),
+    };
+
+    let input = crates(
+        "extern crate serde; // 1.0 features=derive,rc default-features=false
+extern crate rand; // for random numbers
+mod a {
+    extern crate regex; // 1.3
+}
+let a = 1; // extern crate rand; // 0.7",
+    );
+    let c = &input.crates[0];
+    assert_eq!(c.cargo_name, "serde");
+    assert_eq!(c.version.as_deref(), Some("1.0"));
+    assert_eq!(c.features, vec!["derive".to_string(), "rc".to_string()]);
+    assert!(!c.default_features);
+    assert_eq!(
+        c.src_line,
+        "extern crate serde; // 1.0 features=derive,rc default-features=false"
+    );
+    let c = &input.crates[1];
+    assert_eq!(c.cargo_name, "rand");
+    assert!(!c.has_spec());
+    let c = &input.mods[0].2.crates[0];
+    assert_eq!(c.cargo_name, "regex");
+    assert_eq!(c.version.as_deref(), Some("1.3"));
+
+    let input = crates("extern crate serde; // 1.0");
+    assert_eq!(input.crates[0].version.as_deref(), Some("1.0"));
+}
+
 #[test]
 fn test_exprs() {
     // Expr::Binary
@@ -84,7 +234,8 @@ fn test_exprs() {
                 expr: "2 + 2".to_string(),
                 semi: false
             }],
-            crates: vec![]
+            crates: vec![],
+            mods: vec![]
         })
     );
     assert_eq!(
@@ -95,7 +246,8 @@ fn test_exprs() {
                 expr: "2 + 2".to_string(),
                 semi: true
             }],
-            crates: vec![]
+            crates: vec![],
+            mods: vec![]
         })
     );
     // Expr::Macro
@@ -107,7 +259,8 @@ fn test_exprs() {
                 expr: "println!(\"hello\")".to_string(),
                 semi: false
             }],
-            crates: vec![]
+            crates: vec![],
+            mods: vec![]
         })
     );
     assert_eq!(
@@ -118,7 +271,8 @@ fn test_exprs() {
                 expr: "println!(\"hello\")".to_string(),
                 semi: true
             }],
-            crates: vec![]
+            crates: vec![],
+            mods: vec![]
         })
     );
     // Expr::Tuple
@@ -130,7 +284,8 @@ fn test_exprs() {
                 expr: "()".to_string(),
                 semi: false
             }],
-            crates: vec![]
+            crates: vec![],
+            mods: vec![]
         })
     );
     assert_eq!(
@@ -141,7 +296,8 @@ fn test_exprs() {
                 expr: "()".to_string(),
                 semi: true
             }],
-            crates: vec![]
+            crates: vec![],
+            mods: vec![]
         })
     );
     // Expr::Call
@@ -153,7 +309,8 @@ fn test_exprs() {
                 expr: "f()".to_string(),
                 semi: false
             }],
-            crates: vec![]
+            crates: vec![],
+            mods: vec![]
         })
     );
     assert_eq!(
@@ -164,7 +321,8 @@ fn test_exprs() {
                 expr: "f()".to_string(),
                 semi: true
             }],
-            crates: vec![]
+            crates: vec![],
+            mods: vec![]
         })
     );
     // LET
@@ -176,7 +334,8 @@ fn test_exprs() {
                 expr: "let a = 1".to_string(),
                 semi: true
             }],
-            crates: vec![]
+            crates: vec![],
+            mods: vec![]
         })
     );
     // Expr::ForLoop
@@ -188,7 +347,8 @@ fn test_exprs() {
                 expr: "for i in 0..3 {}".to_string(),
                 semi: false
             }],
-            crates: vec![]
+            crates: vec![],
+            mods: vec![]
         })
     );
     // Expr::Path
@@ -200,7 +360,8 @@ fn test_exprs() {
                 expr: "b".to_string(),
                 semi: false
             }],
-            crates: vec![]
+            crates: vec![],
+            mods: vec![]
         })
     );
     assert_eq!(
@@ -211,7 +372,8 @@ fn test_exprs() {
                 expr: "b".to_string(),
                 semi: true
             }],
-            crates: vec![]
+            crates: vec![],
+            mods: vec![]
         })
     );
     // Expr::MethodCall
@@ -223,7 +385,8 @@ fn test_exprs() {
                 expr: "std::env::current_dir()".to_string(),
                 semi: false
             }],
-            crates: vec![]
+            crates: vec![],
+            mods: vec![]
         })
     );
     assert_eq!(
@@ -234,11 +397,79 @@ fn test_exprs() {
                 expr: "std::env::current_dir()".to_string(),
                 semi: true
             }],
-            crates: vec![]
+            crates: vec![],
+            mods: vec![]
         })
     );
 }
 
+#[cfg(feature = "format")] // have to turn formatting on to check this
+#[test]
+fn test_expr_variants() {
+    let expr = |code: &str, expected: &str| {
+        assert_eq!(
+            parse_program(code),
+            InputResult::Program(Input {
+                items: vec![],
+                stmts: vec![Statement {
+                    expr: expected.to_string(),
+                    semi: false
+                }],
+                crates: vec![],
+                mods: vec![]
+            })
+        );
+    };
+
+    expr("[1,2]", "[1, 2]"); // Expr::Array
+    expr("-a", "-a"); // Expr::Unary
+    expr("1", "1"); // Expr::Lit
+    expr("a as u8", "a as u8"); // Expr::Cast
+    expr(
+        "if a > 2 { 1 } else { 0 }",
+        "if a > 2 {\n    1\n} else {\n    0\n}",
+    ); // Expr::If
+    expr("while false {}", "while false {}"); // Expr::While
+    expr("loop {}", "loop {}"); // Expr::Loop
+    expr("match a { _ => 1 }", "match a {\n    _ => 1,\n}"); // Expr::Match
+    expr("|x| x + 1", "|x| x + 1"); // Expr::Closure
+    expr("unsafe { a }", "unsafe { a }"); // Expr::Unsafe
+    expr("{ a }", "{\n    a\n}"); // Expr::Block
+    expr("a = 1", "a = 1"); // Expr::Assign
+    expr("a += 1", "a += 1"); // Expr::AssignOp
+    expr("a.b", "a.b"); // Expr::Field
+    expr("a[0]", "a[0]"); // Expr::Index
+    expr("0..3", "0..3"); // Expr::Range
+    expr("&a", "&a"); // Expr::Reference
+    expr("A { b: 1 }", "A { b: 1 }"); // Expr::Struct
+    expr("[0; 3]", "[0; 3]"); // Expr::Repeat
+    expr("(a)", "(a)"); // Expr::Paren
+    expr("a?", "a?"); // Expr::Try
+    expr("async { 1 }", "async { 1 }"); // Expr::Async
+}
+
+#[test]
+fn fail_unsupported_exprs() {
+    assert_eq!(
+        parse_program("yield 1"),
+        InputResult::InputError(
+            "`yield` is not supported, generators cannot be evaluated".to_string()
+        )
+    );
+    assert_eq!(
+        parse_program("return 1"),
+        InputResult::InputError("`return` is not supported outside of a function".to_string())
+    );
+    assert_eq!(
+        parse_program("break"),
+        InputResult::InputError("`break` is only supported inside of a loop".to_string())
+    );
+    assert_eq!(
+        parse_program("continue"),
+        InputResult::InputError("`continue` is only supported inside of a loop".to_string())
+    );
+}
+
 #[test]
 fn test_determine_result() {
     assert_eq!(
@@ -262,7 +493,8 @@ fn test_determine_result() {
                 expr: "2 + 2".to_string(),
                 semi: false,
             }],
-            crates: Vec::new()
+            crates: Vec::new(),
+            mods: Vec::new()
         })
     );
     assert_eq!(
@@ -0,0 +1,819 @@
+//! Run papyrus as a [Jupyter](https://jupyter.org) kernel.
+//!
+//! A [`Kernel`] binds the ZeroMQ sockets described in a Jupyter connection file and drives a
+//! [`Repl`] from the messages it receives. It implements the
+//! [messaging protocol](https://jupyter-client.readthedocs.io/en/stable/messaging.html) requests
+//! `kernel_info`, `execute`, `complete`, `inspect`, `is_complete`, and `shutdown`.
+//!
+//! - Output written while evaluating is published as `stream` messages.
+//! - A result is published as an `execute_result`, with `text/plain` and `application/json` data.
+//! - Failed evaluations are published as an `error`.
+//! - An `interrupt_request` on the control channel cancels the evaluation, see
+//!   [`CancelHandle`](crate::repl::CancelHandle).
+//!
+//! Requires the _kernel_ feature.
+//!
+//! # Installing
+//! Jupyter finds kernels through a `kernel.json` file, usually in a `kernels/papyrus` folder of the
+//! Jupyter data directory (`jupyter --data-dir`). The `papyrus` binary runs as a kernel with
+//! `papyrus kernel <connection-file>`.
+//!
+//! ```json
+//! {
+//!   "argv": ["papyrus", "kernel", "{connection_file}"],
+//!   "display_name": "Rust (papyrus)",
+//!   "language": "rust",
+//!   "interrupt_mode": "message"
+//! }
+//! ```
+//!
+//! # Example
+//! ```rust,no_run
+//! # #[macro_use] extern crate papyrus;
+//! use papyrus::kernel::{ConnectionInfo, Kernel};
+//! use std::sync::{Arc, Mutex};
+//!
+//! let info = ConnectionInfo::read("kernel-1234.json").unwrap();
+//! let kernel = Kernel::bind(info).unwrap();
+//! kernel.run(repl!(), &Arc::new(Mutex::new(()))).unwrap();
+//! ```
+mod wire;
+
+use self::wire::{Message, PROTOCOL_VERSION};
+use crate::complete::{cmdr::TreeCompleter, modules::ModulesCompleter};
+use crate::input::{self, InputResult};
+use crate::output::OutputChange;
+use crate::repl::{EvalResult, Read, ReadResult, Repl, Signal};
+use crossbeam_channel::RecvTimeoutError;
+use serde_json::{json, Value};
+use std::{
+    error, fmt, fs, io,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+/// How often output and interrupts are checked while evaluating.
+const EVAL_POLL: Duration = Duration::from_millis(20);
+
+/// How often the heartbeat checks if the kernel has stopped, in milliseconds.
+const HEARTBEAT_POLL: i64 = 100;
+
+/// The contents of a Jupyter connection file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionInfo {
+    /// `tcp` or `ipc`.
+    pub transport: String,
+    /// The address to bind to, or the path prefix for `ipc`.
+    pub ip: String,
+    /// Port of the shell channel, for requests.
+    pub shell_port: u16,
+    /// Port of the IOPub channel, for published output and status.
+    pub iopub_port: u16,
+    /// Port of the stdin channel.
+    pub stdin_port: u16,
+    /// Port of the control channel, for interrupts and shutdowns.
+    pub control_port: u16,
+    /// Port of the heartbeat channel.
+    pub hb_port: u16,
+    /// Key used to sign messages. Messages are not signed if empty.
+    pub key: String,
+    /// The signing scheme, only `hmac-sha256` is supported.
+    pub signature_scheme: String,
+}
+
+impl ConnectionInfo {
+    /// Parse the JSON contents of a connection file.
+    pub fn from_json(json: &str) -> Result<Self, KernelError> {
+        let v: Value =
+            serde_json::from_str(json).map_err(|e| KernelError::Connection(e.to_string()))?;
+
+        let string = |key: &str, default: &str| match &v[key] {
+            Value::Null => Ok(default.to_string()),
+            Value::String(s) => Ok(s.clone()),
+            _ => Err(KernelError::Connection(format!(
+                "'{}' is not a string",
+                key
+            ))),
+        };
+        let port = |key: &str| {
+            v[key]
+                .as_u64()
+                .filter(|&x| x <= u64::from(u16::MAX))
+                .map(|x| x as u16)
+                .ok_or_else(|| KernelError::Connection(format!("'{}' is not a port", key)))
+        };
+
+        let info = ConnectionInfo {
+            transport: string("transport", "tcp")?,
+            ip: string("ip", "127.0.0.1")?,
+            shell_port: port("shell_port")?,
+            iopub_port: port("iopub_port")?,
+            stdin_port: port("stdin_port")?,
+            control_port: port("control_port")?,
+            hb_port: port("hb_port")?,
+            key: string("key", "")?,
+            signature_scheme: string("signature_scheme", "hmac-sha256")?,
+        };
+
+        if info.transport != "tcp" && info.transport != "ipc" {
+            return Err(KernelError::Connection(format!(
+                "unsupported transport '{}'",
+                info.transport
+            )));
+        }
+        if !info.key.is_empty() && info.signature_scheme != "hmac-sha256" {
+            return Err(KernelError::Connection(format!(
+                "unsupported signature scheme '{}'",
+                info.signature_scheme
+            )));
+        }
+
+        Ok(info)
+    }
+
+    /// Read and parse a connection file.
+    pub fn read<P: AsRef<Path>>(file: P) -> Result<Self, KernelError> {
+        let json = fs::read_to_string(file).map_err(KernelError::Io)?;
+        Self::from_json(&json)
+    }
+
+    /// The endpoint to bind for `port`. A `tcp` port of zero binds to any free port.
+    fn endpoint(&self, port: u16) -> String {
+        match (self.transport.as_str(), port) {
+            ("ipc", port) => format!("ipc://{}-{}", self.ip, port),
+            (_, 0) => format!("tcp://{}:*", self.ip),
+            (_, port) => format!("tcp://{}:{}", self.ip, port),
+        }
+    }
+}
+
+/// Error type for the kernel.
+#[derive(Debug)]
+pub enum KernelError {
+    /// Generic IO errors.
+    Io(io::Error),
+    /// A ZeroMQ socket failed.
+    Zmq(zmq::Error),
+    /// The connection file is invalid.
+    Connection(String),
+    /// A received message is malformed or its signature is invalid.
+    InvalidMessage(String),
+}
+
+impl error::Error for KernelError {}
+
+impl fmt::Display for KernelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KernelError::Io(e) => write!(f, "io error occurred: {}", e),
+            KernelError::Zmq(e) => write!(f, "zeromq error occurred: {}", e),
+            KernelError::Connection(e) => write!(f, "invalid connection file: {}", e),
+            KernelError::InvalidMessage(e) => write!(f, "invalid message: {}", e),
+        }
+    }
+}
+
+impl From<zmq::Error> for KernelError {
+    fn from(e: zmq::Error) -> Self {
+        KernelError::Zmq(e)
+    }
+}
+
+/// A Jupyter kernel, bound to the sockets of a [`ConnectionInfo`].
+pub struct Kernel {
+    info: ConnectionInfo,
+    key: Vec<u8>,
+    session: String,
+    execution_count: usize,
+
+    shell: zmq::Socket,
+    control: zmq::Socket,
+    iopub: zmq::Socket,
+    /// Bound so frontends can connect, input requests are not supported.
+    _stdin: zmq::Socket,
+
+    heartbeat: Option<JoinHandle<()>>,
+    stop: Arc<AtomicBool>,
+
+    #[cfg(feature = "racer-completion")]
+    code_cache: Option<crate::complete::code::CodeCache>,
+}
+
+impl Kernel {
+    /// Bind the sockets and start replying to heartbeats.
+    ///
+    /// Ports of zero are bound to any free port, the bound ports are available through
+    /// [`connection`](Kernel::connection).
+    pub fn bind(mut info: ConnectionInfo) -> Result<Self, KernelError> {
+        let ctx = zmq::Context::new();
+
+        let bind = |kind, port: &mut u16| -> Result<zmq::Socket, KernelError> {
+            let socket = ctx.socket(kind)?;
+            socket.set_linger(1000)?;
+            socket.bind(&info.endpoint(*port))?;
+            if *port == 0 && info.transport == "tcp" {
+                // tcp://ip:port
+                *port = socket
+                    .get_last_endpoint()?
+                    .ok()
+                    .and_then(|x| x.rsplit(':').next().and_then(|x| x.parse().ok()))
+                    .unwrap_or_default();
+            }
+            Ok(socket)
+        };
+
+        let (mut shell_port, mut control_port, mut stdin_port) =
+            (info.shell_port, info.control_port, info.stdin_port);
+        let (mut iopub_port, mut hb_port) = (info.iopub_port, info.hb_port);
+
+        let shell = bind(zmq::ROUTER, &mut shell_port)?;
+        let control = bind(zmq::ROUTER, &mut control_port)?;
+        let stdin = bind(zmq::ROUTER, &mut stdin_port)?;
+        let iopub = bind(zmq::PUB, &mut iopub_port)?;
+        let hb = bind(zmq::REP, &mut hb_port)?;
+
+        info.shell_port = shell_port;
+        info.control_port = control_port;
+        info.stdin_port = stdin_port;
+        info.iopub_port = iopub_port;
+        info.hb_port = hb_port;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let heartbeat = {
+            let stop = Arc::clone(&stop);
+            std::thread::spawn(move || heartbeat(hb, &stop))
+        };
+
+        Ok(Kernel {
+            key: info.key.as_bytes().to_vec(),
+            info,
+            session: uuid::Uuid::new_v4().to_hyphenated().to_string(),
+            execution_count: 0,
+            shell,
+            control,
+            iopub,
+            _stdin: stdin,
+            heartbeat: Some(heartbeat),
+            stop,
+            #[cfg(feature = "racer-completion")]
+            code_cache: None,
+        })
+    }
+
+    /// The connection information, with the ports that were bound.
+    pub fn connection(&self) -> &ConnectionInfo {
+        &self.info
+    }
+
+    /// Handle requests until a `shutdown_request` is received or the REPL exits.
+    ///
+    /// Evaluation is done through [`eval_async`](Repl::eval_async) with `app_data`.
+    pub fn run<D>(
+        mut self,
+        repl: Repl<Read, D>,
+        app_data: &Arc<Mutex<D>>,
+    ) -> Result<(), KernelError>
+    where
+        D: 'static + Send,
+    {
+        self.publish_status("starting", None)?;
+
+        let mut repl = repl;
+
+        loop {
+            let (shell, control) = {
+                let mut items = [
+                    self.shell.as_poll_item(zmq::POLLIN),
+                    self.control.as_poll_item(zmq::POLLIN),
+                ];
+                zmq::poll(&mut items, -1)?;
+                (items[0].is_readable(), items[1].is_readable())
+            };
+
+            if control {
+                if let Some(msg) = self.recv(&self.control)? {
+                    if !self.handle_control(&msg)? {
+                        return Ok(());
+                    }
+                }
+            }
+
+            if shell {
+                if let Some(msg) = self.recv(&self.shell)? {
+                    repl = match self.handle_shell(repl, &msg, app_data)? {
+                        Some(repl) => repl,
+                        None => return Ok(()),
+                    };
+                }
+            }
+        }
+    }
+
+    /// Receive a message, skipping invalid messages.
+    fn recv(&self, socket: &zmq::Socket) -> Result<Option<Message>, KernelError> {
+        match Message::recv(socket, &self.key) {
+            Ok(msg) => Ok(Some(msg)),
+            Err(KernelError::InvalidMessage(e)) => {
+                warn!("ignoring invalid message: {}", e);
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn reply(
+        &self,
+        socket: &zmq::Socket,
+        parent: &Message,
+        msg_type: &str,
+        content: Value,
+    ) -> Result<(), KernelError> {
+        parent
+            .reply(msg_type, content, &self.session)
+            .send(socket, &self.key)
+    }
+
+    fn publish(
+        &self,
+        parent: Option<&Message>,
+        msg_type: &str,
+        content: Value,
+    ) -> Result<(), KernelError> {
+        let mut msg = Message::new(msg_type, content, parent, &self.session);
+        msg.identities = vec![msg_type.as_bytes().to_vec()];
+        msg.send(&self.iopub, &self.key)
+    }
+
+    fn publish_status(&self, state: &str, parent: Option<&Message>) -> Result<(), KernelError> {
+        self.publish(parent, "status", json!({ "execution_state": state }))
+    }
+
+    fn publish_stream(&self, parent: &Message, text: &str) -> Result<(), KernelError> {
+        if text.is_empty() {
+            return Ok(());
+        }
+        self.publish(
+            Some(parent),
+            "stream",
+            json!({ "name": "stdout", "text": text }),
+        )
+    }
+
+    /// Handle a control message, returning false if the kernel should shut down.
+    fn handle_control(&self, msg: &Message) -> Result<bool, KernelError> {
+        match msg.msg_type() {
+            "shutdown_request" => {
+                self.reply(&self.control, msg, "shutdown_reply", msg.content.clone())?;
+                Ok(false)
+            }
+            // there is no evaluation to interrupt
+            "interrupt_request" => {
+                self.reply(
+                    &self.control,
+                    msg,
+                    "interrupt_reply",
+                    json!({ "status": "ok" }),
+                )?;
+                Ok(true)
+            }
+            "kernel_info_request" => {
+                self.reply(&self.control, msg, "kernel_info_reply", kernel_info())?;
+                Ok(true)
+            }
+            x => {
+                debug!("unhandled control message '{}'", x);
+                Ok(true)
+            }
+        }
+    }
+
+    /// Handle a shell message, returning `None` if the kernel should shut down.
+    fn handle_shell<D>(
+        &mut self,
+        repl: Repl<Read, D>,
+        msg: &Message,
+        app_data: &Arc<Mutex<D>>,
+    ) -> Result<Option<Repl<Read, D>>, KernelError>
+    where
+        D: 'static + Send,
+    {
+        self.publish_status("busy", Some(msg))?;
+
+        let mut repl = Some(repl);
+
+        match msg.msg_type() {
+            "kernel_info_request" => {
+                self.reply(&self.shell, msg, "kernel_info_reply", kernel_info())?
+            }
+            "execute_request" => {
+                let r = repl.take().expect("repl is set");
+                repl = self.execute(r, msg, app_data)?;
+            }
+            "is_complete_request" => {
+                let treat_as_cmd = !repl.as_ref().expect("repl is set").data.cmdtree.at_root();
+                let code = msg.content["code"].as_str().unwrap_or_default();
+                let content = match is_complete(code, treat_as_cmd) {
+                    "incomplete" => json!({ "status": "incomplete", "indent": "" }),
+                    status => json!({ "status": status }),
+                };
+                self.reply(&self.shell, msg, "is_complete_reply", content)?;
+            }
+            "complete_request" => {
+                let r = repl.as_ref().expect("repl is set");
+                let content = self.complete(r, &msg.content);
+                self.reply(&self.shell, msg, "complete_reply", content)?;
+            }
+            "inspect_request" => {
+                let r = repl.as_ref().expect("repl is set");
+                let code = msg.content["code"].as_str().unwrap_or_default();
+                let cursor = msg.content["cursor_pos"].as_u64().unwrap_or_default() as usize;
+                let content = match inspect(r, code, cursor) {
+                    Some(src) => json!({
+                        "status": "ok",
+                        "found": true,
+                        "data": { "text/plain": src },
+                        "metadata": {},
+                    }),
+                    None => json!({ "status": "ok", "found": false, "data": {}, "metadata": {} }),
+                };
+                self.reply(&self.shell, msg, "inspect_reply", content)?;
+            }
+            "history_request" => self.reply(
+                &self.shell,
+                msg,
+                "history_reply",
+                json!({ "status": "ok", "history": [] }),
+            )?,
+            "comm_info_request" => self.reply(
+                &self.shell,
+                msg,
+                "comm_info_reply",
+                json!({ "status": "ok", "comms": {} }),
+            )?,
+            "shutdown_request" => {
+                self.reply(&self.shell, msg, "shutdown_reply", msg.content.clone())?;
+                repl = None;
+            }
+            x => debug!("unhandled shell message '{}'", x),
+        }
+
+        self.publish_status("idle", Some(msg))?;
+
+        Ok(repl)
+    }
+
+    /// Evaluate the code of an `execute_request`, returning `None` if the REPL exited.
+    fn execute<D>(
+        &mut self,
+        mut repl: Repl<Read, D>,
+        req: &Message,
+        app_data: &Arc<Mutex<D>>,
+    ) -> Result<Option<Repl<Read, D>>, KernelError>
+    where
+        D: 'static + Send,
+    {
+        let mut code = req.content["code"].as_str().unwrap_or_default().to_string();
+        let silent = req.content["silent"].as_bool().unwrap_or(false);
+        let store_history = req.content["store_history"].as_bool().unwrap_or(!silent);
+
+        if store_history {
+            self.execution_count += 1;
+        }
+        let count = self.execution_count;
+
+        if !silent {
+            self.publish(
+                Some(req),
+                "execute_input",
+                json!({ "code": code, "execution_count": count }),
+            )?;
+        }
+
+        let mut exit = false;
+        let mut shutdown = false;
+        let mut error = None;
+
+        loop {
+            let treat_as_cmd = !repl.data.cmdtree.at_root();
+            match input::finish_input(&code, treat_as_cmd) {
+                Some(input) => repl.line_input(&input),
+                None => {
+                    error = Some("incomplete input, a delimiter is not closed".to_string());
+                    break;
+                }
+            }
+
+            let is_cmd = treat_as_cmd || code.trim_start().starts_with(crate::CMD_PREFIX);
+
+            let mut eval = match repl.read() {
+                ReadResult::Eval(eval) => eval,
+                ReadResult::Read(_) => unreachable!("input is complete"),
+            };
+
+            let rx = eval.output_listen();
+            let evaluating = eval.eval_async(app_data);
+            let mut lines = Lines::default();
+
+            while !evaluating.completed() {
+                match rx.recv_timeout(EVAL_POLL) {
+                    Ok(chg) => lines.push(chg),
+                    Err(RecvTimeoutError::Timeout) => (),
+                    Err(RecvTimeoutError::Disconnected) => std::thread::sleep(EVAL_POLL),
+                }
+                self.publish_stream(req, &lines.take())?;
+
+                if self.control.poll(zmq::POLLIN, 0)? > 0 {
+                    if let Some(msg) = self.recv(&self.control)? {
+                        if let "interrupt_request" | "shutdown_request" = msg.msg_type() {
+                            evaluating.cancel();
+                        }
+                        if !self.handle_control(&msg)? {
+                            shutdown = true;
+                        }
+                    }
+                }
+            }
+
+            let EvalResult {
+                repl: print,
+                signal,
+            } = evaluating.wait();
+            rx.try_iter().for_each(|chg| lines.push(chg));
+            drop(rx);
+            let mut text = lines.take();
+            text.push_str(&lines.current);
+            self.publish_stream(req, &text)?;
+
+            let failed = print.error(is_cmd).map(String::from);
+            let message = print.message().map(String::from);
+            let (read, output) = print.print();
+            repl = read;
+
+            match signal {
+                Signal::ReEvaluate(input) => {
+                    code = input;
+                    continue;
+                }
+                Signal::Exit => exit = true,
+                Signal::None => (),
+            }
+
+            if failed.is_some() {
+                error = failed;
+            } else if let Some(msg) = message {
+                self.publish_stream(req, &format!("{}\n", msg))?;
+            }
+
+            if let Some((_, kserd, _)) = output.filter(|x| !x.1.unit() && !silent) {
+                let data = json!({
+                    "text/plain": crate::repl::fmt_output(&kserd, Default::default()),
+                    "application/json": crate::repl::kserd_to_json(&kserd),
+                });
+                self.publish(
+                    Some(req),
+                    "execute_result",
+                    json!({ "execution_count": count, "data": data, "metadata": {} }),
+                )?;
+            }
+
+            break;
+        }
+
+        let content = match error {
+            Some(msg) => {
+                let traceback: Vec<_> = msg.lines().collect();
+                let content = json!({
+                    "ename": "Error",
+                    "evalue": traceback.first().copied().unwrap_or_default(),
+                    "traceback": traceback,
+                });
+                self.publish(Some(req), "error", content.clone())?;
+                let mut content = content;
+                content["status"] = json!("error");
+                content["execution_count"] = json!(count);
+                content
+            }
+            None => json!({
+                "status": "ok",
+                "execution_count": count,
+                "user_expressions": {},
+                "payload": [],
+            }),
+        };
+        self.reply(&self.shell, req, "execute_reply", content)?;
+
+        if exit || shutdown {
+            Ok(None)
+        } else {
+            Ok(Some(repl))
+        }
+    }
+
+    fn complete<D>(&mut self, repl: &Repl<Read, D>, content: &Value) -> Value {
+        let code = content["code"].as_str().unwrap_or_default();
+        let cursor = content["cursor_pos"].as_u64().unwrap_or_default() as usize;
+        let pos = byte_pos(code, cursor);
+        let line_start = code[..pos].rfind('\n').map(|x| x + 1).unwrap_or(0);
+        let line = &code[line_start..pos];
+
+        let (start, matches) = if line.starts_with(crate::CMD_PREFIX) {
+            let data = &repl.data;
+            let tree = TreeCompleter::build(&data.cmdtree);
+            let mods = ModulesCompleter::build(&data.cmdtree, data.mods_map());
+            let mut matches: Vec<String> = tree.complete(line).map(|x| x.0.to_string()).collect();
+            matches.extend(mods.complete(line));
+            (line_start + TreeCompleter::word_break(line), matches)
+        } else {
+            self.complete_code(repl, &code[..pos])
+        };
+
+        json!({
+            "status": "ok",
+            "matches": matches,
+            "cursor_start": code[..start].chars().count(),
+            "cursor_end": cursor,
+            "metadata": {},
+        })
+    }
+
+    #[cfg(feature = "racer-completion")]
+    fn complete_code<D>(&mut self, repl: &Repl<Read, D>, code: &str) -> (usize, Vec<String>) {
+        use crate::complete::code::{CodeCache, CodeCompleter};
+
+        let cache = self
+            .code_cache
+            .get_or_insert_with(|| CodeCache::new().unwrap_or_else(|(cache, _)| cache));
+        let matches = CodeCompleter::build(&repl.data)
+            .complete(code, Some(100), cache)
+            .into_iter()
+            .map(|x| x.matchstr)
+            .collect();
+
+        (CodeCompleter::word_break(code), matches)
+    }
+
+    #[cfg(not(feature = "racer-completion"))]
+    fn complete_code<D>(&mut self, _: &Repl<Read, D>, code: &str) -> (usize, Vec<String>) {
+        (code.len(), Vec::new())
+    }
+}
+
+impl Drop for Kernel {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(jh) = self.heartbeat.take() {
+            jh.join().ok();
+        }
+    }
+}
+
+/// Echo heartbeat pings until `stop` is set.
+fn heartbeat(socket: zmq::Socket, stop: &AtomicBool) {
+    while !stop.load(Ordering::SeqCst) {
+        match socket.poll(zmq::POLLIN, HEARTBEAT_POLL) {
+            Ok(0) => (),
+            Ok(_) => {
+                let r = socket
+                    .recv_multipart(0)
+                    .and_then(|msg| socket.send_multipart(msg, 0));
+                if let Err(e) = r {
+                    error!("heartbeat failed: {}", e);
+                    break;
+                }
+            }
+            Err(e) => {
+                error!("heartbeat failed: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+fn kernel_info() -> Value {
+    json!({
+        "status": "ok",
+        "protocol_version": PROTOCOL_VERSION,
+        "implementation": "papyrus",
+        "implementation_version": env!("CARGO_PKG_VERSION"),
+        "language_info": {
+            "name": "rust",
+            "version": "",
+            "mimetype": "text/rust",
+            "file_extension": ".rs",
+        },
+        "banner": "papyrus - A rust REPL and script running tool.",
+        "help_links": [],
+    })
+}
+
+/// Whether `code` is `complete`, `incomplete`, or `invalid`.
+///
+/// Code is complete if it can be executed, see [`input::finish_input`].
+fn is_complete(code: &str, treat_as_cmd: bool) -> &'static str {
+    match input::determine_result(code, code, treat_as_cmd) {
+        InputResult::InputError(_) => "invalid",
+        _ if input::finish_input(code, treat_as_cmd).is_none() => "incomplete",
+        _ => "complete",
+    }
+}
+
+/// The source code defining the identifier under the cursor, if it is a variable or item.
+fn inspect<D>(repl: &Repl<Read, D>, code: &str, cursor: usize) -> Option<String> {
+    let is_ident = |c: char| c.is_alphanumeric() || c == '_';
+
+    let pos = byte_pos(code, cursor);
+    let start = code[..pos]
+        .char_indices()
+        .rev()
+        .take_while(|x| is_ident(x.1))
+        .last()
+        .map(|x| x.0)
+        .unwrap_or(pos);
+    let end = code[pos..]
+        .find(|c| !is_ident(c))
+        .map(|x| pos + x)
+        .unwrap_or_else(|| code.len());
+    let name = &code[start..end];
+    if name.is_empty() {
+        return None;
+    }
+
+    let src = repl.data.current_src();
+    src.stmts
+        .iter()
+        .enumerate()
+        .rev()
+        .find(|(i, grp)| grp.bindings(*i).iter().any(|x| x == name))
+        .map(|x| x.1.src_line())
+        .or_else(|| {
+            src.items
+                .iter()
+                .find(|x| item_ident(&x.0).as_deref() == Some(name))
+                .map(|x| x.0.clone())
+        })
+}
+
+/// The name an item defines, such as the name of a function.
+fn item_ident(src: &str) -> Option<String> {
+    use syn::Item;
+
+    let ident = match syn::parse_str::<Item>(src).ok()? {
+        Item::Const(x) => x.ident,
+        Item::Enum(x) => x.ident,
+        Item::Fn(x) => x.sig.ident,
+        Item::Macro(x) => x.ident?,
+        Item::Mod(x) => x.ident,
+        Item::Static(x) => x.ident,
+        Item::Struct(x) => x.ident,
+        Item::Trait(x) => x.ident,
+        Item::TraitAlias(x) => x.ident,
+        Item::Type(x) => x.ident,
+        Item::Union(x) => x.ident,
+        _ => return None,
+    };
+
+    Some(ident.to_string())
+}
+
+/// The byte position of the `chpos`th character.
+fn byte_pos(s: &str, chpos: usize) -> usize {
+    s.char_indices()
+        .nth(chpos)
+        .map(|x| x.0)
+        .unwrap_or_else(|| s.len())
+}
+
+/// Collects output lines from line change events.
+#[derive(Default)]
+struct Lines {
+    /// The contents of the current line.
+    current: String,
+    /// Finished lines.
+    done: String,
+}
+
+impl Lines {
+    fn push(&mut self, chg: OutputChange) {
+        match chg {
+            OutputChange::CurrentLine(line) => self.current = line,
+            OutputChange::NewLine => {
+                self.done.push_str(&self.current);
+                self.done.push('\n');
+                self.current.clear();
+            }
+        }
+    }
+
+    /// Take the finished lines.
+    fn take(&mut self) -> String {
+        std::mem::take(&mut self.done)
+    }
+}
+
+#[cfg(test)]
+mod tests;
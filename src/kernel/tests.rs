@@ -0,0 +1,223 @@
+use super::*;
+use crate as papyrus;
+
+const KEY: &[u8] = b"secret";
+
+/// A frontend connected to a kernel running on another thread.
+struct Client {
+    shell: zmq::Socket,
+    control: zmq::Socket,
+    iopub: zmq::Socket,
+    kernel: JoinHandle<Result<(), KernelError>>,
+}
+
+impl Client {
+    fn start(compile_dir: &str) -> Self {
+        let info = ConnectionInfo::from_json(
+            r#"{ "ip": "127.0.0.1", "shell_port": 0, "iopub_port": 0, "stdin_port": 0,
+                 "control_port": 0, "hb_port": 0, "key": "secret" }"#,
+        )
+        .unwrap();
+        let kernel = Kernel::bind(info).unwrap();
+        let info = kernel.connection().clone();
+        assert_ne!(info.shell_port, 0);
+
+        let mut repl = repl!();
+        repl.data.with_compilation_dir(compile_dir).unwrap();
+        let kernel = std::thread::spawn(move || kernel.run(repl, &Arc::new(Mutex::new(()))));
+
+        let ctx = zmq::Context::new();
+        let connect = |kind, port| {
+            let socket = ctx.socket(kind).unwrap();
+            socket.set_rcvtimeo(300_000).unwrap();
+            socket.set_linger(0).unwrap();
+            socket
+                .connect(&format!("tcp://127.0.0.1:{}", port))
+                .unwrap();
+            socket
+        };
+
+        let client = Client {
+            shell: connect(zmq::DEALER, info.shell_port),
+            control: connect(zmq::DEALER, info.control_port),
+            iopub: connect(zmq::SUB, info.iopub_port),
+            kernel,
+        };
+        client.iopub.set_subscribe(b"").unwrap();
+
+        // published messages are dropped until the subscription connects
+        loop {
+            let req = client.send(&client.shell, "kernel_info_request", json!({}));
+            Message::recv(&client.shell, KEY).unwrap();
+            if client.iopub.poll(zmq::POLLIN, 100).unwrap() > 0 {
+                client.published(&req);
+                break;
+            }
+        }
+
+        client
+    }
+
+    fn send(&self, socket: &zmq::Socket, msg_type: &str, content: Value) -> Message {
+        let msg = Message::new(msg_type, content, None, "test");
+        msg.send(socket, KEY).unwrap();
+        msg
+    }
+
+    /// Send a shell request, returning the reply and the messages published for the request.
+    fn request(&self, msg_type: &str, content: Value) -> (Message, Vec<Message>) {
+        let req = self.send(&self.shell, msg_type, content);
+        let reply = Message::recv(&self.shell, KEY).unwrap();
+        assert_eq!(reply.parent_header, req.header);
+        assert_eq!(reply.msg_type(), msg_type.replace("request", "reply"));
+        (reply, self.published(&req))
+    }
+
+    /// The messages published for `req`, from busy to idle, excluding the status messages.
+    fn published(&self, req: &Message) -> Vec<Message> {
+        let mut msgs = Vec::new();
+        loop {
+            let msg = Message::recv(&self.iopub, KEY).unwrap();
+            if msg.parent_header != req.header {
+                continue;
+            }
+            if msg.msg_type() == "status" {
+                if msg.content["execution_state"] == "idle" {
+                    return msgs;
+                }
+            } else {
+                msgs.push(msg);
+            }
+        }
+    }
+
+    fn shutdown(self) {
+        let req = self.send(
+            &self.control,
+            "shutdown_request",
+            json!({ "restart": false }),
+        );
+        let reply = Message::recv(&self.control, KEY).unwrap();
+        assert_eq!(reply.parent_header, req.header);
+        assert_eq!(reply.content, json!({ "restart": false }));
+        assert!(self.kernel.join().unwrap().is_ok());
+    }
+}
+
+#[test]
+fn connection_info_test() {
+    let info = ConnectionInfo::from_json(
+        r#"{ "transport": "tcp", "ip": "127.0.0.1", "shell_port": 1, "iopub_port": 2,
+             "stdin_port": 3, "control_port": 4, "hb_port": 5, "key": "a",
+             "signature_scheme": "hmac-sha256", "kernel_name": "papyrus" }"#,
+    )
+    .unwrap();
+    assert_eq!(info.shell_port, 1);
+    assert_eq!(info.hb_port, 5);
+    assert_eq!(info.endpoint(info.shell_port), "tcp://127.0.0.1:1");
+    assert_eq!(info.endpoint(0), "tcp://127.0.0.1:*");
+
+    let e = ConnectionInfo::from_json(r#"{ "shell_port": 1 }"#).unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "invalid connection file: 'iopub_port' is not a port"
+    );
+    let e = ConnectionInfo::from_json(
+        r#"{ "shell_port": 1, "iopub_port": 2, "stdin_port": 3, "control_port": 4,
+             "hb_port": 5, "key": "a", "signature_scheme": "hmac-md5" }"#,
+    )
+    .unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "invalid connection file: unsupported signature scheme 'hmac-md5'"
+    );
+}
+
+#[test]
+fn kernel_requests_test() {
+    let client = Client::start("target/testing/kernel_requests_test");
+
+    let (reply, _) = client.request("kernel_info_request", json!({}));
+    assert_eq!(reply.content["status"], "ok");
+    assert_eq!(reply.content["implementation"], "papyrus");
+    assert_eq!(reply.content["protocol_version"], PROTOCOL_VERSION);
+    assert_eq!(reply.content["language_info"]["name"], "rust");
+
+    let is_complete = |code: &str| {
+        let (reply, _) = client.request("is_complete_request", json!({ "code": code }));
+        reply.content["status"].as_str().unwrap().to_string()
+    };
+    assert_eq!(is_complete("2+2"), "complete");
+    assert_eq!(is_complete("let a = 1;"), "complete");
+    assert_eq!(is_complete("fn a() {"), "incomplete");
+    assert_eq!(is_complete(":help"), "complete");
+    assert_eq!(is_complete("let = 1"), "invalid");
+
+    let (reply, _) = client.request(
+        "complete_request",
+        json!({ "code": "2+2\n:mo", "cursor_pos": 7 }),
+    );
+    assert_eq!(reply.content["matches"][0], ":mod");
+    assert_eq!(reply.content["cursor_start"], 4);
+    assert_eq!(reply.content["cursor_end"], 7);
+
+    let (reply, _) = client.request("inspect_request", json!({ "code": "a", "cursor_pos": 1 }));
+    assert_eq!(reply.content["found"], false);
+
+    client.shutdown();
+}
+
+#[test]
+fn kernel_execute_test() {
+    let client = Client::start("target/testing/kernel_execute_test");
+    let execute = |code: &str| {
+        client.request(
+            "execute_request",
+            json!({ "code": code, "silent": false, "store_history": true }),
+        )
+    };
+
+    let (reply, msgs) = execute("println!(\"Hello\");\nlet a = 2;\na + 2");
+    assert_eq!(reply.content["status"], "ok");
+    assert_eq!(reply.content["execution_count"], 1);
+    let types: Vec<_> = msgs.iter().map(|x| x.msg_type()).collect();
+    assert_eq!(types, vec!["execute_input", "stream", "execute_result"]);
+    assert_eq!(msgs[1].content["text"], "Hello\n");
+    assert_eq!(msgs[2].content["execution_count"], 1);
+    assert_eq!(msgs[2].content["data"]["text/plain"], "4");
+    assert_eq!(msgs[2].content["data"]["application/json"], 4);
+
+    // statements are finished with a unit, previous statements are evaluated again
+    let (reply, msgs) = execute("let b = a * 3;");
+    assert_eq!(reply.content["status"], "ok");
+    assert_eq!(reply.content["execution_count"], 2);
+    let types: Vec<_> = msgs.iter().map(|x| x.msg_type()).collect();
+    assert_eq!(types, vec!["execute_input", "stream"]);
+
+    let (reply, msgs) = execute("b + \"a\"");
+    assert_eq!(reply.content["status"], "error");
+    let error = msgs.iter().find(|x| x.msg_type() == "error").unwrap();
+    assert!(error.content["evalue"].as_str().unwrap().contains("error"));
+
+    let (reply, msgs) = execute(":help");
+    assert_eq!(reply.content["status"], "ok");
+    let stream = msgs.iter().find(|x| x.msg_type() == "stream").unwrap();
+    assert!(stream.content["text"].as_str().unwrap().contains("help"));
+
+    let (reply, msgs) = execute("fn a() {");
+    assert_eq!(reply.content["status"], "error");
+    assert_eq!(msgs.last().unwrap().msg_type(), "error");
+
+    let inspect = |code: &str| {
+        let cursor = code.chars().count();
+        let (reply, _) = client.request(
+            "inspect_request",
+            json!({ "code": code, "cursor_pos": cursor }),
+        );
+        reply.content["data"]["text/plain"].clone()
+    };
+    assert_eq!(inspect("b"), "let b = a * 3; ()");
+    assert_eq!(inspect("1 + out0"), "println!(\"Hello\"); let a = 2; a + 2");
+
+    client.shutdown();
+}
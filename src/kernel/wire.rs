@@ -0,0 +1,259 @@
+//! The Jupyter wire protocol, messages sent as multipart ZeroMQ messages.
+use super::KernelError;
+use hmac::{Hmac, Mac, NewMac};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The messaging protocol version implemented.
+pub const PROTOCOL_VERSION: &str = "5.3";
+
+/// Separates the routing identities from the message.
+const DELIMITER: &[u8] = b"<IDS|MSG>";
+
+/// A Jupyter message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    /// The routing identities of the client.
+    pub identities: Vec<Vec<u8>>,
+    /// The message header, which contains the `msg_type`.
+    pub header: Value,
+    /// The header of the message this message is in response to.
+    pub parent_header: Value,
+    /// Message metadata.
+    pub metadata: Value,
+    /// The message content.
+    pub content: Value,
+}
+
+impl Message {
+    /// A new message with a fresh header, sent in response to `parent` if given.
+    pub fn new(msg_type: &str, content: Value, parent: Option<&Message>, session: &str) -> Self {
+        let username = parent
+            .and_then(|x| x.header["username"].as_str())
+            .unwrap_or("papyrus");
+
+        Message {
+            identities: Vec::new(),
+            header: json!({
+                "msg_id": uuid::Uuid::new_v4().to_hyphenated().to_string(),
+                "session": session,
+                "username": username,
+                "date": timestamp(SystemTime::now()),
+                "msg_type": msg_type,
+                "version": PROTOCOL_VERSION,
+            }),
+            parent_header: parent
+                .map(|x| x.header.clone())
+                .unwrap_or_else(|| json!({})),
+            metadata: json!({}),
+            content,
+        }
+    }
+
+    /// A reply to this message, routed back to the sender.
+    pub fn reply(&self, msg_type: &str, content: Value, session: &str) -> Self {
+        let mut msg = Message::new(msg_type, content, Some(self), session);
+        msg.identities = self.identities.clone();
+        msg
+    }
+
+    /// The message type, such as `execute_request`.
+    pub fn msg_type(&self) -> &str {
+        self.header["msg_type"].as_str().unwrap_or_default()
+    }
+
+    /// Send the message on `socket`, signing it with `key`.
+    pub fn send(&self, socket: &zmq::Socket, key: &[u8]) -> Result<(), KernelError> {
+        let parts = [
+            self.header.to_string(),
+            self.parent_header.to_string(),
+            self.metadata.to_string(),
+            self.content.to_string(),
+        ];
+        let signature = sign(key, &parts);
+
+        let mut frames: Vec<&[u8]> = self.identities.iter().map(|x| x.as_slice()).collect();
+        frames.push(DELIMITER);
+        frames.push(signature.as_bytes());
+        frames.extend(parts.iter().map(|x| x.as_bytes()));
+
+        socket.send_multipart(frames, 0).map_err(KernelError::Zmq)
+    }
+
+    /// Receive a message from `socket`, verifying the signature with `key`.
+    ///
+    /// Messages which are malformed or have an invalid signature are returned as
+    /// [`KernelError::InvalidMessage`].
+    pub fn recv(socket: &zmq::Socket, key: &[u8]) -> Result<Self, KernelError> {
+        let frames = socket.recv_multipart(0).map_err(KernelError::Zmq)?;
+        Self::from_frames(frames, key)
+    }
+
+    fn from_frames(mut frames: Vec<Vec<u8>>, key: &[u8]) -> Result<Self, KernelError> {
+        let invalid = |msg: &str| KernelError::InvalidMessage(msg.to_string());
+
+        let delim = frames
+            .iter()
+            .position(|x| x == DELIMITER)
+            .ok_or_else(|| invalid("missing delimiter"))?;
+        let mut parts = frames.split_off(delim + 1);
+        frames.pop(); // delimiter
+        if parts.len() < 5 {
+            return Err(invalid("missing message parts"));
+        }
+        parts.truncate(5); // buffers are not used
+
+        let signature = String::from_utf8_lossy(&parts[0]);
+        let parts: Vec<_> = parts[1..]
+            .iter()
+            .map(|x| String::from_utf8_lossy(x).into_owned())
+            .collect();
+        if !verify(key, &parts, &signature) {
+            return Err(invalid("invalid signature"));
+        }
+
+        let mut parsed = parts
+            .iter()
+            .map(|x| serde_json::from_str(x).map_err(|e| invalid(&e.to_string())));
+
+        Ok(Message {
+            identities: frames,
+            header: parsed.next().expect("four parts")?,
+            parent_header: parsed.next().expect("four parts")?,
+            metadata: parsed.next().expect("four parts")?,
+            content: parsed.next().expect("four parts")?,
+        })
+    }
+}
+
+fn mac(key: &[u8], parts: &[String]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("hmac accepts any key length");
+    for part in parts {
+        mac.update(part.as_bytes());
+    }
+    mac
+}
+
+/// The hex encoded HMAC-SHA256 signature of `parts`, empty if `key` is empty.
+pub fn sign(key: &[u8], parts: &[String]) -> String {
+    if key.is_empty() {
+        return String::new();
+    }
+
+    mac(key, parts)
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect()
+}
+
+fn verify(key: &[u8], parts: &[String], signature: &str) -> bool {
+    if key.is_empty() {
+        return true;
+    }
+
+    let bytes: Option<Vec<u8>> = (0..signature.len())
+        .step_by(2)
+        .map(|i| {
+            signature
+                .get(i..i + 2)
+                .and_then(|x| u8::from_str_radix(x, 16).ok())
+        })
+        .collect();
+
+    bytes
+        .map(|x| mac(key, parts).verify(&x).is_ok())
+        .unwrap_or(false)
+}
+
+/// An ISO 8601 UTC timestamp, such as `2001-09-09T01:46:40.000000Z`.
+pub fn timestamp(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    let (days, rem) = ((secs / 86_400) as i64, secs % 86_400);
+
+    // convert days to a civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        since.subsec_micros()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn timestamp_test() {
+        let t = |secs| timestamp(UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(t(0), "1970-01-01T00:00:00.000000Z");
+        assert_eq!(t(1_000_000_000), "2001-09-09T01:46:40.000000Z");
+        assert_eq!(t(951_782_400), "2000-02-29T00:00:00.000000Z");
+        assert_eq!(
+            timestamp(UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456)),
+            "2023-11-14T22:13:20.123456Z"
+        );
+    }
+
+    #[test]
+    fn sign_test() {
+        // signature of the parts of a message, from python's hmac
+        let parts = vec!["{}".to_string(); 4];
+        assert_eq!(
+            sign(b"key", &parts),
+            "ca767b02c5bc061ed184a9a029e7f93a7f2f7ed4e8fe74120fc1212f0b008fa3"
+        );
+        assert_eq!(sign(b"", &parts), "");
+    }
+
+    #[test]
+    fn frames_roundtrip_test() {
+        let msg = Message::new("kernel_info_request", json!({}), None, "session");
+        let parts = [
+            msg.header.to_string(),
+            msg.parent_header.to_string(),
+            msg.metadata.to_string(),
+            msg.content.to_string(),
+        ];
+        let frames = |signature: String| {
+            let mut frames = vec![
+                b"client".to_vec(),
+                DELIMITER.to_vec(),
+                signature.into_bytes(),
+            ];
+            frames.extend(parts.iter().map(|x| x.as_bytes().to_vec()));
+            frames
+        };
+
+        let parsed = Message::from_frames(frames(sign(b"key", &parts)), b"key").unwrap();
+        assert_eq!(parsed.identities, vec![b"client".to_vec()]);
+        assert_eq!(parsed.msg_type(), "kernel_info_request");
+        assert_eq!(parsed.header, msg.header);
+
+        let e = Message::from_frames(frames("00".to_string()), b"key").unwrap_err();
+        assert_eq!(e.to_string(), "invalid message: invalid signature");
+        let e = Message::from_frames(frames(sign(b"other", &parts)), b"key").unwrap_err();
+        assert_eq!(e.to_string(), "invalid message: invalid signature");
+        // unsigned when there is no key
+        assert!(Message::from_frames(frames(String::new()), b"").is_ok());
+    }
+}
@@ -2,7 +2,7 @@
 //! [![Latest Version](https://img.shields.io/crates/v/papyrus.svg)](https://crates.io/crates/papyrus)
 //! [![Rust Documentation](https://img.shields.io/badge/api-rustdoc-blue.svg)](https://docs.rs/papyrus)
 //! [![codecov](https://codecov.io/gh/kurtlawrence/papyrus/branch/master/graph/badge.svg)](https://codecov.io/gh/kurtlawrence/papyrus)
-//! [![Rustc Version 1.42+](https://img.shields.io/badge/rustc-1.42+-blue.svg)](https://blog.rust-lang.org/2020/03/12/Rust-1.42.html)
+//! [![Rustc Version 1.76+](https://img.shields.io/badge/rustc-1.76+-blue.svg)](https://blog.rust-lang.org/2024/02/08/Rust-1.76.0.html)
 //!
 //! ## _Papyrus_ - A rust REPL and script running tool.
 //!
@@ -44,6 +44,30 @@
 //! [lib] papyrus=> Thanks for using papyrus!
 //! ```
 //!
+//! ## Running Scripts
+//! Papyrus can also compile and run rust source files, or evaluate a single expression, without
+//! entering the interactive REPL.
+//! ```sh
+//! papyrus run script.rs arg1 arg2   # run a script, arguments are in `app_data: &Vec<String>`
+//! papyrus -e '2+2'                  # evaluate an expression and print the result
+//! papyrus --load prelude.rs         # load a file, then start the REPL
+//! papyrus --isolate                 # evaluate in a worker process, a crash does not end the REPL
+//! papyrus < session.txt             # evaluate piped input lines, printing only the output
+//! papyrus notebook doc.md --check   # evaluate the rust fences of a markdown file, checking outputs
+//! papyrus --rpc                     # serve JSON-RPC requests on stdin, for editor integrations
+//! papyrus connect 127.0.0.1:4000    # connect to a REPL served with `papyrus::run::serve`
+//! ```
+//! A script is evaluated like a single REPL input, if it only defines `fn main()` then `main` is
+//! called. Parsing or compilation failures exit with code `2`, evaluation errors exit with code `1`.
+//! Scripts can be made executable with a shebang, and declare dependencies in the leading `//!` block.
+//! Compiled scripts are cached, so re-running an unchanged script does not recompile.
+//! ```rust,ignore
+//! #!/usr/bin/env papyrus
+//! //! [dependencies]
+//! //! rand = "0.7"
+//! rand::random::<u8>()
+//! ```
+//!
 //! ## Installation
 //! Papyrus can be installed from `crates.io` or building from source on github.
 //! The default installation feature set requires a `nightly` toolchain, but `stable` can be used with
@@ -68,8 +92,10 @@
 //! - _racer-completion_: enable code completion using [`racer`](https://github.com/racer-rust/racer).
 //!     **Requires a nightly compiler**
 //! - _runnable_: papyrus can be _run_, without needing to manually handle repl states and output
+//! - _kernel_: run papyrus as a [Jupyter](https://jupyter.org) kernel with
+//!   `papyrus kernel <connection-file>`. Builds ZeroMQ if it is not installed.
 //!
-//! All features are enabled by default.
+//! All features except _kernel_ are enabled by default.
 //!
 //! ### Cargo
 //! Papyrus leverages installed binaries of both `cargo` and `rustc`. This requirement may lift in the
@@ -142,13 +168,18 @@ pub mod complete;
 pub mod fmt;
 /// Parsing of input.
 pub mod input;
+#[cfg(feature = "kernel")]
+pub mod kernel;
 pub mod linking;
+pub mod notebook;
 pub mod output;
 pub mod repl;
+pub mod rpc;
 
 /// Running the repl. Requires `runnable` feature.
 #[cfg(feature = "runnable")]
 pub mod run;
+pub mod script;
 
 /// Re-exports of most common types and modules.
 pub mod prelude {
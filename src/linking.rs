@@ -194,6 +194,7 @@
 //! implement `::kserd::ToKserd` which would break! At least at this point it is easy to back out
 //! changes in the temporary REPL session.
 
+use std::borrow::Cow;
 use std::collections::HashSet;
 use std::path::{Path, PathBuf};
 use std::{fs, io};
@@ -216,6 +217,14 @@ pub struct LinkingConfiguration {
     /// Indicates a mutable block.
     pub mutable: bool,
 
+    /// Flag whether statements are evaluated against a persistent store.
+    ///
+    /// The evaluation function takes the store as the first argument
+    /// (`mut __store: &mut Vec<Box<dyn Any + Send>>`). Each statement group's bindings are stored the
+    /// first time the group is evaluated, and later groups access them by reference. This way
+    /// only new statements are executed.
+    pub persistent: bool,
+
     /// Additional external libraries to link.
     ///
     /// These are only precompiled libraries, it is preferable
@@ -236,6 +245,7 @@ impl Default for LinkingConfiguration {
         Self {
             data_type: None,
             mutable: false,
+            persistent: false,
             external_libs: HashSet::new(),
             persistent_module_code: String::new(),
         }
@@ -285,7 +295,7 @@ impl LinkingConfiguration {
 pub struct Extern {
     /// Path to rlib.
     path: PathBuf,
-    alias: Option<&'static str>,
+    alias: Option<Cow<'static, str>>,
 }
 
 impl Extern {
@@ -307,8 +317,12 @@ impl Extern {
     /// same folder that the library exists, there _must_ be a `deps` folder,
     /// even if there is no dependencies. This gets validated as well. The
     /// file must exist on disk.
-    pub fn with_alias<P: AsRef<Path>>(rlib_path: P, alias: &'static str) -> io::Result<Self> {
-        Self::ctor(rlib_path, Some(alias))
+    pub fn with_alias<P, A>(rlib_path: P, alias: A) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+        A: Into<Cow<'static, str>>,
+    {
+        Self::ctor(rlib_path, Some(alias.into()))
     }
 
     /// Uses the executable name to derive the library name, and
@@ -339,7 +353,7 @@ impl Extern {
         Self::new(path)
     }
 
-    fn ctor<P: AsRef<Path>>(rlib_path: P, alias: Option<&'static str>) -> io::Result<Self> {
+    fn ctor<P: AsRef<Path>>(rlib_path: P, alias: Option<Cow<'static, str>>) -> io::Result<Self> {
         let path = rlib_path.as_ref();
 
         let path = path.canonicalize()?;
@@ -393,8 +407,8 @@ impl Extern {
     }
 
     /// The alias, is there is one.
-    pub fn alias(&self) -> Option<&'static str> {
-        self.alias
+    pub fn alias(&self) -> Option<&str> {
+        self.alias.as_deref()
     }
 
     /// The canoncialized library path (in `lib*.rlib` format).
@@ -411,7 +425,7 @@ impl Extern {
     pub fn construct_code_str(&self, buf: &mut String) {
         buf.push_str("extern crate "); // 13
         buf.push_str(self.lib_name());
-        if let Some(alias) = self.alias {
+        if let Some(alias) = &self.alias {
             buf.push_str(" as ");
             buf.push_str(alias);
         }
@@ -421,7 +435,7 @@ impl Extern {
     /// Returns the size in bytes that the code representation will require.
     pub fn construct_code_str_length(&self) -> usize {
         13 + self.lib_name().len()
-            + if let Some(alias) = self.alias {
+            + if let Some(alias) = &self.alias {
                 4 + alias.len()
             } else {
                 0
@@ -486,7 +500,7 @@ mod tests {
         assert_eq!(&s, ans);
         assert_eq!(e.construct_code_str_length(), ans.len());
 
-        e.alias = Some("alias");
+        e.alias = Some("alias".into());
 
         let mut s = String::new();
         e.construct_code_str(&mut s);
@@ -2,7 +2,7 @@
 //! [![Latest Version](https://img.shields.io/crates/v/papyrus.svg)](https://crates.io/crates/papyrus)
 //! [![Rust Documentation](https://img.shields.io/badge/api-rustdoc-blue.svg)](https://docs.rs/papyrus)
 //! [![codecov](https://codecov.io/gh/kurtlawrence/papyrus/branch/master/graph/badge.svg)](https://codecov.io/gh/kurtlawrence/papyrus)
-//! [![Rustc Version 1.42+](https://img.shields.io/badge/rustc-1.42+-blue.svg)](https://blog.rust-lang.org/2020/03/12/Rust-1.42.html)
+//! [![Rustc Version 1.76+](https://img.shields.io/badge/rustc-1.76+-blue.svg)](https://blog.rust-lang.org/2024/02/08/Rust-1.76.0.html)
 //!
 //! ## _Papyrus_ - A rust REPL and script running tool.
 //!
@@ -44,6 +44,30 @@
 //! [lib] papyrus=> Thanks for using papyrus!
 //! ```
 //!
+//! ## Running Scripts
+//! Papyrus can also compile and run rust source files, or evaluate a single expression, without
+//! entering the interactive REPL.
+//! ```sh
+//! papyrus run script.rs arg1 arg2   # run a script, arguments are in `app_data: &Vec<String>`
+//! papyrus -e '2+2'                  # evaluate an expression and print the result
+//! papyrus --load prelude.rs         # load a file, then start the REPL
+//! papyrus --isolate                 # evaluate in a worker process, a crash does not end the REPL
+//! papyrus < session.txt             # evaluate piped input lines, printing only the output
+//! papyrus notebook doc.md --check   # evaluate the rust fences of a markdown file, checking outputs
+//! papyrus --rpc                     # serve JSON-RPC requests on stdin, for editor integrations
+//! papyrus connect 127.0.0.1:4000    # connect to a REPL served with `papyrus::run::serve`
+//! ```
+//! A script is evaluated like a single REPL input, if it only defines `fn main()` then `main` is
+//! called. Parsing or compilation failures exit with code `2`, evaluation errors exit with code `1`.
+//! Scripts can be made executable with a shebang, and declare dependencies in the leading `//!` block.
+//! Compiled scripts are cached, so re-running an unchanged script does not recompile.
+//! ```rust,ignore
+//! #!/usr/bin/env papyrus
+//! //! [dependencies]
+//! //! rand = "0.7"
+//! rand::random::<u8>()
+//! ```
+//!
 //! ## Installation
 //! Papyrus can be installed from `crates.io` or building from source on github.
 //! The default installation feature set requires a `nightly` toolchain, but `stable` can be used with
@@ -68,32 +92,352 @@
 //! - _racer-completion_: enable code completion using [`racer`](https://github.com/racer-rust/racer).
 //!     **Requires a nightly compiler**
 //! - _runnable_: papyrus can be _run_, without needing to manually handle repl states and output
+//! - _kernel_: run papyrus as a [Jupyter](https://jupyter.org) kernel with
+//!   `papyrus kernel <connection-file>`. Builds ZeroMQ if it is not installed.
 //!
-//! All features are enabled by default.
+//! All features except _kernel_ are enabled by default.
 //!
 //! ### Cargo
 //! Papyrus leverages installed binaries of both `cargo` and `rustc`. This requirement may lift in the
 //! future but for now, any user wanting to use Papyrus will need an installation of Rust.
 use papyrus::*;
+use std::{fs, path::Path, process};
+
+const USAGE: &str = "papyrus - A rust REPL and script running tool.
+
+USAGE:
+    papyrus                     Start the interactive REPL
+    papyrus run <file> [args]   Compile and run <file> as a script
+    papyrus <file> [args]       Same as `run`, for `#!/usr/bin/env papyrus` scripts
+    papyrus -e <expr>           Evaluate <expr> and print the result
+    papyrus --load <file>       Load <file> into the REPL, then start the interactive REPL
+    papyrus --isolate [--load <file>]
+                                Start the REPL, evaluating in a worker process to survive crashes
+    papyrus [--isolate] --rpc   Serve line-delimited JSON-RPC requests on stdin and stdout
+    papyrus [--isolate] notebook <file> [--check]
+                                Evaluate the rust fences of the markdown <file>, writing the
+                                output blocks, or verifying them with `--check`
+    papyrus connect <addr> [--token <token>]
+                                Connect to a REPL server, such as `127.0.0.1:4000` or
+                                `unix:/tmp/papyrus.sock`, the token defaults to $PAPYRUS_TOKEN
+    papyrus [--isolate] kernel <connection-file>
+                                Run as a Jupyter kernel, requires the `kernel` feature
+    papyrus -h | --help         Print this message
+
+Scripts can access the arguments through `app_data: &Vec<String>`, where the first argument is
+the script path. Dependencies are declared in a leading `//! [dependencies]` comment block.
+Compiled scripts are cached in `$HOME/.papyrus/scripts`. Scripts cannot be run with `--isolate`, a
+worker process cannot access the arguments.
+
+When stdin or stdout is not a terminal the REPL reads input lines without prompts or colours, such
+as `papyrus < session.txt`, and exits with 1 if any input failed.
+
+EXIT CODES:
+    0   Success
+    1   An io error occurred or the evaluation returned an error or panicked
+    2   The code failed to parse or compile";
 
 fn main() {
+    // evaluate and exit if started as a worker process
+    compile::run_if_worker();
+
     windows_term_hack();
 
-    let repl = repl!();
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+
+    let isolate = args.first().map(|x| x == "--isolate").unwrap_or(false);
+    if isolate {
+        args.remove(0);
+    }
+
+    match args.first().map(|x| x.as_str()) {
+        None => run_repl(None, isolate),
+        Some("-h") | Some("--help") => println!("{}", USAGE),
+        // scripts receive their arguments as app data, which a worker process cannot access
+        Some("run") | Some("-e") | Some("--eval") | Some("connect") if isolate => {
+            usage_error(&format!("`--isolate` cannot be used with '{}'", args[0]))
+        }
+        Some(file) if isolate && !file.starts_with('-') && Path::new(file).is_file() => {
+            usage_error("`--isolate` cannot be used with scripts")
+        }
+        Some("run") => match args.get(1) {
+            Some(file) => run_file(file, args[1..].to_vec()),
+            None => usage_error("missing script file"),
+        },
+        Some("-e") | Some("--eval") => match args.get(1) {
+            Some(expr) => run_expr(expr),
+            None => usage_error("missing expression"),
+        },
+        Some("--load") => match args.get(1) {
+            Some(file) => run_repl(Some(file), isolate),
+            None => usage_error("missing file to load"),
+        },
+        Some("--rpc") => run_rpc(isolate),
+        Some("notebook") => match args.get(1) {
+            Some(file) => run_notebook(file, &args[2..], isolate),
+            None => usage_error("missing notebook file"),
+        },
+        Some("connect") => match args.get(1) {
+            Some(addr) => run_connect(addr, &args[2..]),
+            None => usage_error("missing server address"),
+        },
+        Some("kernel") => match args.get(1) {
+            Some(file) => run_kernel(file, isolate),
+            None => usage_error("missing connection file"),
+        },
+        // `#!/usr/bin/env papyrus` scripts are invoked with the path as the first argument
+        Some(file) if !file.starts_with('-') && Path::new(file).is_file() => {
+            run_file(file, args.clone())
+        }
+        Some(x) => usage_error(&format!("unrecognised argument '{}'", x)),
+    }
+}
+
+fn new_repl(isolate: bool) -> repl::Repl<repl::Read, ()> {
+    let mut repl = repl!();
+
+    if isolate {
+        match compile::ExecutionBackend::current_exe_worker() {
+            Ok(backend) => {
+                repl.data.with_execution_backend(backend);
+            }
+            Err(e) => eprintln!("failed to find the worker program: {}", e),
+        }
+    }
+
+    repl
+}
+
+fn run_repl(load: Option<&str>, isolate: bool) {
+    let mut repl = new_repl(isolate);
 
     let app_data = &mut ();
 
-    let run_callbacks =
-        run::RunCallbacks::new(app_data).with_fmtrfn(run::fmt_based_on_terminal_width);
+    if let Some(file) = load {
+        repl = match load_file(repl, file, app_data) {
+            Ok(repl) => repl,
+            Err(code) => process::exit(code),
+        };
+    }
+
+    let headless = run::is_headless();
+    let mut failed = false;
+    let run_callbacks = run::RunCallbacks::new(app_data)
+        .with_fmtrfn(run::fmt_based_on_terminal_width)
+        .with_errorfn(|_| failed = true);
 
     let output = repl.run(run_callbacks);
 
     match output {
+        // piped sessions report failed inputs through the exit code
+        Ok(_) if headless => {
+            if failed {
+                process::exit(1);
+            }
+        }
         Ok(_) => println!("Thanks for using papyrus!"),
         Err(e) => println!("papyrus crashed! {}", e),
     }
 }
 
+/// Evaluates the contents of `file` in the repl. Returns the exit code on failure.
+fn load_file(
+    mut repl: repl::Repl<repl::Read, ()>,
+    file: &str,
+    app_data: &mut (),
+) -> Result<repl::Repl<repl::Read, ()>, i32> {
+    let src = fs::read_to_string(file).map_err(|e| {
+        eprintln!("failed to read '{}': {}", file, e);
+        1
+    })?;
+
+    let code_len = |repl: &repl::Repl<repl::Read, ()>| {
+        repl.data
+            .mods_map()
+            .values()
+            .map(|x| x.items.len() + x.stmts.len() + x.crates.len())
+            .sum::<usize>()
+    };
+    let before = code_len(&repl);
+
+    repl.line_input(&src);
+    let repl = match repl.read() {
+        repl::ReadResult::Eval(repl) => repl,
+        repl::ReadResult::Read(mut repl) => {
+            // the last statement is not an expression, finish with unit
+            repl.line_input("()");
+            match repl.read() {
+                repl::ReadResult::Eval(repl) => repl,
+                repl::ReadResult::Read(_) => {
+                    eprintln!("failed to parse '{}': unexpected end of input", file);
+                    return Err(2);
+                }
+            }
+        }
+    };
+
+    let start = repl.output().len();
+    let (repl, _) = repl.eval(app_data).repl.print();
+
+    // a failed evaluation does not keep the input
+    if !src.trim().is_empty() && code_len(&repl) == before {
+        // skip the echoed input and the trailing prompt
+        let msg = repl.output()[start..].trim_end();
+        let msg = msg.rsplit_once('\n').map(|x| x.0).unwrap_or_default();
+        eprintln!("failed to load '{}':\n{}", file, msg.trim());
+        return Err(2);
+    }
+
+    Ok(repl)
+}
+
+fn run_rpc(isolate: bool) {
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    // output lines are sent to a client, not a terminal
+    colored::control::set_override(false);
+
+    let stdin = io::BufReader::new(io::stdin());
+    if let Err(e) = rpc::serve(
+        new_repl(isolate),
+        &Arc::new(Mutex::new(())),
+        stdin,
+        io::stdout(),
+    ) {
+        eprintln!("rpc server failed: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run_notebook(file: &str, args: &[String], isolate: bool) {
+    let check = match args {
+        [] => false,
+        [flag] if flag == "--check" => true,
+        _ => usage_error("expecting `--check`"),
+    };
+
+    let src = match fs::read_to_string(file) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("failed to read '{}': {}", file, e);
+            process::exit(1);
+        }
+    };
+
+    let mut repl = new_repl(isolate);
+    let dir = repl.data.compilation_dir().join("notebook");
+    if let Err(e) = repl.data.with_compilation_dir(&dir) {
+        eprintln!("failed to create '{}': {}", dir.display(), e);
+        process::exit(1);
+    }
+
+    let cells = notebook::parse_cells(&src);
+    let (_, outputs) = notebook::eval_cells(repl, &mut (), &cells);
+
+    let mut failed = false;
+    for (cell, output) in cells.iter().zip(&outputs) {
+        if check && !cell.matches(&output.output) {
+            failed = true;
+            eprintln!(
+                "{}:{}: output differs\n--- expected\n{}+++ actual\n{}",
+                file,
+                cell.line,
+                cell.output.as_deref().unwrap_or_default(),
+                output.output
+            );
+        } else if let (false, Some(e)) = (check, &output.error) {
+            failed = true;
+            eprintln!("{}:{}: {}", file, cell.line, e);
+        }
+    }
+
+    if !check {
+        let md = notebook::write_outputs(&src, &cells, &outputs);
+        if md != src {
+            if let Err(e) = fs::write(file, md) {
+                eprintln!("failed to write '{}': {}", file, e);
+                process::exit(1);
+            }
+        }
+    }
+
+    if failed {
+        process::exit(1);
+    }
+}
+
+fn run_connect(addr: &str, args: &[String]) {
+    let token = match args {
+        [] => std::env::var("PAPYRUS_TOKEN").ok(),
+        [flag, token] if flag == "--token" => Some(token.clone()),
+        _ => usage_error("expecting `--token <token>`"),
+    };
+
+    if let Err(e) = run::connect(addr, token.as_deref()) {
+        eprintln!("failed to connect to '{}': {}", addr, e);
+        process::exit(1);
+    }
+}
+
+#[cfg(feature = "kernel")]
+fn run_kernel(connection_file: &str, isolate: bool) {
+    use std::sync::{Arc, Mutex};
+
+    let r = kernel::ConnectionInfo::read(connection_file)
+        .and_then(kernel::Kernel::bind)
+        .and_then(|kernel| kernel.run(new_repl(isolate), &Arc::new(Mutex::new(()))));
+
+    if let Err(e) = r {
+        eprintln!("kernel failed: {}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(not(feature = "kernel"))]
+fn run_kernel(_: &str, _: bool) {
+    eprintln!("papyrus was built without the _kernel_ feature");
+    process::exit(1);
+}
+
+fn run_file(file: &str, args: Vec<String>) {
+    let src = match fs::read_to_string(file) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("failed to read '{}': {}", file, e);
+            process::exit(1);
+        }
+    };
+
+    run_src(&src, args, false);
+}
+
+fn run_expr(expr: &str) {
+    run_src(expr, vec![String::from("-e")], true);
+}
+
+fn run_src(src: &str, args: Vec<String>, print_unit: bool) {
+    let dir = script::default_script_dir();
+    match script::run_script(&dir, src, args) {
+        Ok(kserd) => {
+            if print_unit || !kserd.unit() {
+                println!("{}", kserd.as_str());
+            }
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(e.exit_code());
+        }
+    }
+}
+
+fn usage_error(msg: &str) -> ! {
+    eprintln!("error: {}\n\n{}", msg, USAGE);
+    process::exit(2);
+}
+
 #[cfg(windows)]
 fn windows_term_hack() {
     colored::control::set_virtual_terminal(true).ok();
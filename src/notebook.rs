@@ -0,0 +1,439 @@
+//! Running the rust code fences of a markdown document through a REPL session, as a notebook.
+//!
+//! Each ` ```rust ` fence is evaluated in order through one [`Repl`], so later fences can use the
+//! items and outputs of earlier ones. The output of a fence, such as the `[out#]` result, captured
+//! output, or failure message, is written in an ` ```output ` block following the fence.
+//!
+//! ````markdown
+//! ```rust
+//! let a = 2;
+//! a + 2
+//! ```
+//!
+//! ```output
+//! papyrus [out0]: 4
+//! ```
+//! ````
+//!
+//! ```sh
+//! papyrus notebook doc.md           # write the outputs into doc.md
+//! papyrus notebook doc.md --check   # verify the outputs match, without writing
+//! ```
+//!
+//! A fence is evaluated as a single input, code missing a final expression is finished with `()`.
+//! Fences with attributes, such as ` ```rust,ignore `, are evaluated as well, which keeps them out
+//! of `mdbook test` while still being checked by the notebook. Fences which produce no output have
+//! no output block.
+use crate::input;
+use crate::output::OutputChange;
+use crate::repl::{EvalResult, Read, ReadResult, Repl, Signal};
+use std::ops::Range;
+
+/// The info string of output blocks.
+pub const OUTPUT_INFO: &str = "output";
+
+/// A ` ```rust ` fence of a markdown document.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cell {
+    /// The line number of the opening fence, starting at 1.
+    pub line: usize,
+    /// The code within the fence.
+    pub code: String,
+    /// The contents of the output block following the fence, if there is one.
+    pub output: Option<String>,
+    /// The bytes replaced by the output block, starting after the closing fence.
+    output_range: Range<usize>,
+}
+
+impl Cell {
+    /// Whether `output` matches the output block, ignoring trailing whitespace. A missing output
+    /// block matches no output.
+    pub fn matches(&self, output: &str) -> bool {
+        let trim = |s: &str| {
+            s.lines()
+                .map(str::trim_end)
+                .collect::<Vec<_>>()
+                .join("\n")
+                .trim_end()
+                .to_string()
+        };
+        trim(self.output.as_deref().unwrap_or_default()) == trim(output)
+    }
+}
+
+/// The result of evaluating a [`Cell`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct CellOutput {
+    /// The lines written to the REPL output, without the prompt or input.
+    pub output: String,
+    /// The failure message if the code failed to parse, compile, or evaluate.
+    pub error: Option<String>,
+}
+
+/// A fenced code block.
+struct Block<'a> {
+    line: usize,
+    info: &'a str,
+    content: String,
+    /// The bytes of the block, including the fences and the trailing newline.
+    range: Range<usize>,
+}
+
+/// The fenced code blocks of `src`. An unclosed fence runs to the end of the document.
+fn blocks(src: &str) -> Vec<Block<'_>> {
+    let mut blocks = Vec::new();
+    let mut open: Option<(Block, &str)> = None;
+    let mut pos = 0;
+
+    for (idx, line) in src.split_inclusive('\n').enumerate() {
+        let start = pos;
+        pos += line.len();
+        let trimmed = line.trim_end();
+        let unindented = trimmed.trim_start_matches(' ');
+        let fence = if trimmed.len() - unindented.len() <= 3 {
+            let ch = unindented.chars().next().filter(|&c| c == '`' || c == '~');
+            ch.map(|c| {
+                let n = unindented.chars().take_while(|&x| x == c).count();
+                unindented.split_at(n * c.len_utf8())
+            })
+            .filter(|(fence, _)| fence.len() >= 3)
+        } else {
+            None
+        };
+
+        match (&mut open, fence) {
+            (Some((block, open_fence)), Some((fence, info)))
+                if info.trim().is_empty()
+                    && fence.starts_with(&open_fence[..1])
+                    && fence.len() >= open_fence.len() =>
+            {
+                block.range.end = pos;
+                if let Some((block, _)) = open.take() {
+                    blocks.push(block);
+                }
+            }
+            (Some((block, _)), _) => block.content.push_str(line),
+            (None, Some((fence, info))) => {
+                let block = Block {
+                    line: idx + 1,
+                    info: info.trim(),
+                    content: String::new(),
+                    range: start..pos,
+                };
+                open = Some((block, fence));
+            }
+            (None, None) => (),
+        }
+    }
+
+    if let Some((mut block, _)) = open {
+        block.range.end = pos;
+        blocks.push(block);
+    }
+
+    blocks
+}
+
+/// The ` ```rust ` fences of the markdown `src`, with their following output blocks.
+///
+/// A fence is rust if the first word of the info string is `rust`, attributes after it are
+/// ignored. An output block must follow the fence, separated only by blank lines.
+pub fn parse_cells(src: &str) -> Vec<Cell> {
+    let is_rust =
+        |info: &str| info.split(|c: char| c == ',' || c.is_whitespace()).next() == Some("rust");
+
+    let blocks = blocks(src);
+    let mut cells = Vec::new();
+    for (i, block) in blocks.iter().enumerate() {
+        if !is_rust(block.info) {
+            continue;
+        }
+
+        let output = blocks
+            .get(i + 1)
+            .filter(|x| x.info == OUTPUT_INFO)
+            .filter(|x| src[block.range.end..x.range.start].trim().is_empty());
+
+        cells.push(Cell {
+            line: block.line,
+            code: block.content.clone(),
+            output: output.map(|x| x.content.clone()),
+            output_range: block.range.end..output.map(|x| x.range.end).unwrap_or(block.range.end),
+        });
+    }
+
+    cells
+}
+
+/// Evaluate the code of each cell in order, returning the REPL and the output of each cell.
+///
+/// The output is written without colours, restoring the previous colouring afterwards. Evaluation
+/// stops after a cell exits the REPL, such as with `:exit`, so there are no outputs for the cells
+/// following it.
+pub fn eval_cells<D>(
+    repl: Repl<Read, D>,
+    app_data: &mut D,
+    cells: &[Cell],
+) -> (Repl<Read, D>, Vec<CellOutput>) {
+    let colorize = colored::control::SHOULD_COLORIZE.should_colorize();
+    colored::control::set_override(false);
+    let mut repl = repl;
+    let mut outputs = Vec::with_capacity(cells.len());
+    for cell in cells {
+        let (r, output, exit) = eval_cell(repl, app_data, &cell.code);
+        repl = r;
+        outputs.push(output);
+        if exit {
+            break;
+        }
+    }
+    colored::control::set_override(colorize);
+
+    (repl, outputs)
+}
+
+/// Evaluates the code of a cell, returning whether the REPL was exited.
+///
+/// Inputs the REPL asks to re-evaluate, such as from `:edit stmt replace`, are evaluated as part
+/// of the cell.
+fn eval_cell<D>(
+    mut repl: Repl<Read, D>,
+    app_data: &mut D,
+    code: &str,
+) -> (Repl<Read, D>, CellOutput, bool) {
+    let treat_as_cmd = !repl.data.cmdtree.at_root();
+    let mut output = String::new();
+
+    match input::finish_input(code, treat_as_cmd) {
+        Some(input) => repl.line_input(&input),
+        None => return (repl, unfinished(output), false),
+    }
+
+    loop {
+        let mut eval = match repl.read() {
+            ReadResult::Eval(eval) => eval,
+            ReadResult::Read(repl) => return (repl, unfinished(output), false),
+        };
+
+        let rx = eval.output_listen();
+        let is_cmd = eval.is_command();
+        let EvalResult {
+            repl: print,
+            signal,
+        } = eval.eval(app_data);
+        let error = print.error(is_cmd).map(String::from);
+        let (read, _) = print.print();
+        repl = read;
+        repl.close_channel();
+
+        // only whole lines, the current line is the prompt
+        let mut line = String::new();
+        for chg in rx.try_iter() {
+            match chg {
+                OutputChange::CurrentLine(x) => line = x,
+                OutputChange::NewLine => {
+                    output.push_str(&line);
+                    output.push('\n');
+                    line.clear();
+                }
+            }
+        }
+
+        match signal {
+            Signal::ReEvaluate(input) => repl.line_input(&input),
+            Signal::Exit => return (repl, CellOutput { output, error }, true),
+            Signal::None => return (repl, CellOutput { output, error }, false),
+        }
+    }
+}
+
+/// The output of a cell whose input is unfinished, following the `output` written so far.
+fn unfinished(mut output: String) -> CellOutput {
+    const MSG: &str = "unexpected end of input";
+    output.push_str(MSG);
+    output.push('\n');
+    CellOutput {
+        output,
+        error: Some(MSG.to_string()),
+    }
+}
+
+/// Write the outputs into the markdown `src`, replacing the output block of each cell.
+///
+/// Cells without output have their output block removed. The output block's fence is longer than
+/// any run of backticks in the output, so the output can not close it.
+pub fn write_outputs(src: &str, cells: &[Cell], outputs: &[CellOutput]) -> String {
+    let mut md = String::with_capacity(src.len());
+    let mut pos = 0;
+
+    for (cell, output) in cells.iter().zip(outputs) {
+        md.push_str(&src[pos..cell.output_range.start]);
+        pos = cell.output_range.end;
+
+        if output.output.trim().is_empty() {
+            continue;
+        }
+
+        if !md.ends_with('\n') {
+            md.push('\n');
+        }
+        let ticks = output
+            .output
+            .split(|c| c != '`')
+            .map(str::len)
+            .max()
+            .unwrap_or_default();
+        let fence = "`".repeat(ticks.max(2) + 1);
+        md.push('\n');
+        md.push_str(&fence);
+        md.push_str(OUTPUT_INFO);
+        md.push('\n');
+        md.push_str(&output.output);
+        if !output.output.ends_with('\n') {
+            md.push('\n');
+        }
+        md.push_str(&fence);
+        md.push('\n');
+    }
+
+    md.push_str(&src[pos..]);
+    md
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate as papyrus;
+
+    const DOC: &str = "# Notebook
+
+```rust
+let a = 2;
+a + 2
+```
+
+Not an output block.
+
+```text
+plain text
+```
+
+```rust,ignore
+:mod
+```
+
+```output
+stale
+```
+
+````rust
+fn a() {}
+````
+";
+
+    #[test]
+    fn parse_cells_test() {
+        let cells = parse_cells(DOC);
+        assert_eq!(cells.len(), 3);
+
+        assert_eq!(cells[0].line, 3);
+        assert_eq!(cells[0].code, "let a = 2;\na + 2\n");
+        assert_eq!(cells[0].output, None);
+
+        assert_eq!(cells[1].line, 14);
+        assert_eq!(cells[1].code, ":mod\n");
+        assert_eq!(cells[1].output.as_deref(), Some("stale\n"));
+        assert!(cells[1].matches("stale  \n\n"));
+        assert!(!cells[1].matches("fresh"));
+
+        assert_eq!(cells[2].line, 22);
+        assert_eq!(cells[2].code, "fn a() {}\n");
+        assert!(cells[2].matches(""));
+
+        // unclosed fences run to the end, closing fences can be indented
+        let cells = parse_cells("```rust\n1\n  ```\n```rust\n2\n    ```\n");
+        assert_eq!(cells[0].code, "1\n");
+        assert_eq!(cells[1].code, "2\n    ```\n");
+    }
+
+    #[test]
+    fn write_outputs_test() {
+        let cells = parse_cells(DOC);
+        let output = |s: &str| CellOutput {
+            output: s.to_string(),
+            error: None,
+        };
+        let outputs = vec![output("papyrus [out0]: 4\n"), output(""), output("x")];
+
+        let md = write_outputs(DOC, &cells, &outputs);
+        assert_eq!(
+            md,
+            DOC.replace(
+                "a + 2\n```\n",
+                "a + 2\n```\n\n```output\npapyrus [out0]: 4\n```\n"
+            )
+            .replace(":mod\n```\n\n```output\nstale\n```\n", ":mod\n```\n")
+            .replace(
+                "fn a() {}\n````\n",
+                "fn a() {}\n````\n\n```output\nx\n```\n"
+            )
+        );
+
+        // rewriting is stable
+        let cells = parse_cells(&md);
+        assert_eq!(write_outputs(&md, &cells, &outputs), md);
+        assert!(cells[0].matches(&outputs[0].output));
+
+        // backticks in the output do not close the block
+        let src = "```rust\n1\n```\n";
+        let cells = parse_cells(src);
+        let outputs = vec![output("a ```` b\n```\n")];
+        let md = write_outputs(src, &cells, &outputs);
+        assert_eq!(md, "```rust\n1\n```\n\n`````output\na ```` b\n```\n`````\n");
+        let cells = parse_cells(&md);
+        assert!(cells[0].matches(&outputs[0].output));
+        assert_eq!(write_outputs(&md, &cells, &outputs), md);
+    }
+
+    #[test]
+    fn eval_cells_test() {
+        let mut repl = repl!();
+        repl.data
+            .with_compilation_dir("target/testing/eval_cells_test")
+            .unwrap();
+
+        let cells = parse_cells(
+            "```rust\nlet a = 2;\na + 2\n```\n```rust\n:help\n```\n```rust\nfn a() {\n```\n```rust\nout0 + \"b\"\n```\n",
+        );
+        let (_, outputs) = eval_cells(repl, &mut (), &cells);
+
+        assert_eq!(outputs[0].output, "papyrus [out0]: 4\n");
+        assert_eq!(outputs[0].error, None);
+        assert!(outputs[1]
+            .output
+            .starts_with("help -- prints the help messages\n"));
+        assert_eq!(outputs[1].error, None);
+        assert_eq!(outputs[2].error.as_deref(), Some("unexpected end of input"));
+        assert!(outputs[3].error.as_deref().unwrap().contains("error"));
+        assert!(outputs[3].output.contains("error"));
+    }
+
+    #[test]
+    fn eval_cells_signals() {
+        let mut repl = repl!();
+        repl.data
+            .with_compilation_dir("target/testing/eval_cells_signals")
+            .unwrap();
+
+        let cells = parse_cells(
+            "```rust\n1\n```\n```rust\n:edit stmt replace 0 2\n```\n```rust\n:exit\n```\n```rust\n3\n```\n",
+        );
+        let (_, outputs) = eval_cells(repl, &mut (), &cells);
+
+        assert_eq!(outputs.len(), 3, "cells after exiting are not evaluated");
+        assert_eq!(outputs[0].output, "papyrus [out0]: 1\n");
+        assert_eq!(outputs[1].output, "papyrus [out0]: 2\n");
+        assert_eq!(outputs[1].error, None);
+        assert_eq!(outputs[2].error, None);
+    }
+}
@@ -1,6 +1,7 @@
 use super::*;
 use crate::code::{
-    parse_crates_in_file, validate_static_file_path, AddingStaticFileError, ModsMap, SourceCode,
+    parse_crates_in_file, validate_static_file_path, AddingStaticFileError, CrateType, ModsMap,
+    SourceCode,
 };
 
 impl<Data> Default for ReplData<Data> {
@@ -24,6 +25,14 @@ impl<Data> Default for ReplData<Data> {
             static_files: StaticFiles::new(),
             loadedlibs: VecDeque::new(),
             loaded_libs_size_limit: 0,
+            stores: None,
+            compiler: Box::new(CargoBackend),
+            execution: ExecutionBackend::default(),
+            cancel: CancelHandle::default(),
+            eval_timeout: None,
+            capture_output: true,
+            show_types: false,
+            compile_progress: None,
         };
 
         r.with_cmdtree_builder(Builder::new("papyrus"))
@@ -45,6 +54,11 @@ impl<Data> ReplData<Data> {
         Ok(self)
     }
 
+    /// The directory for which compilation is done within.
+    pub fn compilation_dir(&self) -> &Path {
+        &self.compilation_dir
+    }
+
     /// Link an external library.
     ///
     /// This is primarily used for linking the calling library, and there
@@ -91,6 +105,145 @@ impl<Data> ReplData<Data> {
         &mut self.linking.persistent_module_code
     }
 
+    /// Add a crate dependency to the current module.
+    ///
+    /// A crate with the same cargo name in the current module is replaced. The crate is written to
+    /// `Cargo.toml` on the next evaluation.
+    pub fn add_dependency(&mut self, crate_type: CrateType) -> &mut Self {
+        let crates = &mut self
+            .mods_map
+            .get_mut(&self.current_mod)
+            .expect("current mod should always exist")
+            .crates;
+        match crates
+            .iter_mut()
+            .find(|x| x.cargo_name == crate_type.cargo_name)
+        {
+            Some(c) => *c = crate_type,
+            None => crates.push(crate_type),
+        }
+        self
+    }
+
+    /// Evaluate only new statements, persisting the values of earlier ones.
+    ///
+    /// By default each evaluation replays the whole statement history of the module. With
+    /// persistent evaluation each statement group's `let` bindings and `out#` value are kept in a
+    /// host-side store the first time the group is evaluated, and only the newly entered group is
+    /// executed. Side effects are not repeated and expensive values are not recomputed.
+    ///
+    /// Earlier bindings are accessed as shared references which only live for the evaluation, so
+    /// they cannot be mutated or moved into a spawned thread, app data, or a `static`.
+    /// Persisted values must be `Send + 'static` and the output must implement `Clone`. If an input
+    /// does not compile under these constraints, statements are replayed instead until that input
+    /// is removed. Changing items, crates, or static files clears the store.
+    ///
+    /// In the REPL this is toggled with the `:persist on|off` command.
+    pub fn with_persistent_evaluation(&mut self, persistent: bool) -> &mut Self {
+        if persistent != self.stores.is_some() {
+            self.stores = if persistent {
+                Some(Default::default())
+            } else {
+                None
+            };
+        }
+        self
+    }
+
+    /// Whether only new statements are evaluated. See
+    /// [`with_persistent_evaluation`](ReplData::with_persistent_evaluation).
+    pub fn persistent_evaluation(&self) -> bool {
+        self.stores.is_some()
+    }
+
+    /// Set how the compile directory is compiled. The default is [`CargoBackend`].
+    ///
+    /// [`RustcBackend`](crate::compile::RustcBackend) compiles faster when no crates are
+    /// referenced, and [`MockBackend`](crate::compile::MockBackend) allows testing without a Rust
+    /// toolchain.
+    pub fn with_compiler_backend<B: CompilerBackend + 'static>(&mut self, backend: B) -> &mut Self {
+        self.compiler = Box::new(backend);
+        self
+    }
+
+    /// Set how compiled code is executed.
+    ///
+    /// Use [`ExecutionBackend::Worker`] to evaluate in a child process so a crash in the evaluated
+    /// code does not end the REPL. Worker evaluation cannot access app data and does not use
+    /// [persistent evaluation](ReplData::with_persistent_evaluation).
+    pub fn with_execution_backend(&mut self, backend: ExecutionBackend) -> &mut Self {
+        self.execution = backend;
+        self
+    }
+
+    /// How compiled code is executed.
+    pub fn execution_backend(&self) -> &ExecutionBackend {
+        &self.execution
+    }
+
+    /// Set the maximum time evaluated code can run for, `None` being no limit. The default is no
+    /// limit.
+    ///
+    /// Compilation is not included. The timeout requires the [`ExecutionBackend::Worker`] backend,
+    /// code executing in-process cannot be stopped. Evaluating statements in-process with a
+    /// timeout set fails with an error rather than ignoring the timeout.
+    pub fn with_eval_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.eval_timeout = timeout;
+        self
+    }
+
+    /// The maximum time evaluated code can run for.
+    pub fn eval_timeout(&self) -> Option<Duration> {
+        self.eval_timeout
+    }
+
+    /// Set whether stdout and stderr of evaluated code is captured into the [`Output`]. The
+    /// default is to capture.
+    ///
+    /// Captured text is streamed into the output as it is produced, triggering line changes.
+    /// Capturing is only supported on unix, elsewhere the evaluated code writes straight to the
+    /// process's stdout and stderr.
+    pub fn with_capture_output(&mut self, capture: bool) -> &mut Self {
+        self.capture_output = capture;
+        self
+    }
+
+    /// Whether stdout and stderr of evaluated code is captured into the output.
+    pub fn capture_output(&self) -> bool {
+        self.capture_output
+    }
+
+    /// Set whether the type name of an output is printed after `[out#]`. The default is to not
+    /// show types.
+    ///
+    /// The type name is always returned from [`print`](Repl::print).
+    pub fn with_show_types(&mut self, show: bool) -> &mut Self {
+        self.show_types = show;
+        self
+    }
+
+    /// Whether the type name of an output is printed.
+    pub fn show_types(&self) -> bool {
+        self.show_types
+    }
+
+    /// Begin listening to compiler progress lines, such as `Compiling kserd v0.4.2`.
+    ///
+    /// Progress lines are also written to the output as the current line, which is erased when
+    /// compilation finishes.
+    pub fn compile_progress_listen(&mut self) -> Receiver<String> {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        self.compile_progress = Some(tx);
+        rx
+    }
+
+    /// A handle to cancel the current or next evaluation.
+    ///
+    /// The cancel flag is reset when an evaluation begins.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
     /// The current static files.
     pub fn static_files(&self) -> &StaticFiles {
         &self.static_files
@@ -147,10 +300,46 @@ impl<Data> ReplData<Data> {
         removed
     }
 
-    fn static_file_name(&self, path: &Path) -> PathBuf {
+    pub(super) fn static_file_name(&self, path: &Path) -> PathBuf {
         self.compilation_dir.join("src").join(path)
     }
 
+    /// Export the REPL code as a standalone binary crate in `dir`.
+    ///
+    /// The `lib` module's statements are placed in `main`, which prints the final output. Other
+    /// modules are written as files with their statements in a `run` function called from `main`.
+    /// The referenced crates are added as dependencies, and static files are copied over. See
+    /// [`build_export_dir`](crate::compile::build_export_dir) for details. The package is named
+    /// after the directory.
+    ///
+    /// An error is returned if `dir` already contains a `Cargo.toml`, an existing project is never
+    /// overwritten.
+    pub fn export_project<P: AsRef<Path>>(&self, dir: P) -> io::Result<()> {
+        let dir = dir.as_ref();
+        if dir.join("Cargo.toml").exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("`{}` already contains a Cargo.toml", dir.display()),
+            ));
+        }
+
+        let name = dir
+            .file_name()
+            .and_then(|x| x.to_str())
+            .map(package_name)
+            .filter(|x| !x.is_empty())
+            .unwrap_or_else(|| String::from("papyrus-export"));
+
+        crate::compile::build_export_dir(
+            dir,
+            &name,
+            &self.mods_map,
+            &self.linking,
+            &self.static_files,
+            self.compilation_dir.join("src"),
+        )
+    }
+
     /// Clears the cached loaded libraries.
     ///
     /// This can be used to clear resources. Loaded libraries are stored up to the
@@ -161,6 +350,34 @@ impl<Data> ReplData<Data> {
         self.loadedlibs.clear()
     }
 
+    /// Hash of the code which persisted values depend upon.
+    pub(super) fn persist_env_hash(&self) -> u64 {
+        use std::hash::{Hash, Hasher};
+
+        let mut hasher = fxhash::FxHasher64::default();
+        for (path, src) in &self.mods_map {
+            path.hash(&mut hasher);
+            src.items.hash(&mut hasher);
+            for c in &src.crates {
+                c.src_line.hash(&mut hasher);
+            }
+        }
+        for file in self.static_files.iter() {
+            file.path.hash(&mut hasher);
+            file.codehash.hash(&mut hasher);
+        }
+        self.linking.data_type.hash(&mut hasher);
+        self.linking.persistent_module_code.hash(&mut hasher);
+        // externs are unordered
+        let externs = self
+            .linking
+            .external_libs
+            .iter()
+            .fold(0, |acc, x| acc ^ fxhash::hash64(x));
+        externs.hash(&mut hasher);
+        hasher.finish()
+    }
+
     /// Not meant to used by developer. Use the macros instead.
     /// [See _linking_ module](../pfh/linking.html)
     ///
@@ -175,6 +392,19 @@ impl<Data> ReplData<Data> {
     }
 }
 
+/// Converts a directory name into a valid cargo package name.
+fn package_name(dir_name: &str) -> String {
+    let name: String = dir_name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | '0'..='9' | '-' | '_' => c,
+            'A'..='Z' => c.to_ascii_lowercase(),
+            _ => '-',
+        })
+        .collect();
+    name.trim_matches('-').to_string()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -202,4 +432,25 @@ mod tests {
         data.add_static_file("path/to/something.rs".into(), "")
             .unwrap();
     }
+
+    #[test]
+    fn export_project_test() {
+        assert_eq!(package_name("My Project!"), "my-project");
+        assert_eq!(package_name("..."), "");
+
+        let dir = Path::new("target/testing/export-project-test/My Project");
+        fs::remove_dir_all(dir).ok();
+
+        let mut data: ReplData<()> = ReplData::default();
+        data.with_compilation_dir("target/testing/export-project-test/compile")
+            .unwrap();
+        data.export_project(dir).unwrap();
+        assert!(fs::read_to_string(dir.join("Cargo.toml"))
+            .unwrap()
+            .contains("name = \"my-project\""));
+
+        // won't overwrite
+        let e = data.export_project(dir).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+    }
 }
@@ -16,6 +16,8 @@ impl<D> Repl<Evaluate, D> {
         use std::cell::Cell;
         use std::rc::Rc;
 
+        self.data.cancel.reset();
+
         let ptr = Rc::into_raw(Rc::new(app_data));
 
         // as I am playing around with pointers here, I am going to do assertions in the rebuilding
@@ -51,6 +53,8 @@ impl<D> Repl<Evaluate, D> {
         let (tx, rx) = crossbeam_channel::bounded(1);
 
         let clone = Arc::clone(app_data);
+        self.data.cancel.reset();
+        let cancel = self.data.cancel_handle();
 
         std::thread::spawn(move || {
             let eval = map_variants(
@@ -62,7 +66,7 @@ impl<D> Repl<Evaluate, D> {
             tx.send(eval).unwrap();
         });
 
-        Evaluating { jh: rx }
+        Evaluating { jh: rx, cancel }
     }
 
     /// Begin listening to line change events on the output.
@@ -81,6 +85,11 @@ impl<D> Repl<Evaluate, D> {
     pub fn output(&self) -> &str {
         self.state.output.buffer()
     }
+
+    /// The input being evaluated is a command.
+    pub(crate) fn is_command(&self) -> bool {
+        matches!(self.state.result, InputResult::Command(_))
+    }
 }
 
 impl<D> Evaluating<D> {
@@ -89,6 +98,13 @@ impl<D> Evaluating<D> {
         !self.jh.is_empty()
     }
 
+    /// Cancel the evaluation. See [`CancelHandle`].
+    ///
+    /// The result is still received through [`wait`](Evaluating::wait).
+    pub fn cancel(&self) {
+        self.cancel.cancel()
+    }
+
     /// Waits for the evaluating to finish before return the result.
     /// If evaluating is `completed` this will return immediately.
     pub fn wait(self) -> EvalResult<D> {
@@ -161,16 +177,15 @@ where
 }
 
 impl<D> ReplData<D> {
-    fn handle_command<F, R, W>(
+    fn handle_command<F, R>(
         &mut self,
         cmds: &str,
-        writer: &mut W,
+        writer: &mut Output<output::Write>,
         obtain_mut_app_data: F,
     ) -> Result<Cow<'static, str>, Signal>
     where
         F: FnOnce() -> R,
         R: DerefMut<Target = D>,
-        W: io::Write,
     {
         use cmdtree::LineResult as lr;
 
@@ -199,6 +214,7 @@ impl<D> ReplData<D> {
                 CommandResult::SwitchModule(path) => {
                     Cow::Borrowed(crate::cmds::switch_module(self, &path))
                 }
+                CommandResult::TypeOf(expr) => self.type_of(&expr, writer, obtain_mut_app_data),
 
                 CommandResult::ActionOnReplData(action) => Cow::Owned(action(self, writer)),
                 CommandResult::ActionOnAppData(action) => {
@@ -229,9 +245,21 @@ impl<D> ReplData<D> {
         Rbrw: Deref<Target = D>,
     {
         let (nitems, ncrates) = (input.items.len(), input.crates.len());
+        let mods = std::mem::take(&mut input.mods);
 
         let has_stmts = !input.stmts.is_empty();
 
+        // in-process code cannot be stopped, so do not pretend to honour the timeout
+        if has_stmts
+            && self.eval_timeout.is_some()
+            && self.execution == compile::ExecutionBackend::InProcess
+        {
+            self.editing = None;
+            return EvalOutput::Print(Cow::Borrowed(
+                "an evaluation timeout requires the worker execution backend",
+            ));
+        }
+
         let (lstmts, litem, lcrates) = {
             let src = self.current_src();
             (src.stmts.len(), src.items.len(), src.crates.len())
@@ -281,9 +309,12 @@ impl<D> ReplData<D> {
         };
 
         self.insert_input(input, stmt_idx, item_idx, crate_idx);
+        let mods_undo = self.insert_mods(mods);
 
         let maybe_pop_input = |repl_data: &mut ReplData<D>| {
             if undo {
+                repl_data.remove_mods(&mods_undo);
+
                 let src = repl_data.get_current_file_mut();
 
                 if has_stmts {
@@ -300,34 +331,39 @@ impl<D> ReplData<D> {
             }
         };
 
-        // build directory
-        let res = compile::build_compile_dir(
-            &self.compilation_dir,
-            &self.mods_map,
-            &self.linking,
-            &self.static_files,
-        );
-        if let Err(e) = res {
-            maybe_pop_input(self); // failed so don't save
-            return EvalOutput::Print(Cow::Owned(format!(
-                "failed to build compile directory: {}",
-                e
-            )));
-        }
-
-        // compile
-        let lib_file = compile::compile(&self.compilation_dir, &self.linking, |line| {
-            writer.erase_last_line();
-            writer.write_str(line);
-        });
+        // evaluate only the new statements if values are being persisted
+        let env = self.persist_env_hash();
+        let in_process = self.execution == compile::ExecutionBackend::InProcess;
+        let persist = match self.stores.as_mut() {
+            Some(stores) if has_stmts && in_process && !self.linking.mutable => {
+                let stmts = &self.mods_map[&self.current_mod].stmts;
+                stores
+                    .sync(env, &self.current_mod, stmts)
+                    .fallback_at
+                    .is_none()
+            }
+            _ => false,
+        };
 
-        writer.erase_last_line();
+        let mut lib_file = self.build_and_compile(persist, writer);
+        if persist && matches!(lib_file, Err((_, true))) {
+            // the values are not persistable, try replaying the statements
+            lib_file = self.build_and_compile(false, writer);
+            if lib_file.is_ok() {
+                let stmts = &self.mods_map[&self.current_mod].stmts;
+                if let Some(stores) = self.stores.as_mut() {
+                    stores.sync(env, &self.current_mod, stmts).fallback_at = Some(stmts.len());
+                }
+            }
+        }
+        let persist = persist && lib_file.is_ok() && self.linking.persistent;
+        self.linking.persistent = false;
 
         let lib_file = match lib_file {
             Ok(f) => f,
-            Err(e) => {
+            Err((e, _)) => {
                 maybe_pop_input(self); // failed so don't save
-                return EvalOutput::Print(Cow::Owned(format!("{}", e)));
+                return EvalOutput::Print(e);
             }
         };
 
@@ -342,36 +378,84 @@ impl<D> ReplData<D> {
                 let mut fn_name = String::new();
                 code::eval_fn_name(&code::into_mod_path_vec(self.current_mod()), &mut fn_name);
 
-                if self.linking.mutable {
+                let wtr = if self.capture_output {
+                    Some(&mut *writer)
+                } else {
+                    None
+                };
+
+                if let compile::ExecutionBackend::Worker { program } = &self.execution {
+                    if self.linking.data_type.is_some() {
+                        Err(compile::ExecError::Failed(Cow::Borrowed(
+                            "app data can not be passed to a worker process",
+                        )))
+                    } else {
+                        let cancel = &self.cancel;
+                        let timeout = self.eval_timeout;
+                        let start = std::time::Instant::now();
+                        let interrupt = || {
+                            if cancel.is_cancelled() {
+                                Some(Cow::Borrowed("evaluation cancelled"))
+                            } else {
+                                timeout.filter(|x| start.elapsed() >= *x).map(|x| {
+                                    Cow::Owned(format!(
+                                        "evaluation timed out after {:.1}s",
+                                        x.as_secs_f64()
+                                    ))
+                                })
+                            }
+                        };
+                        compile::exec_in_worker(program, &lib_file, &fn_name, interrupt, wtr)
+                            .map(|(kserd, type_name)| (kserd, type_name, None))
+                    }
+                } else if persist {
+                    let r = obtain_brw_data();
+                    let app_data: &D = r.borrow();
+                    let stmts = &self.mods_map[&self.current_mod].stmts;
+                    let store = self
+                        .stores
+                        .as_mut()
+                        .expect("persisting so should exist")
+                        .sync(env, &self.current_mod, stmts);
+                    compile::exec_persistent(&lib_file, &fn_name, &mut store.values, app_data, wtr)
+                        .map(|(kserd, type_name, lib)| {
+                            (kserd, type_name, store.record(stmts, lib).map(Box::new))
+                        })
+                } else if self.linking.mutable {
                     let mut r = obtain_mut_data();
                     let app_data: &mut D = r.borrow_mut();
-                    compile::exec(&lib_file, &fn_name, app_data)
+                    compile::exec(&lib_file, &fn_name, app_data, wtr)
+                        .map(|(kserd, type_name, lib)| (kserd, type_name, Some(Box::new(lib))))
                 } else {
                     let r = obtain_brw_data();
                     let app_data: &D = r.borrow();
-                    compile::exec(&lib_file, &fn_name, app_data)
+                    compile::exec(&lib_file, &fn_name, app_data, wtr)
+                        .map(|(kserd, type_name, lib)| (kserd, type_name, Some(Box::new(lib))))
                 }
             };
             match exec_res {
-                Ok((kserd, lib)) => {
+                Ok((kserd, type_name, lib)) => {
                     // store vec, maybe
-                    add_to_limit_vec(
-                        &mut self.loadedlibs,
-                        Box::new(lib),
-                        self.loaded_libs_size_limit,
-                    );
+                    // libraries which persisted values are kept by the store
+                    if let Some(lib) = lib {
+                        add_to_limit_vec(&mut self.loadedlibs, lib, self.loaded_libs_size_limit);
+                    }
 
                     if self.linking.mutable {
                         maybe_pop_input(self); // don't save mutating inputs
                         EvalOutput::Print(Cow::Owned(format!("finished mutating block: {}", kserd)))
                     // don't print as `out#`
                     } else {
-                        EvalOutput::Data(kserd)
+                        EvalOutput::Data(kserd, type_name)
                     }
                 }
                 Err(e) => {
+                    // render against the code as it was compiled, before rolling back the input
+                    self.linking.persistent = persist;
+                    let e = e.render(&self.mods_map, &self.linking, &self.static_files);
+                    self.linking.persistent = false;
                     maybe_pop_input(self); // failed so don't save
-                    EvalOutput::Print(Cow::Borrowed(e))
+                    EvalOutput::Print(Cow::Owned(e))
                 }
             }
         } else {
@@ -380,11 +464,120 @@ impl<D> ReplData<D> {
         }
     }
 
+    /// Evaluates `expr` and returns its type name, the input is not kept.
+    ///
+    /// Returns the error message if `expr` fails to evaluate.
+    fn type_of<F, R>(
+        &mut self,
+        expr: &str,
+        writer: &mut Output<output::Write>,
+        obtain_mut_app_data: F,
+    ) -> Cow<'static, str>
+    where
+        F: FnOnce() -> R,
+        R: DerefMut<Target = D>,
+    {
+        use crate::input::{parse_program, InputResult};
+
+        let input = match parse_program(expr) {
+            InputResult::Program(input)
+                if input.items.is_empty()
+                    && input.crates.is_empty()
+                    && input.mods.is_empty()
+                    && input.stmts.last().map(|x| !x.semi).unwrap_or(false) =>
+            {
+                input
+            }
+            InputResult::InputError(e) => return Cow::Owned(e),
+            _ => return Cow::Borrowed("type expects an expression"),
+        };
+
+        // evaluate as a new input, not an edit or mutating block
+        let editing = self.editing.take();
+        let mutable = std::mem::replace(&mut self.linking.mutable, false);
+
+        // only one of the app data functions is called
+        let obtain = std::cell::Cell::new(Some(obtain_mut_app_data));
+        let obtain = || obtain.take().expect("app data obtained once")();
+        let r = match self.handle_program(input, writer, obtain, obtain) {
+            EvalOutput::Data(_, type_name) => {
+                self.get_current_file_mut().stmts.pop();
+                Cow::Owned(type_name)
+            }
+            EvalOutput::Print(e) => e, // failed inputs are not kept
+        };
+
+        self.editing = editing;
+        self.linking.mutable = mutable;
+
+        r
+    }
+
+    /// Build the compile directory and compile it, with or without a persistent store.
+    ///
+    /// The linking configuration is left with `persistent` set to `persistent`.
+    /// Builds and compiles the compile directory. The error flags whether compilation failed
+    /// because of the code persisting statement values.
+    fn build_and_compile(
+        &mut self,
+        persistent: bool,
+        writer: &mut Output<output::Write>,
+    ) -> Result<PathBuf, (Cow<'static, str>, bool)> {
+        self.linking.persistent = persistent;
+
+        // build directory
+        compile::build_compile_dir(
+            &self.compilation_dir,
+            &self.mods_map,
+            &self.linking,
+            &self.static_files,
+        )
+        .map_err(|e| {
+            (
+                Cow::Owned(format!("failed to build compile directory: {}", e)),
+                false,
+            )
+        })?;
+
+        // compile
+        let cancel = &self.cancel;
+        let progress = &self.compile_progress;
+        let lib_file = self.compiler.compile(
+            &self.compilation_dir,
+            &self.linking,
+            &mut || cancel.is_cancelled(),
+            &mut |line| {
+                if let Some(tx) = progress {
+                    tx.try_send(line.to_string()).ok();
+                }
+                writer.erase_last_line();
+                writer.write_str(line);
+            },
+        );
+
+        writer.erase_last_line();
+
+        lib_file.map_err(|e| match e {
+            compile::CompilationError::Diagnostics(diagnostics) => {
+                let rendered: Vec<_> = diagnostics
+                    .iter()
+                    .map(|x| x.render(&self.mods_map, &self.linking, &self.static_files))
+                    .collect();
+                let persistence = diagnostics.iter().any(|x| {
+                    x.blames_persistence(&self.mods_map, &self.linking, &self.static_files)
+                });
+                (Cow::Owned(rendered.join("\n")), persistence)
+            }
+            e => (Cow::Owned(format!("{}", e)), false),
+        })
+    }
+
     fn insert_input(&mut self, input: Input, stmt_idx: usize, item_idx: usize, crate_idx: usize) {
         let Input {
             items,
             crates,
             stmts,
+            ..
         } = input;
 
         let src = self.get_current_file_mut();
@@ -402,6 +595,37 @@ impl<D> ReplData<D> {
         }
     }
 
+    /// Inserts inline modules relative to the current module, creating them if necessary.
+    ///
+    /// Returns the touched modules in order, with the previous item and crate count and
+    /// visibility, or `None` if the module was created.
+    fn insert_mods(&mut self, mods: Vec<(String, String, Input)>) -> Vec<ModUndo> {
+        let mut undo = Vec::new();
+        let parent = self.current_mod.clone();
+        code::insert_mods(&mut self.mods_map, &parent, mods, &mut |path, prev| {
+            let prev = prev.map(|x| (x.items.len(), x.crates.len(), x.vis.clone()));
+            undo.push((path.to_path_buf(), prev))
+        });
+        undo
+    }
+
+    fn remove_mods(&mut self, undo: &[ModUndo]) {
+        for (path, prev) in undo.iter().rev() {
+            match prev {
+                Some((nitems, ncrates, vis)) => {
+                    if let Some(src) = self.mods_map.get_mut(path) {
+                        src.items.truncate(*nitems);
+                        src.crates.truncate(*ncrates);
+                        src.vis = vis.clone();
+                    }
+                }
+                None => {
+                    self.mods_map.remove(path);
+                }
+            }
+        }
+    }
+
     fn get_current_file_mut(&mut self) -> &mut SourceCode {
         let cmod = &self.current_mod;
         self.mods_map
@@ -410,6 +634,10 @@ impl<D> ReplData<D> {
     }
 }
 
+/// A module path with the previous `(items, crates)` lengths and visibility, `None` if it did not
+/// exist.
+type ModUndo = (PathBuf, Option<(usize, usize, String)>);
+
 fn add_to_limit_vec<T>(store: &mut VecDeque<T>, item: T, limit: usize) {
     match (limit, store.len()) {
         (0, 0) => (),             // do nothing, lib will drop after this
@@ -444,3 +672,52 @@ fn vec_limited_testing() {
     add_to_limit_vec(&mut vec, 2, 1);
     assert_eq!(&vec, &[2]);
 }
+
+#[test]
+fn insert_and_remove_mods_test() {
+    let mut data: ReplData<()> = ReplData::default();
+    let input = |items: Vec<&str>, mods| Input {
+        items: items.into_iter().map(|x| (x.to_string(), false)).collect(),
+        stmts: vec![],
+        crates: vec![],
+        mods,
+    };
+
+    let undo = data.insert_mods(vec![(
+        String::new(),
+        "a".to_string(),
+        input(
+            vec!["fn b() {}"],
+            vec![("pub".to_string(), "c".to_string(), input(vec![], vec![]))],
+        ),
+    )]);
+    assert_eq!(data.mods_map.get(Path::new("a")).unwrap().items.len(), 1);
+    assert_eq!(data.mods_map.get(Path::new("a")).unwrap().vis, "");
+    assert_eq!(data.mods_map.get(Path::new("a/c")).unwrap().vis, "pub");
+
+    // existing modules are appended to
+    let undo2 = data.insert_mods(vec![(
+        "pub(crate)".to_string(),
+        "a".to_string(),
+        input(vec!["fn d() {}"], vec![]),
+    )]);
+    assert_eq!(data.mods_map.get(Path::new("a")).unwrap().items.len(), 2);
+    assert_eq!(data.mods_map.get(Path::new("a")).unwrap().vis, "pub(crate)");
+
+    data.remove_mods(&undo2);
+    assert_eq!(data.mods_map.get(Path::new("a")).unwrap().items.len(), 1);
+    assert_eq!(data.mods_map.get(Path::new("a")).unwrap().vis, "");
+
+    data.remove_mods(&undo);
+    assert!(!data.mods_map.contains_key(Path::new("a")));
+    assert!(!data.mods_map.contains_key(Path::new("a/c")));
+
+    // nested relative to current module
+    crate::cmds::switch_module(&mut data, Path::new("x"));
+    data.insert_mods(vec![(
+        String::new(),
+        "y".to_string(),
+        input(vec![], vec![]),
+    )]);
+    assert!(data.mods_map.contains_key(Path::new("x/y")));
+}
@@ -6,14 +6,20 @@ mod data;
 mod eval;
 mod print;
 mod read;
+mod session;
+mod store;
 
+pub(crate) use self::print::{fmt_output, kserd_to_json};
+pub use self::session::{SessionError, SESSION_VERSION};
 use crate::{
     cmds::CommandResult,
     code::{ModsMap, StaticFile, StaticFiles},
+    compile::{CargoBackend, CompilerBackend, ExecutionBackend},
     input::InputResult,
     linking::{self, LinkingConfiguration},
     output::{self, Output},
 };
+
 use cmdtree::*;
 use colored::*;
 use crossbeam_channel::Receiver;
@@ -24,6 +30,11 @@ use std::{
     fmt, fs, io,
     marker::PhantomData,
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
 /// The repl structure. Stored as a state machine.
@@ -99,6 +110,29 @@ pub struct ReplData<Data> {
     /// The default is to keep the size limit at zero, thus ensuring no libraries are kept in
     /// memory. This is recommended unless issues are arising from esoteric use cases.
     pub loaded_libs_size_limit: usize,
+
+    /// Persisted statement values, if evaluating with a persistent store.
+    stores: Option<store::Stores>,
+
+    /// How the compile directory is compiled.
+    compiler: Box<dyn CompilerBackend>,
+
+    /// How compiled code is executed.
+    execution: ExecutionBackend,
+
+    /// Flags the current evaluation to be cancelled.
+    cancel: CancelHandle,
+    /// Maximum time the evaluated code can run for.
+    eval_timeout: Option<Duration>,
+
+    /// Capture stdout and stderr of evaluated code into the output.
+    capture_output: bool,
+
+    /// Show the type name of outputs when printing.
+    show_types: bool,
+
+    /// Compiler progress lines are sent here, if listening.
+    compile_progress: Option<crossbeam_channel::Sender<String>>,
 }
 
 /// Repl read state.
@@ -117,6 +151,38 @@ pub struct Evaluate {
 /// Repl evaluating state. This can be constructed via a `eval_async` call.
 pub struct Evaluating<D> {
     jh: Receiver<EvalResult<D>>,
+    cancel: CancelHandle,
+}
+
+/// A handle to cancel an evaluation.
+///
+/// Cancelling kills the `cargo` process if compiling, or the worker process if executing with
+/// [`ExecutionBackend::Worker`]. Code executing in-process cannot be stopped. The cancelled input is
+/// rolled back.
+///
+/// Obtain a handle with [`ReplData::cancel_handle`] or use [`Evaluating::cancel`].
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+    /// Flag the evaluation to be cancelled.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// The evaluation has been flagged to be cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// The flag which is set when cancelled. Can be used to set the flag from a signal handler.
+    pub fn flag(&self) -> &Arc<AtomicBool> {
+        &self.0
+    }
+
+    fn reset(&self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
 }
 
 /// Repl print state.
@@ -129,11 +195,14 @@ pub struct Print {
 /// Was the eval something that produces data??
 #[derive(Debug)]
 enum EvalOutput {
-    /// If there is data, then it should be prefixed with `[out#]`.
-    Data(Kserd<'static>),
+    /// If there is data, then it should be prefixed with `[out#]`. Holds the type name as well.
+    Data(Kserd<'static>, String),
     Print(Cow<'static, str>),
 }
 
+/// A printed output, as the statement index (the `#` of `out#`), the data, and its type name.
+pub type PrintedOutput = (usize, Kserd<'static>, String);
+
 /// Represents an evaluating result. Signal should be checked and handled.
 pub struct EvalResult<D> {
     /// The repl, in print ready state.
@@ -193,7 +262,7 @@ pub enum Editing {
 }
 
 /// `$HOME/.papyrus`
-fn default_compile_dir() -> PathBuf {
+pub(crate) fn default_compile_dir() -> PathBuf {
     dirs::home_dir().unwrap_or_default().join(".papyrus/")
 }
 
@@ -5,17 +5,20 @@ use ::kserd::fmt::FormattingConfig;
 impl<D> Repl<Print, D> {
     /// Prints the result if successful as `[out#]` or the failure message if any.
     /// Uses the default formatter for the `Kserd` data.
-    pub fn print(self) -> (Repl<Read, D>, Option<(usize, Kserd<'static>)>) {
+    pub fn print(self) -> (Repl<Read, D>, Option<PrintedOutput>) {
         self.print_with_formatting(FormattingConfig::default())
     }
 
     /// Prints the result if successful as `[out#]` or the failure message if any.
     /// Uses the given formatting configuration for the `Kserd` data.
-    /// The return is (<repl in read state>, <maybe <stmt index, data>>)
+    /// The return is (<repl in read state>, <maybe <stmt index, data, type name>>)
+    ///
+    /// The type name is printed as `[out#]: type = data` if
+    /// [`show_types`](ReplData::with_show_types) is set.
     pub fn print_with_formatting(
         self,
         config: FormattingConfig,
-    ) -> (Repl<Read, D>, Option<(usize, Kserd<'static>)>) {
+    ) -> (Repl<Read, D>, Option<PrintedOutput>) {
         let Repl {
             state,
             data,
@@ -30,21 +33,28 @@ impl<D> Repl<Print, D> {
         let mut kserd = None;
 
         match data {
-            EvalOutput::Data(k) => {
+            EvalOutput::Data(k, type_name) => {
                 let num = repl_data.current_src().stmts.len().saturating_sub(1);
 
                 let out_stmt = format!("[out{}]", num);
 
+                let type_str = if repl_data.show_types {
+                    format!("{} = ", type_name)
+                } else {
+                    String::new()
+                };
+
                 let line = format!(
-                    "{} {}: {}",
+                    "{} {}: {}{}",
                     repl_data.cmdtree.path().color(repl_data.prompt_colour),
                     out_stmt.color(repl_data.out_colour),
-                    k.as_str_with_config(config)
+                    type_str,
+                    fmt_output(&k, config)
                 );
 
                 output.write_line(&line);
 
-                kserd = Some((num, k));
+                kserd = Some((num, k, type_name));
             }
             EvalOutput::Print(print) => {
                 if print.len() > 0 {
@@ -67,6 +77,68 @@ impl<D> Repl<Print, D> {
 
         (r, kserd)
     }
+
+    /// The message that will be printed if the evaluation did not produce data, such as a
+    /// command's output or an error. `None` if empty.
+    pub(crate) fn message(&self) -> Option<&str> {
+        match &self.state.data {
+            EvalOutput::Print(print) if !print.is_empty() => Some(print),
+            _ => None,
+        }
+    }
+
+    /// The message of a failed evaluation, such as a compilation error.
+    ///
+    /// Successful evaluations only print a message for commands and finished mutable blocks.
+    pub(crate) fn error(&self, input_was_cmd: bool) -> Option<&str> {
+        self.message()
+            .filter(|msg| !input_was_cmd && !msg.starts_with("finished mutating block"))
+    }
+}
+
+/// Formats an output, showing the text of outputs rendered through `Debug` or `Display` as is.
+pub(crate) fn fmt_output(k: &Kserd, config: FormattingConfig) -> String {
+    use crate::code::{DEBUG_ID, DISPLAY_ID, TYPE_NAME_ID};
+
+    match (k.id(), k.str()) {
+        (Some(DEBUG_ID), Some(s)) | (Some(DISPLAY_ID), Some(s)) => s.to_string(),
+        (Some(TYPE_NAME_ID), Some(s)) => format!("<{}>", s),
+        _ => k.as_str_with_config(config),
+    }
+}
+
+/// Converts the data into its natural JSON representation.
+///
+/// Maps are represented as an array of `[key, value]` pairs, as keys may not be strings.
+pub(crate) fn kserd_to_json(kserd: &Kserd) -> serde_json::Value {
+    use ::kserd::{Number, Value};
+    use serde_json::{json, Value as Json};
+    use std::convert::TryFrom;
+
+    match &kserd.val {
+        Value::Unit => Json::Null,
+        Value::Bool(x) => json!(x),
+        Value::Num(Number::Uint(x)) if *x <= u128::from(u64::MAX) => json!(*x as u64),
+        Value::Num(Number::Int(x)) if i64::try_from(*x).is_ok() => json!(*x as i64),
+        Value::Num(Number::Float(x)) => serde_json::Number::from_f64(*x)
+            .map(Json::Number)
+            .unwrap_or_else(|| json!(x.to_string())),
+        Value::Num(Number::Uint(x)) => json!(x.to_string()),
+        Value::Num(Number::Int(x)) => json!(x.to_string()),
+        Value::Str(x) => json!(x.as_str()),
+        Value::Barr(x) => json!(x.as_bytes()),
+        Value::Tuple(x) | Value::Seq(x) => Json::Array(x.iter().map(kserd_to_json).collect()),
+        Value::Cntr(x) => Json::Object(
+            x.iter()
+                .map(|(k, v)| (k.as_str().to_string(), kserd_to_json(v)))
+                .collect(),
+        ),
+        Value::Map(x) => Json::Array(
+            x.iter()
+                .map(|(k, v)| json!([kserd_to_json(k), kserd_to_json(v)]))
+                .collect(),
+        ),
+    }
 }
 
 fn prepare_read<D>(repl: &mut Repl<Read, D>) {
@@ -83,3 +155,34 @@ fn prepare_read<D>(repl: &mut Repl<Read, D>) {
     });
     repl.data.editing_src = editing_src;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn kserd_to_json_test() {
+        use std::collections::BTreeMap;
+
+        assert_eq!(kserd_to_json(&Kserd::new_unit()), serde_json::Value::Null);
+        assert_eq!(kserd_to_json(&Kserd::new_num(-4)), json!(-4));
+        assert_eq!(kserd_to_json(&Kserd::new_num(1.5)), json!(1.5));
+        assert_eq!(
+            kserd_to_json(&Kserd::new_num(u128::MAX)),
+            json!(u128::MAX.to_string())
+        );
+        assert_eq!(kserd_to_json(&Kserd::new_str("a")), json!("a"));
+        assert_eq!(
+            kserd_to_json(&Kserd::new(::kserd::Value::Seq(vec![Kserd::new_bool(
+                true
+            )]))),
+            json!([true])
+        );
+        let cntr = Kserd::new_cntr(vec![("a", Kserd::new_num(1))]).unwrap();
+        assert_eq!(kserd_to_json(&cntr), json!({ "a": 1 }));
+        let mut map = BTreeMap::new();
+        map.insert(Kserd::new_num(1), Kserd::new_str("one"));
+        assert_eq!(kserd_to_json(&Kserd::new_map(map)), json!([[1, "one"]]));
+    }
+}
@@ -44,6 +44,24 @@ impl<D> Repl<Read, D> {
         self.state.output.input_buf_line()
     }
 
+    /// Whether the current input buffer is incomplete and [`read`] would require `More` input,
+    /// such as if a block `{` was started and not closed out.
+    ///
+    /// This can be used to keep editing a multi-line input as a whole, using [`line_input`] to
+    /// overwrite the block, rather than stacking lines through [`read`].
+    ///
+    /// [`read`]: Repl::read
+    /// [`line_input`]: Repl::line_input
+    pub fn more_input_required(&self) -> bool {
+        let treat_as_cmd = !self.data.cmdtree.at_root();
+
+        crate::input::determine_result(
+            self.state.output.input_buffer(),
+            self.state.output.input_buf_line(),
+            treat_as_cmd,
+        ) == InputResult::More
+    }
+
     /// Read the current contents of the input buffer.
     /// This may move the repl into an evaluating state.
     pub fn read(mut self) -> ReadResult<D> {
@@ -72,6 +90,21 @@ impl<D> Repl<Read, D> {
         }
     }
 
+    /// Switch to the module at `path`, such as `a/b`, creating it if necessary. This is the same
+    /// as the `:mod switch` command.
+    ///
+    /// Returns false if `path` is not a valid module path.
+    pub fn switch_module(&mut self, path: &str) -> bool {
+        match crate::cmds::make_path(path) {
+            Some(path) => {
+                crate::cmds::switch_module(&mut self.data, &path);
+                self.draw_prompt();
+                true
+            }
+            None => false,
+        }
+    }
+
     pub(super) fn draw_prompt(&mut self) {
         self.state.output.set_prompt_and_trigger(&self.prompt(true));
     }
@@ -131,4 +164,38 @@ mod tests {
         repl.line_input("");
         assert_eq!(repl.input_buffer(), "{\n");
     }
+
+    #[test]
+    fn test_more_input_required() {
+        let mut repl = repl!();
+
+        repl.line_input("fn a() {");
+        assert!(repl.more_input_required());
+
+        // the whole block can be overwritten through line_input
+        repl.line_input("fn a() {\n    1\n");
+        assert!(repl.more_input_required());
+        assert_eq!(repl.input_buffer(), "fn a() {\n    1\n");
+
+        repl.line_input("fn a() {\n    1\n}");
+        assert!(!repl.more_input_required());
+        assert_eq!(repl.input_buffer(), "fn a() {\n    1\n}");
+        assert_eq!(repl.input_buffer_line(), "fn a() {\n    1\n}");
+
+        repl.line_input(":help");
+        assert!(!repl.more_input_required());
+    }
+
+    #[test]
+    fn switch_module_test() {
+        let mut repl = repl!();
+
+        assert!(repl.switch_module("a/b"));
+        assert_eq!(repl.data.current_mod(), std::path::Path::new("a/b"));
+        assert!(repl.data.mods_map().contains_key(std::path::Path::new("a")));
+        assert!(repl.prompt(false).starts_with("[a/b]"));
+
+        assert!(!repl.switch_module(" "));
+        assert_eq!(repl.data.current_mod(), std::path::Path::new("a/b"));
+    }
 }
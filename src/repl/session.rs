@@ -0,0 +1,611 @@
+//! Saving and loading of REPL sessions.
+//!
+//! A session is stored as a line based text format. The first line is the format header
+//! `papyrus-session VERSION`, followed by a line per entry. Entries which hold code end with the
+//! number of lines of code which follow the entry line, such that multi-line code is stored
+//! verbatim.
+//!
+//! ```text
+//! papyrus-session 1
+//! current-mod lib
+//! persistent-module-code 1
+//! use std::io;
+//! extern - 1
+//! /path/to/libsome_lib.rlib
+//! static-file 2
+//! foo.rs
+//! pub fn foo() {}
+//! mod lib
+//! crate 1
+//! extern crate rand;
+//! item false 1
+//! fn a() -> u8 { 1 }
+//! group
+//! stmt true 1
+//! let a = 1
+//! stmt false 1
+//! a
+//! mod a
+//! vis 1
+//! pub
+//! ```
+//!
+//! The line count of a `static-file` entry includes the file's path, which is stored on the line
+//! after the entry such that paths may contain spaces.
+//!
+//! A `vis` entry is only written for modules declared with a visibility, such as `pub mod a {}`.
+use super::*;
+use crate::code::{
+    validate_static_file_path, AddingStaticFileError, CrateType, SourceCode, Statement, StmtGrp,
+};
+use std::{error, str::Split};
+
+/// The current version of the session format.
+pub const SESSION_VERSION: u32 = 1;
+
+const HEADER: &str = "papyrus-session";
+
+/// Errors around saving or loading a session.
+#[derive(Debug)]
+pub enum SessionError {
+    /// An io error occurred.
+    Io(io::Error),
+    /// The session file is malformed at the line number.
+    Parse(usize, String),
+    /// The session file format version is not supported.
+    UnsupportedVersion(u32),
+    /// An external library could not be linked.
+    Extern(PathBuf, io::Error),
+    /// A static file could not be added.
+    StaticFile(PathBuf, AddingStaticFileError),
+}
+
+impl error::Error for SessionError {}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SessionError::Io(e) => write!(f, "an io error occurred: {}", e),
+            SessionError::Parse(line, msg) => {
+                write!(f, "malformed session file at line {}: {}", line, msg)
+            }
+            SessionError::UnsupportedVersion(v) => write!(
+                f,
+                "session format version {} is not supported, expecting version {} or lower",
+                v, SESSION_VERSION
+            ),
+            SessionError::Extern(p, e) => {
+                write!(
+                    f,
+                    "failed to link external library `{}`: {}",
+                    p.display(),
+                    e
+                )
+            }
+            SessionError::StaticFile(p, e) => {
+                write!(f, "failed to add static file `{}`: {}", p.display(), e)
+            }
+        }
+    }
+}
+
+impl From<io::Error> for SessionError {
+    fn from(e: io::Error) -> Self {
+        SessionError::Io(e)
+    }
+}
+
+/// The parsed contents of a session file.
+struct Session {
+    current_mod: PathBuf,
+    persistent_module_code: String,
+    externs: Vec<(PathBuf, Option<String>)>,
+    static_files: Vec<(PathBuf, String)>,
+    mods_map: ModsMap,
+}
+
+impl<Data> ReplData<Data> {
+    /// Save the session to `path`.
+    ///
+    /// The session consists of the modules' source code, the current module, the static files, the
+    /// external libraries, and the [persistent module code](ReplData::persistent_module_code).
+    /// The session is written in a versioned text format, see [`SESSION_VERSION`].
+    pub fn save_session<P: AsRef<Path>>(&self, path: P) -> Result<(), SessionError> {
+        let mut buf = format!("{} {}\n", HEADER, SESSION_VERSION);
+
+        write_line(&mut buf, "current-mod", &[&path_str(&self.current_mod)]);
+        write_block(
+            &mut buf,
+            "persistent-module-code",
+            &[],
+            &self.linking.persistent_module_code,
+        );
+
+        let mut externs: Vec<_> = self.linking.external_libs.iter().collect();
+        externs.sort_by(|a, b| a.lib_path().cmp(b.lib_path()));
+        for lib in externs {
+            write_block(
+                &mut buf,
+                "extern",
+                &[lib.alias().unwrap_or("-")],
+                &lib.lib_path().display().to_string(),
+            );
+        }
+
+        for (path, code) in self.static_files_code()? {
+            write_static_file(&mut buf, &path, &code);
+        }
+
+        for (path, src) in &self.mods_map {
+            write_line(&mut buf, "mod", &[&path_str(path)]);
+            if !src.vis.is_empty() {
+                write_block(&mut buf, "vis", &[], &src.vis);
+            }
+            for c in &src.crates {
+                write_block(&mut buf, "crate", &[], &c.src_line);
+            }
+            for (item, top) in &src.items {
+                write_block(&mut buf, "item", &[&top.to_string()], item);
+            }
+            for grp in &src.stmts {
+                write_line(&mut buf, "group", &[]);
+                for stmt in &grp.0 {
+                    write_block(&mut buf, "stmt", &[&stmt.semi.to_string()], &stmt.expr);
+                }
+            }
+        }
+
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, buf)?;
+
+        Ok(())
+    }
+
+    /// Load a session from `path`, replacing the current session.
+    ///
+    /// The file is parsed and the external libraries and static file paths validated before any
+    /// changes are made. If a static file fails to be written, the previous static files are
+    /// restored. Loading a session clears any editing flag.
+    ///
+    /// > External libraries are added to the linking configuration, a library linked by the
+    /// > application will not be removed.
+    pub fn load_session<P: AsRef<Path>>(&mut self, path: P) -> Result<(), SessionError> {
+        let session = parse_session(&fs::read_to_string(path)?)?;
+
+        let mut externs = Vec::with_capacity(session.externs.len());
+        for (path, alias) in session.externs {
+            let lib = match alias {
+                Some(alias) => linking::Extern::with_alias(&path, alias),
+                None => linking::Extern::new(&path),
+            };
+            externs.push(lib.map_err(|e| SessionError::Extern(path, e))?);
+        }
+
+        for (path, _) in &session.static_files {
+            validate_static_file_path(path).map_err(|e| {
+                SessionError::StaticFile(path.clone(), AddingStaticFileError::InvalidPath(e))
+            })?;
+        }
+
+        let previous = self.static_files_code()?;
+        if let Err(e) = self.replace_static_files(session.static_files) {
+            self.replace_static_files(previous).ok();
+            return Err(e);
+        }
+
+        self.linking.external_libs.extend(externs);
+        self.linking.persistent_module_code = session.persistent_module_code;
+        self.mods_map = session.mods_map;
+        self.current_mod = session.current_mod;
+        self.editing = None;
+        self.editing_src = None;
+
+        Ok(())
+    }
+
+    /// The path and code of each static file, with the crate references at the top of the code.
+    fn static_files_code(&self) -> io::Result<Vec<(PathBuf, String)>> {
+        self.static_files
+            .iter()
+            .map(|sf| {
+                let mut code: String = sf.crates.iter().map(|c| c.src_line.as_str()).collect();
+                code.push_str(&fs::read_to_string(self.static_file_name(&sf.path))?);
+                Ok((sf.path.clone(), code))
+            })
+            .collect()
+    }
+
+    fn replace_static_files(&mut self, files: Vec<(PathBuf, String)>) -> Result<(), SessionError> {
+        let existing: Vec<_> = self.static_files.iter().map(|x| x.path.clone()).collect();
+        for path in existing {
+            self.remove_static_file(path);
+        }
+        for (path, code) in files {
+            self.add_static_file(path.clone(), &code)
+                .map_err(|e| SessionError::StaticFile(path, e))?;
+        }
+        Ok(())
+    }
+}
+
+fn path_str(path: &Path) -> String {
+    // store with forward slashes so sessions are portable
+    path.iter()
+        .map(|x| x.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn write_line(buf: &mut String, tag: &str, args: &[&str]) {
+    buf.push_str(tag);
+    for arg in args {
+        buf.push(' ');
+        buf.push_str(arg);
+    }
+    buf.push('\n');
+}
+
+fn write_block(buf: &mut String, tag: &str, args: &[&str], content: &str) {
+    let lines = content.split('\n').count().to_string();
+    let mut a = args.to_vec();
+    a.push(&lines);
+    write_line(buf, tag, &a);
+    buf.push_str(content);
+    buf.push('\n');
+}
+
+fn write_static_file(buf: &mut String, path: &Path, code: &str) {
+    write_block(
+        buf,
+        "static-file",
+        &[],
+        &format!("{}\n{}", path_str(path), code),
+    );
+}
+
+struct Reader<'a> {
+    lines: Split<'a, char>,
+    line: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn next(&mut self) -> Option<&'a str> {
+        self.line += 1;
+        self.lines.next()
+    }
+
+    fn err<T, S: Into<String>>(&self, msg: S) -> Result<T, SessionError> {
+        Err(SessionError::Parse(self.line, msg.into()))
+    }
+
+    /// Reads `count` lines, joining them back into a single string.
+    fn block(&mut self, count: &str) -> Result<String, SessionError> {
+        let count: usize = match count.parse() {
+            Ok(x) => x,
+            Err(_) => return self.err(format!("expecting a line count, found `{}`", count)),
+        };
+        let mut s = String::new();
+        for i in 0..count {
+            if i > 0 {
+                s.push('\n');
+            }
+            match self.next() {
+                Some(line) => s.push_str(line),
+                None => return self.err("unexpected end of file"),
+            }
+        }
+        Ok(s)
+    }
+
+    fn bool(&self, s: &str) -> Result<bool, SessionError> {
+        s.parse()
+            .or_else(|_| self.err(format!("expecting `true` or `false`, found `{}`", s)))
+    }
+}
+
+fn parse_session(s: &str) -> Result<Session, SessionError> {
+    let mut rdr = Reader {
+        lines: s.split('\n'),
+        line: 0,
+    };
+
+    let version = match rdr.next().map(|x| x.split(' ').collect::<Vec<_>>()) {
+        Some(v) if v.len() == 2 && v[0] == HEADER => match v[1].parse::<u32>() {
+            Ok(x) => x,
+            Err(_) => return rdr.err("invalid version number"),
+        },
+        _ => return rdr.err(format!("expecting `{} VERSION` header", HEADER)),
+    };
+    if version > SESSION_VERSION {
+        return Err(SessionError::UnsupportedVersion(version));
+    }
+
+    let mut session = Session {
+        current_mod: PathBuf::from("lib"),
+        persistent_module_code: String::new(),
+        externs: Vec::new(),
+        static_files: Vec::new(),
+        mods_map: ModsMap::new(),
+    };
+    let mut src: Option<&mut SourceCode> = None;
+    let mut current_mod_line = 0;
+
+    while let Some(line) = rdr.next() {
+        let words: Vec<_> = line.split(' ').collect();
+        match words.as_slice() {
+            [""] => (),
+            ["current-mod", path] => {
+                session.current_mod = PathBuf::from(path);
+                current_mod_line = rdr.line;
+            }
+            ["persistent-module-code", n] => session.persistent_module_code = rdr.block(n)?,
+            ["extern", alias, n] => {
+                let path = PathBuf::from(rdr.block(n)?);
+                let alias = Some(alias.to_string()).filter(|x| x != "-");
+                session.externs.push((path, alias));
+            }
+            ["static-file", n] => match rdr.block(n)?.split_once('\n') {
+                Some((path, code)) => {
+                    session
+                        .static_files
+                        .push((PathBuf::from(path), code.to_string()));
+                }
+                None => return rdr.err("expecting a path followed by the file's code"),
+            },
+            ["mod", path] => {
+                src = Some(session.mods_map.entry(PathBuf::from(path)).or_default());
+            }
+            [tag, ..]
+                if src.is_none() && ["vis", "crate", "item", "group", "stmt"].contains(tag) =>
+            {
+                return rdr.err(format!("`{}` must be inside a `mod`", tag))
+            }
+            ["vis", n] => src.as_mut().unwrap().vis = rdr.block(n)?,
+            ["crate", n] => {
+                let line = rdr.block(n)?;
+                match CrateType::parse_str(&line) {
+                    Ok(c) => src.as_mut().unwrap().crates.push(c),
+                    Err(e) => return rdr.err(e),
+                }
+            }
+            ["item", top, n] => {
+                let top = rdr.bool(top)?;
+                let item = rdr.block(n)?;
+                src.as_mut().unwrap().items.push((item, top));
+            }
+            ["group"] => src.as_mut().unwrap().stmts.push(StmtGrp(Vec::new())),
+            ["stmt", semi, n] => {
+                let semi = rdr.bool(semi)?;
+                let expr = rdr.block(n)?;
+                match src.as_mut().unwrap().stmts.last_mut() {
+                    Some(grp) => grp.0.push(Statement { expr, semi }),
+                    None => return rdr.err("`stmt` must be inside a `group`"),
+                }
+            }
+            _ => return rdr.err(format!("unrecognised entry `{}`", line)),
+        }
+    }
+
+    session.mods_map.entry(PathBuf::from("lib")).or_default();
+    if !session.mods_map.contains_key(&session.current_mod) {
+        return Err(SessionError::Parse(
+            current_mod_line,
+            format!(
+                "current module `{}` does not exist",
+                session.current_mod.display()
+            ),
+        ));
+    }
+
+    Ok(session)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_and_load_session() {
+        let dir = "target/testing/session-test";
+        let file = Path::new(dir).join("test.session");
+
+        let mut data: ReplData<()> = ReplData::default();
+        data.with_compilation_dir(dir).unwrap();
+        data.persistent_module_code().push_str("use std::io;\n");
+        data.add_static_file("foo.rs".into(), "extern crate rand;\npub fn foo() {}")
+            .unwrap();
+        {
+            let src = data.mods_map.get_mut(Path::new("lib")).unwrap();
+            src.crates
+                .push(CrateType::parse_str("extern crate rand;").unwrap());
+            src.items
+                .push(("fn a() -> u8 {\n    1\n}".to_string(), false));
+            src.items.push(("#![allow(unused)]".to_string(), true));
+            src.stmts.push(StmtGrp(vec![
+                Statement {
+                    expr: "let a = \"multi\nline\n\"".to_string(),
+                    semi: true,
+                },
+                Statement {
+                    expr: "a".to_string(),
+                    semi: false,
+                },
+            ]));
+        }
+        crate::cmds::switch_module(&mut data, Path::new("test/inner"));
+        data.mods_map.get_mut(Path::new("test")).unwrap().vis = "pub(crate)".to_string();
+
+        data.save_session(&file).unwrap();
+
+        let mut loaded: ReplData<()> = ReplData::default();
+        loaded
+            .with_compilation_dir("target/testing/session-test-load")
+            .unwrap();
+        loaded.add_static_file("bar.rs".into(), "").unwrap();
+        loaded.load_session(&file).unwrap();
+
+        assert_eq!(loaded.current_mod(), Path::new("test/inner"));
+        assert_eq!(
+            loaded.mods_map().keys().collect::<Vec<_>>(),
+            vec![Path::new("lib"), Path::new("test"), Path::new("test/inner")]
+        );
+        let src = loaded.mods_map().get(Path::new("lib")).unwrap();
+        assert_eq!(
+            src.crates,
+            vec![CrateType::parse_str("extern crate rand;").unwrap()]
+        );
+        assert_eq!(
+            src.items,
+            vec![
+                ("fn a() -> u8 {\n    1\n}".to_string(), false),
+                ("#![allow(unused)]".to_string(), true)
+            ]
+        );
+        assert_eq!(src.stmts.len(), 1);
+        assert_eq!(src.stmts[0].src_line(), "let a = \"multi\nline\n\"; a");
+        assert_eq!(loaded.linking().persistent_module_code, "use std::io;\n");
+        assert_eq!(loaded.mods_map()[Path::new("test")].vis, "pub(crate)");
+        assert_eq!(loaded.mods_map()[Path::new("test/inner")].vis, "");
+
+        let sfs: Vec<_> = loaded.static_files().iter().collect();
+        assert_eq!(sfs.len(), 1, "bar.rs should be removed");
+        assert_eq!(sfs[0].path, Path::new("foo.rs"));
+        assert_eq!(sfs[0].crates.len(), 1);
+        assert_eq!(
+            fs::read_to_string("target/testing/session-test-load/src/foo.rs").unwrap(),
+            "\npub fn foo() {}"
+        );
+
+        // round trips to the same file
+        let file2 = Path::new(dir).join("test2.session");
+        loaded.save_session(&file2).unwrap();
+        assert_eq!(
+            fs::read_to_string(&file).unwrap(),
+            fs::read_to_string(&file2).unwrap()
+        );
+    }
+
+    #[test]
+    fn failed_load_keeps_session() {
+        let dir = Path::new("target/testing/session-failed-load-test");
+        fs::remove_dir_all(dir).ok();
+        let mut data: ReplData<()> = ReplData::default();
+        data.with_compilation_dir(dir).unwrap();
+        data.add_static_file("foo.rs".into(), "pub fn foo() {}")
+            .unwrap();
+        crate::cmds::switch_module(&mut data, Path::new("a"));
+
+        let file = dir.join("test.session");
+        let session = |static_file: &str| {
+            let s = format!(
+                "papyrus-session 1\nstatic-file 2\nbar.rs\npub fn bar() {{}}\nstatic-file 2\n{}\n\nmod lib\n",
+                static_file
+            );
+            fs::write(&file, s).unwrap();
+        };
+        let unchanged = |data: &ReplData<()>| {
+            let sfs: Vec<_> = data.static_files().iter().map(|x| &x.path).collect();
+            assert_eq!(sfs, vec![Path::new("foo.rs")]);
+            assert_eq!(
+                fs::read_to_string(dir.join("src/foo.rs")).unwrap(),
+                "pub fn foo() {}"
+            );
+            assert!(!dir.join("src/bar.rs").exists());
+            assert_eq!(data.current_mod(), Path::new("a"));
+        };
+
+        // invalid paths are found before any changes
+        session("baz.txt");
+        match data.load_session(&file) {
+            Err(SessionError::StaticFile(p, AddingStaticFileError::InvalidPath(_))) => {
+                assert_eq!(p, Path::new("baz.txt"))
+            }
+            x => panic!("expecting invalid path error, found {:?}", x),
+        }
+        unchanged(&data);
+
+        // failing to write a file restores the previous static files
+        fs::write(dir.join("src/q"), "").unwrap();
+        session("q/x.rs");
+        match data.load_session(&file) {
+            Err(SessionError::StaticFile(p, AddingStaticFileError::Io(_))) => {
+                assert_eq!(p, Path::new("q/x.rs"))
+            }
+            x => panic!("expecting io error, found {:?}", x),
+        }
+        unchanged(&data);
+    }
+
+    #[test]
+    fn static_file_path_with_spaces() {
+        let mut buf = format!("{} {}\n", HEADER, SESSION_VERSION);
+        write_static_file(&mut buf, Path::new("a dir/my file.rs"), "fn a() {}\n");
+        let session = parse_session(&buf).unwrap();
+        assert_eq!(
+            session.static_files,
+            vec![(PathBuf::from("a dir/my file.rs"), "fn a() {}\n".to_string())]
+        );
+
+        // the path is validated on load rather than failing to parse
+        let dir = Path::new("target/testing/session-spaced-path-test");
+        fs::remove_dir_all(dir).ok();
+        let mut data: ReplData<()> = ReplData::default();
+        data.with_compilation_dir(dir).unwrap();
+        let file = dir.join("test.session");
+        fs::write(&file, buf).unwrap();
+        match data.load_session(&file) {
+            Err(SessionError::StaticFile(p, AddingStaticFileError::InvalidPath(_))) => {
+                assert_eq!(p, Path::new("a dir/my file.rs"))
+            }
+            x => panic!("expecting invalid path error, found {:?}", x),
+        }
+    }
+
+    #[test]
+    fn parse_session_errors() {
+        let e = |s: &str| parse_session(s).err().unwrap().to_string();
+
+        assert_eq!(
+            e(""),
+            "malformed session file at line 1: expecting `papyrus-session VERSION` header"
+        );
+        assert_eq!(
+            e("papyrus-session 99"),
+            "session format version 99 is not supported, expecting version 1 or lower"
+        );
+        assert_eq!(
+            e("papyrus-session 1\nitem false 1\nfn a() {}"),
+            "malformed session file at line 2: `item` must be inside a `mod`"
+        );
+        assert_eq!(
+            e("papyrus-session 1\nmod lib\nitem false 3\nfn a() {}"),
+            "malformed session file at line 5: unexpected end of file"
+        );
+        assert_eq!(
+            e("papyrus-session 1\nmod lib\ngroup\nstmt maybe 1\na"),
+            "malformed session file at line 4: expecting `true` or `false`, found `maybe`"
+        );
+        assert_eq!(
+            e("papyrus-session 1\nmod lib\nstmt true 1\na"),
+            "malformed session file at line 4: `stmt` must be inside a `group`"
+        );
+        assert_eq!(
+            e("papyrus-session 1\nwhat"),
+            "malformed session file at line 2: unrecognised entry `what`"
+        );
+        assert_eq!(
+            e("papyrus-session 1\nstatic-file 1\nfoo.rs"),
+            "malformed session file at line 3: expecting a path followed by the file's code"
+        );
+        assert_eq!(
+            e("papyrus-session 1\ncurrent-mod foo"),
+            "malformed session file at line 2: current module `foo` does not exist"
+        );
+
+        let session = parse_session("papyrus-session 1\nmod foo\ncurrent-mod foo").unwrap();
+        assert!(session.mods_map.contains_key(Path::new("lib")));
+    }
+}
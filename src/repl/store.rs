@@ -0,0 +1,138 @@
+//! The host-side store of persisted statement values.
+//!
+//! When evaluating with a persistent store, each statement group's bindings are boxed and kept
+//! here. The values can reference code in the library which evaluated them, so those libraries
+//! are kept alive alongside the values.
+use crate::code::StmtGrp;
+use libloading::Library;
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// The stores of each module.
+#[derive(Default)]
+pub(super) struct Stores {
+    /// Hash of the code environment the values were evaluated in.
+    env: u64,
+    mods: BTreeMap<PathBuf, Store>,
+}
+
+/// The persisted values of a module's statement groups.
+#[derive(Default)]
+pub(super) struct Store {
+    /// The bindings of each statement group, in order.
+    pub values: Vec<Box<dyn Any + Send>>,
+    /// The hash of the statement group that produced each value.
+    hashes: Vec<u64>,
+    /// The libraries that evaluated the values.
+    libs: Vec<Library>,
+    /// Set to the number of statement groups when a group could not be persisted.
+    ///
+    /// Statements are replayed until the number of groups drops below this.
+    pub fallback_at: Option<usize>,
+}
+
+impl Stores {
+    /// Get the store for a module, invalidating values that no longer match the code.
+    ///
+    /// If `env` differs from the previous call _all_ stores are cleared. Values of statement groups
+    /// that have changed, and any values after them, are dropped.
+    pub fn sync(&mut self, env: u64, mod_path: &Path, stmts: &[StmtGrp]) -> &mut Store {
+        if env != self.env {
+            self.mods.clear();
+            self.env = env;
+        }
+
+        let store = self.mods.entry(mod_path.to_path_buf()).or_default();
+        let keep = store
+            .hashes
+            .iter()
+            .zip(stmts)
+            .take_while(|(&hash, grp)| hash == grp_hash(grp))
+            .count();
+        store.truncate(keep);
+        if store.fallback_at.map(|x| stmts.len() < x).unwrap_or(false) {
+            store.fallback_at = None;
+        }
+
+        store
+    }
+}
+
+impl Store {
+    /// Record the hashes of newly stored values, keeping `lib` alive if it stored any.
+    ///
+    /// Returns the library if no values were stored.
+    pub fn record(&mut self, stmts: &[StmtGrp], lib: Library) -> Option<Library> {
+        let start = self.hashes.len();
+        let end = self.values.len();
+        if end > start {
+            self.hashes
+                .extend(stmts[start..end.min(stmts.len())].iter().map(grp_hash));
+            self.libs.push(lib);
+            None
+        } else {
+            Some(lib)
+        }
+    }
+
+    fn truncate(&mut self, len: usize) {
+        // later values may reference earlier ones, so drop in reverse
+        while self.values.len() > len {
+            self.values.pop();
+        }
+        self.hashes.truncate(len);
+    }
+}
+
+impl Drop for Store {
+    fn drop(&mut self) {
+        // values must be dropped before the libraries containing their code
+        self.truncate(0);
+        while self.libs.pop().is_some() {}
+    }
+}
+
+fn grp_hash(grp: &StmtGrp) -> u64 {
+    let stmts: Vec<_> = grp.0.iter().map(|x| (&x.expr, x.semi)).collect();
+    fxhash::hash64(&stmts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::code::Statement;
+
+    fn grp(expr: &str) -> StmtGrp {
+        StmtGrp(vec![Statement {
+            expr: expr.to_string(),
+            semi: false,
+        }])
+    }
+
+    #[test]
+    fn sync_test() {
+        let mut stores = Stores::default();
+        let path = Path::new("lib");
+        let stmts = [grp("1"), grp("2"), grp("3")];
+
+        let store = stores.sync(0, path, &stmts);
+        store.values.push(Box::new(1));
+        store.values.push(Box::new(2));
+        store.hashes = stmts[..2].iter().map(grp_hash).collect();
+
+        // unchanged
+        assert_eq!(stores.sync(0, path, &stmts).values.len(), 2);
+        // changed group drops it and those after
+        let changed = [grp("1"), grp("20"), grp("3")];
+        assert_eq!(stores.sync(0, path, &changed).values.len(), 1);
+        // env change clears
+        assert_eq!(stores.sync(1, path, &stmts).values.len(), 0);
+
+        // fallback is reset once groups are removed
+        let store = stores.sync(1, path, &stmts);
+        store.fallback_at = Some(3);
+        assert_eq!(stores.sync(1, path, &stmts).fallback_at, Some(3));
+        assert_eq!(stores.sync(1, path, &stmts[..2]).fallback_at, None);
+    }
+}
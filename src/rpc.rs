@@ -0,0 +1,695 @@
+//! A headless [JSON-RPC 2.0](https://www.jsonrpc.org/specification) server, for embedding papyrus
+//! in editors.
+//!
+//! [`serve`] reads requests as lines of JSON and writes responses and notifications as lines of
+//! JSON. `papyrus --rpc` serves over stdin and stdout.
+//!
+//! ```sh
+//! --> {"jsonrpc": "2.0", "id": 1, "method": "eval", "params": {"code": "2+2"}}
+//! <-- {"jsonrpc": "2.0", "method": "compileProgress", "params": {"line": "Compiling papyrus_mem_code v0.1.0"}}
+//! <-- {"jsonrpc": "2.0", "method": "output", "params": {"change": "currentLine", "line": "[lib] papyrus [out0]: 4"}}
+//! <-- {"jsonrpc": "2.0", "id": 1, "result": {"status": "ok", "out": 0, "value": "4", "json": 4, "type": "i32"}}
+//! ```
+//!
+//! # Methods
+//! | method         | params                             | result |
+//! | -------------- | ---------------------------------- | ------ |
+//! | `eval`         | `{"code": string}`                 | `{"status": "ok" | "error" | "incomplete", "out"?, "value"?, "json"?, "type"?, "message"?, "exit"?}` |
+//! | `complete`     | `{"code": string, "cursor"?: int}` | `{"start": int, "matches": [string]}` |
+//! | `commands`     |                                    | `[{"command": string, "help": string, "kind": "class" | "action"}]` |
+//! | `switchModule` | `{"path": string}`                 | `{"module": string}` |
+//! | `listOutputs`  |                                    | `[{"out": int, "src": string, "value"?: string, "type"?: string}]` |
+//! | `cancel`       |                                    | `true` if an evaluation was cancelled |
+//!
+//! - `eval` evaluates code as a whole. Code missing a final expression, such as `let a = 1;`, is
+//!   finished with `()`. A result has the `out#` number, the `value` as text, its `json`
+//!   representation, and the `type` name. Commands and failures have a `message`. `exit` is set if
+//!   the REPL exited, which stops the server.
+//! - `complete` completes commands or code at `cursor`, a character position which defaults to the
+//!   end of the code. Matches replace the code from the `start` character position.
+//! - `listOutputs` lists the statements of the current module, with the value last printed for each
+//!   statement.
+//! - `cancel` cancels the current evaluation, see [`CancelHandle`](crate::repl::CancelHandle).
+//!
+//! Requests are handled in order, except `cancel` which is handled while evaluating.
+//!
+//! Servers which require a token, such as [`run::serve`](crate::run::serve), expect an
+//! `authenticate` request with `{"token": string}` first, responding `true`. An invalid token is
+//! responded to with an error and the connection is closed.
+//!
+//! # Notifications
+//! - `output`: each [`OutputChange`], `{"change": "currentLine", "line": string}` or
+//!   `{"change": "newLine"}`. The first notification is the current line, the prompt.
+//! - `compileProgress`: each compiler progress line, `{"line": string}`.
+//!
+//! Output of evaluated code is captured into the REPL output on unix, elsewhere it is written to the
+//! process's stdout.
+use crate::complete::{cmdr::TreeCompleter, modules::ModulesCompleter};
+use crate::input;
+use crate::output::{self, OutputChange};
+use crate::repl::{EvalResult, Read, ReadResult, Repl, Signal};
+use crossbeam_channel::{select, Receiver};
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::{self, BufRead, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// How often an evaluation is checked for completion.
+const EVAL_POLL: Duration = Duration::from_millis(20);
+
+/// Invalid JSON was received.
+const PARSE_ERROR: i64 = -32700;
+/// The JSON is not a valid request.
+const INVALID_REQUEST: i64 = -32600;
+/// The method does not exist.
+const METHOD_NOT_FOUND: i64 = -32601;
+/// Invalid method parameters.
+const INVALID_PARAMS: i64 = -32602;
+/// The authentication token is invalid.
+const UNAUTHORIZED: i64 = -32000;
+
+/// Serve JSON-RPC requests read from `input`, writing responses and notifications to `output`.
+///
+/// Returns when `input` ends or the REPL exits. Evaluation is done through
+/// [`eval_async`](Repl::eval_async) with `app_data`.
+pub fn serve<D, R, W>(
+    repl: Repl<Read, D>,
+    app_data: &Arc<Mutex<D>>,
+    input: R,
+    output: W,
+) -> io::Result<()>
+where
+    D: 'static + Send,
+    R: BufRead + Send + 'static,
+    W: Write,
+{
+    let mut repl = repl;
+    let prompt = repl
+        .output()
+        .rsplit('\n')
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    let mut server = Server {
+        wtr: output,
+        output: repl.output_listen(),
+        progress: repl.data.compile_progress_listen(),
+        requests: read_lines(input),
+        pending: VecDeque::new(),
+        input_ended: false,
+        outputs: BTreeMap::new(),
+        #[cfg(feature = "racer-completion")]
+        code_cache: None,
+    };
+    server.notify_output(OutputChange::CurrentLine(prompt))?;
+
+    loop {
+        server.notify_changes()?;
+
+        let line = match server.pending.pop_front() {
+            Some(line) => line,
+            None if server.input_ended => return Ok(()),
+            None => match server.requests.recv() {
+                Ok(line) => line?,
+                Err(_) => return Ok(()),
+            },
+        };
+
+        let req = match Request::parse(&line) {
+            Ok(req) => req,
+            Err(e) => {
+                server.respond(&Value::Null, Err(e))?;
+                continue;
+            }
+        };
+
+        let result = match req.method.as_str() {
+            "eval" => match req.str_param("code") {
+                Ok(code) => {
+                    let (r, result) = server.eval(repl, code, app_data)?;
+                    let exit = result["exit"] == true;
+                    server.notify_changes()?;
+                    server.respond(&req.id, Ok(result))?;
+                    if exit {
+                        return Ok(());
+                    }
+                    repl = r;
+                    continue;
+                }
+                Err(e) => Err(e),
+            },
+            "complete" => req
+                .str_param("code")
+                .map(|code| server.complete(&repl, &code, req.params["cursor"].as_u64())),
+            "commands" => Ok(commands(&repl)),
+            "switchModule" => req.str_param("path").and_then(|path| {
+                if repl.switch_module(&path) {
+                    Ok(json!({ "module": repl.data.current_mod().display().to_string() }))
+                } else {
+                    Err((INVALID_PARAMS, format!("'{}' is not a module path", path)))
+                }
+            }),
+            "listOutputs" => Ok(server.list_outputs(&repl)),
+            // there is no evaluation to cancel
+            "cancel" => Ok(json!(false)),
+            x => Err((METHOD_NOT_FOUND, format!("method '{}' not found", x))),
+        };
+
+        server.notify_changes()?;
+        server.respond(&req.id, result)?;
+    }
+}
+
+/// A parsed request. Notifications have a `null` id and are not responded to.
+struct Request {
+    id: Value,
+    method: String,
+    params: Value,
+}
+
+type RpcResult = Result<Value, (i64, String)>;
+
+impl Request {
+    fn parse(line: &str) -> Result<Self, (i64, String)> {
+        let mut v: Value =
+            serde_json::from_str(line).map_err(|e| (PARSE_ERROR, format!("parse error: {}", e)))?;
+
+        match v["method"].as_str() {
+            Some(method) => Ok(Request {
+                method: method.to_string(),
+                id: v["id"].take(),
+                params: v["params"].take(),
+            }),
+            None => Err((INVALID_REQUEST, "invalid request".to_string())),
+        }
+    }
+
+    fn str_param(&self, name: &str) -> Result<String, (i64, String)> {
+        match self.params[name].as_str() {
+            Some(s) => Ok(s.to_string()),
+            None => Err((
+                INVALID_PARAMS,
+                format!("expecting a string '{}' parameter", name),
+            )),
+        }
+    }
+}
+
+struct Server<W> {
+    wtr: W,
+    output: output::Receiver,
+    progress: Receiver<String>,
+    requests: Receiver<io::Result<String>>,
+    /// Requests received while evaluating.
+    pending: VecDeque<String>,
+    /// The input ended while evaluating.
+    input_ended: bool,
+    /// The last printed value and type of each statement, keyed by module.
+    outputs: BTreeMap<(PathBuf, usize), (String, String)>,
+    #[cfg(feature = "racer-completion")]
+    code_cache: Option<crate::complete::code::CodeCache>,
+}
+
+impl<W: Write> Server<W> {
+    fn write(&mut self, msg: &Value) -> io::Result<()> {
+        writeln!(self.wtr, "{}", msg)?;
+        self.wtr.flush()
+    }
+
+    fn respond(&mut self, id: &Value, result: RpcResult) -> io::Result<()> {
+        // notifications have no id, parse errors have a null id
+        if id.is_null() && !matches!(result, Err((PARSE_ERROR, _)) | Err((INVALID_REQUEST, _))) {
+            return Ok(());
+        }
+
+        self.write(&response(id, result))
+    }
+
+    fn notify(&mut self, method: &str, params: Value) -> io::Result<()> {
+        self.write(&json!({ "jsonrpc": "2.0", "method": method, "params": params }))
+    }
+
+    fn notify_output(&mut self, chg: OutputChange) -> io::Result<()> {
+        let params = match chg {
+            OutputChange::CurrentLine(line) => json!({ "change": "currentLine", "line": line }),
+            OutputChange::NewLine => json!({ "change": "newLine" }),
+        };
+        self.notify("output", params)
+    }
+
+    /// Send notifications for the output changes and progress lines received so far.
+    fn notify_changes(&mut self) -> io::Result<()> {
+        while let Ok(line) = self.progress.try_recv() {
+            self.notify("compileProgress", json!({ "line": line }))?;
+        }
+        while let Ok(chg) = self.output.try_recv() {
+            self.notify_output(chg)?;
+        }
+        Ok(())
+    }
+
+    /// Evaluate `code`, returning the REPL and the result.
+    fn eval<D>(
+        &mut self,
+        mut repl: Repl<Read, D>,
+        mut code: String,
+        app_data: &Arc<Mutex<D>>,
+    ) -> io::Result<(Repl<Read, D>, Value)>
+    where
+        D: 'static + Send,
+    {
+        loop {
+            let treat_as_cmd = !repl.data.cmdtree.at_root();
+            match input::finish_input(&code, treat_as_cmd) {
+                Some(input) => repl.line_input(&input),
+                None => return Ok((repl, json!({ "status": "incomplete" }))),
+            }
+
+            let is_cmd = treat_as_cmd || code.trim_start().starts_with(crate::CMD_PREFIX);
+
+            let eval = match repl.read() {
+                ReadResult::Eval(eval) => eval,
+                ReadResult::Read(_) => unreachable!("input is complete"),
+            };
+
+            let evaluating = eval.eval_async(app_data);
+            while !evaluating.completed() {
+                select! {
+                    recv(self.output) -> chg => if let Ok(chg) = chg {
+                        self.notify_output(chg)?;
+                    },
+                    recv(self.progress) -> line => if let Ok(line) = line {
+                        self.notify("compileProgress", json!({ "line": line }))?;
+                    },
+                    recv(self.requests) -> line => match line {
+                        Ok(line) => {
+                            let line = line?;
+                            match Request::parse(&line) {
+                                Ok(req) if req.method == "cancel" => {
+                                    evaluating.cancel();
+                                    self.respond(&req.id, Ok(json!(true)))?;
+                                }
+                                _ => self.pending.push_back(line),
+                            }
+                        }
+                        // finish the evaluation, then serve the pending requests
+                        Err(_) => {
+                            self.requests = crossbeam_channel::never();
+                            self.input_ended = true;
+                        }
+                    },
+                    default(EVAL_POLL) => (),
+                }
+            }
+
+            let EvalResult {
+                repl: print,
+                signal,
+            } = evaluating.wait();
+
+            let error = print.error(is_cmd).map(String::from);
+            let message = print.message().map(String::from);
+            let (read, output) = print.print();
+            repl = read;
+
+            let mut result = match signal {
+                Signal::ReEvaluate(input) => {
+                    code = input;
+                    continue;
+                }
+                Signal::Exit => json!({ "status": "ok", "exit": true }),
+                Signal::None => json!({ "status": "ok" }),
+            };
+
+            if let Some(error) = error {
+                result["status"] = json!("error");
+                result["message"] = json!(error);
+            } else if let Some(message) = message {
+                result["message"] = json!(message);
+            }
+
+            if let Some((num, kserd, type_name)) = output {
+                let value = crate::repl::fmt_output(&kserd, Default::default());
+                result["out"] = json!(num);
+                result["value"] = json!(value);
+                result["json"] = crate::repl::kserd_to_json(&kserd);
+                result["type"] = json!(type_name);
+                let key = (repl.data.current_mod().to_path_buf(), num);
+                self.outputs.insert(key, (value, type_name));
+            }
+
+            return Ok((repl, result));
+        }
+    }
+
+    fn complete<D>(&mut self, repl: &Repl<Read, D>, code: &str, cursor: Option<u64>) -> Value {
+        let pos = cursor
+            .and_then(|x| code.char_indices().nth(x as usize))
+            .map(|x| x.0)
+            .unwrap_or_else(|| code.len());
+        let line_start = code[..pos].rfind('\n').map(|x| x + 1).unwrap_or(0);
+        let line = &code[line_start..pos];
+
+        let (start, matches) = if line.starts_with(crate::CMD_PREFIX) {
+            let data = &repl.data;
+            let tree = TreeCompleter::build(&data.cmdtree);
+            let mods = ModulesCompleter::build(&data.cmdtree, data.mods_map());
+            let mut matches: Vec<String> = tree.complete(line).map(|x| x.0.to_string()).collect();
+            matches.extend(mods.complete(line));
+            (line_start + TreeCompleter::word_break(line), matches)
+        } else {
+            self.complete_code(repl, &code[..pos])
+        };
+
+        json!({ "start": code[..start].chars().count(), "matches": matches })
+    }
+
+    #[cfg(feature = "racer-completion")]
+    fn complete_code<D>(&mut self, repl: &Repl<Read, D>, code: &str) -> (usize, Vec<String>) {
+        use crate::complete::code::{CodeCache, CodeCompleter};
+
+        let cache = self
+            .code_cache
+            .get_or_insert_with(|| CodeCache::new().unwrap_or_else(|(cache, _)| cache));
+        let matches = CodeCompleter::build(&repl.data)
+            .complete(code, Some(100), cache)
+            .into_iter()
+            .map(|x| x.matchstr)
+            .collect();
+
+        (CodeCompleter::word_break(code), matches)
+    }
+
+    #[cfg(not(feature = "racer-completion"))]
+    fn complete_code<D>(&mut self, _: &Repl<Read, D>, code: &str) -> (usize, Vec<String>) {
+        (code.len(), Vec::new())
+    }
+
+    fn list_outputs<D>(&self, repl: &Repl<Read, D>) -> Value {
+        let module = repl.data.current_mod();
+        let outputs = repl
+            .data
+            .current_src()
+            .stmts
+            .iter()
+            .enumerate()
+            .map(|(num, stmt)| {
+                let mut out = json!({ "out": num, "src": stmt.src_line() });
+                if let Some((value, type_name)) = self.outputs.get(&(module.to_path_buf(), num)) {
+                    out["value"] = json!(value);
+                    out["type"] = json!(type_name);
+                }
+                out
+            })
+            .collect();
+
+        Value::Array(outputs)
+    }
+}
+
+fn response(id: &Value, result: RpcResult) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err((code, message)) => json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": code, "message": message },
+        }),
+    }
+}
+
+/// Read an `authenticate` request from `input`, responding on `output`. Returns whether the token
+/// matches `token`.
+pub(crate) fn authenticate<R: BufRead, W: Write>(
+    input: &mut R,
+    output: &mut W,
+    token: &str,
+) -> io::Result<bool> {
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+        return Ok(false);
+    }
+
+    let (id, result) = match Request::parse(&line) {
+        Ok(req) if req.method == "authenticate" => {
+            let valid = req
+                .str_param("token")
+                .map(|x| token_eq(&x, token))
+                .unwrap_or(false);
+            let result = if valid {
+                Ok(json!(true))
+            } else {
+                Err((UNAUTHORIZED, "invalid token".to_string()))
+            };
+            (req.id, result)
+        }
+        Ok(req) => (
+            req.id,
+            Err((
+                UNAUTHORIZED,
+                "expecting an authenticate request".to_string(),
+            )),
+        ),
+        Err(e) => (Value::Null, Err(e)),
+    };
+
+    let valid = result.is_ok();
+    writeln!(output, "{}", response(&id, result))?;
+    output.flush()?;
+    Ok(valid)
+}
+
+/// Compares every byte, so the time taken does not reveal how much of the token matched.
+fn token_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+/// The commands available, as they would be typed.
+fn commands<D>(repl: &Repl<Read, D>) -> Value {
+    use cmdtree::ItemType;
+
+    let cmds = TreeCompleter::build(&repl.data.cmdtree)
+        .complete("")
+        .map(|(_, info)| {
+            let kind = match info.itemtype {
+                ItemType::Class => "class",
+                ItemType::Action => "action",
+            };
+            json!({
+                "command": info.completestr,
+                "help": info.help_msg.as_str(),
+                "kind": kind,
+            })
+        })
+        .collect();
+
+    Value::Array(cmds)
+}
+
+/// Read lines on another thread, so cancellation can be received while evaluating.
+fn read_lines<R: BufRead + Send + 'static>(input: R) -> Receiver<io::Result<String>> {
+    let (tx, rx) = crossbeam_channel::unbounded();
+    std::thread::spawn(move || {
+        for line in input.lines() {
+            let stop = line.is_err();
+            if tx.send(line).is_err() || stop {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate as papyrus;
+    use std::io::Cursor;
+
+    /// Serve the requests, returning the responses by id and the notifications.
+    fn serve_requests(compile_dir: &str, requests: &[Value]) -> (BTreeMap<u64, Value>, Vec<Value>) {
+        let mut repl = repl!();
+        repl.data.with_compilation_dir(compile_dir).unwrap();
+
+        let input: String = requests.iter().map(|x| format!("{}\n", x)).collect();
+        let mut output = Vec::new();
+        serve(
+            repl,
+            &Arc::new(Mutex::new(())),
+            Cursor::new(input),
+            &mut output,
+        )
+        .unwrap();
+
+        let mut responses = BTreeMap::new();
+        let mut notifications = Vec::new();
+        for line in String::from_utf8(output).unwrap().lines() {
+            let msg: Value = serde_json::from_str(line).unwrap();
+            assert_eq!(msg["jsonrpc"], "2.0");
+            match msg["id"].as_u64() {
+                Some(id) => {
+                    responses.insert(id, msg);
+                }
+                None if msg.get("method").is_some() => notifications.push(msg),
+                None => {
+                    responses.insert(0, msg);
+                }
+            }
+        }
+
+        (responses, notifications)
+    }
+
+    fn request(id: u64, method: &str, params: Value) -> Value {
+        json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params })
+    }
+
+    #[test]
+    fn rpc_requests_test() {
+        let (responses, notifications) = serve_requests(
+            "target/testing/rpc_requests_test",
+            &[
+                json!("not a request"),
+                request(1, "not-a-method", json!({})),
+                request(2, "commands", Value::Null),
+                request(3, "complete", json!({ "code": "2+2\n:mo" })),
+                request(4, "complete", json!({ "code": ":mod sw", "cursor": 6 })),
+                request(5, "switchModule", json!({ "path": "a/b" })),
+                request(6, "switchModule", json!({ "path": " " })),
+                request(7, "listOutputs", Value::Null),
+                request(8, "cancel", Value::Null),
+                request(9, "eval", json!({ "code": "fn a() {" })),
+                request(10, "eval", json!({})),
+                // notifications are not responded to
+                json!({ "jsonrpc": "2.0", "method": "cancel" }),
+            ],
+        );
+
+        assert_eq!(responses[&0]["error"]["code"], INVALID_REQUEST);
+        assert_eq!(responses[&1]["error"]["code"], METHOD_NOT_FOUND);
+
+        let cmds = responses[&2]["result"].as_array().unwrap();
+        assert!(cmds.contains(&json!({
+            "command": ":mod switch",
+            "help": "Switch to a module, creating one if necessary. switch path/to/module",
+            "kind": "action",
+        })));
+
+        assert_eq!(responses[&3]["result"]["start"], 4);
+        assert_eq!(responses[&3]["result"]["matches"][0], ":mod");
+        assert_eq!(
+            responses[&4]["result"],
+            json!({ "start": 5, "matches": ["switch"] })
+        );
+
+        assert_eq!(responses[&5]["result"], json!({ "module": "a/b" }));
+        assert_eq!(responses[&6]["error"]["code"], INVALID_PARAMS);
+        assert_eq!(responses[&7]["result"], json!([]));
+        assert_eq!(responses[&8]["result"], false);
+        assert_eq!(responses[&9]["result"], json!({ "status": "incomplete" }));
+        assert_eq!(responses[&10]["error"]["code"], INVALID_PARAMS);
+        assert_eq!(responses.len(), 11);
+
+        assert_eq!(notifications[0]["params"]["change"], "currentLine");
+        assert!(notifications[0]["params"]["line"]
+            .as_str()
+            .unwrap()
+            .contains("papyrus=>"));
+    }
+
+    #[test]
+    fn rpc_parse_error_test() {
+        let (responses, _) = serve_requests("target/testing/rpc_parse_error_test", &[]);
+        assert!(responses.is_empty());
+
+        let r = Request::parse("{");
+        assert_eq!(r.err().map(|x| x.0), Some(PARSE_ERROR));
+    }
+
+    #[test]
+    fn authenticate_test() {
+        let auth = |line: &str| {
+            let mut output = Vec::new();
+            let valid = authenticate(&mut line.as_bytes(), &mut output, "secret").unwrap();
+            let response = String::from_utf8(output).unwrap();
+            (valid, response)
+        };
+
+        let (valid, response) =
+            auth(r#"{"jsonrpc":"2.0","id":1,"method":"authenticate","params":{"token":"secret"}}"#);
+        assert!(valid);
+        assert_eq!(response, "{\"id\":1,\"jsonrpc\":\"2.0\",\"result\":true}\n");
+
+        let (valid, response) =
+            auth(r#"{"jsonrpc":"2.0","id":1,"method":"authenticate","params":{"token":"secreT"}}"#);
+        assert!(!valid);
+        assert!(response.contains("invalid token"));
+
+        let (valid, response) = auth(r#"{"jsonrpc":"2.0","id":1,"method":"eval"}"#);
+        assert!(!valid);
+        assert!(response.contains(&UNAUTHORIZED.to_string()));
+
+        assert!(!auth("").0);
+        assert!(token_eq("abc", "abc"));
+        assert!(!token_eq("abc", "abcd"));
+    }
+
+    #[test]
+    fn rpc_eval_test() {
+        let (responses, notifications) = serve_requests(
+            "target/testing/rpc_eval_test",
+            &[
+                request(1, "eval", json!({ "code": "let a = 2;\na + 2" })),
+                request(2, "eval", json!({ "code": "a + \"b\"" })),
+                request(3, "eval", json!({ "code": ":help" })),
+                request(4, "listOutputs", Value::Null),
+                request(5, "eval", json!({ "code": ":exit" })),
+                request(6, "commands", Value::Null),
+            ],
+        );
+
+        assert_eq!(
+            responses[&1]["result"],
+            json!({ "status": "ok", "out": 0, "value": "4", "json": 4, "type": "i32" })
+        );
+        assert_eq!(responses[&2]["result"]["status"], "error");
+        assert!(responses[&2]["result"]["message"]
+            .as_str()
+            .unwrap()
+            .contains("error"));
+        assert_eq!(responses[&3]["result"], json!({ "status": "ok" }));
+        assert_eq!(
+            responses[&4]["result"],
+            json!([{ "out": 0, "src": "let a = 2; a + 2", "value": "4", "type": "i32" }])
+        );
+        assert_eq!(
+            responses[&5]["result"],
+            json!({ "status": "ok", "exit": true })
+        );
+        // the server stops when the REPL exits
+        assert!(!responses.contains_key(&6));
+
+        assert!(notifications
+            .iter()
+            .any(|x| x["method"] == "compileProgress"));
+        assert!(notifications.iter().any(|x| x["method"] == "output"
+            && x["params"]["line"]
+                .as_str()
+                .map(|x| x.contains("[out0]: 4"))
+                .unwrap_or(false)));
+        // command output is written to the output
+        assert!(notifications.iter().any(|x| x["method"] == "output"
+            && x["params"]["line"]
+                .as_str()
+                .map(|x| x.contains("help"))
+                .unwrap_or(false)));
+    }
+}
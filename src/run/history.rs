@@ -0,0 +1,204 @@
+use std::{
+    collections::VecDeque,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// The default number of history entries kept.
+pub const DEFAULT_HISTORY_SIZE: usize = 100;
+
+/// The input history, optionally persisted to a file.
+///
+/// Consecutive duplicate entries and empty entries are not added. Once the history reaches the
+/// size limit the _oldest_ entry is removed.
+pub struct History {
+    lines: VecDeque<String>,
+    limit: usize,
+    file: Option<PathBuf>,
+}
+
+impl History {
+    /// An empty history which is not persisted.
+    pub fn new(limit: usize) -> Self {
+        Self {
+            lines: VecDeque::with_capacity(limit),
+            limit,
+            file: None,
+        }
+    }
+
+    /// Loads the history from `file`, further additions are written back to the file.
+    ///
+    /// A file that does not exist is treated as empty history.
+    pub fn load<P: AsRef<Path>>(file: P, limit: usize) -> io::Result<Self> {
+        let file = file.as_ref();
+        let mut history = Self::new(limit);
+
+        match fs::read_to_string(file) {
+            Ok(s) => {
+                for line in s.lines() {
+                    history.push_line(unescape(line));
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+
+        history.file = Some(file.to_path_buf());
+
+        Ok(history)
+    }
+
+    /// Number of entries.
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    /// Get the entry at `idx`, where `0` is the oldest entry.
+    pub fn get(&self, idx: usize) -> Option<&str> {
+        self.lines.get(idx).map(|x| x.as_str())
+    }
+
+    /// Push the line onto the history stack, writing the history to file if persisted.
+    ///
+    /// Returns `false` if the line was not added, which occurs if it is empty or the same as the
+    /// previous entry.
+    pub fn push(&mut self, line: String) -> bool {
+        let added = self.push_line(line);
+        if added {
+            if let Err(e) = self.save() {
+                error!("failed to save history: {}", e);
+            }
+        }
+        added
+    }
+
+    fn push_line(&mut self, line: String) -> bool {
+        if self.limit == 0
+            || line.trim().is_empty()
+            || self.lines.back().map(|x| x == &line).unwrap_or(false)
+        {
+            return false;
+        }
+
+        while self.lines.len() >= self.limit {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+        true
+    }
+
+    fn save(&self) -> io::Result<()> {
+        if let Some(file) = &self.file {
+            if let Some(parent) = file.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut contents = String::new();
+            for line in &self.lines {
+                contents.push_str(&escape(line));
+                contents.push('\n');
+            }
+            fs::write(file, contents)?;
+        }
+        Ok(())
+    }
+
+    /// Search backwards for an entry containing `query`, starting _before_ the index `from`.
+    ///
+    /// Returns the index of the matching entry.
+    pub fn search_back(&self, query: &str, from: usize) -> Option<usize> {
+        let from = std::cmp::min(from, self.lines.len());
+        self.lines
+            .iter()
+            .take(from)
+            .rposition(|line| line.contains(query))
+    }
+}
+
+/// History entries are stored per line, so new lines and backslashes are escaped.
+fn escape(line: &str) -> String {
+    line.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape(line: &str) -> String {
+    let mut s = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(ch) = chars.next() {
+        match (ch, chars.clone().next()) {
+            ('\\', Some('n')) => {
+                s.push('\n');
+                chars.next();
+            }
+            ('\\', Some('\\')) => {
+                s.push('\\');
+                chars.next();
+            }
+            (ch, _) => s.push(ch),
+        }
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn push_dedups_and_limits() {
+        let mut history = History::new(2);
+        assert!(history.push("a".to_string()));
+        assert!(!history.push("a".to_string())); // consecutive duplicate
+        assert!(!history.push("  ".to_string())); // empty
+        assert!(history.push("b".to_string()));
+        assert!(history.push("a".to_string())); // not consecutive
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.get(0), Some("b"));
+        assert_eq!(history.get(1), Some("a"));
+
+        let mut history = History::new(0);
+        assert!(!history.push("a".to_string()));
+        assert_eq!(history.len(), 0);
+    }
+
+    #[test]
+    fn search_back_test() {
+        let mut history = History::new(10);
+        history.push("let apple = 1;".to_string());
+        history.push("let banana = 2;".to_string());
+        history.push("apple + banana".to_string());
+
+        assert_eq!(history.search_back("apple", 3), Some(2));
+        assert_eq!(history.search_back("apple", 2), Some(0));
+        assert_eq!(history.search_back("apple", 0), None);
+        assert_eq!(history.search_back("banana", 100), Some(2));
+        assert_eq!(history.search_back("cherry", 3), None);
+    }
+
+    #[test]
+    fn escaping_test() {
+        let s = "a\\nb\nc\\";
+        assert_eq!(escape(s), "a\\\\nb\\nc\\\\");
+        assert_eq!(unescape(&escape(s)), s);
+    }
+
+    #[test]
+    fn persist_history_test() {
+        let file = "target/testing/history-test/history";
+        fs::remove_file(file).ok();
+
+        let mut history = History::load(file, 10).unwrap();
+        assert_eq!(history.len(), 0);
+        history.push("let a = 1;".to_string());
+        history.push("{\nmultiline\n}".to_string());
+
+        let history = History::load(file, 10).unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.get(0), Some("let a = 1;"));
+        assert_eq!(history.get(1), Some("{\nmultiline\n}"));
+
+        // loading applies limit
+        let history = History::load(file, 1).unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history.get(0), Some("{\nmultiline\n}"));
+    }
+}
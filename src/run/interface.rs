@@ -1,6 +1,7 @@
-use super::map_xterm_err;
+use super::{history::History, map_xterm_err};
 use crate::output::OutputChange;
-use crossbeam_channel::{unbounded, Receiver};
+use crate::repl::CancelHandle;
+use crossbeam_channel::{bounded, select, unbounded, Receiver};
 use crossterm as xterm;
 use std::{
     collections::VecDeque,
@@ -20,8 +21,14 @@ use xterm::{
 };
 
 const TAB_WIDTH: usize = 8;
+/// The indentation added after opening a block.
+const INDENT: &str = "    ";
 
-pub struct Screen(pub(super) Receiver<Event>);
+pub struct Screen {
+    rx: Receiver<Event>,
+    /// Events received while evaluating, these are read before new events.
+    pending: VecDeque<Event>,
+}
 
 impl Screen {
     pub fn new() -> io::Result<Self> {
@@ -43,13 +50,57 @@ impl Screen {
                     Err(_) => break,
                 }
             })?;
-        Ok(Screen(rx))
+        Ok(Screen::with_receiver(rx))
+    }
+
+    pub(super) fn with_receiver(rx: Receiver<Event>) -> Self {
+        Screen {
+            rx,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Runs `f`, cancelling through `cancel` if Ctrl+C is pressed meanwhile.
+    ///
+    /// Other events are kept and read by the next interface.
+    pub fn watch_cancel<T, F: FnOnce() -> T>(&mut self, cancel: &CancelHandle, f: F) -> T {
+        const BREAK: Event = Key(KeyEvent {
+            modifiers: KeyModifiers::CONTROL,
+            code: Char('c'),
+        });
+
+        let rx = self.rx.clone();
+        let cancel = cancel.clone();
+        let (stop_tx, stop_rx) = bounded::<()>(0);
+        let jh = std::thread::spawn(move || {
+            let mut pending = Vec::new();
+            loop {
+                select! {
+                    recv(rx) -> ev => match ev {
+                        Ok(BREAK) => cancel.cancel(),
+                        Ok(ev) => pending.push(ev),
+                        Err(_) => break,
+                    },
+                    recv(stop_rx) -> _ => break,
+                }
+            }
+            pending
+        });
+
+        let r = f();
+        drop(stop_tx);
+        self.pending.extend(jh.join().unwrap_or_default());
+        r
+    }
+
+    fn next_event(&mut self) -> Option<Event> {
+        self.pending.pop_front().or_else(|| self.rx.recv().ok())
     }
 
     pub fn begin_interface_input<'a>(
         &'a mut self,
         preallocated_buf: &'a mut InputBuffer,
-        history: &'a mut VecDeque<String>,
+        history: &'a mut History,
     ) -> XResult<Interface<'a>> {
         enable_raw_mode()?;
         preallocated_buf.clear();
@@ -72,12 +123,23 @@ pub struct Interface<'a> {
     buf: &'a mut InputBuffer,
     prompt_len: usize,
     prev_lines_covered: u16,
-    history: &'a mut VecDeque<String>,
+    history: &'a mut History,
     /// history.len() is starting position. Counting backwards for so history.len() - 1 is 1st
     /// entry. Once hits zero, loop back to history.len().
     history_pos: usize,
 }
 
+/// State of a reverse incremental history search.
+struct Search {
+    query: String,
+    /// The history index of the current match, history.len() if no match.
+    idx: usize,
+    /// The prompt to restore once the search finishes.
+    prompt: String,
+    /// The line to restore if the search is cancelled.
+    line: String,
+}
+
 impl<'a> Interface<'a> {
     pub fn buffer(&self) -> String {
         self.buf.buffer(self.prompt_len..)
@@ -141,13 +203,89 @@ impl<'a> Interface<'a> {
                 }
             };
         }
+        macro_rules! ctrl {
+            ($code:expr) => {
+                Key(KeyEvent {
+                    modifiers: KeyModifiers::CONTROL,
+                    code: $code,
+                })
+            };
+        }
+        macro_rules! alt {
+            ($code:expr) => {
+                Key(KeyEvent {
+                    modifiers: KeyModifiers::ALT,
+                    code: $code,
+                })
+            };
+        }
+        const SEARCH: Event = ctrl!(Char('r'));
+        const CTRL_A: Event = ctrl!(Char('a'));
+        const CTRL_E: Event = ctrl!(Char('e'));
+        const CTRL_W: Event = ctrl!(Char('w'));
+        const CTRL_U: Event = ctrl!(Char('u'));
+        const CTRL_K: Event = ctrl!(Char('k'));
+        const CTRL_Y: Event = ctrl!(Char('y'));
+        const CTRL_L: Event = ctrl!(Char('l'));
+        const CTRL_LEFT: Event = ctrl!(Left);
+        const CTRL_RIGHT: Event = ctrl!(Right);
+        const ALT_B: Event = alt!(Char('b'));
+        const ALT_F: Event = alt!(Char('f'));
+        const ALT_ENTER: Event = alt!(Enter);
+        const CANCEL_SEARCH: Event = ctrl!(Char('g'));
         let mut last = Event::Key(KeyEvent {
             modifiers: KeyModifiers::CONTROL,
             code: xterm::event::KeyCode::Char('c'),
         });
+        let mut search: Option<Search> = None;
 
-        while let Ok(ev) = self.screen.0.recv() {
+        while let Some(ev) = self.screen.next_event() {
             last = ev;
+
+            if let Some(mut s) = search.take() {
+                let searching = match ev {
+                    SEARCH => {
+                        self.search_history(&mut s, true);
+                        true
+                    }
+                    Key(KeyEvent {
+                        modifiers: NOMOD,
+                        code: Char(c),
+                    })
+                    | Key(KeyEvent {
+                        modifiers: KeyModifiers::SHIFT,
+                        code: Char(c),
+                    }) => {
+                        s.query.push(c);
+                        self.search_history(&mut s, false);
+                        true
+                    }
+                    Key(nomod!(Backspace)) => {
+                        s.query.pop();
+                        s.idx = self.history.len();
+                        self.search_history(&mut s, false);
+                        true
+                    }
+                    CANCEL_SEARCH | Key(nomod!(Esc)) => {
+                        self.end_search(&s, true);
+                        false
+                    }
+                    _ => {
+                        self.end_search(&s, false);
+                        false
+                    }
+                };
+
+                self.flush_buffer()?;
+
+                if searching {
+                    search = Some(s);
+                    continue;
+                } else if ev == CANCEL_SEARCH || ev == Key(nomod!(Esc)) {
+                    continue;
+                }
+            }
+
             if events.contains(&ev) {
                 break;
             }
@@ -179,6 +317,8 @@ impl<'a> Interface<'a> {
                     self.buf.delete();
                     true
                 }
+                Key(nomod!(Up)) if self.buf.move_line_up(self.prompt_len) => true,
+                Key(nomod!(Down)) if self.buf.move_line_down(self.prompt_len) => true,
                 Key(nomod!(Up)) => {
                     // update history position, if on last, loop back to start
                     if self.history_pos == 0 {
@@ -199,6 +339,70 @@ impl<'a> Interface<'a> {
                     self.apply_history_line();
                     true
                 }
+                Key(nomod!(Home)) | CTRL_A => {
+                    self.buf.move_to(self.prompt_len);
+                    true
+                }
+                Key(nomod!(End)) | CTRL_E => {
+                    self.buf.move_end();
+                    true
+                }
+                CTRL_LEFT | ALT_B => {
+                    self.buf.move_word_left(self.prompt_len);
+                    true
+                }
+                CTRL_RIGHT | ALT_F => {
+                    self.buf.move_word_right();
+                    true
+                }
+                CTRL_W => {
+                    self.buf.kill_word_left(self.prompt_len);
+                    true
+                }
+                CTRL_U => {
+                    self.buf.kill_to_start(self.prompt_len);
+                    true
+                }
+                CTRL_K => {
+                    self.buf.kill_to_end();
+                    true
+                }
+                CTRL_Y => {
+                    self.buf.yank();
+                    true
+                }
+                ALT_ENTER => {
+                    self.insert_newline();
+                    true
+                }
+                Key(KeyEvent {
+                    modifiers: NOMOD,
+                    code: Char('}'),
+                })
+                | Key(KeyEvent {
+                    modifiers: KeyModifiers::SHIFT,
+                    code: Char('}'),
+                }) => {
+                    self.buf.dedent(self.prompt_len, INDENT.len());
+                    self.buf.insert('}');
+                    true
+                }
+                CTRL_L => {
+                    queue!(self.stdout, Clear(ClearType::All), MoveTo(0, 0))?;
+                    self.prev_lines_covered = 0;
+                    true
+                }
+                SEARCH => {
+                    let mut s = Search {
+                        query: String::new(),
+                        idx: self.history.len(),
+                        prompt: self.buf.buffer(..self.prompt_len),
+                        line: self.buffer(),
+                    };
+                    self.search_history(&mut s, false);
+                    search = Some(s);
+                    true
+                }
                 Key(KeyEvent {
                     modifiers: NOMOD,
                     code: Char(c),
@@ -214,44 +418,88 @@ impl<'a> Interface<'a> {
             };
 
             if modified {
-                // flushing will update prev lines changed and terminal cursor to end of buffer
-                // we get the cursor delta with the current buffer position to find out what needs
-                // to be moved!
-                self.flush_buffer()?;
-                let (col, rows) = self.buf.cursor_delta(self.buf.pos, term_width_nofail());
-                let uprows = self.prev_lines_covered;
-                queue!(self.stdout, MoveToColumn(col as u16 + 1))?;
-                if uprows > 0 {
-                    queue!(self.stdout, MoveUp(uprows))?;
-                }
-                if rows > 0 {
-                    queue!(self.stdout, MoveDown(rows as u16))?;
-                }
-                self.stdout.flush()?;
+                self.redraw()?;
             }
         }
 
         Ok(last)
     }
 
+    /// Flush the buffer and place the terminal cursor at the buffer position.
+    pub fn redraw(&mut self) -> XResult<()> {
+        // flushing will update prev lines changed and terminal cursor to end of buffer
+        // we get the cursor delta with the current buffer position to find out what needs
+        // to be moved!
+        self.flush_buffer()?;
+        let (col, rows) = self.buf.cursor_delta(self.buf.pos, term_width_nofail());
+        let uprows = self.prev_lines_covered;
+        queue!(self.stdout, MoveToColumn(col as u16 + 1))?;
+        if uprows > 0 {
+            queue!(self.stdout, MoveUp(uprows))?;
+        }
+        if rows > 0 {
+            queue!(self.stdout, MoveDown(rows as u16))?;
+        }
+        self.stdout.flush()?;
+        Ok(())
+    }
+
+    /// Insert a new line at the buffer position, indenting to match the current line. The
+    /// indentation is increased if the line opens a block `{`.
+    ///
+    /// **Does not alter terminal in anyway.**
+    pub fn insert_newline(&mut self) {
+        let indent = self.buf.newline_indent(self.prompt_len);
+        self.buf.insert('\n');
+        self.buf.insert_str(&indent);
+    }
+
     /// Push the line onto the history stack.
-    /// Pops off oldest history to keep history len constant.
+    /// Empty lines and consecutive duplicates are not added.
     pub fn add_history(&mut self, line: String) {
-        // must keep self.history.len() constant.
-        self.history.pop_front();
-        self.history.push_back(line);
+        self.history.push(line);
     }
 
     fn apply_history_line(&mut self) {
         // get line
-        let line = self
-            .history
-            .get(self.history_pos)
-            .map(|x| x.as_str())
-            .unwrap_or("");
+        let line = self.history.get(self.history_pos).unwrap_or("");
         self.buf.truncate(self.prompt_len);
         self.buf.insert_str(line);
     }
+
+    /// Searches the history with the query, writing the search prompt and the matching entry into
+    /// the buffer. If `next` is true, the search begins _before_ the current match.
+    fn search_history(&mut self, search: &mut Search, next: bool) {
+        let from = if next {
+            search.idx
+        } else {
+            // the current match may still match the altered query
+            search.idx.saturating_add(1)
+        };
+
+        if let Some(idx) = self.history.search_back(&search.query, from) {
+            search.idx = idx;
+        }
+
+        let line = self.history.get(search.idx).unwrap_or("").to_owned();
+        self.set_prompt(&format!("(reverse-i-search)`{}': ", search.query));
+        self.buf.truncate(self.prompt_len);
+        self.buf.insert_str(&line);
+    }
+
+    /// Finish the search, restoring the prompt. The line is restored to before the search began
+    /// if `cancel` is true, otherwise the matched entry is kept.
+    fn end_search(&mut self, search: &Search, cancel: bool) {
+        let line = if cancel {
+            search.line.clone()
+        } else {
+            self.buffer()
+        };
+        self.set_prompt(&search.prompt);
+        self.buf.truncate(self.prompt_len);
+        self.buf.insert_str(&line);
+        self.history_pos = self.history.len();
+    }
 }
 
 impl<'a> Drop for Interface<'a> {
@@ -264,6 +512,8 @@ impl<'a> Drop for Interface<'a> {
 pub struct InputBuffer {
     buf: Vec<char>,
     pos: usize,
+    /// The last killed text, used for yanking.
+    killed: String,
 }
 
 impl InputBuffer {
@@ -271,9 +521,12 @@ impl InputBuffer {
         Self {
             buf: Vec::new(),
             pos: 0,
+            killed: String::new(),
         }
     }
 
+    /// Clears the buffer. **The killed text is kept** such that it can be yanked into later
+    /// inputs.
     pub fn clear(&mut self) {
         self.buf.clear();
         self.pos = 0;
@@ -348,6 +601,161 @@ impl InputBuffer {
         }
     }
 
+    /// Move to `ch_pos`, clamped to the buffer length.
+    pub fn move_to(&mut self, ch_pos: usize) {
+        self.pos = std::cmp::min(ch_pos, self.buf.len());
+    }
+
+    /// Moves to the start of the previous word, not moving before `min`.
+    /// Return the number moved.
+    pub fn move_word_left(&mut self, min: usize) -> usize {
+        let start = self.prev_word_start(min);
+        let n = self.pos - start;
+        self.pos = start;
+        n
+    }
+
+    /// Moves to the end of the next word.
+    /// Return the number moved.
+    pub fn move_word_right(&mut self) -> usize {
+        let end = self.next_word_end();
+        let n = end - self.pos;
+        self.pos = end;
+        n
+    }
+
+    /// Kill the word before the position, not killing before `min`.
+    pub fn kill_word_left(&mut self, min: usize) {
+        let start = self.prev_word_start(min);
+        self.kill(start, self.pos);
+    }
+
+    /// Kill from `min` to the position.
+    pub fn kill_to_start(&mut self, min: usize) {
+        if self.pos > min {
+            self.kill(min, self.pos);
+        }
+    }
+
+    /// Kill from the position to the end of the buffer.
+    pub fn kill_to_end(&mut self) {
+        self.kill(self.pos, self.buf.len());
+    }
+
+    /// Insert the last killed text at the position.
+    pub fn yank(&mut self) {
+        let killed = std::mem::take(&mut self.killed);
+        self.insert_str(&killed);
+        self.killed = killed;
+    }
+
+    /// Moves to the same column on the previous line, clamped to the line length.
+    /// The first line starts at `min`. Returns `false` if on the first line.
+    pub fn move_line_up(&mut self, min: usize) -> bool {
+        let start = self.line_start(self.pos, min);
+        if start <= min {
+            return false;
+        }
+        let col = self.pos - start;
+        let prev_start = self.line_start(start - 1, min);
+        self.pos = std::cmp::min(prev_start + col, start - 1);
+        true
+    }
+
+    /// Moves to the same column on the next line, clamped to the line length.
+    /// The first line starts at `min`. Returns `false` if on the last line.
+    pub fn move_line_down(&mut self, min: usize) -> bool {
+        let end = self.line_end(self.pos);
+        if end == self.buf.len() {
+            return false;
+        }
+        let col = self.pos - self.line_start(self.pos, min);
+        let next_start = end + 1;
+        self.pos = std::cmp::min(next_start + col, self.line_end(next_start));
+        true
+    }
+
+    /// The indentation for a new line inserted at the position. This is the leading whitespace
+    /// of the current line, plus an [`INDENT`] if the text before the position ends with `{`.
+    pub fn newline_indent(&self, min: usize) -> String {
+        let start = self.line_start(self.pos, min);
+        let line = &self.buf[start..self.pos];
+        let mut indent: String = line
+            .iter()
+            .take_while(|c| **c == ' ' || **c == '\t')
+            .collect();
+        if line.iter().rev().find(|c| !c.is_whitespace()) == Some(&'{') {
+            indent.push_str(INDENT);
+        }
+        indent
+    }
+
+    /// Removes up to `n` spaces before the position if the current line is only whitespace up to
+    /// the position. Used to dedent when closing a block.
+    pub fn dedent(&mut self, min: usize, n: usize) {
+        let start = self.line_start(self.pos, min);
+        if start < self.pos && self.buf[start..self.pos].iter().all(|c| *c == ' ') {
+            let n = std::cmp::min(n, self.pos - start);
+            self.buf.drain(self.pos - n..self.pos);
+            self.pos -= n;
+        }
+    }
+
+    /// The start of the line containing `ch_pos`, not before `min`.
+    fn line_start(&self, ch_pos: usize, min: usize) -> usize {
+        self.buf[..ch_pos]
+            .iter()
+            .rposition(|c| *c == '\n')
+            .map(|i| i + 1)
+            .filter(|i| *i > min)
+            .unwrap_or(min)
+    }
+
+    /// The end of the line containing `ch_pos`, the position of the `\n` or the buffer length.
+    fn line_end(&self, ch_pos: usize) -> usize {
+        self.buf[ch_pos..]
+            .iter()
+            .position(|c| *c == '\n')
+            .map(|i| i + ch_pos)
+            .unwrap_or_else(|| self.buf.len())
+    }
+
+    /// Removes the characters `start..end`, storing them as the killed text.
+    /// Nothing is stored if the range is empty.
+    fn kill(&mut self, start: usize, end: usize) {
+        if start < end {
+            self.killed = self.buf.drain(start..end).collect();
+            if self.pos > end {
+                self.pos -= end - start;
+            } else if self.pos > start {
+                self.pos = start;
+            }
+        }
+    }
+
+    fn prev_word_start(&self, min: usize) -> usize {
+        let mut i = self.pos;
+        while i > min && !is_word_char(self.buf[i - 1]) {
+            i -= 1;
+        }
+        while i > min && is_word_char(self.buf[i - 1]) {
+            i -= 1;
+        }
+        i
+    }
+
+    fn next_word_end(&self) -> usize {
+        let len = self.buf.len();
+        let mut i = self.pos;
+        while i < len && !is_word_char(self.buf[i]) {
+            i += 1;
+        }
+        while i < len && is_word_char(self.buf[i]) {
+            i += 1;
+        }
+        i
+    }
+
     /// Starting for column 0, calculates the cursor movement given the current buffer to `ch_pos`.
     /// Returns _(column, row)_.
     /// Ignores escape sequences.
@@ -381,6 +789,10 @@ impl InputBuffer {
     }
 }
 
+fn is_word_char(ch: char) -> bool {
+    ch.is_alphanumeric() || ch == '_'
+}
+
 impl fmt::Display for InputBuffer {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         for ch in self.buf.iter().copied() {
@@ -474,9 +886,11 @@ fn overwrite_text<T: fmt::Display + Clone>(
 }
 
 /// Returns the number of lines the written text accounts for
+///
+/// Writes to the terminal, so this can be used while the evaluated code's output is captured.
 pub fn write_output_chg(current_lines_covered: u16, change: OutputChange) -> io::Result<u16> {
     use OutputChange::*;
-    let mut stdout = stdout();
+    let mut stdout = crate::compile::terminal();
     match change {
         CurrentLine(line) => {
             for _ in 1..current_lines_covered {
@@ -494,7 +908,7 @@ pub fn write_output_chg(current_lines_covered: u16, change: OutputChange) -> io:
 
 /// Resets position to start of line.
 /// **Does not flush, should be called afterwards.**
-pub fn erase_current_line(mut stdout: Stdout) -> io::Result<Stdout> {
+pub fn erase_current_line<W: Write>(mut stdout: W) -> io::Result<W> {
     queue!(stdout, Clear(ClearType::CurrentLine), MoveToColumn(0))
         .map(|_| stdout)
         .map_err(|e| map_xterm_err(e, &line!().to_string()))
@@ -532,6 +946,124 @@ mod tests {
     use super::*;
     use colored::*;
 
+    #[test]
+    fn test_word_movement() {
+        let mut input = InputBuffer::new();
+        input.insert_str("> let apple_1 = foo(x);");
+        let min = 2;
+
+        assert_eq!(input.move_word_left(min), 3);
+        assert_eq!(input.pos, 20); // before x
+        input.move_word_left(min);
+        assert_eq!(input.pos, 16); // before foo
+        input.move_word_left(min);
+        assert_eq!(input.pos, 6); // before apple_1
+        input.move_word_left(min);
+        assert_eq!(input.pos, 2);
+        assert_eq!(input.move_word_left(min), 0, "can't move before min");
+
+        input.move_word_right();
+        assert_eq!(input.pos, 5); // after let
+        input.move_word_right();
+        assert_eq!(input.pos, 13); // after apple_1
+        input.move_end();
+        assert_eq!(input.move_word_right(), 0);
+
+        input.move_to(100);
+        assert_eq!(input.pos, input.len());
+    }
+
+    #[test]
+    fn test_kill_yank() {
+        let mut input = InputBuffer::new();
+        input.insert_str("> let apple = 1;");
+        let min = 2;
+
+        input.kill_word_left(min);
+        assert_eq!(&input.buffer(..), "> let apple = ");
+        assert_eq!(input.pos, 14);
+
+        input.yank();
+        input.yank();
+        assert_eq!(&input.buffer(..), "> let apple = 1;1;");
+
+        input.move_to(6);
+        input.kill_to_end();
+        assert_eq!(&input.buffer(..), "> let ");
+        assert_eq!(input.pos, 6);
+
+        input.kill_to_start(min);
+        assert_eq!(&input.buffer(..), "> ");
+        assert_eq!(input.pos, 2);
+        input.kill_to_start(min);
+        assert_eq!(&input.buffer(..), "> ", "can't kill before min");
+
+        // killed text survives clearing
+        input.clear();
+        input.yank();
+        assert_eq!(&input.buffer(..), "let ");
+    }
+
+    #[test]
+    fn test_line_movement() {
+        let mut input = InputBuffer::new();
+        input.insert_str("> fn a() {\n    let apple = 1;\n}");
+        let min = 2;
+
+        assert!(!input.move_line_down(min), "on last line");
+        assert_eq!(input.pos, 31);
+        assert!(input.move_line_up(min));
+        assert_eq!(input.pos, 12); // column 1 of second line
+        input.move_end();
+        input.move_pos_left(1); // before `}`
+        input.move_line_up(min);
+        input.move_pos_right(6);
+        assert!(input.move_line_up(min));
+        assert_eq!(
+            input.pos, 8,
+            "column 6 is past first line, clamped to line end"
+        );
+        assert_eq!(input.buffer(..input.pos), "> fn a()");
+        assert!(!input.move_line_up(min), "on first line");
+
+        input.move_to(min);
+        assert!(input.move_line_down(min));
+        assert_eq!(input.pos, 11);
+        input.move_pos_right(18); // end of second line
+        assert!(input.move_line_down(min));
+        assert_eq!(input.pos, 31, "clamped to end of last line");
+    }
+
+    #[test]
+    fn test_newline_indent() {
+        let mut input = InputBuffer::new();
+        let min = 2;
+        input.insert_str("> fn a() {");
+        assert_eq!(input.newline_indent(min), "    ");
+        input.insert_str("\n    if true {  ");
+        assert_eq!(input.newline_indent(min), "        ");
+        input.insert_str("\n        1");
+        assert_eq!(input.newline_indent(min), "        ");
+        input.move_to(min);
+        assert_eq!(input.newline_indent(min), "", "prompt is not indentation");
+
+        let mut input = InputBuffer::new();
+        input.insert_str("> {\n        ");
+        input.dedent(min, 4);
+        assert_eq!(&input.buffer(..), "> {\n    ");
+        input.dedent(min, 4);
+        assert_eq!(&input.buffer(..), "> {\n");
+        input.dedent(min, 4);
+        assert_eq!(&input.buffer(..), "> {\n");
+        input.insert_str("  a ");
+        input.dedent(min, 4);
+        assert_eq!(
+            &input.buffer(..),
+            "> {\n  a ",
+            "only whitespace is dedented"
+        );
+    }
+
     #[test]
     fn test_input_movement() {
         let mut input = InputBuffer::new();
@@ -622,7 +1154,9 @@ mod tests {
         let (origcols, _origrows) = size()?;
         let mut screen = Screen::new()?;
         let mut inputbuf = InputBuffer::new();
-        let mut history = VecDeque::from(vec!["Hello".to_string(), "World".to_string()]);
+        let mut history = History::new(2);
+        history.push("Hello".to_string());
+        history.push("World".to_string());
         let mut input = screen.begin_interface_input(&mut inputbuf, &mut history)?;
 
         let repl: Repl<_, ()> = Repl::default();
@@ -676,10 +1210,9 @@ mod tests {
         // test adding history items
         input.add_history("Item 1".to_string());
         drop(input);
-        assert_eq!(
-            history.into_iter().collect::<Vec<_>>().as_slice(),
-            &["World".to_string(), "Item 1".to_string()]
-        );
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.get(0), Some("World"));
+        assert_eq!(history.get(1), Some("Item 1"));
 
         // Ensure to reset terminal state
         disable_raw_mode()?;
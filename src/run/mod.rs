@@ -1,20 +1,27 @@
 #[cfg(feature = "racer-completion")]
 use crate::complete::code::{CodeCache, CodeCompleter};
 use crate::complete::{cmdr::TreeCompleter, modules::ModulesCompleter};
+use crate::output::OutputChange;
 use crate::prelude::*;
 use crossterm as xterm;
 use crossterm::event::Event;
 use kserd::{fmt::FormattingConfig, Kserd};
 use repl::{EvalResult, Evaluate, Print, Read, ReadResult};
 use std::io::{self, prelude::*};
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 
+mod history;
 mod interface;
+mod serve;
 #[cfg(test)]
 mod tests;
 
+use history::{History, DEFAULT_HISTORY_SIZE};
 use interface::{CItem, Interface, Screen};
 
+pub use serve::{connect, serve, ReplServer};
+
 #[cfg(feature = "racer-completion")]
 const CODE_COMPLETIONS: Option<usize> = Some(10);
 
@@ -70,11 +77,23 @@ enum Data<'a, D> {
 ///
 /// These callbacks are mostly around setting up formatting and feeding evaluation results to the
 /// caller.
-pub struct RunCallbacks<'a, D, T, U, V> {
+pub struct RunCallbacks<'a, D, T, U, V, W> {
     data: Data<'a, D>,
     fmtrfn: Option<T>,
     resultfn: Option<U>,
     exitfn: Option<V>,
+    errorfn: Option<W>,
+    history_size: usize,
+    history_file: HistoryFile,
+    headless_prompts: bool,
+}
+
+/// Where the input history gets persisted.
+enum HistoryFile {
+    /// `history` inside the compilation directory.
+    CompilationDir,
+    Path(PathBuf),
+    None,
 }
 
 impl<'a, D>
@@ -84,6 +103,7 @@ impl<'a, D>
         fn(&Repl<Print, D>) -> FormattingConfig,
         fn(usize, Kserd<'static>, &Repl<Read, D>),
         fn(&mut ReplData<D>, &mut D),
+        fn(&str),
     >
 {
     /// New callback using a synchronous model of data ownership. (eg `eval`).
@@ -93,6 +113,10 @@ impl<'a, D>
             fmtrfn: None,
             resultfn: None,
             exitfn: None,
+            errorfn: None,
+            history_size: DEFAULT_HISTORY_SIZE,
+            history_file: HistoryFile::CompilationDir,
+            headless_prompts: false,
         }
     }
 
@@ -109,13 +133,17 @@ impl<'a, D>
             fmtrfn: None,
             resultfn: None,
             exitfn: None,
+            errorfn: None,
+            history_size: DEFAULT_HISTORY_SIZE,
+            history_file: HistoryFile::CompilationDir,
+            headless_prompts: false,
         }
     }
 }
 
-impl<'a, D, T, U, V> RunCallbacks<'a, D, T, U, V> {
+impl<'a, D, T, U, V, W> RunCallbacks<'a, D, T, U, V, W> {
     /// Specify code to be run which dictates the formatting configuration to use.
-    pub fn with_fmtrfn<F>(self, f: F) -> RunCallbacks<'a, D, F, U, V>
+    pub fn with_fmtrfn<F>(self, f: F) -> RunCallbacks<'a, D, F, U, V, W>
     where
         F: FnMut(&Repl<Print, D>) -> FormattingConfig,
     {
@@ -123,6 +151,10 @@ impl<'a, D, T, U, V> RunCallbacks<'a, D, T, U, V> {
             data,
             resultfn,
             exitfn,
+            errorfn,
+            history_size,
+            history_file,
+            headless_prompts,
             ..
         } = self;
         RunCallbacks {
@@ -130,6 +162,10 @@ impl<'a, D, T, U, V> RunCallbacks<'a, D, T, U, V> {
             fmtrfn: Some(f),
             resultfn,
             exitfn,
+            errorfn,
+            history_size,
+            history_file,
+            headless_prompts,
         }
     }
 
@@ -137,7 +173,7 @@ impl<'a, D, T, U, V> RunCallbacks<'a, D, T, U, V> {
     ///
     /// The closure supplies the statement index `usize` and the result `Kserd`, along with the
     /// `Repl`.
-    pub fn with_resultfn<F>(self, f: F) -> RunCallbacks<'a, D, T, F, V>
+    pub fn with_resultfn<F>(self, f: F) -> RunCallbacks<'a, D, T, F, V, W>
     where
         F: FnMut(usize, Kserd<'static>, &Repl<Read, D>),
     {
@@ -145,6 +181,10 @@ impl<'a, D, T, U, V> RunCallbacks<'a, D, T, U, V> {
             data,
             fmtrfn,
             exitfn,
+            errorfn,
+            history_size,
+            history_file,
+            headless_prompts,
             ..
         } = self;
         RunCallbacks {
@@ -152,13 +192,17 @@ impl<'a, D, T, U, V> RunCallbacks<'a, D, T, U, V> {
             fmtrfn,
             resultfn: Some(f),
             exitfn,
+            errorfn,
+            history_size,
+            history_file,
+            headless_prompts,
         }
     }
 
     /// Specify code to be run after the exit signal is received.
     ///
     /// This can be used to clean up resources within [`ReplData`] or `D`.
-    pub fn with_exitfn<F>(self, f: F) -> RunCallbacks<'a, D, T, U, F>
+    pub fn with_exitfn<F>(self, f: F) -> RunCallbacks<'a, D, T, U, F, W>
     where
         F: FnOnce(&ReplData<D>, &mut D),
     {
@@ -166,6 +210,10 @@ impl<'a, D, T, U, V> RunCallbacks<'a, D, T, U, V> {
             data,
             fmtrfn,
             resultfn,
+            errorfn,
+            history_size,
+            history_file,
+            headless_prompts,
             ..
         } = self;
         RunCallbacks {
@@ -173,32 +221,132 @@ impl<'a, D, T, U, V> RunCallbacks<'a, D, T, U, V> {
             fmtrfn,
             resultfn,
             exitfn: Some(f),
+            errorfn,
+            history_size,
+            history_file,
+            headless_prompts,
         }
     }
+
+    /// Specify code to be run after an input fails to parse, compile, or evaluate.
+    ///
+    /// The closure supplies the error message, which is also written to the REPL output.
+    pub fn with_errorfn<F>(self, f: F) -> RunCallbacks<'a, D, T, U, V, F>
+    where
+        F: FnMut(&str),
+    {
+        let RunCallbacks {
+            data,
+            fmtrfn,
+            resultfn,
+            exitfn,
+            history_size,
+            history_file,
+            headless_prompts,
+            ..
+        } = self;
+        RunCallbacks {
+            data,
+            fmtrfn,
+            resultfn,
+            exitfn,
+            errorfn: Some(f),
+            history_size,
+            history_file,
+            headless_prompts,
+        }
+    }
+
+    /// Specify the number of input history entries to keep. Defaults to 100.
+    ///
+    /// A size of zero will not record any history.
+    pub fn with_history_size(mut self, size: usize) -> Self {
+        self.history_size = size;
+        self
+    }
+
+    /// Specify the file the input history is persisted to, or `None` to not persist history.
+    ///
+    /// Defaults to a `history` file inside the [compilation directory](ReplData::compilation_dir),
+    /// which is `$HOME/.papyrus/history` unless altered.
+    pub fn with_history_file(mut self, file: Option<PathBuf>) -> Self {
+        self.history_file = file.map(HistoryFile::Path).unwrap_or(HistoryFile::None);
+        self
+    }
+
+    /// Write the prompt and input lines when running [headless](Repl::run_headless), as a
+    /// transcript of the session. Defaults to `false`, only the REPL output is written.
+    pub fn with_headless_prompts(mut self, prompts: bool) -> Self {
+        self.headless_prompts = prompts;
+        self
+    }
+}
+
+/// Whether [`Repl::run`] runs [headless](Repl::run_headless), which is when stdin or stdout is not
+/// a terminal, such as when input is piped in.
+pub fn is_headless() -> bool {
+    use crossterm::tty::IsTty;
+    !io::stdin().is_tty() || !io::stdout().is_tty()
 }
 
 /// Available with the `runnable` feature and when the REPL is in the `Read` state.
 impl<D> Repl<Read, D> {
     /// Run the repl inside the terminal, consuming the repl. Returns the output of the REPL.
-    pub fn run<T, U, V>(self, run_callbacks: RunCallbacks<D, T, U, V>) -> io::Result<String>
+    ///
+    /// If stdin or stdout is not a terminal the repl is run [headless](Repl::run_headless) over
+    /// stdin and stdout, see [`is_headless`].
+    pub fn run<T, U, V, W>(self, run_callbacks: RunCallbacks<D, T, U, V, W>) -> io::Result<String>
     where
         T: FnMut(&Repl<Print, D>) -> kserd::fmt::FormattingConfig,
         U: FnMut(usize, kserd::Kserd<'static>, &Repl<Read, D>),
         V: FnOnce(&mut ReplData<D>, &mut D),
+        W: FnMut(&str),
     {
+        if is_headless() {
+            let stdin = io::stdin();
+            return self.run_headless(run_callbacks, stdin.lock(), io::stdout());
+        }
+
         run(self, run_callbacks, Screen::new).map_err(|e| map_xterm_err(e, "running REPL failed"))
     }
+
+    /// Run the repl without a terminal, consuming the repl. Returns the output of the REPL.
+    ///
+    /// Each line of `input` is read as if typed, until the input ends or the exit command. Only the
+    /// output of evaluations is written to `output`, without colours, unless
+    /// [prompts](RunCallbacks::with_headless_prompts) are enabled. Failed inputs can be handled with
+    /// [`with_errorfn`](RunCallbacks::with_errorfn), input ending within a block is a failure.
+    pub fn run_headless<T, U, V, W, R, O>(
+        self,
+        run_callbacks: RunCallbacks<D, T, U, V, W>,
+        input: R,
+        output: O,
+    ) -> io::Result<String>
+    where
+        T: FnMut(&Repl<Print, D>) -> kserd::fmt::FormattingConfig,
+        U: FnMut(usize, kserd::Kserd<'static>, &Repl<Read, D>),
+        V: FnOnce(&mut ReplData<D>, &mut D),
+        W: FnMut(&str),
+        R: BufRead,
+        O: Write + Send + 'static,
+    {
+        colored::control::set_override(false);
+        let r = run_headless(self, run_callbacks, input, output);
+        colored::control::unset_override();
+        r
+    }
 }
 
-fn run<D, FmtrFn, ResultFn, ExitFn>(
+fn run<D, FmtrFn, ResultFn, ExitFn, ErrorFn>(
     mut read: Repl<Read, D>,
-    mut runcb: RunCallbacks<D, FmtrFn, ResultFn, ExitFn>,
+    mut runcb: RunCallbacks<D, FmtrFn, ResultFn, ExitFn, ErrorFn>,
     screen_fn: impl FnOnce() -> io::Result<Screen>,
 ) -> xterm::Result<String>
 where
     FmtrFn: FnMut(&Repl<Print, D>) -> kserd::fmt::FormattingConfig,
     ResultFn: FnMut(usize, kserd::Kserd<'static>, &Repl<Read, D>),
     ExitFn: FnOnce(&mut ReplData<D>, &mut D),
+    ErrorFn: FnMut(&str),
 {
     // set a custom panic handler to dump to a file
     // must be done as the screen captures the io streams and will
@@ -238,8 +386,18 @@ where
     let cache = CacheWrapper;
     let mut reevaluate: Option<String> = None;
 
-    // must seed the history size, this is maintained as constant.
-    let mut history = std::collections::VecDeque::from(vec![String::default(); 100]); // 100 history
+    let history_file = match &runcb.history_file {
+        HistoryFile::CompilationDir => Some(read.data.compilation_dir().join("history")),
+        HistoryFile::Path(file) => Some(file.clone()),
+        HistoryFile::None => None,
+    };
+    let mut history = history_file
+        .and_then(|file| {
+            History::load(&file, runcb.history_size)
+                .map_err(|e| error!("failed to load history from {}: {}", file.display(), e))
+                .ok()
+        })
+        .unwrap_or_else(|| History::new(runcb.history_size));
 
     let output = loop {
         let mut interface = screen.begin_interface_input(&mut inputbuf, &mut history)?;
@@ -258,20 +416,20 @@ where
         } else if do_read(&mut read, &mut interface, &cache)? {
             break read.output().to_owned();
         }
+        // leave raw mode while evaluating so Ctrl+C raises an interrupt
+        drop(interface);
 
         match read.read() {
             ReadResult::Read(repl) => read = repl,
             ReadResult::Eval(repl) => {
-                match do_eval(repl, &mut runcb) {
+                let mut covered_lines = 0;
+                let write_chg = move |chg| {
+                    covered_lines = interface::write_output_chg(covered_lines, chg).unwrap_or(0);
+                };
+                let cancel = repl.data.cancel_handle();
+                match screen.watch_cancel(&cancel, || do_eval(repl, &mut runcb, write_chg)) {
                     (mut repl, Signal::Exit) => {
-                        // run exit function
-                        if let Some(exitfn) = runcb.exitfn {
-                            match runcb.data {
-                                Data::Sync(d, _) => exitfn(&mut repl.data, d),
-                                Data::Async(d, _) => exitfn(&mut repl.data, &mut d.lock().unwrap()),
-                            }
-                        }
-
+                        call_exitfn(&mut repl, &mut runcb);
                         break repl.output().to_owned();
                     }
                     (repl, signal) => {
@@ -293,6 +451,112 @@ where
     Ok(output)
 }
 
+fn run_headless<D, FmtrFn, ResultFn, ExitFn, ErrorFn, R, O>(
+    mut read: Repl<Read, D>,
+    mut runcb: RunCallbacks<D, FmtrFn, ResultFn, ExitFn, ErrorFn>,
+    input: R,
+    output: O,
+) -> io::Result<String>
+where
+    FmtrFn: FnMut(&Repl<Print, D>) -> kserd::fmt::FormattingConfig,
+    ResultFn: FnMut(usize, kserd::Kserd<'static>, &Repl<Read, D>),
+    ExitFn: FnOnce(&mut ReplData<D>, &mut D),
+    ErrorFn: FnMut(&str),
+    R: BufRead,
+    O: Write + Send + 'static,
+{
+    let output = Arc::new(Mutex::new(LineWriter {
+        wtr: output,
+        line: String::new(),
+    }));
+    let mut lines = input.lines();
+    let mut reevaluate: Option<String> = None;
+
+    loop {
+        if let Some(val) = reevaluate.take() {
+            read.line_input(&val);
+        } else {
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => break,
+            };
+            if runcb.headless_prompts {
+                let mut output = output.lock().expect("output lock poisoned");
+                writeln!(output.wtr, "{}{}", read.prompt(false), line)?;
+            }
+            read.line_input(&line);
+        }
+
+        match read.read() {
+            ReadResult::Read(repl) => read = repl,
+            ReadResult::Eval(repl) => {
+                let wtr = Arc::clone(&output);
+                let write_chg = move |chg| {
+                    wtr.lock()
+                        .expect("output lock poisoned")
+                        .write_chg(chg)
+                        .ok();
+                };
+                match do_eval(repl, &mut runcb, write_chg) {
+                    (mut repl, Signal::Exit) => {
+                        call_exitfn(&mut repl, &mut runcb);
+                        return Ok(repl.output().to_owned());
+                    }
+                    (repl, signal) => {
+                        if let Signal::ReEvaluate(s) = signal {
+                            reevaluate = Some(s);
+                        }
+                        read = repl;
+                    }
+                }
+            }
+        }
+    }
+
+    // the input ended without finishing a block
+    if !read.input_buffer().trim().is_empty() {
+        let msg = "unexpected end of input";
+        writeln!(output.lock().expect("output lock poisoned").wtr, "{}", msg)?;
+        if let Some(f) = &mut runcb.errorfn {
+            f(msg);
+        }
+    }
+
+    Ok(read.output().to_owned())
+}
+
+/// Writes whole lines of output, the current line is a prompt when reading input.
+struct LineWriter<W> {
+    wtr: W,
+    line: String,
+}
+
+impl<W: Write> LineWriter<W> {
+    fn write_chg(&mut self, chg: OutputChange) -> io::Result<()> {
+        match chg {
+            OutputChange::CurrentLine(line) => self.line = line,
+            OutputChange::NewLine => {
+                writeln!(self.wtr, "{}", self.line)?;
+                self.line.clear();
+                self.wtr.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn call_exitfn<D, T, U, V, W>(repl: &mut Repl<Read, D>, runcb: &mut RunCallbacks<D, T, U, V, W>)
+where
+    V: FnOnce(&mut ReplData<D>, &mut D),
+{
+    if let Some(exitfn) = runcb.exitfn.take() {
+        match &mut runcb.data {
+            Data::Sync(d, _) => exitfn(&mut repl.data, d),
+            Data::Async(d, _) => exitfn(&mut repl.data, &mut d.lock().unwrap()),
+        }
+    }
+}
+
 fn construct_crash_report(
     mut content_buf: Vec<u8>,
     app_name: &str,
@@ -374,18 +638,39 @@ fn do_read<D>(
     let prompt = repl.prompt(true);
     let verbatim_prompt = format!("{}\u{1b}[44m ", &prompt[..prompt.len() - 1]);
 
+    let mut prompt_set = None;
+
     loop {
-        if verbatim_mode {
-            interface.set_prompt(&verbatim_prompt);
-        } else {
-            interface.set_prompt(&prompt);
+        // only set the prompt on mode changes, setting moves the position to the end of the block
+        if prompt_set != Some(verbatim_mode) {
+            if verbatim_mode {
+                interface.set_prompt(&verbatim_prompt);
+            } else {
+                interface.set_prompt(&prompt);
+            }
+            interface.flush_buffer()?;
+            prompt_set = Some(verbatim_mode);
         }
-        interface.flush_buffer()?;
 
         let ev = interface.read_until(STOPEVENTS)?;
 
         match (ev, verbatim_mode) {
-            (ENTER, false) | (STOP_VERBATIM_MODE, true) => {
+            (ENTER, false) => {
+                // the pending input is edited as one block, only submit once complete
+                let block = interface.buffer();
+                repl.line_input(&block);
+                if repl.more_input_required() {
+                    interface.insert_newline();
+                    interface.redraw()?;
+                    continue;
+                }
+                interface.add_history(block);
+                interface.mv_bufpos_end();
+                interface.writeln("");
+                interface.flush_buffer()?;
+                break Ok(false);
+            }
+            (STOP_VERBATIM_MODE, true) => {
                 let line = interface.buffer();
                 repl.line_input(&line);
                 interface.add_history(line);
@@ -415,7 +700,10 @@ fn do_read<D>(
                     } else {
                         #[cfg(feature = "racer-completion")]
                         let c = {
-                            let injection = format!("{}\n{}", repl.input_buffer(), line);
+                            // input stacked from previous reads, the line overwrites the rest
+                            let input = repl.input_buffer();
+                            let stacked = &input[..input.len() - repl.input_buffer_line().len()];
+                            let injection = format!("{}{}", stacked, line);
                             complete_code(&codecmpltr, &cache.0, &injection, code_chpos)
                         };
 
@@ -486,28 +774,44 @@ fn complete_code(
         })
 }
 
-fn do_eval<D, FmtrFn, ResultFn, ExitFn>(
+fn do_eval<D, FmtrFn, ResultFn, ExitFn, ErrorFn, ChgFn>(
     mut repl: Repl<Evaluate, D>,
-    runcb: &mut RunCallbacks<D, FmtrFn, ResultFn, ExitFn>,
+    runcb: &mut RunCallbacks<D, FmtrFn, ResultFn, ExitFn, ErrorFn>,
+    mut write_chg: ChgFn,
 ) -> (Repl<Read, D>, Signal)
 where
     FmtrFn: FnMut(&Repl<Print, D>) -> kserd::fmt::FormattingConfig,
     ResultFn: FnMut(usize, kserd::Kserd<'static>, &Repl<Read, D>),
+    ErrorFn: FnMut(&str),
+    ChgFn: FnMut(OutputChange) + Send + 'static,
 {
     let rx = repl.output_listen();
+    let is_cmd = repl.is_command();
 
     let jh = std::thread::spawn(move || {
-        let mut covered_lines = 0;
         for chg in rx.iter() {
-            covered_lines = interface::write_output_chg(covered_lines, chg).unwrap_or(0);
+            write_chg(chg);
         }
     });
 
+    // Ctrl+C cancels the evaluation rather than ending the process
+    #[cfg(unix)]
+    let sigint = register_sigint(&repl.data.cancel_handle());
+
     let r = match &mut runcb.data {
         Data::Sync(d, evalfn) => evalfn(repl, d),
         Data::Async(d, evalfn) => evalfn(repl, &d),
     };
 
+    #[cfg(unix)]
+    if let Some(id) = sigint {
+        signal_hook::unregister(id);
+    }
+
+    if let (Some(e), Some(f)) = (r.repl.error(is_cmd), &mut runcb.errorfn) {
+        f(e);
+    }
+
     // prepare the formatter for output
     let fmt = runcb
         .fmtrfn
@@ -518,7 +822,7 @@ where
     let (mut read, signal) = {
         let (repl, signal) = (r.repl, r.signal);
         let (repl, result) = repl.print_with_formatting(fmt);
-        if let Some((idx, kserd)) = result {
+        if let Some((idx, kserd, _)) = result {
             if let Some(f) = &mut runcb.resultfn {
                 f(idx, kserd, &repl);
             }
@@ -532,6 +836,24 @@ where
     (read, signal)
 }
 
+/// Sets the cancel flag on `SIGINT`. If the flag is already set the process is aborted, so a
+/// second Ctrl+C still ends code which cannot be cancelled.
+#[cfg(unix)]
+fn register_sigint(cancel: &repl::CancelHandle) -> Option<signal_hook::SigId> {
+    use std::sync::atomic::Ordering;
+
+    let flag = Arc::clone(cancel.flag());
+    let action = move || {
+        if flag.swap(true, Ordering::SeqCst) {
+            std::process::abort();
+        }
+    };
+    // safety: the action only uses an atomic and abort, which are signal safe
+    unsafe { signal_hook::register(signal_hook::SIGINT, action) }
+        .map_err(|e| error!("failed to register Ctrl+C handler: {}", e))
+        .ok()
+}
+
 fn map_xterm_err(xtermerr: crossterm::ErrorKind, msg: &str) -> io::Error {
     match xtermerr {
         crossterm::ErrorKind::IoError(e) => e,
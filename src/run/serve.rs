@@ -0,0 +1,517 @@
+//! Serving REPL sessions over a socket, and a client to connect to them.
+use crate::prelude::*;
+use crate::rpc;
+use crossterm::QueueableCommand;
+use repl::Read;
+use serde_json::{json, Value};
+use std::io::{self, prelude::*, BufReader};
+use std::net::{Shutdown, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    mpsc, Arc, Mutex,
+};
+
+/// Prefix of a Unix socket address.
+const UNIX_PREFIX: &str = "unix:";
+
+type NewRepl<D> = dyn Fn() -> Repl<Read, D> + Send + Sync;
+
+/// Bind a server to `addr`, which accepts REPL sessions with [`run`](ReplServer::run).
+///
+/// `addr` is a TCP address such as `127.0.0.1:4000`, or a Unix socket path prefixed with `unix:`
+/// such as `unix:/tmp/papyrus.sock`.
+///
+/// Each connection is a separate REPL session, with its own [`ReplData`] and compilation directory,
+/// speaking the [`rpc`] protocol. Sessions share `app_data`, evaluating through
+/// [`eval_async`](Repl::eval_async).
+///
+/// # Security
+/// A session evaluates arbitrary code with the permissions of the serving process. Without
+/// [`with_token`](ReplServer::with_token) any client that can connect may do so, so
+/// [`run`](ReplServer::run) refuses to serve a TCP address which is not loopback unless a token is
+/// set. A Unix socket is guarded by its file permissions.
+///
+/// # Example
+/// ```rust,no_run
+/// # use std::sync::{Arc, Mutex};
+/// let server = papyrus::run::serve("127.0.0.1:4000", Arc::new(Mutex::new(())))
+///     .expect("failed to bind")
+///     .with_token("secret");
+/// std::thread::spawn(move || server.run());
+/// ```
+pub fn serve<D>(addr: &str, app_data: Arc<Mutex<D>>) -> io::Result<ReplServer<D>>
+where
+    D: 'static + Send,
+{
+    Ok(ReplServer {
+        listener: Listener::bind(addr)?,
+        app_data,
+        token: None,
+        new_repl: Arc::new(Repl::default),
+        sessions: Arc::new(AtomicUsize::new(0)),
+    })
+}
+
+/// A server accepting REPL sessions, see [`serve`].
+pub struct ReplServer<D> {
+    listener: Listener,
+    app_data: Arc<Mutex<D>>,
+    token: Option<String>,
+    new_repl: Arc<NewRepl<D>>,
+    sessions: Arc<AtomicUsize>,
+}
+
+impl<D> ReplServer<D>
+where
+    D: 'static + Send,
+{
+    /// Require clients to authenticate with `token` before any other request.
+    pub fn with_token<T: Into<String>>(mut self, token: T) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Create the REPL of each session with `f`, such as `|| repl!(MyData)` to access the app data
+    /// as `MyData`. Each session compiles within a `session-*` directory of the REPL's compilation
+    /// directory, which is removed when the session ends.
+    ///
+    /// Defaults to [`Repl::default`].
+    pub fn with_repl<F>(mut self, f: F) -> Self
+    where
+        F: Fn() -> Repl<Read, D> + Send + Sync + 'static,
+    {
+        self.new_repl = Arc::new(f);
+        self
+    }
+
+    /// The address the server is bound to, in the form accepted by [`serve`].
+    pub fn local_addr(&self) -> io::Result<String> {
+        match &self.listener {
+            Listener::Tcp(x) => x.local_addr().map(|x| x.to_string()),
+            #[cfg(unix)]
+            Listener::Unix(x) => x.local_addr().map(|x| {
+                let path = x.as_pathname().unwrap_or_else(|| Path::new(""));
+                format!("{}{}", UNIX_PREFIX, path.display())
+            }),
+        }
+    }
+
+    /// Accept connections, serving each session on its own thread. Blocks until accepting fails.
+    ///
+    /// Fails with [`PermissionDenied`](io::ErrorKind::PermissionDenied) if bound to a TCP address
+    /// which is not loopback without a token, see [`serve`](serve#security).
+    pub fn run(self) -> io::Result<()> {
+        if let Listener::Tcp(x) = &self.listener {
+            let addr = x.local_addr()?;
+            if self.token.is_none() && !addr.ip().is_loopback() {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!(
+                        "refusing to serve {} without a token, use `with_token` to set one",
+                        addr
+                    ),
+                ));
+            }
+            if self.token.is_none() {
+                warn!("serving {} without a token", addr);
+            }
+        }
+
+        loop {
+            let stream = self.listener.accept()?;
+            let session = Session {
+                app_data: Arc::clone(&self.app_data),
+                token: self.token.clone(),
+                new_repl: Arc::clone(&self.new_repl),
+                id: self.sessions.fetch_add(1, Ordering::SeqCst),
+            };
+            std::thread::spawn(move || session.serve(stream));
+        }
+    }
+}
+
+struct Session<D> {
+    app_data: Arc<Mutex<D>>,
+    token: Option<String>,
+    new_repl: Arc<NewRepl<D>>,
+    id: usize,
+}
+
+impl<D: 'static + Send> Session<D> {
+    fn serve(self, stream: Stream) -> io::Result<()> {
+        let mut repl = (self.new_repl)();
+        let dir =
+            repl.data
+                .compilation_dir()
+                .join(format!("session-{}-{}", std::process::id(), self.id));
+        repl.data.with_compilation_dir(&dir)?;
+
+        let r = self.serve_repl(repl, &stream);
+        stream.shutdown().ok();
+        std::fs::remove_dir_all(&dir).ok();
+        r
+    }
+
+    fn serve_repl(&self, repl: Repl<Read, D>, stream: &Stream) -> io::Result<()> {
+        let mut input = BufReader::new(stream.try_clone()?);
+        let mut output = stream.try_clone()?;
+
+        if let Some(token) = &self.token {
+            if !rpc::authenticate(&mut input, &mut output, token)? {
+                return Ok(());
+            }
+        }
+
+        rpc::serve(repl, &self.app_data, input, output)
+    }
+}
+
+/// Connect to a REPL server at `addr`, reading input from stdin and writing the REPL output to
+/// stdout. Returns when stdin ends or the REPL exits.
+///
+/// `addr` is in the form accepted by [`serve`]. Lines are sent for evaluation, incomplete input,
+/// such as an unclosed block, is sent with the following lines.
+pub fn connect(addr: &str, token: Option<&str>) -> io::Result<()> {
+    use crossterm::tty::IsTty;
+
+    let stdin = io::stdin();
+    // a terminal echoes the input, which is overwritten by the REPL output
+    let echoed = stdin.is_tty();
+    connect_with(addr, token, stdin.lock(), io::stdout(), echoed).map(|_| ())
+}
+
+fn connect_with<R, W>(
+    addr: &str,
+    token: Option<&str>,
+    input: R,
+    output: W,
+    echoed: bool,
+) -> io::Result<W>
+where
+    R: BufRead,
+    W: Write + Send + 'static,
+{
+    let stream = Stream::connect(addr)?;
+    let output = Arc::new(Mutex::new(output));
+    let (tx, responses) = mpsc::channel();
+    let reader = {
+        let stream = BufReader::new(stream.try_clone()?);
+        let output = Arc::clone(&output);
+        std::thread::spawn(move || print_output(stream, &output, tx))
+    };
+
+    let mut client = Client {
+        wtr: stream.try_clone()?,
+        responses,
+        id: 0,
+        output: Arc::clone(&output),
+        echoed,
+    };
+    let r = client.interact(token, input);
+    stream.shutdown().ok();
+    reader.join().expect("output thread panicked");
+    drop(client);
+
+    let output = Arc::try_unwrap(output)
+        .ok()
+        .expect("output is not shared")
+        .into_inner()
+        .expect("output lock poisoned");
+    r.map(|_| output)
+}
+
+struct Client<W> {
+    wtr: Stream,
+    responses: mpsc::Receiver<Value>,
+    id: u64,
+    output: Arc<Mutex<W>>,
+    echoed: bool,
+}
+
+impl<W: Write> Client<W> {
+    fn interact<R: BufRead>(&mut self, token: Option<&str>, input: R) -> io::Result<()> {
+        if let Some(token) = token {
+            self.request("authenticate", json!({ "token": token }))?;
+        }
+
+        let mut code = String::new();
+        for line in input.lines() {
+            let line = line?;
+            if !code.is_empty() {
+                code.push('\n');
+            }
+            code.push_str(&line);
+
+            if self.echoed {
+                // move up to the prompt line, the REPL output includes the input
+                let mut output = self.output.lock().expect("output lock poisoned");
+                let lines = code.lines().count().max(1) as u16;
+                output.queue(crossterm::cursor::MoveUp(lines)).ok();
+            }
+
+            let result = self.request("eval", json!({ "code": code }))?;
+            if result["exit"] == true {
+                break;
+            }
+            if result["status"] != "incomplete" {
+                code.clear();
+            }
+        }
+
+        Ok(())
+    }
+
+    fn request(&mut self, method: &str, params: Value) -> io::Result<Value> {
+        self.id += 1;
+        let req = json!({ "jsonrpc": "2.0", "id": self.id, "method": method, "params": params });
+        writeln!(self.wtr, "{}", req)?;
+        self.wtr.flush()?;
+
+        let mut res = self.responses.recv().map_err(|_| {
+            io::Error::new(io::ErrorKind::ConnectionAborted, "the server disconnected")
+        })?;
+
+        match res["error"]["message"].as_str() {
+            Some(msg) => Err(io::Error::new(io::ErrorKind::InvalidInput, msg)),
+            None => Ok(res["result"].take()),
+        }
+    }
+}
+
+/// Write the output notifications, sending the responses.
+fn print_output<R: BufRead, W: Write>(input: R, output: &Mutex<W>, tx: mpsc::Sender<Value>) {
+    for line in input.lines() {
+        let msg: Value = match line.map(|x| serde_json::from_str(&x)) {
+            Ok(Ok(msg)) => msg,
+            _ => break,
+        };
+
+        let mut output = output.lock().expect("output lock poisoned");
+        let output = &mut *output;
+        let params = &msg["params"];
+        let written = match msg["method"].as_str() {
+            Some("output") => match params["line"].as_str() {
+                Some(line) => {
+                    erase_console_line(output);
+                    write!(output, "{}", line)
+                }
+                None => writeln!(output),
+            },
+            Some(_) => Ok(()),
+            None => {
+                tx.send(msg).ok();
+                Ok(())
+            }
+        };
+
+        if written.and_then(|_| output.flush()).is_err() {
+            break;
+        }
+    }
+}
+
+fn erase_console_line<W: Write>(output: &mut W) {
+    use crossterm::{cursor, terminal};
+    output
+        .queue(terminal::Clear(terminal::ClearType::CurrentLine))
+        .and_then(|x| x.queue(cursor::MoveToColumn(0)))
+        .ok();
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    fn bind(addr: &str) -> io::Result<Self> {
+        match addr.strip_prefix(UNIX_PREFIX) {
+            #[cfg(unix)]
+            Some(path) => UnixListener::bind(path).map(Listener::Unix),
+            #[cfg(not(unix))]
+            Some(_) => Err(unix_unsupported()),
+            None => TcpListener::bind(addr).map(Listener::Tcp),
+        }
+    }
+
+    fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(x) => x.accept().map(|x| Stream::Tcp(x.0)),
+            #[cfg(unix)]
+            Listener::Unix(x) => x.accept().map(|x| Stream::Unix(x.0)),
+        }
+    }
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    fn connect(addr: &str) -> io::Result<Self> {
+        match addr.strip_prefix(UNIX_PREFIX) {
+            #[cfg(unix)]
+            Some(path) => UnixStream::connect(path).map(Stream::Unix),
+            #[cfg(not(unix))]
+            Some(_) => Err(unix_unsupported()),
+            None => TcpStream::connect(addr).map(Stream::Tcp),
+        }
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Stream::Tcp(x) => x.try_clone().map(Stream::Tcp),
+            #[cfg(unix)]
+            Stream::Unix(x) => x.try_clone().map(Stream::Unix),
+        }
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(x) => x.shutdown(Shutdown::Both),
+            #[cfg(unix)]
+            Stream::Unix(x) => x.shutdown(Shutdown::Both),
+        }
+    }
+}
+
+impl io::Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(x) => x.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(x) => x.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(x) => x.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(x) => x.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(x) => x.flush(),
+            #[cfg(unix)]
+            Stream::Unix(x) => x.flush(),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn unix_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        "unix sockets are not supported on this platform",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate as papyrus;
+
+    fn start<D: 'static + Send>(server: ReplServer<D>) -> String {
+        let addr = server.local_addr().unwrap();
+        std::thread::spawn(move || server.run());
+        addr
+    }
+
+    /// Send a request, returning the response and skipping the notifications.
+    fn request(stream: &mut BufReader<TcpStream>, id: u64, method: &str, params: Value) -> Value {
+        let req = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+        writeln!(stream.get_mut(), "{}", req).unwrap();
+        let mut line = String::new();
+        loop {
+            line.clear();
+            stream.read_line(&mut line).unwrap();
+            let msg: Value = serde_json::from_str(&line).unwrap();
+            if msg["id"] == id {
+                return msg;
+            }
+        }
+    }
+
+    #[test]
+    fn serve_sessions_test() {
+        let server = serve("127.0.0.1:0", Arc::new(Mutex::new(String::from("hello"))))
+            .unwrap()
+            .with_repl(|| {
+                let mut repl = repl!(String);
+                repl.data
+                    .with_compilation_dir("target/testing/serve_sessions_test")
+                    .unwrap();
+                repl
+            });
+        let addr = start(server);
+        let connect = || BufReader::new(TcpStream::connect(&addr).unwrap());
+        let mut a = connect();
+        let mut b = connect();
+
+        let res = request(&mut a, 1, "eval", json!({ "code": "app_data.len()" }));
+        assert_eq!(res["result"]["json"], 5);
+        let res = request(&mut a, 2, "listOutputs", Value::Null);
+        assert_eq!(res["result"].as_array().unwrap().len(), 1);
+
+        // sessions have their own modules
+        let res = request(&mut b, 1, "listOutputs", Value::Null);
+        assert_eq!(res["result"], json!([]));
+        let res = request(&mut b, 2, "switchModule", json!({ "path": "b" }));
+        assert_eq!(res["result"]["module"], "b");
+        let res = request(&mut a, 3, "listOutputs", Value::Null);
+        assert_eq!(res["result"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn refuse_non_loopback_without_token_test() {
+        let server = serve("0.0.0.0:0", Arc::new(Mutex::new(()))).unwrap();
+        let e = server.run().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn connect_test() {
+        let server = serve("127.0.0.1:0", Arc::new(Mutex::new(())))
+            .unwrap()
+            .with_token("secret");
+        let addr = start(server);
+
+        let input = ":help\n:exit\n2+2\n".as_bytes();
+        let output = connect_with(&addr, Some("secret"), input, Vec::new(), false).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("papyrus=>"));
+        assert!(output.contains("help -- prints the help messages"));
+
+        let e = connect_with(&addr, Some("secreT"), input, Vec::new(), false).unwrap_err();
+        assert_eq!(e.to_string(), "invalid token");
+        let e = connect_with(&addr, None, input, Vec::new(), false).unwrap_err();
+        assert_eq!(e.to_string(), "expecting an authenticate request");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn serve_unix_test() {
+        let path = "target/testing/serve_unix_test.sock";
+        std::fs::create_dir_all("target/testing").unwrap();
+        std::fs::remove_file(path).ok();
+
+        let server = serve(&format!("unix:{}", path), Arc::new(Mutex::new(()))).unwrap();
+        let addr = start(server);
+        assert_eq!(addr, format!("unix:{}", path));
+
+        let output = connect_with(&addr, None, ":help\n".as_bytes(), Vec::new(), false).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("help -- prints the help messages"));
+    }
+}
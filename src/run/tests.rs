@@ -62,8 +62,8 @@ fn cusor_positions() {
     slp();
     assert_eq!(
         col(),
-        21,
-        "expecting cursor to be after '[lib] papyrus.> apple'"
+        5,
+        "expecting cursor to be after 'apple' on the second line of the block"
     );
 
     tx.enter();
@@ -141,13 +141,12 @@ fn test_cursor_moving() {
 
     tx.enter();
     slp();
-    assert_eq!(col(), 16, "cursor should be after prompt");
+    assert_eq!(col(), 0, "cursor should be on a new line in the block");
 
+    tx.ctrl('c');
     let result = finish_repl(jh, tx);
     println!("{}", result);
-    let expected = "[lib] papyrus=> let apple = 1;
-[lib] papyrus.> :exit
-[lib] papyrus=> ";
+    let expected = "[lib] papyrus=> let apple = 1;";
     assert_eq!(result, expected);
 }
 
@@ -181,16 +180,120 @@ fn test_input_inside_line() {
 
     tx.enter();
     slp();
-    assert_eq!(col(), 16, "cursor should be after prompt");
+    assert_eq!(col(), 0, "cursor should be on a new line in the block");
 
+    tx.ctrl('c');
     let result = finish_repl(jh, tx);
     println!("{}", result);
-    let expected = "[lib] papyrus=> let banana = 1;
-[lib] papyrus.> :exit
+    let expected = "[lib] papyrus=> let banana = 1;";
+    assert_eq!(result, expected);
+}
+
+#[test]
+#[cfg(feature = "test-runnable")]
+fn edit_multiline_block() {
+    colour_off();
+    let (tx, rx) = unbounded();
+    let tx = Tx(tx);
+    let jh = fire_off_run(rx);
+
+    tx.text("fn a() -> u8 {").enter();
+    slp();
+    assert_eq!(col(), 4, "auto-indented");
+
+    tx.text("1").alt_enter().text("}");
+    slp();
+    assert_eq!(col(), 1, "closing brace dedents");
+
+    // go back and edit the body of the block, submitting from the middle line
+    tx.up(1).right(4).backspace(1).text("2");
+    slp();
+    assert_eq!(col(), 5, "after the edited '2'");
+    tx.enter().text("a()").enter();
+
+    let result = finish_repl(jh, tx);
+    println!("{}", result);
+    let expected = "[lib] papyrus=> fn a() -> u8 {
+    2
+}
+[lib] papyrus=> a()
+papyrus [out0]: 2
+[lib] papyrus=> :exit
 [lib] papyrus=> ";
     assert_eq!(result, expected);
 }
 
+#[test]
+#[cfg(feature = "test-runnable")]
+fn ctrl_c_cancels_evaluation() {
+    colour_off();
+    let (tx, rx) = unbounded();
+    let tx = Tx(tx);
+    let jh = fire_off_run(rx);
+
+    tx.text("1").enter();
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    // keys typed while evaluating are kept for the next input
+    tx.ctrl('c').text("2").enter();
+    slp();
+
+    let result = finish_repl(jh, tx);
+    println!("{}", result);
+    assert!(result.contains("compilation cancelled"), "{}", result);
+    assert!(
+        result.ends_with(
+            "[lib] papyrus=> 2
+papyrus [out0]: 2
+[lib] papyrus=> :exit
+[lib] papyrus=> "
+        ),
+        "{}",
+        result
+    );
+}
+
+// HEADLESS TESTS -------------------------------------------------------------
+#[test]
+fn headless_transcript() {
+    let output = SharedBuf::default();
+    let mut errors = Vec::new();
+    let input = ":help\n:mod switch a\n:exit\n:help\n";
+
+    let repl = crate::repl::Repl::<_, ()>::default();
+    let app_data = &mut ();
+    let runcb = RunCallbacks::new(app_data)
+        .with_errorfn(|e| errors.push(e.to_string()))
+        .with_headless_prompts(true);
+    repl.run_headless(runcb, input.as_bytes(), output.clone())
+        .unwrap();
+
+    let output = output.to_string();
+    assert!(output.starts_with("[lib] papyrus=> :help\nhelp -- prints the help messages\n"));
+    assert!(output.ends_with("[lib] papyrus=> :mod switch a\n[a] papyrus=> :exit\n"));
+    assert!(!output.contains('\u{1b}'), "expecting no colours");
+    assert_eq!(errors, Vec::<String>::new());
+}
+
+#[test]
+fn headless_failures() {
+    let output = SharedBuf::default();
+    let mut errors = Vec::new();
+    let input = "let = 1\nfn a() {\n";
+
+    let repl = crate::repl::Repl::<_, ()>::default();
+    let app_data = &mut ();
+    let runcb = RunCallbacks::new(app_data).with_errorfn(|e| errors.push(e.to_string()));
+    repl.run_headless(runcb, input.as_bytes(), output.clone())
+        .unwrap();
+
+    assert_eq!(errors.len(), 2);
+    assert_eq!(errors[1], "unexpected end of input");
+    let output = output.to_string();
+    assert!(!output.contains("papyrus=>"), "expecting no prompts");
+    assert!(output.contains(&errors[0]));
+    assert!(output.ends_with("unexpected end of input\n"));
+}
+
 // INTERFACE INTEGRATION TESTS ------------------------------------------------
 #[test]
 #[cfg(feature = "test-runnable")]
@@ -198,10 +301,10 @@ fn interface_integration() {
     let (tx, rx) = unbounded();
     let tx = Tx(tx);
     let mut inputbuf = InputBuffer::new();
-    let mut screen = Screen(rx);
+    let mut screen = Screen::with_receiver(rx);
     writeln!(io::stdout()).unwrap();
     slp();
-    let mut history = std::collections::VecDeque::from(vec![String::default(); 2]);
+    let mut history = super::history::History::new(2);
     let mut interface = screen
         .begin_interface_input(&mut inputbuf, &mut history)
         .unwrap();
@@ -259,6 +362,26 @@ fn interface_integration() {
     assert_eq!(interface.buf_pos(), 0);
 }
 
+/// Output shared with the headless run.
+#[derive(Clone, Default)]
+struct SharedBuf(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for SharedBuf {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&String::from_utf8_lossy(&self.0.lock().unwrap()))
+    }
+}
+
 struct Tx(Sender<Event>);
 
 impl Tx {
@@ -300,6 +423,11 @@ impl Tx {
         self
     }
 
+    fn alt_enter(&self) -> &Self {
+        self.send(Event::Key(KeyEvent::new(KeyCode::Enter, KeyModifiers::ALT)));
+        self
+    }
+
     fn backspace(&self, n: usize) -> &Self {
         for _ in 0..n {
             self.send(Event::Key(KeyEvent::new(
@@ -362,9 +490,13 @@ fn colour_off() {
 
 fn fire_off_run(rx: Receiver<Event>) -> JoinHandle<Result<String>> {
     std::thread::spawn(|| {
-        let screen = Screen(rx);
+        let screen = Screen::with_receiver(rx);
         let repl = crate::repl::Repl::<_, ()>::default();
-        run(repl, RunCallbacks::new(&mut ()), || Ok(screen))
+        run(
+            repl,
+            RunCallbacks::new(&mut ()).with_history_file(None),
+            || Ok(screen),
+        )
     })
 }
 
@@ -0,0 +1,518 @@
+//! Running rust source code as a script, outside of the interactive REPL.
+//!
+//! A script is parsed, built, compiled, and executed in the same fashion as a single REPL input.
+//! The script arguments are passed through as the app data, so the script can access them through
+//! `app_data: &Vec<String>`.
+//!
+//! If the script only defines a `fn main()`, it will be invoked.
+//!
+//! ```sh
+//! papyrus run script.rs arg1 arg2
+//! ```
+//!
+//! # Shebang and Dependencies
+//! A script can be made executable by starting it with `#!/usr/bin/env papyrus`. Dependencies are
+//! declared in the leading `//!` comment block using a `[dependencies]` table, with each entry on
+//! a single line:
+//!
+//! ```text
+//! #!/usr/bin/env papyrus
+//! //! Prints a random number.
+//! //!
+//! //! [dependencies]
+//! //! rand = "0.7"
+//! //! serde = { version = "1.0", features = ["derive"] }
+//! rand::random::<u8>()
+//! ```
+//!
+//! The compiled script is cached in a directory named by the hash of the script, so running an
+//! unchanged script skips compilation.
+use crate::{
+    code::{self, CrateSource, CrateType, ModsMap, SourceCode, Statement, StaticFiles, StmtGrp},
+    compile::{self, CompilationError},
+    input::{self, InputResult},
+    linking::LinkingConfiguration,
+};
+use kserd::Kserd;
+use std::{
+    error, fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+/// The name of the cached library file in a script's compile directory.
+const CACHED_LIB: &str = "papyrus.script.lib";
+
+/// The data type the script arguments are passed through as.
+pub const ARGS_TYPE: &str = "Vec<String>";
+
+/// The default directory scripts are compiled in, `$HOME/.papyrus/scripts`.
+pub fn default_script_dir() -> PathBuf {
+    crate::repl::default_compile_dir().join("scripts")
+}
+
+/// Errors from running a script.
+#[derive(Debug)]
+pub enum ScriptError {
+    /// An io error occurred.
+    Io(io::Error),
+    /// The script could not be parsed.
+    Parse(String),
+    /// The script failed to compile.
+    Compile(CompilationError),
+    /// The script returned an error or panicked.
+    Eval(String),
+}
+
+impl ScriptError {
+    /// The process exit code to use for the error.
+    ///
+    /// Parsing and compilation errors exit with `2`, runtime errors exit with `1`.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ScriptError::Parse(_) | ScriptError::Compile(_) => 2,
+            ScriptError::Io(_) | ScriptError::Eval(_) => 1,
+        }
+    }
+}
+
+impl error::Error for ScriptError {}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScriptError::Io(e) => write!(f, "an io error occurred: {}", e),
+            ScriptError::Parse(e) => write!(f, "failed to parse script: {}", e),
+            ScriptError::Compile(e) => write!(f, "failed to compile script:\n{}", e),
+            ScriptError::Eval(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Strips the shebang and leading `//!` comment block of a script, returning the remaining source
+/// and the dependencies declared in the `[dependencies]` table.
+///
+/// The stripped lines are replaced with empty lines so line numbers are preserved.
+pub fn parse_header(src: &str) -> Result<(String, Vec<CrateType>), ScriptError> {
+    let mut lines = src.lines().peekable();
+    let mut stripped = String::with_capacity(src.len());
+    let mut deps = Vec::new();
+
+    if let Some(line) = lines.peek() {
+        let is_shebang = line
+            .strip_prefix("#!")
+            .map(|x| !x.trim_start().starts_with('['))
+            .unwrap_or(false);
+        if is_shebang {
+            lines.next();
+            stripped.push('\n');
+        }
+    }
+
+    let mut in_deps = false;
+    while let Some(line) = lines.peek() {
+        let line = match line.trim_start().strip_prefix("//!") {
+            Some(x) => x.trim(),
+            None => break,
+        };
+        lines.next();
+        stripped.push('\n');
+
+        let entry = if let Some(table) = line.strip_prefix('[') {
+            let (name, rest) = table.split_once(']').ok_or_else(|| {
+                ScriptError::Parse(format!("manifest table is not closed: `{}`", line))
+            })?;
+            if name.trim() != "dependencies" {
+                return Err(ScriptError::Parse(format!(
+                    "unsupported manifest table `[{}]`, only `[dependencies]` is supported",
+                    name.trim()
+                )));
+            }
+            in_deps = true;
+            rest.trim()
+        } else if in_deps {
+            line
+        } else {
+            "" // doc comment
+        };
+
+        if !entry.is_empty() {
+            deps.push(parse_dependency(entry)?);
+        }
+    }
+
+    for line in lines {
+        stripped.push_str(line);
+        stripped.push('\n');
+    }
+
+    Ok((stripped, deps))
+}
+
+fn parse_dependency(entry: &str) -> Result<CrateType, ScriptError> {
+    let invalid = |e: String| ScriptError::Parse(format!("invalid dependency `{}`: {}", entry, e));
+
+    let (name, spec) = entry
+        .split_once('=')
+        .map(|(n, s)| (n.trim().trim_matches('"'), s.trim()))
+        .filter(|(n, s)| {
+            !n.is_empty()
+                && n.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+                && !s.is_empty()
+        })
+        .ok_or_else(|| invalid("expecting `name = specification`".into()))?;
+
+    let mut c = CrateType {
+        src_line: String::new(),
+        cargo_name: name.to_string(),
+        version: None,
+        features: Vec::new(),
+        default_features: true,
+        source: None,
+    };
+
+    let mut parser = TomlParser(spec);
+    let value = parser.value().map_err(invalid)?;
+    if !parser.0.trim().is_empty() {
+        return Err(invalid(format!("unexpected `{}`", parser.0.trim())));
+    }
+
+    match value {
+        TomlValue::Str(v) => c.version = Some(v),
+        TomlValue::Table(fields) => {
+            for (key, value) in fields {
+                match (key.as_str(), value) {
+                    ("version", TomlValue::Str(v)) => c.version = Some(v),
+                    ("features", TomlValue::Array(v)) => {
+                        for f in v {
+                            match f {
+                                TomlValue::Str(f) => c.features.push(f),
+                                _ => return Err(invalid("features must be strings".into())),
+                            }
+                        }
+                    }
+                    ("default-features", TomlValue::Bool(v))
+                    | ("default_features", TomlValue::Bool(v)) => c.default_features = v,
+                    ("git", TomlValue::Str(v)) => c.source = Some(CrateSource::Git(v)),
+                    ("path", TomlValue::Str(v)) => c.source = Some(CrateSource::Path(v.into())),
+                    (key, _) => {
+                        return Err(invalid(format!("unsupported or invalid key `{}`", key)))
+                    }
+                }
+            }
+        }
+        _ => return Err(invalid("expecting a version string or inline table".into())),
+    }
+
+    Ok(c)
+}
+
+/// The subset of TOML values used in dependency specifications.
+enum TomlValue {
+    Str(String),
+    Bool(bool),
+    Array(Vec<TomlValue>),
+    Table(Vec<(String, TomlValue)>),
+}
+
+/// Parses single line TOML values, holding the remaining input.
+struct TomlParser<'a>(&'a str);
+
+impl<'a> TomlParser<'a> {
+    fn skip_ws(&mut self) {
+        self.0 = self.0.trim_start();
+    }
+
+    fn eat(&mut self, ch: char) -> bool {
+        self.skip_ws();
+        match self.0.strip_prefix(ch) {
+            Some(rest) => {
+                self.0 = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn value(&mut self) -> Result<TomlValue, String> {
+        self.skip_ws();
+        if self.0.starts_with('"') {
+            self.string().map(TomlValue::Str)
+        } else if self.eat('[') {
+            let mut v = Vec::new();
+            while !self.eat(']') {
+                v.push(self.value()?);
+                if !self.eat(',') && !self.0.starts_with(']') {
+                    return Err("expecting `,` or `]` in array".into());
+                }
+            }
+            Ok(TomlValue::Array(v))
+        } else if self.eat('{') {
+            let mut v = Vec::new();
+            while !self.eat('}') {
+                self.skip_ws();
+                let key = if self.0.starts_with('"') {
+                    self.string()?
+                } else {
+                    let end = self
+                        .0
+                        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+                        .unwrap_or(self.0.len());
+                    let (key, rest) = self.0.split_at(end);
+                    self.0 = rest;
+                    key.to_string()
+                };
+                if key.is_empty() || !self.eat('=') {
+                    return Err("expecting `key = value` in inline table".into());
+                }
+                v.push((key, self.value()?));
+                if !self.eat(',') && !self.0.trim_start().starts_with('}') {
+                    return Err("expecting `,` or `}` in inline table".into());
+                }
+            }
+            Ok(TomlValue::Table(v))
+        } else if let Some(rest) = self.0.strip_prefix("true") {
+            self.0 = rest;
+            Ok(TomlValue::Bool(true))
+        } else if let Some(rest) = self.0.strip_prefix("false") {
+            self.0 = rest;
+            Ok(TomlValue::Bool(false))
+        } else {
+            Err(format!("unexpected `{}`", self.0))
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let mut s = String::new();
+        let mut chars = self.0.char_indices().skip(1); // opening quote
+        while let Some((i, ch)) = chars.next() {
+            match ch {
+                '"' => {
+                    self.0 = &self.0[i + 1..];
+                    return Ok(s);
+                }
+                '\\' => match chars.next() {
+                    Some((_, '"')) => s.push('"'),
+                    Some((_, '\\')) => s.push('\\'),
+                    Some((_, 'n')) => s.push('\n'),
+                    Some((_, 't')) => s.push('\t'),
+                    _ => return Err("unsupported escape sequence in string".into()),
+                },
+                ch => s.push(ch),
+            }
+        }
+        Err("string is not closed".into())
+    }
+}
+
+/// Parse `src` into a modules map, ready to be built.
+///
+/// The script header is stripped and declared dependencies are added to the `lib` module.
+///
+/// A trailing statement which ends with a semi-colon, or no statements at all, is treated as
+/// returning `()`.
+pub fn parse_script(src: &str) -> Result<ModsMap, ScriptError> {
+    let (src, deps) = parse_header(src)?;
+
+    let input = match input::parse_program(&src) {
+        InputResult::Program(input) => input,
+        InputResult::Empty => code::Input {
+            items: Vec::new(),
+            stmts: Vec::new(),
+            crates: Vec::new(),
+            mods: Vec::new(),
+        },
+        InputResult::More => return Err(ScriptError::Parse("unexpected end of input".into())),
+        InputResult::InputError(e) => return Err(ScriptError::Parse(e)),
+        InputResult::Command(_) | InputResult::Eof => {
+            return Err(ScriptError::Parse("script is not valid rust code".into()))
+        }
+    };
+
+    let code::Input {
+        items,
+        mut stmts,
+        mut crates,
+        mods,
+    } = input;
+
+    crates.extend(deps);
+
+    if stmts.is_empty() && items.iter().any(|(item, _)| is_main_fn(item)) {
+        stmts.push(Statement {
+            expr: "main()".into(),
+            semi: false,
+        });
+    } else if stmts.last().map(|x| x.semi).unwrap_or(true) {
+        stmts.push(Statement {
+            expr: "()".into(),
+            semi: false,
+        });
+    }
+
+    let lib = PathBuf::from("lib");
+    let mut mods_map = ModsMap::new();
+    mods_map.insert(
+        lib.clone(),
+        SourceCode {
+            items,
+            crates,
+            stmts: vec![StmtGrp(stmts)],
+            vis: String::new(),
+        },
+    );
+    code::insert_mods(&mut mods_map, &lib, mods, &mut |_, _| ());
+
+    Ok(mods_map)
+}
+
+fn is_main_fn(item: &str) -> bool {
+    let item = item.trim_start();
+    let item = item.strip_prefix("pub ").unwrap_or(item);
+    item.strip_prefix("fn ")
+        .map(|x| x.trim_start().starts_with("main(") || x.trim_start().starts_with("main ("))
+        .unwrap_or(false)
+}
+
+/// The compile directory for the script source code, `cache_dir/<hash>`.
+pub fn script_dir<P: AsRef<Path>>(cache_dir: P, src: &str) -> PathBuf {
+    let hash = fxhash::hash64(&(env!("CARGO_PKG_VERSION"), ARGS_TYPE, src));
+    cache_dir.as_ref().join(format!("{:016x}", hash))
+}
+
+/// Run the script source code, caching the build in a [`script_dir`] of `cache_dir`.
+///
+/// If the script has previously been compiled, the cached library is executed without compiling.
+/// `args` are passed through to the script as `app_data: &Vec<String>`. Returns the value of the
+/// final expression.
+pub fn run_script<P: AsRef<Path>>(
+    cache_dir: P,
+    src: &str,
+    args: Vec<String>,
+) -> Result<Kserd<'static>, ScriptError> {
+    let compile_dir = script_dir(cache_dir, src);
+    let cached = compile_dir.join(CACHED_LIB);
+
+    if !cached.exists() {
+        let mods_map = parse_script(src)?;
+        let linking = unsafe { LinkingConfiguration::default().with_data(ARGS_TYPE) };
+
+        compile::build_compile_dir(&compile_dir, &mods_map, &linking, &StaticFiles::new())
+            .map_err(ScriptError::Io)?;
+
+        let lib_file =
+            compile::compile(&compile_dir, &linking, |_| ()).map_err(ScriptError::Compile)?;
+
+        // copy then rename so a partially written library is never cached
+        let tmp = compile_dir.join(format!("{}.tmp", CACHED_LIB));
+        fs::copy(lib_file, &tmp)
+            .and_then(|_| fs::rename(&tmp, &cached))
+            .map_err(ScriptError::Io)?;
+    }
+
+    let mut fn_name = String::new();
+    code::eval_fn_name(&code::into_mod_path_vec(Path::new("lib")), &mut fn_name);
+
+    compile::exec(&cached, &fn_name, &args, None::<&mut std::io::Sink>)
+        .map(|(kserd, _, _lib)| kserd)
+        .map_err(|e| ScriptError::Eval(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_script_test() {
+        let lib = |s: &str| parse_script(s).unwrap().remove(Path::new("lib")).unwrap();
+
+        let src = lib("let a = 1;\nlet b = 2;");
+        assert_eq!(src.stmts.len(), 1);
+        assert_eq!(src.stmts[0].src_line(), "let a = 1; let b = 2; ()");
+
+        let src = lib("fn main() {\n    println!(\"hi\");\n}");
+        assert_eq!(src.stmts[0].src_line(), "main()");
+        assert_eq!(src.items.len(), 1);
+
+        let src = lib("pub fn main() {}\nmain();\n1");
+        assert_eq!(src.stmts[0].src_line(), "main(); 1");
+
+        let src = lib("fn mainly() {}");
+        assert_eq!(src.stmts[0].src_line(), "()");
+
+        let mods = parse_script("mod a { pub fn b() {} }").unwrap();
+        assert!(mods.contains_key(Path::new("a")));
+
+        assert_eq!(lib("").stmts[0].src_line(), "()");
+
+        match parse_script("let a = ") {
+            Err(e) => assert_eq!(e.exit_code(), 2),
+            Ok(_) => panic!("expecting parse error"),
+        }
+    }
+
+    #[test]
+    fn parse_header_test() {
+        let src = "#!/usr/bin/env papyrus
+//! Some docs.
+//!
+//! [dependencies]
+//! rand = \"0.7\"
+//!   serde = { version = \"1.0\", features = [\"derive\"] }
+let a = 1;
+//! not header
+a";
+        let (s, deps) = parse_header(src).unwrap();
+        assert_eq!(s, "\n\n\n\n\n\nlet a = 1;\n//! not header\na\n");
+        assert_eq!(deps.len(), 2);
+        assert_eq!(deps[0].cargo_name, "rand");
+        assert_eq!(deps[0].dependency_spec(), "\"0.7\"");
+        assert_eq!(deps[1].cargo_name, "serde");
+        assert_eq!(
+            deps[1].dependency_spec(),
+            "{ version = \"1.0\", features = [\"derive\"] }"
+        );
+
+        let (s, deps) = parse_header("//! [dependencies] serde = \"1.0\"\n1").unwrap();
+        assert_eq!(s, "\n1\n");
+        assert_eq!(deps[0].cargo_name, "serde");
+
+        let (s, deps) = parse_header("#![allow(unused)]\n1").unwrap();
+        assert_eq!(s, "#![allow(unused)]\n1\n");
+        assert!(deps.is_empty());
+
+        assert!(parse_header("//! [package]").is_err());
+        assert!(parse_header("//! [dependencies").is_err());
+        assert!(parse_header("//! [dependencies]\n//! serde").is_err());
+        assert!(parse_header("//! [dependencies]\n//! a doc = comment").is_err());
+
+        let mods =
+            parse_script("#!/usr/bin/env papyrus\n//! [dependencies]\n//! rand = \"0.7\"\n1")
+                .unwrap();
+        assert_eq!(mods[Path::new("lib")].crates[0].cargo_name, "rand");
+    }
+
+    #[test]
+    fn run_script_test() {
+        let dir = "target/testing/run-script-test";
+        let src = "#!/usr/bin/env papyrus\napp_data.len() + 1";
+        let r = run_script(dir, src, vec!["a".into(), "b".into()]).unwrap();
+        assert_eq!(r, Kserd::new_num(3));
+        assert!(script_dir(dir, src).join(CACHED_LIB).exists());
+        // cached
+        let r = run_script(dir, src, vec!["a".into()]).unwrap();
+        assert_eq!(r, Kserd::new_num(2));
+
+        let dir = "target/testing/run-script-test-err";
+        let e = run_script(dir, "let x: u8 = \"a\".parse()?;\nx", vec![]).unwrap_err();
+        assert_eq!(e.exit_code(), 1);
+        assert_eq!(
+            e.to_string(),
+            "evaluation returned an error: invalid digit found in string"
+        );
+
+        let dir = "target/testing/run-script-test-compile";
+        let e = run_script(dir, "let x: u8 = \"a\";\nx", vec![]).unwrap_err();
+        assert_eq!(e.exit_code(), 2);
+    }
+}
@@ -48,7 +48,7 @@ a
             assert_eq!(signal, Signal::None);
             let (repl, result_kserd) = repl.print();
             let expected_kserd = Kserd::new_str("Hello\nMultiline\nInput\n");
-            assert_eq!(result_kserd, Some((0, expected_kserd)));
+            assert_eq!(result_kserd, Some((0, expected_kserd, "&str".to_string())));
             repl
         }
     };
@@ -70,8 +70,503 @@ a
             assert_eq!(signal, Signal::None);
             let (repl, result_kserd) = repl.print();
             let expected_kserd = Kserd::new_str("Hello\nWorld!");
-            assert_eq!(result_kserd, Some((1, expected_kserd)));
+            assert_eq!(result_kserd, Some((1, expected_kserd, "&str".to_string())));
             repl
         }
     };
 }
+
+#[test]
+#[cfg(feature = "test-runnable")]
+fn persistent_evaluation() {
+    let mut repl = chg_compile_dir(repl!());
+    repl.data.with_persistent_evaluation(true);
+
+    let mut eval = |repl: Repl<repl::Read, ()>, input: &str| {
+        let mut repl = repl;
+        repl.line_input(input);
+        match repl.read() {
+            ReadResult::Read(_) => panic!("should be at Eval state!"),
+            ReadResult::Eval(repl) => {
+                let repl::EvalResult { repl, signal } = repl.eval(&mut ());
+                assert_eq!(signal, Signal::None);
+                repl.print()
+            }
+        }
+    };
+
+    // the counter is incremented each time the statement is executed
+    let (repl, r) = eval(
+        repl,
+        r#"let n: usize = std::env::var("PAPYRUS_PERSIST_TEST").map(|x| x.parse().unwrap()).unwrap_or(0) + 1;
+std::env::set_var("PAPYRUS_PERSIST_TEST", n.to_string());
+n"#,
+    );
+    assert_eq!(r, Some((0, Kserd::new_num(1), "usize".to_string())));
+
+    // only the new statement is executed
+    let (repl, r) = eval(repl, r#"std::env::var("PAPYRUS_PERSIST_TEST").unwrap()"#);
+    assert_eq!(
+        r,
+        Some((
+            1,
+            Kserd::new_string("1".to_string()),
+            "alloc::string::String".to_string()
+        ))
+    );
+    let (mut repl, r) = eval(repl, "out0 + n");
+    assert_eq!(r, Some((2, Kserd::new_num(2), "usize".to_string())));
+
+    // other errors are not retried without persistence
+    let progress = repl.data.compile_progress_listen();
+    let (repl, r) = eval(repl, "let y: u8 = \"a\"; y");
+    assert_eq!(r, None);
+    assert!(repl.output().contains("mismatched types"));
+    let builds = progress
+        .try_iter()
+        .filter(|x| x.contains("Compiling"))
+        .count();
+    assert_eq!(builds, 1);
+
+    // persisted values only live for the evaluation
+    let (repl, r) = eval(repl, "std::thread::spawn(move || n + 1).join().unwrap()");
+    assert_eq!(r, None);
+    assert!(repl.output().contains("escapes"), "{}", repl.output());
+
+    // borrowing a local can not be persisted, so statements are replayed
+    let (_, r) = eval(repl, "let x = &out0; x.clone()");
+    assert_eq!(r, Some((3, Kserd::new_num(2), "usize".to_string())));
+}
+
+#[test]
+#[cfg(feature = "test-runnable")]
+fn persist_command() {
+    let repl = chg_compile_dir(repl!());
+
+    let eval = |repl: Repl<repl::Read, ()>, input: &str| {
+        let mut repl = repl;
+        repl.line_input(input);
+        match repl.read() {
+            ReadResult::Read(_) => panic!("should be at Eval state!"),
+            ReadResult::Eval(repl) => {
+                let repl::EvalResult { repl, signal } = repl.eval(&mut ());
+                assert_eq!(signal, Signal::None);
+                repl.print()
+            }
+        }
+    };
+
+    let (repl, _) = eval(repl, ":persist on");
+    assert!(repl.data.persistent_evaluation());
+
+    let (repl, r) = eval(
+        repl,
+        r#"let n: usize = std::env::var("PAPYRUS_PERSIST_CMD_TEST").map(|x| x.parse().unwrap()).unwrap_or(0) + 1;
+std::env::set_var("PAPYRUS_PERSIST_CMD_TEST", n.to_string());
+n"#,
+    );
+    assert_eq!(r, Some((0, Kserd::new_num(1), "usize".to_string())));
+    let (repl, r) = eval(repl, "out0 + n");
+    assert_eq!(r, Some((1, Kserd::new_num(2), "usize".to_string())));
+
+    let (repl, _) = eval(repl, ":persist off");
+    assert!(!repl.data.persistent_evaluation());
+}
+
+#[test]
+#[cfg(feature = "test-runnable")]
+fn nested_pub_modules_are_reachable() {
+    let mut repl = chg_compile_dir(repl!());
+
+    repl.line_input("pub mod a { pub mod b { pub fn f() -> u8 { 1 } } }");
+    let repl = match repl.read() {
+        ReadResult::Read(_) => panic!("should be at Eval state!"),
+        ReadResult::Eval(repl) => repl.eval(&mut ()).repl.print().0,
+    };
+
+    let mut repl = repl;
+    repl.line_input("a::b::f()");
+    match repl.read() {
+        ReadResult::Read(_) => panic!("should be at Eval state!"),
+        ReadResult::Eval(repl) => {
+            let repl::EvalResult { repl, signal } = repl.eval(&mut ());
+            assert_eq!(signal, Signal::None);
+            let (_, r) = repl.print();
+            assert_eq!(r, Some((0, Kserd::new_num(1), "u8".to_string())));
+        }
+    }
+}
+
+#[test]
+#[cfg(feature = "test-runnable")]
+fn worker_process_survives_crash() {
+    let mut repl = chg_compile_dir(repl!());
+    repl.data
+        .with_execution_backend(papyrus::compile::ExecutionBackend::Worker {
+            program: env!("CARGO_BIN_EXE_papyrus").into(),
+        });
+
+    let eval = |mut repl: Repl<repl::Read, ()>, input: &str| {
+        repl.line_input(input);
+        match repl.read() {
+            ReadResult::Read(_) => panic!("should be at Eval state!"),
+            ReadResult::Eval(repl) => {
+                let repl::EvalResult { repl, signal } = repl.eval(&mut ());
+                assert_eq!(signal, Signal::None);
+                repl
+            }
+        }
+    };
+
+    let repl = eval(repl, "std::process::abort();\n1");
+    let (repl, r) = repl.print();
+    assert_eq!(r, None);
+    assert!(
+        repl.output().contains("evaluation crashed (SIGABRT)"),
+        "{}",
+        repl.output()
+    );
+
+    let repl = eval(repl, "vec![1, 2, 3]");
+    let (repl, r) = repl.print();
+    assert_eq!(
+        r,
+        Some((
+            0,
+            Kserd::new(Value::Seq(vec![
+                Kserd::new_num(1),
+                Kserd::new_num(2),
+                Kserd::new_num(3)
+            ])),
+            "alloc::vec::Vec<i32>".to_string()
+        ))
+    );
+
+    // output can not be mistaken for the result, and is flushed before the worker exits
+    let repl = eval(
+        repl,
+        r#"print!("\u{1e}papyrus-worker-result\u{1e}{{\"err\":\"fake\"}}");
+2"#,
+    );
+    let (repl, r) = repl.print();
+    assert_eq!(r, Some((1, Kserd::new_num(2), "i32".to_string())));
+    assert!(
+        repl.output()
+            .contains("papyrus-worker-result\u{1e}{\"err\":\"fake\"}"),
+        "{}",
+        repl.output()
+    );
+}
+
+#[test]
+#[cfg(feature = "test-runnable")]
+fn worker_evaluation_times_out_and_cancels() {
+    let mut repl = chg_compile_dir(repl!());
+    repl.data
+        .with_execution_backend(papyrus::compile::ExecutionBackend::Worker {
+            program: env!("CARGO_BIN_EXE_papyrus").into(),
+        })
+        .with_eval_timeout(Some(std::time::Duration::from_millis(500)));
+
+    let eval = |mut repl: Repl<repl::Read, ()>, input: &str| {
+        repl.line_input(input);
+        match repl.read() {
+            ReadResult::Read(_) => panic!("should be at Eval state!"),
+            ReadResult::Eval(repl) => {
+                let repl::EvalResult { repl, signal } = repl.eval(&mut ());
+                assert_eq!(signal, Signal::None);
+                repl
+            }
+        }
+    };
+
+    let repl = eval(repl, "loop {}\n1");
+    let (mut repl, r) = repl.print();
+    assert_eq!(r, None);
+    assert!(
+        repl.output().contains("evaluation timed out after 0.5s"),
+        "{}",
+        repl.output()
+    );
+
+    // cancel through the evaluating handle
+    repl.data.with_eval_timeout(None);
+    repl.line_input("loop {}\n1");
+    let repl = match repl.read() {
+        ReadResult::Read(_) => panic!("should be at Eval state!"),
+        ReadResult::Eval(repl) => {
+            let evaluating = repl.eval_async(&std::sync::Arc::new(std::sync::Mutex::new(())));
+            std::thread::sleep(std::time::Duration::from_millis(500));
+            assert!(!evaluating.completed());
+            evaluating.cancel();
+            evaluating.wait().repl
+        }
+    };
+    let (repl, r) = repl.print();
+    assert_eq!(r, None);
+    assert!(
+        repl.output().contains("evaluation cancelled"),
+        "{}",
+        repl.output()
+    );
+
+    // failed inputs are rolled back
+    let repl = eval(repl, "1");
+    let (_, r) = repl.print();
+    assert_eq!(r, Some((0, Kserd::new_num(1), "i32".to_string())));
+}
+
+#[test]
+#[cfg(feature = "test-runnable")]
+fn in_process_evaluation_rejects_timeout() {
+    let mut repl = chg_compile_dir(repl!());
+    repl.data
+        .with_eval_timeout(Some(std::time::Duration::from_millis(500)));
+
+    repl.line_input("loop {}\n1");
+    let repl = match repl.read() {
+        ReadResult::Read(_) => panic!("should be at Eval state!"),
+        ReadResult::Eval(repl) => repl.eval(&mut ()).repl,
+    };
+    let (repl, r) = repl.print();
+    assert_eq!(r, None);
+    assert!(
+        repl.output()
+            .contains("an evaluation timeout requires the worker execution backend"),
+        "{}",
+        repl.output()
+    );
+    assert!(repl.data.current_src().stmts.is_empty());
+}
+
+#[test]
+#[cfg(feature = "test-runnable")]
+fn captures_evaluation_output() {
+    use papyrus::output::OutputChange;
+
+    let mut repl = chg_compile_dir(repl!());
+
+    repl.line_input(
+        "println!(\"Hello, world!\");\neprintln!(\"oops\");\nprint!(\"no newline\");\n1",
+    );
+    let repl = match repl.read() {
+        ReadResult::Read(_) => panic!("should be at Eval state!"),
+        ReadResult::Eval(mut repl) => {
+            let rx = repl.output_listen();
+            let repl::EvalResult { repl, signal } = repl.eval(&mut ());
+            assert_eq!(signal, Signal::None);
+            let (mut repl, r) = repl.print();
+            assert_eq!(r, Some((0, Kserd::new_num(1), "i32".to_string())));
+            repl.close_channel();
+
+            // captured text is streamed as line changes
+            let lines: Vec<_> = rx
+                .iter()
+                .filter_map(|x| match x {
+                    OutputChange::CurrentLine(line) => Some(line),
+                    OutputChange::NewLine => None,
+                })
+                .collect();
+            assert!(lines.iter().any(|x| x == "Hello, world!"), "{:?}", lines);
+            repl
+        }
+    };
+    assert!(
+        repl.output().contains("Hello, world!\noops\nno newline"),
+        "{}",
+        repl.output()
+    );
+
+    // opting out leaves output on the process stdio
+    let mut repl = repl;
+    repl.data.with_capture_output(false);
+    repl.line_input("println!(\"not captured\");\n2");
+    let repl = match repl.read() {
+        ReadResult::Read(_) => panic!("should be at Eval state!"),
+        ReadResult::Eval(repl) => repl.eval(&mut ()).repl,
+    };
+    let (repl, r) = repl.print();
+    assert_eq!(r, Some((1, Kserd::new_num(2), "i32".to_string())));
+    assert!(
+        !repl.output().contains("\nnot captured"),
+        "{}",
+        repl.output()
+    );
+}
+
+#[test]
+#[cfg(feature = "test-runnable")]
+fn panic_is_reported_against_input() {
+    let eval = |mut repl: Repl<repl::Read, ()>, input: &str| {
+        repl.line_input(input);
+        match repl.read() {
+            ReadResult::Read(_) => panic!("should be at Eval state!"),
+            ReadResult::Eval(repl) => repl.eval(&mut ()).repl.print(),
+        }
+    };
+
+    let repl = chg_compile_dir(repl!());
+    let (repl, r) = eval(repl, "let x: Option<i32> = None;\nx.unwrap()");
+    assert_eq!(r, None);
+    assert!(
+        repl.output().contains(
+            "evaluation panicked: called `Option::unwrap()` on a `None` value\n --> [out0]\n  |\n  | x.unwrap()\n  |   ^"
+        ),
+        "{}",
+        repl.output()
+    );
+
+    // the panicking input is rolled back
+    let (_, r) = eval(repl, "1");
+    assert_eq!(r, Some((0, Kserd::new_num(1), "i32".to_string())));
+
+    // panics in a worker are reported the same
+    let mut repl = chg_compile_dir(repl!());
+    repl.data
+        .with_execution_backend(papyrus::compile::ExecutionBackend::Worker {
+            program: env!("CARGO_BIN_EXE_papyrus").into(),
+        });
+    let (repl, r) = eval(repl, "vec![1][2]");
+    assert_eq!(r, None);
+    assert!(
+        repl.output()
+            .contains("evaluation panicked: index out of bounds"),
+        "{}",
+        repl.output()
+    );
+    assert!(repl.output().contains(" --> [out0]"), "{}", repl.output());
+}
+
+#[test]
+#[cfg(feature = "test-runnable")]
+fn outputs_without_to_kserd_fall_back() {
+    let eval = |mut repl: Repl<repl::Read, ()>, input: &str| {
+        repl.line_input(input);
+        match repl.read() {
+            ReadResult::Read(_) => panic!("should be at Eval state!"),
+            ReadResult::Eval(repl) => repl.eval(&mut ()).repl.print(),
+        }
+    };
+    let text = |id: &'static str, s: &str| {
+        let mut k = Kserd::new_string(s.to_string());
+        k.id = Some(id.into());
+        Some(k)
+    };
+
+    let repl = chg_compile_dir(repl!());
+    let (repl, r) = eval(repl, "#[derive(Debug)] struct Point { x: i32 }");
+    assert_eq!(r, None);
+    let (repl, r) = eval(repl, "Point { x: 1 }");
+    assert_eq!(r.map(|x| x.1), text("fmt::Debug", "Point { x: 1 }"));
+    assert!(
+        repl.output().contains("[out0]: Point { x: 1 }"),
+        "{}",
+        repl.output()
+    );
+
+    let (repl, _) = eval(
+        repl,
+        "struct Text; impl std::fmt::Display for Text { fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result { write!(f, \"text\") } }",
+    );
+    let (repl, r) = eval(repl, "Text");
+    assert_eq!(r.map(|x| x.1), text("fmt::Display", "text"));
+
+    let (repl, r) = eval(repl, "struct Opaque; Opaque");
+    assert_eq!(r.map(|x| x.1), text("any::type_name", "Opaque"));
+    assert!(repl.output().contains("<Opaque>"), "{}", repl.output());
+
+    // values borrowed from the persistent store are rendered the same
+    let mut repl = chg_compile_dir(repl!());
+    repl.data.with_persistent_evaluation(true);
+    let (repl, r) = eval(repl, "vec![1]");
+    assert_eq!(
+        r.map(|x| x.1),
+        Some(Kserd::new(Value::Seq(vec![Kserd::new_num(1)])))
+    );
+    let (repl, r) = eval(repl, "std::sync::Mutex::new(1)");
+    assert_eq!(
+        r.map(|x| x.1),
+        text("fmt::Debug", "Mutex { data: 1, poisoned: false, .. }")
+    );
+    let (_, r) = eval(repl, "struct Opaque; Opaque");
+    assert_eq!(r.map(|x| x.1), text("any::type_name", "Opaque"));
+}
+
+#[test]
+#[cfg(feature = "test-runnable")]
+fn output_types_are_shown() {
+    let eval = |mut repl: Repl<repl::Read, ()>, input: &str| {
+        repl.line_input(input);
+        match repl.read() {
+            ReadResult::Read(_) => panic!("should be at Eval state!"),
+            ReadResult::Eval(repl) => repl.eval(&mut ()).repl.print(),
+        }
+    };
+
+    let mut repl = chg_compile_dir(repl!());
+    repl.data.with_show_types(true);
+    let (repl, r) = eval(repl, "let v = vec![1u8]; v");
+    assert_eq!(r.map(|x| x.2), Some("alloc::vec::Vec<u8>".to_string()));
+    assert!(
+        repl.output().contains("[out0]: alloc::vec::Vec<u8> = ["),
+        "{}",
+        repl.output()
+    );
+
+    // the type command evaluates without keeping the input
+    let (repl, r) = eval(repl, ":type out0.len()");
+    assert_eq!(r, None);
+    assert!(
+        repl.output().contains(":type out0.len()\nusize\n"),
+        "{}",
+        repl.output()
+    );
+    let (repl, r) = eval(repl, ":type nope");
+    assert_eq!(r, None);
+    assert!(
+        repl.output().contains("cannot find value `nope`"),
+        "{}",
+        repl.output()
+    );
+
+    let (_, r) = eval(repl, "out0[0]");
+    assert_eq!(r, Some((1, Kserd::new_num(1), "u8".to_string())));
+}
+
+#[test]
+fn mock_compiler_backend() {
+    use papyrus::compile::{CompilationError, MockBackend};
+    use std::sync::{Arc, Mutex};
+
+    let eval = |mut repl: Repl<repl::Read, ()>, input: &str| {
+        repl.line_input(input);
+        match repl.read() {
+            ReadResult::Read(_) => panic!("should be at Eval state!"),
+            ReadResult::Eval(repl) => repl.eval(&mut ()).repl.print(),
+        }
+    };
+
+    let sources = Arc::new(Mutex::new(Vec::new()));
+    let mut repl = chg_compile_dir(repl!());
+    let srcs = Arc::clone(&sources);
+    repl.data
+        .with_compiler_backend(MockBackend::new(move |files| {
+            let lib = files.iter().find(|x| x.0 == std::path::Path::new("lib.rs"));
+            srcs.lock().unwrap().push(lib.unwrap().1.clone());
+            Err(CompilationError::CompileError("mock failure".to_string()))
+        }));
+
+    let (repl, r) = eval(repl, "1+1");
+    assert_eq!(r, None);
+    assert!(repl.output().contains("mock failure"), "{}", repl.output());
+
+    // the failed input is rolled back
+    let (_, r) = eval(repl, "2+2");
+    assert_eq!(r, None);
+
+    let sources = sources.lock().unwrap();
+    assert_eq!(sources.len(), 2);
+    assert!(sources[0].contains("let out0 = 1 + 1;"), "{}", sources[0]);
+    assert!(sources[1].contains("let out0 = 2 + 2;"));
+    assert!(!sources[1].contains("1 + 1"));
+}